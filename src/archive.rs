@@ -0,0 +1,262 @@
+use crate::crypt::Encryption;
+use crate::layout::{Layout, write_atomic};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the manifest added to every archive.
+pub const MANIFEST: &str = "manifest.json";
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    #[value(name = "tar.gz")]
+    TarGz,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+}
+
+/// What each archive holds.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArchiveScope {
+    /// One archive per device, saved with its files
+    #[default]
+    Device,
+    /// One archive for the whole run, with a directory per device
+    Run,
+}
+
+/// A file to put in an archive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    /// Host name of the device the file came from.
+    pub hostname: String,
+    /// Path inside the archive.
+    pub path: String,
+    pub contents: Vec<u8>,
+}
+
+/// Path of a device's archive.
+pub fn device_archive_path(layout: &Layout, hostname: &str, format: ArchiveFormat) -> PathBuf {
+    layout.file_path(hostname, &format!("backup.{}", format.extension()))
+}
+
+/// Path of the archive for a whole run: named after the backup set if runs
+/// are timestamped.
+pub fn run_archive_path(layout: &Layout, format: ArchiveFormat) -> PathBuf {
+    let name = layout.timestamp.as_deref().unwrap_or("wled-backup");
+    layout
+        .out_dir
+        .join(format!("{name}.{}", format.extension()))
+}
+
+/// Describe the archived files, so an archive can be checked without the
+/// tool that made it.
+fn manifest(entries: &[ArchiveEntry], created: DateTime<Utc>) -> Vec<u8> {
+    let files: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "device": entry.hostname,
+                "path": entry.path,
+                "size": entry.contents.len(),
+            })
+        })
+        .collect();
+
+    let manifest = json!({
+        "created": created.to_rfc3339(),
+        "files": files,
+    });
+    serde_json::to_vec_pretty(&manifest).unwrap()
+}
+
+/// Write the entries, plus a manifest, to a new archive at `path`, encrypted
+/// if `encryption` is given.
+pub fn write_archive(
+    path: &Path,
+    format: ArchiveFormat,
+    entries: &[ArchiveEntry],
+    created: DateTime<Utc>,
+    encryption: Option<&Encryption>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let manifest = manifest(entries, created);
+    let files = entries
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry.contents.as_slice()))
+        .chain(std::iter::once((MANIFEST, manifest.as_slice())));
+
+    // Build the archive in memory, so it can be encrypted before anything
+    // reaches the disk, then replace the old one atomically.
+    let mut contents = write_entries(format, files, created)?;
+    if let Some(encryption) = encryption {
+        contents = encryption.encrypt(&contents)?;
+    }
+    write_atomic(path, &contents)?;
+    Ok(())
+}
+
+fn write_entries<'a>(
+    format: ArchiveFormat,
+    files: impl Iterator<Item = (&'a str, &'a [u8])>,
+    created: DateTime<Utc>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut out = Cursor::new(vec![]);
+    match format {
+        ArchiveFormat::Zip => {
+            let mut zip = zip::ZipWriter::new(&mut out);
+            let options = zip::write::SimpleFileOptions::default()
+                .compression_method(zip::CompressionMethod::Deflated);
+            for (name, contents) in files {
+                zip.start_file(name, options)?;
+                zip.write_all(contents)?;
+            }
+            zip.finish()?.flush()?;
+        }
+        ArchiveFormat::TarGz => {
+            let gz = flate2::write::GzEncoder::new(&mut out, flate2::Compression::default());
+            let mut tar = tar::Builder::new(gz);
+            for (name, contents) in files {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(contents.len() as u64);
+                header.set_mode(0o644);
+                header.set_mtime(created.timestamp().max(0) as u64);
+                header.set_cksum();
+                tar.append_data(&mut header, name, contents)?;
+            }
+            tar.into_inner()?.finish()?.flush()?;
+        }
+    }
+
+    Ok(out.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::Decryption;
+    use crate::layout::{LayoutKind, temp_path};
+    use chrono::TimeZone;
+    use flate2::read::GzDecoder;
+    use std::fs::File;
+    use std::io::Read;
+    use tempfile::tempdir;
+
+    fn entries() -> Vec<ArchiveEntry> {
+        vec![
+            ArchiveEntry {
+                hostname: "porch".to_string(),
+                path: "porch/cfg.json".to_string(),
+                contents: b"cfg data".to_vec(),
+            },
+            ArchiveEntry {
+                hostname: "porch".to_string(),
+                path: "porch/presets.json".to_string(),
+                contents: b"presets data".to_vec(),
+            },
+        ]
+    }
+
+    fn created() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, 15, 3, 4, 5).unwrap()
+    }
+
+    #[test]
+    fn test_write_archive_zip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("backup.zip");
+        write_archive(&path, ArchiveFormat::Zip, &entries(), created(), None).unwrap();
+
+        let mut zip = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mut names: Vec<String> = zip.file_names().map(|n| n.unwrap().to_string()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["manifest.json", "porch/cfg.json", "porch/presets.json"]
+        );
+
+        let mut contents = String::new();
+        zip.by_name("porch/cfg.json")
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "cfg data");
+
+        let mut manifest = String::new();
+        zip.by_name(MANIFEST)
+            .unwrap()
+            .read_to_string(&mut manifest)
+            .unwrap();
+        let manifest: serde_json::Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["created"], "2026-10-15T03:04:05+00:00");
+        assert_eq!(manifest["files"][1]["path"], "porch/presets.json");
+        assert_eq!(manifest["files"][1]["size"], 12);
+        assert!(!temp_path(&path).exists());
+    }
+
+    #[test]
+    fn test_write_archive_encrypted() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("backup.zip.age");
+        let identity = age::x25519::Identity::generate();
+        let encryption = Encryption::Recipients(vec![identity.to_public()]);
+        let (format, entries) = (ArchiveFormat::Zip, entries());
+        write_archive(&path, format, &entries, created(), Some(&encryption)).unwrap();
+
+        let mut decryption = Decryption::default();
+        decryption.add_identity(identity);
+        let contents = decryption.decrypt(&std::fs::read(&path).unwrap()).unwrap();
+        let zip = zip::ZipArchive::new(Cursor::new(contents)).unwrap();
+        assert_eq!(zip.len(), 3);
+    }
+
+    #[test]
+    fn test_write_archive_tar_gz() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("backup.tar.gz");
+        write_archive(&path, ArchiveFormat::TarGz, &entries(), created(), None).unwrap();
+
+        let mut tar = tar::Archive::new(GzDecoder::new(File::open(&path).unwrap()));
+        let mut found = vec![];
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            found.push((entry.path().unwrap().display().to_string(), contents));
+        }
+
+        assert_eq!(found.len(), 3);
+        assert_eq!(
+            found[0],
+            ("porch/cfg.json".to_string(), "cfg data".to_string())
+        );
+        assert_eq!(found[2].0, MANIFEST);
+    }
+
+    #[test]
+    fn test_archive_paths() {
+        let mut layout = Layout::new(LayoutKind::Flat, Path::new("/backup"));
+        assert_eq!(
+            device_archive_path(&layout, "porch", ArchiveFormat::Zip),
+            PathBuf::from("/backup/porch_backup.zip")
+        );
+        assert_eq!(
+            run_archive_path(&layout, ArchiveFormat::TarGz),
+            PathBuf::from("/backup/wled-backup.tar.gz")
+        );
+
+        layout.timestamp = Some("20261015T030405Z".to_string());
+        assert_eq!(
+            run_archive_path(&layout, ArchiveFormat::Zip),
+            PathBuf::from("/backup/20261015T030405Z.zip")
+        );
+    }
+}
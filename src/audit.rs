@@ -0,0 +1,432 @@
+use crate::error::BoxError;
+use crate::http::Fetcher;
+use crate::model::WledCfg;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// The newest WLED release on GitHub.
+pub const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/wled/WLED/releases/latest";
+
+/// A WLED firmware version, such as "0.15.0" or "0.15.0-b3", ordered so
+/// that a prerelease comes before its release.
+#[derive(Debug, Clone)]
+pub struct Version {
+    text: String,
+    numbers: Vec<u64>,
+    /// Such as ("b", 3) for -b3.
+    pre: Option<(String, u64)>,
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        let bare = text.strip_prefix(['v', 'V']).unwrap_or(text);
+        let (release, pre) = match bare.split_once('-') {
+            Some((release, pre)) => (release, Some(pre)),
+            None => (bare, None),
+        };
+        let numbers: Option<Vec<u64>> = release.split('.').map(|n| n.parse().ok()).collect();
+        let numbers = numbers.ok_or_else(|| format!("{text} isn't a version like 0.15.0"))?;
+        let pre = pre.map(|pre| {
+            let digits = pre.trim_start_matches(|c: char| !c.is_ascii_digit());
+            let label = pre[..pre.len() - digits.len()].to_lowercase();
+            (label, digits.parse().unwrap_or(0))
+        });
+        Ok(Version {
+            text: text.to_string(),
+            numbers,
+            pre,
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // 0.15 is the same release as 0.15.0.
+        let len = self.numbers.len().max(other.numbers.len());
+        let number = |numbers: &[u64], i: usize| numbers.get(i).copied().unwrap_or(0);
+        (0..len)
+            .map(|i| number(&self.numbers, i).cmp(&number(&other.numbers, i)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| match (&self.pre, &other.pre) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(pre), Some(other)) => pre.cmp(other),
+            })
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// One device's firmware version, and why it needs attention, if it does.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceVersion {
+    pub device: String,
+    pub version: Option<String>,
+    pub problems: Vec<String>,
+    /// Why its version couldn't be fetched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The firmware versions across the fleet.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct VersionAudit {
+    pub devices: Vec<DeviceVersion>,
+
+    /// The version most devices run.
+    pub common: Option<String>,
+
+    /// Devices older than this are reported.
+    pub minimum: Option<String>,
+
+    /// The newest WLED release, if it was looked up.
+    pub latest: Option<String>,
+}
+
+impl VersionAudit {
+    pub fn is_ok(&self) -> bool {
+        self.devices.iter().all(|device| device.problems.is_empty())
+    }
+}
+
+/// The version most of `devices` run, preferring the newest on a tie.
+fn common_version(devices: &[DeviceVersion]) -> Option<String> {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for version in devices
+        .iter()
+        .filter_map(|device| device.version.as_deref())
+    {
+        *counts.entry(version).or_default() += 1;
+    }
+    let newest = |version: &str| version.parse::<Version>().ok();
+    counts
+        .into_iter()
+        .max_by(|(a, a_count), (b, b_count)| {
+            a_count.cmp(b_count).then_with(|| newest(a).cmp(&newest(b)))
+        })
+        .map(|(version, _)| version.to_string())
+}
+
+/// Note the devices not running the fleet's usual version, older than
+/// `minimum`, or older than the `latest` release.
+pub fn audit_versions(
+    mut devices: Vec<DeviceVersion>,
+    minimum: Option<&Version>,
+    latest: Option<&Version>,
+) -> VersionAudit {
+    let common = common_version(&devices);
+    for device in devices.iter_mut() {
+        let Some(text) = device.version.clone() else {
+            continue;
+        };
+        if let Some(common) = &common
+            && text != *common
+        {
+            device
+                .problems
+                .push(format!("differs from {common}, which most devices run"));
+        }
+        let Ok(version) = text.parse::<Version>() else {
+            device.problems.push(format!("unrecognized version {text}"));
+            continue;
+        };
+        if let Some(minimum) = minimum
+            && version < *minimum
+        {
+            device.problems.push(format!("older than {minimum}"));
+        }
+        if let Some(latest) = latest
+            && version < *latest
+        {
+            device.problems.push(format!("{latest} is available"));
+        }
+    }
+    VersionAudit {
+        devices,
+        common,
+        minimum: minimum.map(Version::to_string),
+        latest: latest.map(Version::to_string),
+    }
+}
+
+/// WLED's factory OTA password.
+pub const DEFAULT_OTA_PASSWORD: &str = "wledota";
+
+/// WLED's factory access point password.
+pub const DEFAULT_AP_PASSWORD: &str = "wled1234";
+
+/// A risky setting found on a device.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    /// Names the check, such as "ota_unlocked", for scripts to filter on.
+    pub check: &'static str,
+    pub message: String,
+}
+
+impl Finding {
+    fn new(check: &'static str, message: &str) -> Self {
+        Finding {
+            check,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// One device's risky settings.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceFindings {
+    pub device: String,
+    pub findings: Vec<Finding>,
+    /// Why its cfg.json couldn't be fetched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The risky settings in a device's `cfg`. `pin` is whether the device
+/// needed a settings PIN to serve it. Passwords are only checked if cfg.json
+/// includes them, as older firmware's does.
+pub fn security_findings(cfg: &WledCfg, pin: bool) -> Vec<Finding> {
+    let mut findings = vec![];
+    if !pin {
+        findings.push(Finding::new(
+            "no_settings_pin",
+            "No settings PIN, so anyone on the network can change its settings",
+        ));
+    }
+    if cfg.ota_locked() == Some(false) {
+        findings.push(Finding::new(
+            "ota_unlocked",
+            "OTA lock is off, so anyone on the network can update its firmware",
+        ));
+    }
+    match cfg.ota_password() {
+        Some("") => findings.push(Finding::new("ota_password_empty", "OTA password is empty")),
+        Some(DEFAULT_OTA_PASSWORD) => findings.push(Finding::new(
+            "ota_password_default",
+            "OTA password is the default, wledota",
+        )),
+        _ => {}
+    }
+    if cfg.arduino_ota() == Some(true) {
+        findings.push(Finding::new(
+            "arduino_ota",
+            "ArduinoOTA is on, accepting firmware uploads from the Arduino IDE",
+        ));
+    }
+    if cfg.ap_behavior() == Some(2) {
+        findings.push(Finding::new(
+            "ap_always_on",
+            "Access point is always on, even when connected to Wi-Fi",
+        ));
+    }
+    // The access point never opens at 3, so its password doesn't matter.
+    if cfg.ap_behavior() != Some(3) {
+        if cfg.ap_password() == Some("") || cfg.ap_password_len() == Some(0) {
+            findings.push(Finding::new(
+                "ap_open",
+                "Access point has no password, so anyone nearby can join it",
+            ));
+        } else if cfg.ap_password() == Some(DEFAULT_AP_PASSWORD) {
+            findings.push(Finding::new(
+                "ap_password_default",
+                "Access point password is the default, wled1234",
+            ));
+        }
+    }
+    if cfg.mqtt_enabled() == Some(true)
+        && (cfg.mqtt_user().is_none_or(str::is_empty) || cfg.mqtt_password_len() == Some(0))
+    {
+        findings.push(Finding::new(
+            "mqtt_no_auth",
+            "MQTT is on without a username and password",
+        ));
+    }
+    findings
+}
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+}
+
+/// The newest release at `url`, a GitHub releases/latest API URL such as
+/// [`LATEST_RELEASE_URL`].
+pub async fn latest_release(fetcher: &Fetcher, url: &str) -> Result<Version, BoxError> {
+    let body = fetcher
+        .client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let release: Release =
+        serde_json::from_slice(&body).map_err(|e| format!("Invalid release from {url}: {e}"))?;
+    Ok(release.tag_name.parse()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::*;
+
+    fn version(text: &str) -> Version {
+        text.parse().unwrap()
+    }
+
+    fn device(name: &str, version: Option<&str>) -> DeviceVersion {
+        DeviceVersion {
+            device: name.to_string(),
+            version: version.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_version_order() {
+        assert!(version("0.14.4") < version("0.15.0"));
+        assert!(version("0.15.0-b3") < version("0.15.0"));
+        assert!(version("0.15.0-b3") < version("0.15.0-b10"));
+        assert!(version("0.15.0-b3") < version("0.15.0-rc1"));
+        assert!(version("0.9.1") < version("0.10.0"));
+        assert_eq!(version("v0.15").cmp(&version("0.15.0")), Ordering::Equal);
+        assert_eq!(version("v0.15.0").to_string(), "v0.15.0");
+        assert!("latest".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn test_audit_versions() {
+        let devices = vec![
+            device("porch", Some("0.15.0")),
+            device("garden", Some("0.15.0")),
+            device("attic", Some("0.13.3")),
+            device("garage", None),
+        ];
+        let audit = audit_versions(devices, Some(&version("0.14.0")), Some(&version("0.15.1")));
+
+        assert_eq!(audit.common.as_deref(), Some("0.15.0"));
+        assert_eq!(audit.latest.as_deref(), Some("0.15.1"));
+        assert_eq!(audit.devices[0].problems, ["0.15.1 is available"]);
+        assert_eq!(
+            audit.devices[2].problems,
+            [
+                "differs from 0.15.0, which most devices run",
+                "older than 0.14.0",
+                "0.15.1 is available"
+            ]
+        );
+        assert!(audit.devices[3].problems.is_empty());
+        assert!(!audit.is_ok());
+
+        // Every device the same, and nothing to compare against.
+        let audit = audit_versions(
+            vec![
+                device("porch", Some("0.15.0")),
+                device("garden", Some("0.15.0")),
+            ],
+            None,
+            None,
+        );
+        assert!(audit.is_ok());
+
+        // A tie goes to the newer version.
+        let audit = audit_versions(
+            vec![
+                device("porch", Some("0.14.4")),
+                device("garden", Some("0.15.0")),
+            ],
+            None,
+            None,
+        );
+        assert_eq!(audit.common.as_deref(), Some("0.15.0"));
+    }
+
+    #[test]
+    fn test_security_findings() {
+        let cfg =
+            |contents: serde_json::Value| WledCfg::parse(contents.to_string().as_bytes()).unwrap();
+        let checks = |cfg: &WledCfg, pin: bool| -> Vec<&str> {
+            security_findings(cfg, pin)
+                .iter()
+                .map(|finding| finding.check)
+                .collect()
+        };
+
+        let risky = cfg(serde_json::json!({
+            "ota": {"lock": false, "pwd": "wledota", "aota": true},
+            "ap": {"behav": 2, "psk": "wled1234", "pskl": 8},
+            "if": {"mqtt": {"en": true, "user": "", "pskl": 0}},
+        }));
+        assert_eq!(
+            checks(&risky, false),
+            [
+                "no_settings_pin",
+                "ota_unlocked",
+                "ota_password_default",
+                "arduino_ota",
+                "ap_always_on",
+                "ap_password_default",
+                "mqtt_no_auth",
+            ]
+        );
+
+        let safe = cfg(serde_json::json!({
+            "ota": {"lock": true, "aota": false},
+            "ap": {"behav": 0, "pskl": 12},
+            "if": {"mqtt": {"en": true, "user": "wled", "pskl": 16}},
+        }));
+        assert!(checks(&safe, true).is_empty());
+
+        // An access point that never opens can't be joined.
+        let open_ap = cfg(serde_json::json!({"ap": {"behav": 1, "pskl": 0}}));
+        assert_eq!(checks(&open_ap, true), ["ap_open"]);
+        let never = cfg(serde_json::json!({"ap": {"behav": 3, "pskl": 0}}));
+        assert!(checks(&never, true).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_latest_release() {
+        let server = mock_routes_server(
+            "127.0.0.1:151",
+            &[("/latest", r#"{"tag_name": "v0.15.1", "name": "Kōsen"}"#)],
+        );
+
+        let fetcher = Fetcher::default();
+        let latest = latest_release(&fetcher, "http://127.0.0.1:151/latest")
+            .await
+            .unwrap();
+        assert_eq!(latest, version("v0.15.1"));
+        assert!(
+            latest_release(&fetcher, "http://127.0.0.1:151/missing")
+                .await
+                .is_err()
+        );
+
+        server.join().unwrap();
+    }
+}
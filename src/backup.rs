@@ -0,0 +1,2908 @@
+use crate::archive::{
+    ArchiveEntry, ArchiveFormat, ArchiveScope, device_archive_path, run_archive_path, write_archive,
+};
+use crate::compress::Compression;
+use crate::crypt::{Encryption, age_path};
+use crate::device::{Device, DeviceOptions, normalize_mac};
+use crate::error::{BackupError, BoxError};
+use crate::filter::DeviceFilter;
+use crate::http::{self, Fetcher};
+use crate::layout::{LATEST, Layout, sanitize_name, write_atomic};
+use crate::manifest::{FileSource, ManifestFile, RunManifest, manifest_path};
+use crate::meta::{DeviceMeta, META_FILE};
+use crate::model::{FsEntry, Presets, WledCfg, WledInfo};
+use crate::progress;
+use crate::redact::{SECRETS_FILE, SecretHandling, has_secrets, merge, split_file};
+use crate::report::{DeviceReport, DeviceStatus};
+use crate::storage::StreamTarget;
+use futures::{Stream, StreamExt, stream};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use tracing::{Instrument, debug, error, error_span, info, warn};
+
+pub fn get_hostname_from_cfg(cfg: &WledCfg) -> Result<&str, BackupError> {
+    let missing =
+        |field: &str| BackupError::ParseCfg(format!("Missing '{field}' field in cfg.json"));
+    let hostname = cfg
+        .id
+        .as_ref()
+        .ok_or_else(|| missing("id"))?
+        .name
+        .as_deref()
+        .ok_or_else(|| missing("name"))?;
+
+    if hostname.trim().is_empty() {
+        return Err(BackupError::ParseCfg(
+            "Hostname is empty or contains only whitespace".to_string(),
+        ));
+    }
+
+    Ok(hostname)
+}
+
+/// Runtime JSON API endpoints that can be saved alongside the configuration.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    /// /json/info, firmware and hardware details
+    Info,
+    /// /json/state, the current on/brightness/segment state
+    State,
+    /// /json/eff, effect names
+    Eff,
+    /// /json/pal, palette names
+    Pal,
+}
+
+impl Endpoint {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Endpoint::Info => "info",
+            Endpoint::State => "state",
+            Endpoint::Eff => "eff",
+            Endpoint::Pal => "pal",
+        }
+    }
+
+    pub fn url_path(&self) -> String {
+        format!("/json/{}", self.name())
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("{}.json", self.name())
+    }
+}
+
+/// Parts of a backup that can be saved on their own.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Part {
+    /// cfg.json, the device settings
+    Cfg,
+    /// presets.json
+    Presets,
+    /// 2D LED maps, ledmap.json to ledmap9.json
+    Ledmaps,
+    /// Custom palettes, palette0.json to palette9.json
+    Palettes,
+    /// /json/info
+    Info,
+    /// /json/state
+    State,
+    /// /json/eff
+    Eff,
+    /// /json/pal
+    Pal,
+}
+
+impl Part {
+    /// The endpoint this part is saved from, if it's one.
+    pub fn endpoint(&self) -> Option<Endpoint> {
+        match self {
+            Part::Info => Some(Endpoint::Info),
+            Part::State => Some(Endpoint::State),
+            Part::Eff => Some(Endpoint::Eff),
+            Part::Pal => Some(Endpoint::Pal),
+            Part::Cfg | Part::Presets | Part::Ledmaps | Part::Palettes => None,
+        }
+    }
+}
+
+/// Requests to one device, with failures reported as [`BackupError::Http`].
+pub(crate) struct DeviceHttp<'a> {
+    fetcher: &'a Fetcher,
+    device: SocketAddr,
+    base_url: String,
+    options: &'a DeviceOptions,
+}
+
+impl<'a> DeviceHttp<'a> {
+    pub(crate) fn new(
+        fetcher: &'a Fetcher,
+        ip: &IpAddr,
+        port: u16,
+        options: &'a DeviceOptions,
+    ) -> Self {
+        DeviceHttp {
+            fetcher,
+            device: SocketAddr::new(*ip, port),
+            base_url: options.base_url(ip, port),
+            options,
+        }
+    }
+
+    /// Send the settings PIN, if the device has one, before asking for
+    /// cfg.json.
+    pub(crate) async fn unlock(&self) -> Result<(), BackupError> {
+        let url = format!("{}/json/state", self.base_url);
+        http::unlock(&self.fetcher.client, &self.base_url, self.options)
+            .await
+            .map_err(self.http_error(url))
+    }
+
+    fn http_error(&self, url: String) -> impl FnOnce(BoxError) -> BackupError {
+        let device = self.device.to_string();
+        move |source| BackupError::Http {
+            device,
+            url,
+            source,
+        }
+    }
+
+    /// Download `path`, such as "/cfg.json".
+    pub(crate) async fn get(&self, path: &str) -> Result<Vec<u8>, BackupError> {
+        let url = format!("{}{path}", self.base_url);
+        let download = async {
+            let mut response = self
+                .fetcher
+                .get(&url, self.options)
+                .await?
+                .error_for_status()?;
+            let len = response.content_length().unwrap_or_default();
+            if len < progress::LARGE_DOWNLOAD {
+                return Ok(response.bytes().await?.to_vec());
+            }
+
+            let bar = progress::download(&url, len);
+            let mut body = vec![];
+            while let Some(chunk) = response.chunk().await? {
+                body.extend_from_slice(&chunk);
+                bar.set_position(body.len() as u64);
+            }
+            bar.finish_and_clear();
+            Ok::<_, BoxError>(body)
+        };
+        download.await.map_err(self.http_error(url))
+    }
+
+    /// Send `body`, a JSON document, to `path`, such as "/json/cfg".
+    pub(crate) async fn post_json(&self, path: &str, body: String) -> Result<(), BackupError> {
+        let url = format!("{}{path}", self.base_url);
+        let post = async {
+            let request = self
+                .fetcher
+                .client
+                .post(&url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body);
+            self.options
+                .apply(request)
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok::<_, BoxError>(())
+        };
+        post.await.map_err(self.http_error(url))
+    }
+
+    /// Start downloading `path`, to read as it arrives, or None if the
+    /// device doesn't have it and `optional`.
+    async fn open(
+        &self,
+        path: &str,
+        optional: bool,
+    ) -> Result<Option<reqwest::Response>, BackupError> {
+        let url = format!("{}{path}", self.base_url);
+        let open = async {
+            let response = self.fetcher.get(&url, self.options).await?;
+            if optional && response.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            Ok::<_, BoxError>(Some(response.error_for_status()?))
+        };
+        open.await.map_err(self.http_error(url))
+    }
+
+    /// Download `path`, or None if the device doesn't have it.
+    pub(crate) async fn get_optional(&self, path: &str) -> Result<Option<Vec<u8>>, BackupError> {
+        let url = format!("{}{path}", self.base_url);
+        self.fetcher
+            .get_optional(&url, self.options)
+            .await
+            .map_err(self.http_error(url))
+    }
+}
+
+/// Ask a WLED for /json/info.
+pub async fn fetch_info(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+) -> Result<WledInfo, BackupError> {
+    let http = DeviceHttp::new(fetcher, ip, port, options);
+    let contents = http.get(&Endpoint::Info.url_path()).await?;
+    serde_json::from_slice(&contents)
+        .map_err(|e| BackupError::Invalid(format!("Invalid /json/info: {e}")))
+}
+
+/// Ask a WLED for its cfg.json.
+pub async fn fetch_cfg(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+) -> Result<WledCfg, BackupError> {
+    let http = DeviceHttp::new(fetcher, ip, port, options);
+    http.unlock().await?;
+    let contents = http.get("/cfg.json").await?;
+    WledCfg::parse(&contents).map_err(|e| BackupError::ParseCfg(e.to_string()))
+}
+
+/// Ask a WLED for its firmware version, from /json/info.
+pub async fn fetch_version(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+) -> Result<String, BackupError> {
+    fetch_info(fetcher, ip, port, options)
+        .await?
+        .ver
+        .ok_or_else(|| BackupError::Invalid("Missing 'ver' field in /json/info".to_string()))
+}
+
+/// Names devices' files are saved under in this run, so that two devices
+/// with the same host name, such as the factory default "WLED", don't
+/// overwrite each other's backups.
+#[derive(Debug, Default)]
+pub struct SavedNames(Mutex<HashSet<String>>);
+
+impl SavedNames {
+    /// Take a name for this run, unless another device already has it. Case
+    /// is ignored, for case insensitive filesystems.
+    fn claim(&self, name: &str) -> bool {
+        self.0.lock().unwrap().insert(name.to_lowercase())
+    }
+
+    /// Give back a name claimed by a failed attempt.
+    fn release(&self, name: &str) {
+        self.0.lock().unwrap().remove(&name.to_lowercase());
+    }
+}
+
+/// What to do when a device has the same host name as another device
+/// already backed up in the run.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Collision {
+    /// Fail the device's backup
+    Error,
+    /// Add the end of its MAC to its file names, or its address if the MAC
+    /// is unknown
+    #[default]
+    SuffixMac,
+    /// Add its address to its file names
+    SuffixIp,
+}
+
+/// The name to save a device's files under: its host name, unless another
+/// device in the run already has that. Then the end of its MAC or its
+/// address is added, as in "WLED-d3e4f5", if `collision` allows.
+async fn claim_name(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    names: &SavedNames,
+    hostname: &str,
+    collision: Collision,
+) -> Result<String, BackupError> {
+    if names.claim(hostname) {
+        return Ok(hostname.to_string());
+    }
+
+    let mac = match collision {
+        Collision::Error => {
+            return Err(BackupError::Collision(hostname.to_string()));
+        }
+        Collision::SuffixMac => fetch_info(fetcher, ip, port, options)
+            .await
+            .ok()
+            .and_then(|info| info.mac)
+            .map(|mac| normalize_mac(&mac))
+            .filter(|mac| mac.len() == 12),
+        Collision::SuffixIp => None,
+    };
+    let suffix = match mac {
+        Some(mac) => mac[6..].to_string(),
+        None => ip.to_string().replace(['.', ':'], "-"),
+    };
+    let name = format!("{hostname}-{suffix}");
+    names.claim(&name);
+    warn!("another device is already named {hostname}, saving as {name}");
+    Ok(name)
+}
+
+/// Check presets.json is a JSON object of presets, so a captive portal's HTML page or a
+/// truncated download isn't saved as the backup.
+pub fn validate_presets(contents: &[u8]) -> Result<(), BackupError> {
+    Presets::parse(contents).map_err(|e| BackupError::Invalid(e.to_string()))?;
+    Ok(())
+}
+
+/// Re-serialize JSON indented, with its keys sorted, so backups diff
+/// readably.
+pub fn pretty_json(contents: &[u8]) -> Result<Vec<u8>, serde_json::Error> {
+    let value: Value = serde_json::from_slice(contents)?;
+    let mut pretty = serde_json::to_vec_pretty(&value)?;
+    pretty.push(b'\n');
+    Ok(pretty)
+}
+
+/// LED map files for 2D and mapped installs: ledmap.json, then ledmap1.json
+/// to ledmap9.json.
+pub fn ledmap_file_names() -> Vec<String> {
+    std::iter::once("ledmap.json".to_string())
+        .chain((1..10).map(|n| format!("ledmap{n}.json")))
+        .collect()
+}
+
+/// Custom palette files: palette0.json to palette9.json.
+pub fn palette_file_names() -> Vec<String> {
+    (0..10).map(|n| format!("palette{n}.json")).collect()
+}
+
+/// File names from a `/edit?list=/` filesystem listing, such as
+/// `[{"type":"file","name":"/cfg.json","size":1024}]`. Directories, and
+/// names that could escape the backup directory, are skipped.
+pub fn parse_fs_listing(listing: &[u8]) -> Result<Vec<String>, BackupError> {
+    let entries: Vec<FsEntry> = serde_json::from_slice(listing)
+        .map_err(|e| BackupError::Invalid(format!("Invalid filesystem listing: {e}")))?;
+
+    let mut names = vec![];
+    for entry in entries.iter() {
+        if entry.kind == "dir" {
+            continue;
+        }
+        let name = entry.name.trim_start_matches('/');
+        if name.is_empty() || name.contains('/') || name.contains('\\') || name == ".." {
+            continue;
+        }
+        names.push(name.to_string());
+    }
+
+    Ok(names)
+}
+
+/// Settings for a backup run, shared by every device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackupSettings {
+    pub layout: Layout,
+
+    /// Save files even if they match the previous backup.
+    pub force: bool,
+
+    /// Extra endpoints to save.
+    pub endpoints: Vec<Endpoint>,
+
+    /// Save every file on the device's filesystem.
+    pub full_fs: bool,
+
+    /// Also bundle the saved files into archives.
+    pub archive: Option<ArchiveFormat>,
+    pub archive_scope: ArchiveScope,
+
+    /// Which devices to back up.
+    pub filter: DeviceFilter,
+
+    /// What to do when two devices have the same host name.
+    pub on_collision: Collision,
+
+    /// Save cfg.json and presets.json indented, with sorted keys.
+    pub pretty: bool,
+
+    /// Whether to keep passwords and keys in cfg.json, leave them out, or
+    /// save them separately.
+    pub secrets: SecretHandling,
+
+    /// Compress saved JSON files, adding .zst or .gz to their names.
+    pub compression: Option<Compression>,
+
+    /// Encrypt every file saved, adding .age to its name.
+    pub encryption: Option<Encryption>,
+
+    /// Fetch cfg.json and presets.json again after saving them, and fail if
+    /// they differ from what was saved.
+    pub verify: bool,
+
+    /// Find each device's name, and the files it would be saved as, but
+    /// download and save nothing else.
+    pub dry_run: bool,
+
+    /// Save only these parts, or everything if empty. cfg.json is still
+    /// downloaded for the device's name.
+    pub only: Vec<Part>,
+
+    /// Start no more backups once a device fails.
+    pub fail_fast: bool,
+
+    /// Send each file straight to this destination as it downloads, instead
+    /// of saving it in out_dir.
+    pub stream: Option<StreamTarget>,
+}
+
+impl BackupSettings {
+    pub fn new(layout: Layout) -> Self {
+        BackupSettings {
+            layout,
+            force: false,
+            endpoints: vec![],
+            full_fs: false,
+            archive: None,
+            archive_scope: ArchiveScope::Device,
+            filter: DeviceFilter::default(),
+            on_collision: Collision::default(),
+            pretty: false,
+            secrets: SecretHandling::default(),
+            compression: None,
+            encryption: None,
+            verify: false,
+            dry_run: false,
+            only: vec![],
+            fail_fast: false,
+            stream: None,
+        }
+    }
+
+    /// Is `part` to be saved?
+    pub fn saves(&self, part: Part) -> bool {
+        self.only.is_empty() || self.only.contains(&part)
+    }
+
+    /// The endpoints to save: those asked for, and any picked by `only`.
+    pub fn all_endpoints(&self) -> Vec<Endpoint> {
+        let mut endpoints = self.endpoints.clone();
+        for endpoint in self.only.iter().filter_map(Part::endpoint) {
+            if !endpoints.contains(&endpoint) {
+                endpoints.push(endpoint);
+            }
+        }
+        endpoints
+    }
+}
+
+/// The previous backup of a file, saved at `path` before any compression,
+/// decompressed.
+fn read_previous(settings: &BackupSettings, path: PathBuf) -> Option<Vec<u8>> {
+    let contents = fs::read(Compression::saved_path(settings.compression, &path)).ok()?;
+    match compression_for(settings, &path) {
+        Some(compression) => compression.decompress(&contents).ok(),
+        None => Some(contents),
+    }
+}
+
+/// How the file at `path` is compressed, if at all.
+fn compression_for(settings: &BackupSettings, path: &Path) -> Option<Compression> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    settings
+        .compression
+        .filter(|_| Compression::applies_to(&name))
+}
+
+/// Compress and then encrypt a file's contents, as the settings ask. Returns
+/// the path to save them at, with .zst, .gz or .age added.
+fn encode_file(
+    settings: &BackupSettings,
+    path: PathBuf,
+    contents: &[u8],
+) -> Result<(PathBuf, Vec<u8>), BackupError> {
+    let mut contents = contents.to_vec();
+    let mut path = path;
+    if let Some(compression) = compression_for(settings, &path) {
+        path = Compression::saved_path(Some(compression), &path);
+        contents = compression
+            .compress(&contents)
+            .map_err(BackupError::io(&path))?;
+    }
+
+    match &settings.encryption {
+        Some(encryption) => {
+            let encrypted =
+                encryption
+                    .encrypt(&contents)
+                    .map_err(|source| BackupError::Encrypt {
+                        path: path.clone(),
+                        source: source.into(),
+                    })?;
+            Ok((age_path(&path), encrypted))
+        }
+        None => Ok((path, contents)),
+    }
+}
+
+/// Where a file is saved: `path`, with .age added if encrypting.
+fn encrypted_path(settings: &BackupSettings, path: PathBuf) -> PathBuf {
+    match settings.encryption {
+        Some(_) => age_path(&path),
+        None => path,
+    }
+}
+
+/// Fetch cfg.json and presets.json again, and check they match the saved
+/// copies, to catch a body cut short mid-download. They're compared as JSON,
+/// with secrets removed as they were when saved.
+async fn read_back(
+    http: &DeviceHttp<'_>,
+    settings: &BackupSettings,
+    files: &[FetchedFile],
+) -> Result<(), BackupError> {
+    let invalid =
+        |name: &str, e: serde_json::Error| BackupError::Invalid(format!("Invalid {name}: {e}"));
+    for file in files
+        .iter()
+        .filter(|file| file.name == "cfg.json" || file.name == "presets.json")
+    {
+        let mut contents = http.get(&format!("/{}", file.name)).await?;
+        if settings.secrets != SecretHandling::Keep && has_secrets(&file.name) {
+            contents = split_file(&contents).map_err(|e| invalid(&file.name, e))?.0;
+        }
+
+        let saved: Value =
+            serde_json::from_slice(&file.contents).map_err(|e| invalid(&file.name, e))?;
+        let fetched: Value =
+            serde_json::from_slice(&contents).map_err(|e| invalid(&file.name, e))?;
+        if saved != fetched {
+            return Err(BackupError::Invalid(format!(
+                "{} read back differently, so the saved copy may be incomplete",
+                file.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Where a backup would save a device's files, found without downloading
+/// them. The LED maps and palettes come from the device's file listing, so
+/// firmware too old to list its files is assumed to have none.
+async fn planned_files(
+    http: &DeviceHttp<'_>,
+    options: &DeviceOptions,
+    settings: &BackupSettings,
+    hostname: &str,
+) -> Result<Vec<PathBuf>, BackupError> {
+    let mut names = vec![];
+    if settings.saves(Part::Cfg) {
+        names.push("cfg.json".to_string());
+    }
+    if settings.saves(Part::Presets) && !options.skip_presets {
+        names.push("presets.json".to_string());
+    }
+
+    let listing = if settings.full_fs {
+        Some(http.get("/edit?list=/").await?)
+    } else {
+        http.get_optional("/edit?list=/").await?
+    };
+    let on_device = match listing {
+        Some(listing) => parse_fs_listing(&listing)?,
+        None => vec![],
+    };
+    let mut optional: Vec<String> = vec![];
+    if settings.saves(Part::Ledmaps) {
+        optional.extend(ledmap_file_names());
+    }
+    if settings.saves(Part::Palettes) {
+        optional.extend(palette_file_names());
+    }
+    for name in on_device {
+        let skipped = options.skip_presets && name == "presets.json";
+        if (settings.full_fs || optional.contains(&name)) && !skipped && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    names.extend(settings.all_endpoints().iter().map(Endpoint::file_name));
+    names.push(META_FILE.to_string());
+    if settings.secrets == SecretHandling::Separate && settings.saves(Part::Cfg) {
+        names.push(SECRETS_FILE.to_string());
+    }
+
+    let layout = &settings.layout;
+    let mut paths: Vec<PathBuf> = names
+        .iter()
+        .map(|name| {
+            let path = layout.file_path(hostname, name);
+            encrypted_path(
+                settings,
+                Compression::saved_path(settings.compression, &path),
+            )
+        })
+        .collect();
+    if let (Some(format), ArchiveScope::Device) = (settings.archive, settings.archive_scope) {
+        paths.push(encrypted_path(
+            settings,
+            device_archive_path(layout, hostname, format),
+        ));
+    }
+    Ok(paths)
+}
+
+/// A downloaded file, waiting to be saved.
+struct FetchedFile {
+    name: String,
+    contents: Vec<u8>,
+
+    /// Runtime data, like uptime, changes constantly, so it doesn't count
+    /// when deciding if a device changed.
+    runtime: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackupStatus {
+    /// At least one file differed from the previous backup, and was saved.
+    Saved,
+    /// Every file matched the previous backup.
+    Unchanged,
+}
+
+/// The outcome of backing up one device.
+pub struct DeviceBackup {
+    pub hostname: String,
+    pub status: BackupStatus,
+    files: Vec<FetchedFile>,
+
+    /// Files written, including any archive.
+    pub saved: Vec<PathBuf>,
+
+    /// Files left in place because they matched what the device has.
+    pub kept: Vec<PathBuf>,
+
+    /// Where the files came from, for the run's manifest.
+    pub source: FileSource,
+
+    /// Files a dry run would have saved.
+    pub planned: Vec<PathBuf>,
+
+    /// Files streamed to the destination, relative to out_dir.
+    pub uploaded: Vec<ManifestFile>,
+}
+
+impl DeviceBackup {
+    /// The device's files, to archive under `prefix`. Separated secrets are
+    /// left out, so the archive is as safe to share as the backup.
+    fn archive_entries(&self, prefix: &str) -> Vec<ArchiveEntry> {
+        self.files
+            .iter()
+            .filter(|file| file.name != SECRETS_FILE)
+            .map(|file| ArchiveEntry {
+                hostname: self.hostname.clone(),
+                path: format!("{prefix}{}", file.name),
+                contents: file.contents.clone(),
+            })
+            .collect()
+    }
+}
+
+pub async fn backup_wled(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    device: &Device,
+    settings: &BackupSettings,
+    names: &SavedNames,
+) -> Result<DeviceBackup, BackupError> {
+    let (port, options) = (device.port, &device.options);
+    let http = DeviceHttp::new(fetcher, ip, port, options);
+    let fetched = chrono::Utc::now();
+
+    http.unlock().await?;
+    let cfg_contents = http.get("/cfg.json").await?;
+    let cfg = WledCfg::parse(&cfg_contents).map_err(|e| BackupError::ParseCfg(e.to_string()))?;
+
+    let hostname = get_hostname_from_cfg(&cfg)?.to_string();
+    debug!("host name: {hostname}");
+
+    // The name goes into file paths, so it mustn't lead out of out_dir.
+    let hostname = sanitize_name(&hostname);
+    let collision = settings.on_collision;
+    let hostname = claim_name(fetcher, ip, port, options, names, &hostname, collision).await?;
+
+    // A failed attempt gives the name back, so the device's next address
+    // saves under it too.
+    let result = save_wled(
+        &http,
+        ip,
+        device,
+        settings,
+        &hostname,
+        cfg_contents,
+        fetched,
+    )
+    .await;
+    if result.is_err() {
+        names.release(&hostname);
+    }
+    result
+}
+
+/// Fetch and save the rest of a device's backup, under its claimed name.
+async fn save_wled(
+    http: &DeviceHttp<'_>,
+    ip: &IpAddr,
+    device: &Device,
+    settings: &BackupSettings,
+    hostname: &str,
+    cfg_contents: Vec<u8>,
+    fetched: chrono::DateTime<chrono::Utc>,
+) -> Result<DeviceBackup, BackupError> {
+    let (fetcher, port, options) = (http.fetcher, device.port, &device.options);
+    let layout = &settings.layout;
+
+    if settings.dry_run {
+        let planned = planned_files(http, options, settings, hostname).await?;
+        for path in planned.iter() {
+            info!("would save: {}", layout.display_path(path));
+        }
+        return Ok(DeviceBackup {
+            hostname: hostname.to_string(),
+            status: BackupStatus::Saved,
+            files: vec![],
+            saved: vec![],
+            kept: vec![],
+            source: FileSource {
+                device: hostname.to_string(),
+                address: ip.to_string(),
+                fetched: fetched.to_rfc3339(),
+                ..Default::default()
+            },
+            planned,
+            uploaded: vec![],
+        });
+    }
+
+    if let Some(target) = &settings.stream {
+        let uploaded = stream_wled(http, target, settings, hostname, cfg_contents).await?;
+        let source = stream_meta(http, target, layout, device, ip, hostname, fetched).await?;
+        return Ok(DeviceBackup {
+            hostname: hostname.to_string(),
+            status: BackupStatus::Saved,
+            files: vec![],
+            saved: vec![],
+            kept: vec![],
+            uploaded: uploaded.into_iter().chain([source.0]).collect(),
+            source: source.1,
+            planned: vec![],
+        });
+    }
+
+    // Fetch everything before saving anything, so a failed download doesn't
+    // leave a partial backup.
+    let mut files = vec![];
+    if settings.saves(Part::Cfg) {
+        files.push(FetchedFile {
+            name: "cfg.json".to_string(),
+            contents: cfg_contents,
+            runtime: false,
+        });
+    }
+
+    if options.skip_presets {
+        info!("skipped: presets.json");
+    } else if settings.saves(Part::Presets) {
+        let contents = http.get("/presets.json").await?;
+        validate_presets(&contents)?;
+        files.push(FetchedFile {
+            name: "presets.json".to_string(),
+            contents,
+            runtime: false,
+        });
+    }
+
+    // Most devices have no LED maps, so missing files are fine.
+    let ledmaps = match settings.saves(Part::Ledmaps) {
+        true => ledmap_file_names(),
+        false => vec![],
+    };
+    for ledmap in ledmaps {
+        let path = format!("/edit?download=/{ledmap}");
+        if let Some(contents) = http.get_optional(&path).await? {
+            files.push(FetchedFile {
+                name: ledmap,
+                contents,
+                runtime: false,
+            });
+        }
+    }
+
+    // Likewise most devices have no custom palettes.
+    let palettes = match settings.saves(Part::Palettes) {
+        true => palette_file_names(),
+        false => vec![],
+    };
+    for palette in palettes {
+        if let Some(contents) = http.get_optional(&format!("/{palette}")).await? {
+            files.push(FetchedFile {
+                name: palette,
+                contents,
+                runtime: false,
+            });
+        }
+    }
+
+    if settings.full_fs {
+        let listing = http.get("/edit?list=/").await?;
+
+        for name in parse_fs_listing(&listing)? {
+            let skipped = options.skip_presets && name == "presets.json";
+            if skipped || files.iter().any(|file| file.name == name) {
+                continue;
+            }
+            let contents = http.get(&format!("/edit?download=/{name}")).await?;
+            files.push(FetchedFile {
+                name,
+                contents,
+                runtime: false,
+            });
+        }
+    }
+
+    for endpoint in settings.all_endpoints().iter() {
+        files.push(FetchedFile {
+            name: endpoint.file_name(),
+            contents: http.get(&endpoint.url_path()).await?,
+            runtime: true,
+        });
+    }
+
+    // The firmware version and MAC go in the manifest. Older firmware may not
+    // answer /json/info, which is fine.
+    let info = match files.iter().find(|f| f.name == Endpoint::Info.file_name()) {
+        Some(file) => serde_json::from_slice::<WledInfo>(&file.contents).ok(),
+        None => fetch_info(fetcher, ip, port, options).await.ok(),
+    };
+    let meta = DeviceMeta::new(device, ip, hostname, info.as_ref(), fetched);
+    files.push(FetchedFile {
+        name: META_FILE.to_string(),
+        contents: serde_json::to_vec_pretty(&meta)
+            .map_err(|e| BackupError::Invalid(format!("Invalid {META_FILE}: {e}")))?,
+        runtime: true,
+    });
+    let source = FileSource {
+        device: hostname.to_string(),
+        address: ip.to_string(),
+        mac: info
+            .as_ref()
+            .and_then(|info| info.mac.as_deref())
+            .map(normalize_mac),
+        version: info.and_then(|info| info.ver),
+        fetched: fetched.to_rfc3339(),
+    };
+
+    if settings.secrets != SecretHandling::Keep {
+        let mut secrets = Value::Object(Default::default());
+        for file in files.iter_mut().filter(|file| has_secrets(&file.name)) {
+            let (contents, taken) = split_file(&file.contents)
+                .map_err(|e| BackupError::Invalid(format!("Invalid {}: {e}", file.name)))?;
+            file.contents = contents;
+            merge(&mut secrets, taken);
+        }
+        // Without cfg.json there are no secrets to save, and the saved ones
+        // are kept.
+        if settings.secrets == SecretHandling::Separate && settings.saves(Part::Cfg) {
+            files.push(FetchedFile {
+                name: SECRETS_FILE.to_string(),
+                contents: secrets.to_string().into_bytes(),
+                runtime: false,
+            });
+        }
+    }
+
+    if settings.pretty {
+        for file in files
+            .iter_mut()
+            .filter(|file| file.name == "cfg.json" || file.name == "presets.json")
+        {
+            file.contents = pretty_json(&file.contents)
+                .map_err(|e| BackupError::Invalid(format!("Invalid {}: {e}", file.name)))?;
+        }
+    }
+
+    // Compare against the previous backup of each file. Encrypted files
+    // differ every time, so they are always saved.
+    let changed: Vec<bool> = files
+        .iter()
+        .map(|file| {
+            settings.force
+                || settings.encryption.is_some()
+                || file.runtime
+                || read_previous(settings, layout.previous_file_path(hostname, &file.name))
+                    .is_none_or(|previous| previous != file.contents)
+        })
+        .collect();
+
+    let status = if files
+        .iter()
+        .zip(changed.iter())
+        .any(|(file, changed)| *changed && !file.runtime)
+    {
+        BackupStatus::Saved
+    } else {
+        BackupStatus::Unchanged
+    };
+
+    // A device's timestamped backup set is only worth creating if something
+    // changed. Runs sharing one set need every device's files in it.
+    let mut backup = DeviceBackup {
+        hostname: hostname.to_string(),
+        status,
+        files,
+        saved: vec![],
+        kept: vec![],
+        source,
+        planned: vec![],
+        uploaded: vec![],
+    };
+    if status == BackupStatus::Unchanged && !layout.shares_sets() && layout.timestamp.is_some() {
+        info!("unchanged: keeping previous backup set");
+
+        // The previous set still holds the device's backup, so the manifest
+        // lists its files.
+        if let Ok(previous) = fs::read_link(layout.sets_dir(hostname).join(LATEST)) {
+            let previous = Layout {
+                timestamp: Some(previous.to_string_lossy().into_owned()),
+                ..layout.clone()
+            };
+            backup.kept = backup
+                .files
+                .iter()
+                .map(|file| previous.file_path(hostname, &file.name))
+                .map(|path| Compression::saved_path(settings.compression, &path))
+                .filter(|path| path.exists())
+                .collect();
+        }
+        return Ok(backup);
+    }
+
+    layout
+        .create_device_dir(hostname)
+        .map_err(BackupError::io(layout.device_dir(hostname)))?;
+
+    for (file, changed) in backup.files.iter().zip(changed) {
+        let path = layout.file_path(hostname, &file.name);
+        if !changed && layout.timestamp.is_none() {
+            let path = Compression::saved_path(settings.compression, &path);
+            info!("unchanged: {}", layout.display_path(&path));
+            backup.kept.push(path);
+            continue;
+        }
+
+        let (path, contents) = encode_file(settings, path, &file.contents)?;
+        write_atomic(&path, &contents).map_err(BackupError::io(&path))?;
+        info!("saved: {}", layout.display_path(&path));
+        backup.saved.push(path);
+    }
+
+    if settings.verify {
+        read_back(http, settings, &backup.files).await?;
+        info!("verified: cfg.json and presets.json read back the same");
+    }
+
+    if let (Some(format), ArchiveScope::Device) = (settings.archive, settings.archive_scope) {
+        let path = encrypted_path(settings, device_archive_path(layout, hostname, format));
+        if status == BackupStatus::Saved || !path.exists() {
+            let entries = backup.archive_entries("");
+            let encryption = settings.encryption.as_ref();
+            let now = chrono::Utc::now();
+            write_archive(&path, format, &entries, now, encryption).map_err(|source| {
+                BackupError::Archive {
+                    path: path.clone(),
+                    source,
+                }
+            })?;
+            info!("saved: {}", layout.display_path(&path));
+            backup.saved.push(path);
+        }
+    }
+
+    layout
+        .device_done(hostname)
+        .map_err(BackupError::io(layout.device_dir(hostname)))?;
+
+    Ok(backup)
+}
+
+/// Send one file to `target`, at its path in out_dir. Returns how much was
+/// sent, and its hash.
+async fn stream_file<T, E>(
+    http: &DeviceHttp<'_>,
+    target: &StreamTarget,
+    layout: &Layout,
+    path: &Path,
+    len: Option<u64>,
+    chunks: impl Stream<Item = Result<T, E>> + Send + 'static,
+) -> Result<ManifestFile, BackupError>
+where
+    T: AsRef<[u8]> + Send + 'static,
+    E: Into<BoxError> + 'static,
+{
+    let relative = layout.display_path(path).to_string().replace('\\', "/");
+    let (size, sha256) = target
+        .put(&http.fetcher.client, &relative, len, chunks)
+        .await
+        .map_err(|source| BackupError::Upload {
+            path: relative.clone(),
+            source,
+        })?;
+    info!("uploaded: {relative}");
+    Ok(ManifestFile {
+        path: relative,
+        size,
+        sha256,
+        source: None,
+    })
+}
+
+/// Back up a device for backup --stream: pass each download on to the
+/// destination as it arrives, so no file is held whole. cfg.json was already
+/// downloaded for the device's name. presets.json can't be checked first, as
+/// it's gone before it has all arrived.
+async fn stream_wled(
+    http: &DeviceHttp<'_>,
+    target: &StreamTarget,
+    settings: &BackupSettings,
+    hostname: &str,
+    cfg_contents: Vec<u8>,
+) -> Result<Vec<ManifestFile>, BackupError> {
+    let layout = &settings.layout;
+    let options = http.options;
+    let mut uploaded = vec![];
+    if settings.saves(Part::Cfg) {
+        let path = layout.file_path(hostname, "cfg.json");
+        let len = Some(cfg_contents.len() as u64);
+        let chunks = stream::iter([Ok::<_, BoxError>(cfg_contents)]);
+        uploaded.push(stream_file(http, target, layout, &path, len, chunks).await?);
+    }
+
+    // The device's files, by name and where each is downloaded from, and
+    // whether it may be missing.
+    let mut wanted: Vec<(String, String, bool)> = vec![];
+    if options.skip_presets {
+        info!("skipped: presets.json");
+    } else if settings.saves(Part::Presets) {
+        wanted.push((
+            "presets.json".to_string(),
+            "/presets.json".to_string(),
+            false,
+        ));
+    }
+    if settings.saves(Part::Ledmaps) {
+        for ledmap in ledmap_file_names() {
+            let path = format!("/edit?download=/{ledmap}");
+            wanted.push((ledmap, path, true));
+        }
+    }
+    if settings.saves(Part::Palettes) {
+        for palette in palette_file_names() {
+            let path = format!("/{palette}");
+            wanted.push((palette, path, true));
+        }
+    }
+    if settings.full_fs {
+        let listing = http.get("/edit?list=/").await?;
+        for name in parse_fs_listing(&listing)? {
+            let skipped = options.skip_presets && name == "presets.json";
+            if skipped || name == "cfg.json" || wanted.iter().any(|(want, ..)| *want == name) {
+                continue;
+            }
+            let path = format!("/edit?download=/{name}");
+            wanted.push((name, path, false));
+        }
+    }
+    for endpoint in settings.all_endpoints().iter() {
+        wanted.push((endpoint.file_name(), endpoint.url_path(), false));
+    }
+
+    for (name, url_path, optional) in wanted {
+        if let Some(response) = http.open(&url_path, optional).await? {
+            let path = layout.file_path(hostname, &name);
+            let len = response.content_length();
+            let chunks = response.bytes_stream();
+            uploaded.push(stream_file(http, target, layout, &path, len, chunks).await?);
+        }
+    }
+    Ok(uploaded)
+}
+
+/// Send the device's meta.json for backup --stream. Returns it, and where
+/// the device's files came from.
+async fn stream_meta(
+    http: &DeviceHttp<'_>,
+    target: &StreamTarget,
+    layout: &Layout,
+    device: &Device,
+    ip: &IpAddr,
+    hostname: &str,
+    fetched: chrono::DateTime<chrono::Utc>,
+) -> Result<(ManifestFile, FileSource), BackupError> {
+    // Older firmware may not answer /json/info, which is fine.
+    let info = fetch_info(http.fetcher, ip, device.port, http.options)
+        .await
+        .ok();
+    let meta = DeviceMeta::new(device, ip, hostname, info.as_ref(), fetched);
+    let contents = serde_json::to_vec_pretty(&meta)
+        .map_err(|e| BackupError::Invalid(format!("Invalid {META_FILE}: {e}")))?;
+    let path = layout.file_path(hostname, META_FILE);
+    let len = Some(contents.len() as u64);
+    let chunks = stream::iter([Ok::<_, BoxError>(contents)]);
+    let file = stream_file(http, target, layout, &path, len, chunks).await?;
+    let source = FileSource {
+        device: hostname.to_string(),
+        address: ip.to_string(),
+        mac: info
+            .as_ref()
+            .and_then(|info| info.mac.as_deref())
+            .map(normalize_mac),
+        version: info.and_then(|info| info.ver),
+        fetched: fetched.to_rfc3339(),
+    };
+    Ok((file, source))
+}
+
+/// Run `attempt` at each of a device's addresses in turn, until one works.
+/// Returns the address last tried and its result. If every address failed,
+/// the error says what went wrong at each.
+pub async fn try_addresses<T>(
+    addresses: &[IpAddr],
+    mut attempt: impl AsyncFnMut(&IpAddr) -> Result<T, BackupError>,
+) -> (Option<IpAddr>, Result<T, BackupError>) {
+    let mut errors = vec![];
+    for (i, ip) in addresses.iter().enumerate() {
+        if i > 0 {
+            debug!("trying {ip}");
+        }
+        match attempt(ip).await {
+            Ok(value) => return (Some(*ip), Ok(value)),
+            Err(result) if addresses.len() == 1 => return (Some(*ip), Err(result)),
+            Err(result) => {
+                if i + 1 < addresses.len() {
+                    warn!("FAILED at {ip}: {result}");
+                }
+                errors.push((*ip, result));
+            }
+        }
+    }
+
+    if errors.is_empty() {
+        return (None, Err(BackupError::NoAddresses));
+    }
+    (
+        addresses.last().copied(),
+        Err(BackupError::Addresses(errors)),
+    )
+}
+
+/// Should the filter skip this device? Its cfg.json name is only fetched if
+/// its other names don't already exclude it.
+pub async fn filtered_out(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    wled: &Device,
+    filter: &DeviceFilter,
+) -> bool {
+    if !filter.is_set() {
+        return false;
+    }
+
+    let mut names: Vec<&str> = std::iter::once(wled.name.as_str())
+        .chain(wled.aliases.iter().map(String::as_str))
+        .collect();
+    if filter.excludes(&names) {
+        return true;
+    }
+
+    // If cfg.json can't be read, go by the other names, and let the backup
+    // report the problem.
+    let http = DeviceHttp::new(fetcher, ip, wled.port, &wled.options);
+    let cfg: Option<WledCfg> = match http.unlock().await {
+        Ok(()) => http
+            .get("/cfg.json")
+            .await
+            .ok()
+            .and_then(|body| WledCfg::parse(&body).ok()),
+        Err(_) => None,
+    };
+    let hostname = cfg.as_ref().and_then(|cfg| get_hostname_from_cfg(cfg).ok());
+    names.extend(hostname);
+
+    !filter.allows(&names)
+}
+
+/// How a backup run went.
+#[derive(Debug, Default)]
+pub struct BackupRun {
+    /// How each device went, in the order they finished.
+    pub reports: Vec<DeviceReport>,
+    /// Everything that failed, each named by its device, or by the file for
+    /// files saved for the whole run.
+    pub failures: Vec<(String, BackupError)>,
+}
+
+/// Back up all of the devices, running up to `jobs` backups at once. The
+/// fetcher is shared so connections are pooled across devices. A device
+/// failing doesn't stop the others, unless `settings.fail_fast`, when
+/// devices not yet started are reported as aborted.
+pub async fn backup_wleds(
+    fetcher: &Fetcher,
+    wleds: Vec<Device>,
+    settings: &BackupSettings,
+    jobs: usize,
+) -> BackupRun {
+    backup_stream(fetcher, stream::iter(wleds), settings, jobs).await
+}
+
+/// Like [`backup_wleds`], but backs up each device as it arrives, such as
+/// while discovery is still searching for more.
+pub async fn backup_stream(
+    fetcher: &Fetcher,
+    wleds: impl Stream<Item = Device>,
+    settings: &BackupSettings,
+    jobs: usize,
+) -> BackupRun {
+    let failures = Mutex::new(vec![]);
+    let failed = AtomicBool::new(false);
+    let names = SavedNames::default();
+    let run_entries = Mutex::new(vec![]);
+    let reports = Mutex::new(vec![]);
+    let manifest = Mutex::new(RunManifest {
+        created: chrono::Utc::now().to_rfc3339(),
+        files: vec![],
+    });
+
+    wleds
+        .for_each_concurrent(jobs.max(1), |wled| {
+            // Backups run at once, so each device's messages say which it is.
+            // At error level so failures name the device even with -qq.
+            let span = error_span!("device", name = %wled.name);
+            // The backup owns its device, and borrows what the run shares.
+            let (names, run_entries, reports, manifest) =
+                (&names, &run_entries, &reports, &manifest);
+            let (failures, failed) = (&failures, &failed);
+            async move {
+                let wled = &wled;
+                if let Some(ip) = wled.addresses.first() {
+                    if filtered_out(fetcher, ip, wled, &settings.filter).await {
+                        info!("Skipping {}: filtered out", wled.name);
+                        return;
+                    }
+
+                    let mut report = DeviceReport {
+                        name: wled.name.clone(),
+                        address: ip.to_string(),
+                        port: wled.port,
+                        ..Default::default()
+                    };
+                    if settings.fail_fast && failed.load(Ordering::SeqCst) {
+                        warn!("Skipping {}: an earlier device failed", wled.name);
+                        report.error = Some(BackupError::Aborted.to_string());
+                        report.error_kind = Some(BackupError::Aborted.kind().to_string());
+                        reports.lock().unwrap().push(report);
+                        let failure = (wled.name.clone(), BackupError::Aborted);
+                        failures.lock().unwrap().push(failure);
+                        return;
+                    }
+
+                    info!("Backing up {}", wled.name);
+                    let spinner = progress::spinner(&wled.name);
+                    let started = Instant::now();
+
+                    let (address, result) = try_addresses(&wled.addresses, async |ip| {
+                        backup_wled(fetcher, ip, wled, settings, names).await
+                    })
+                    .await;
+                    report.address = address.map(|ip| ip.to_string()).unwrap_or_default();
+
+                    match result {
+                        Ok(backup) if settings.dry_run => {
+                            let layout = &settings.layout;
+                            report.success = true;
+                            report.files = backup
+                                .planned
+                                .iter()
+                                .map(|path| layout.display_path(path).to_string())
+                                .collect();
+                            report.hostname = Some(backup.hostname);
+                        }
+                        Ok(backup) => {
+                            if settings.archive_scope == ArchiveScope::Run {
+                                let prefix = format!("{}/", backup.hostname);
+                                run_entries
+                                    .lock()
+                                    .unwrap()
+                                    .extend(backup.archive_entries(&prefix));
+                            }
+
+                            let layout = &settings.layout;
+                            for path in backup.saved.iter().chain(backup.kept.iter()) {
+                                let source = Some(backup.source.clone());
+                                if let Err(result) =
+                                    manifest.lock().unwrap().add(&layout.out_dir, path, source)
+                                {
+                                    error!(
+                                        "FAILED to hash {}: {result}",
+                                        layout.display_path(path)
+                                    );
+                                }
+                            }
+
+                            report.success = true;
+                            report.unchanged = backup.status == BackupStatus::Unchanged;
+                            report.files = backup
+                                .saved
+                                .iter()
+                                .map(|path| layout.display_path(path).to_string())
+                                .chain(backup.uploaded.iter().map(|file| file.path.clone()))
+                                .collect();
+                            report.bytes = backup
+                                .saved
+                                .iter()
+                                .filter_map(|path| fs::metadata(path).ok())
+                                .map(|metadata| metadata.len())
+                                .chain(backup.uploaded.iter().map(|file| file.size))
+                                .sum();
+                            manifest
+                                .lock()
+                                .unwrap()
+                                .files
+                                .extend(backup.uploaded.into_iter().map(|file| ManifestFile {
+                                    source: Some(backup.source.clone()),
+                                    ..file
+                                }));
+                            report.hostname = Some(backup.hostname);
+                        }
+                        Err(result) => {
+                            report.error = Some(result.to_string());
+                            report.error_kind = Some(result.kind().to_string());
+                            failed.store(true, Ordering::SeqCst);
+                            failures.lock().unwrap().push((wled.name.clone(), result));
+                        }
+                    }
+                    match report.status() {
+                        DeviceStatus::Failed => error!("{}", report.status_line()),
+                        _ => info!("{}", report.status_line()),
+                    }
+
+                    spinner.finish_and_clear();
+                    report.duration_ms = started.elapsed().as_millis() as u64;
+                    reports.lock().unwrap().push(report);
+                } else {
+                    let names: Vec<&str> = std::iter::once(wled.name.as_str())
+                        .chain(wled.aliases.iter().map(String::as_str))
+                        .collect();
+                    if settings.filter.excludes(&names) {
+                        info!("Skipping {}: filtered out", wled.name);
+                        return;
+                    }
+
+                    let report = DeviceReport {
+                        name: wled.name.clone(),
+                        port: wled.port,
+                        error: Some(BackupError::NoAddresses.to_string()),
+                        error_kind: Some(BackupError::NoAddresses.kind().to_string()),
+                        ..Default::default()
+                    };
+                    warn!("{}", report.status_line());
+                    reports.lock().unwrap().push(report);
+                    let failure = (wled.name.clone(), BackupError::NoAddresses);
+                    failures.lock().unwrap().push(failure);
+                }
+            }
+            .instrument(span)
+        })
+        .await;
+
+    if let (Some(format), ArchiveScope::Run) = (settings.archive, settings.archive_scope) {
+        let mut entries = run_entries.into_inner().unwrap();
+        if !entries.is_empty() {
+            entries.sort_by(|a, b| a.path.cmp(&b.path));
+            let layout = &settings.layout;
+            let path = encrypted_path(settings, run_archive_path(layout, format));
+            let encryption = settings.encryption.as_ref();
+            let written = fs::create_dir_all(&layout.out_dir)
+                .map_err(BackupError::io(&layout.out_dir))
+                .and_then(|_| {
+                    write_archive(&path, format, &entries, chrono::Utc::now(), encryption).map_err(
+                        |source| BackupError::Archive {
+                            path: path.clone(),
+                            source,
+                        },
+                    )
+                });
+            match written {
+                Ok(()) => {
+                    info!("Saved {}", layout.display_path(&path));
+                    let mut manifest = manifest.lock().unwrap();
+                    if let Err(result) = manifest.add(&layout.out_dir, &path, None) {
+                        error!("FAILED to hash {}: {result}", layout.display_path(&path));
+                    }
+                }
+                Err(result) => {
+                    error!("FAILED to save {}: {result}", layout.display_path(&path));
+                    let name = layout.display_path(&path).to_string();
+                    failures.lock().unwrap().push((name, result));
+                }
+            }
+        }
+    }
+
+    // List every file saved in the run, so it can be verified later.
+    let mut manifest = manifest.into_inner().unwrap();
+    if !manifest.files.is_empty() {
+        manifest.files.sort_by(|a, b| a.path.cmp(&b.path));
+        let layout = &settings.layout;
+        let path = manifest_path(layout);
+        let written = match &settings.stream {
+            Some(target) => upload_manifest(fetcher, target, layout, &manifest).await,
+            None => manifest.write(&path).map_err(BackupError::io(&path)),
+        };
+        match written {
+            Ok(()) => info!("Saved {}", layout.display_path(&path)),
+            Err(result) => {
+                error!("FAILED to save {}: {result}", layout.display_path(&path));
+                let name = layout.display_path(&path).to_string();
+                failures.lock().unwrap().push((name, result));
+            }
+        }
+    }
+
+    BackupRun {
+        reports: reports.into_inner().unwrap(),
+        failures: failures.into_inner().unwrap(),
+    }
+}
+
+/// Send the run's manifest to backup --stream's destination, in place of
+/// out_dir.
+async fn upload_manifest(
+    fetcher: &Fetcher,
+    target: &StreamTarget,
+    layout: &Layout,
+    manifest: &RunManifest,
+) -> Result<(), BackupError> {
+    let path = layout.display_path(&manifest_path(layout)).to_string();
+    let mut contents = serde_json::to_vec_pretty(manifest)
+        .map_err(|e| BackupError::Invalid(format!("Invalid {path}: {e}")))?;
+    contents.push(b'\n');
+    let len = Some(contents.len() as u64);
+    let chunks = stream::iter([Ok::<_, BoxError>(contents)]);
+    target
+        .put(&fetcher.client, &path, len, chunks)
+        .await
+        .map(|_| ())
+        .map_err(|source| BackupError::Upload { path, source })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::LayoutKind;
+    use crate::manifest::sha256_hex;
+    use crate::storage::Destination;
+    use crate::test_util::*;
+    use serde_json::json;
+    use std::net::Ipv4Addr;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_validate_presets() {
+        assert!(validate_presets(PRESETS_BODY.as_bytes()).is_ok());
+        assert!(validate_presets(br#"{"1":{"n":"Warm","on":true}}"#).is_ok());
+
+        let error = validate_presets(b"<html>Sign in to Wi-Fi</html>").unwrap_err();
+        assert!(
+            error.to_string().contains("Invalid presets.json"),
+            "{error}"
+        );
+        assert!(validate_presets(br#"{"1":{"n":"Wa"#).is_err());
+        assert!(validate_presets(b"").is_err());
+        assert!(validate_presets(b"[]").is_err());
+    }
+
+    #[test]
+    fn test_pretty_json() {
+        let pretty = pretty_json(br#"{"b":1,"a":{"d":[1,2],"c":"x"}}"#).unwrap();
+        assert_eq!(
+            String::from_utf8(pretty).unwrap(),
+            "{\n  \"a\": {\n    \"c\": \"x\",\n    \"d\": [\n      1,\n      2\n    ]\n  },\n  \"b\": 1\n}\n"
+        );
+        assert!(pretty_json(b"not json").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_pretty() {
+        let server = mock_wled_server("127.0.0.1:128", &cfg_body("testwled"), Some(PRESETS_BODY));
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.pretty = true;
+        assert_eq!(backup_localhost(128, &settings).await, BackupStatus::Saved);
+        server.join().unwrap();
+
+        validate_response_file(
+            dir.path().join("testwled_cfg.json"),
+            "{\n  \"id\": {\n    \"name\": \"testwled\"\n  }\n}\n",
+        );
+        validate_response_file(
+            dir.path().join("testwled_presets.json"),
+            "{\n  \"0\": {}\n}\n",
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_redact_secrets() {
+        let cfg = r#"{"id":{"name":"testwled"},"nw":{"ins":[{"ssid":"home","psk":"hunter2"}]}}"#;
+        let server = mock_wled_server("127.0.0.1:130", cfg, Some(PRESETS_BODY));
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.secrets = SecretHandling::Redact;
+        assert_eq!(backup_localhost(130, &settings).await, BackupStatus::Saved);
+        server.join().unwrap();
+
+        validate_response_file(
+            dir.path().join("testwled_cfg.json"),
+            r#"{"id":{"name":"testwled"},"nw":{"ins":[{"ssid":"home"}]}}"#,
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_separate_secrets() {
+        let cfg = r#"{"id":{"name":"testwled"},"ap":{"ssid":"WLED-AP","psk":"wled1234"}}"#;
+        let server = mock_wled_server("127.0.0.1:132", cfg, Some(PRESETS_BODY));
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.secrets = SecretHandling::Separate;
+        settings.archive = Some(ArchiveFormat::Zip);
+        assert_eq!(backup_localhost(132, &settings).await, BackupStatus::Saved);
+        server.join().unwrap();
+
+        validate_response_file(
+            dir.path().join("testwled_cfg.json"),
+            r#"{"ap":{"ssid":"WLED-AP"},"id":{"name":"testwled"}}"#,
+        );
+        validate_response_file(
+            dir.path().join("testwled_secrets.json"),
+            r#"{"ap":{"psk":"wled1234"}}"#,
+        );
+
+        // The secrets stay out of the archive.
+        let archive = fs::File::open(dir.path().join("testwled_backup.zip")).unwrap();
+        let zip = zip::ZipArchive::new(archive).unwrap();
+        let mut names: Vec<String> = zip.file_names().map(|n| n.unwrap().to_string()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["cfg.json", "manifest.json", "meta.json", "presets.json"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_encrypted() {
+        let server = mock_wled_server("127.0.0.1:134", &cfg_body("testwled"), Some(PRESETS_BODY));
+
+        let dir = tempdir().unwrap();
+        let identity = age::x25519::Identity::generate();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.encryption = Some(Encryption::Recipients(vec![identity.to_public()]));
+        assert_eq!(backup_localhost(134, &settings).await, BackupStatus::Saved);
+        server.join().unwrap();
+
+        assert!(!dir.path().join("testwled_cfg.json").exists());
+        let mut decryption = crate::crypt::Decryption::default();
+        decryption.add_identity(identity);
+        let encrypted = fs::read(dir.path().join("testwled_cfg.json.age")).unwrap();
+        assert_eq!(
+            decryption.decrypt(&encrypted).unwrap(),
+            cfg_body("testwled").as_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_compressed() {
+        let server = mock_wled_server("127.0.0.1:135", &cfg_body("testwled"), Some(PRESETS_BODY));
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.compression = Some(Compression::Gzip);
+        assert_eq!(backup_localhost(135, &settings).await, BackupStatus::Saved);
+        server.join().unwrap();
+
+        assert!(!dir.path().join("testwled_cfg.json").exists());
+        let compressed = fs::read(dir.path().join("testwled_presets.json.gz")).unwrap();
+        assert_eq!(
+            Compression::Gzip.decompress(&compressed).unwrap(),
+            PRESETS_BODY.as_bytes()
+        );
+
+        // The next backup compares against the decompressed file.
+        let path = dir.path().join("testwled_presets.json");
+        assert_eq!(
+            read_previous(&settings, path).unwrap(),
+            PRESETS_BODY.as_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_rejects_invalid_presets() {
+        let server = mock_wled_server("127.0.0.1:127", &cfg_body("testwled"), Some("<html>"));
+
+        let dir = tempdir().unwrap();
+        let settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        let BackupRun { reports, failures } = backup_wleds(
+            &Fetcher::default(),
+            vec![mock_device("testwled", "127.0.0.1", 127)],
+            &settings,
+            1,
+        )
+        .await;
+
+        assert!(!failures.is_empty());
+        assert!(!reports[0].success);
+        assert!(!dir.path().join("testwled_presets.json").exists());
+        assert!(!dir.path().join("testwled_cfg.json").exists());
+
+        server.join().unwrap();
+    }
+
+    fn hostname_of(cfg: Value) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let cfg = WledCfg::parse(cfg.to_string().as_bytes())?;
+        Ok(get_hostname_from_cfg(&cfg)?.to_string())
+    }
+
+    #[test]
+    fn test_get_hostname_from_cfg_success() {
+        let cfg = json!({
+            "id": {
+                "name": "test_device"
+            }
+        });
+
+        let result = hostname_of(cfg);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), "test_device");
+    }
+
+    #[test]
+    fn test_get_hostname_from_cfg_missing_id() {
+        let cfg = json!({
+            "other": "value"
+        });
+
+        let result = hostname_of(cfg);
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Missing 'id' field in cfg.json"
+        );
+    }
+
+    #[test]
+    fn test_get_hostname_from_cfg_missing_name() {
+        let cfg = json!({
+            "id": {
+                "other": "value"
+            }
+        });
+
+        let result = hostname_of(cfg);
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Missing 'name' field in cfg.json"
+        );
+    }
+
+    #[test]
+    fn test_get_hostname_from_cfg_name_not_string() {
+        let cfg = json!({
+            "id": {
+                "name": 123
+            }
+        });
+
+        let result = hostname_of(cfg);
+        assert!(result.is_err());
+        let error = result.unwrap_err().to_string();
+        assert!(error.starts_with("Invalid cfg.json"), "{error}");
+        assert!(error.contains("expected a string"), "{error}");
+    }
+
+    #[test]
+    fn test_get_hostname_from_cfg_empty_hostname() {
+        let cfg = json!({
+            "id": {
+                "name": ""
+            }
+        });
+
+        let result = hostname_of(cfg);
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Hostname is empty or contains only whitespace"
+        );
+    }
+
+    #[test]
+    fn test_get_hostname_from_cfg_whitespace_only_hostname() {
+        let cfg = json!({
+            "id": {
+                "name": "   \t\n  "
+            }
+        });
+
+        let result = hostname_of(cfg);
+        assert!(result.is_err());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Hostname is empty or contains only whitespace"
+        );
+    }
+
+    #[test]
+    fn test_get_hostname_from_cfg_hostname_with_whitespace() {
+        let cfg = json!({
+            "id": {
+                "name": "  test_device  "
+            }
+        });
+
+        let result = hostname_of(cfg);
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), "  test_device  ");
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_creates_file() {
+        // Start server in a background thread
+        let servers = vec![mock_wled_server(
+            "127.0.0.1:88",
+            &cfg_body("testwled"),
+            Some(PRESETS_BODY),
+        )];
+
+        // Use a temp directory
+        let dir = tempdir().unwrap();
+        let out_dir = dir.path().to_path_buf();
+
+        // Perform the backup.
+        let backup_wled = backup_wled(
+            &Fetcher::default(),
+            &IpAddr::V4("127.0.0.1".parse::<Ipv4Addr>().unwrap()),
+            &Device::new("testwled", vec![], 88),
+            &BackupSettings::new(Layout::new(LayoutKind::Flat, &out_dir)),
+            &SavedNames::default(),
+        )
+        .await;
+
+        assert!(backup_wled.is_ok(), "Backup failed");
+
+        // Check that the file exists
+        validate_response_files(&out_dir, "testwled");
+
+        // Shutdown the server
+        for handle in servers {
+            handle.join().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_creates_files() {
+        // Start server in a background thread
+        let servers = vec![
+            mock_wled_server("127.0.0.1:80", &cfg_body("testwled"), Some(PRESETS_BODY)),
+            mock_wled_server(
+                "127.0.0.1:8080",
+                &cfg_body("testwled_port"),
+                Some(PRESETS_BODY),
+            ),
+        ];
+
+        // Prepare mock WLED device
+        let wleds = vec![
+            mock_device("mdns_name", "127.0.0.1", 80),
+            mock_device("mdns_name_port", "127.0.0.1", 8080),
+        ];
+
+        // Use a temp directory
+        let dir = tempdir().unwrap();
+        let out_dir = dir.path().to_path_buf();
+
+        // Perform the backup.
+        let BackupRun { failures, .. } = backup_wleds(
+            &Fetcher::default(),
+            wleds,
+            &BackupSettings::new(Layout::new(LayoutKind::Flat, &out_dir)),
+            1,
+        )
+        .await;
+
+        assert!(failures.is_empty(), "Backup failed");
+
+        // Check that the file exists
+        validate_response_files(&out_dir, "testwled");
+        validate_response_files(&out_dir, "testwled_port");
+
+        // Shutdown the server
+        for handle in servers {
+            handle.join().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_invalid_cfg_json_no_files_written() {
+        let servers = vec![mock_wled_server(
+            "127.0.0.1:89",
+            "invalid json content",
+            None,
+        )];
+
+        let dir = tempdir().unwrap();
+        let out_dir = dir.path().to_path_buf();
+
+        let backup_result = backup_wled(
+            &Fetcher::default(),
+            &IpAddr::V4("127.0.0.1".parse::<Ipv4Addr>().unwrap()),
+            &Device::new("testwled", vec![], 89),
+            &BackupSettings::new(Layout::new(LayoutKind::Flat, &out_dir)),
+            &SavedNames::default(),
+        )
+        .await;
+
+        assert!(
+            backup_result.is_err(),
+            "Backup should fail with invalid JSON"
+        );
+
+        let entries: Vec<_> = fs::read_dir(&out_dir).unwrap().collect();
+        assert_eq!(
+            entries.len(),
+            0,
+            "No files should be written when cfg.json parsing fails"
+        );
+
+        for handle in servers {
+            handle.join().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_same_hostname() {
+        let info = r#"{"ver":"0.15.0","mac":"a0b1c2d3e4f5"}"#;
+        let servers = vec![
+            mock_wled_server("127.0.0.1:121", &cfg_body("WLED"), Some(PRESETS_BODY)),
+            mock_routes_server(
+                "127.0.0.1:122",
+                &[
+                    ("/cfg.json", &cfg_body("WLED")),
+                    ("/presets.json", PRESETS_BODY),
+                    ("/json/info", info),
+                ],
+            ),
+            mock_wled_server("127.0.0.1:123", &cfg_body("wled"), Some(PRESETS_BODY)),
+        ];
+        let wleds = vec![
+            mock_device("wled-1", "127.0.0.1", 121),
+            mock_device("wled-2", "127.0.0.1", 122),
+            mock_device("wled-3", "127.0.0.1", 123),
+        ];
+
+        let dir = tempdir().unwrap();
+        let settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        let BackupRun { reports, failures } =
+            backup_wleds(&Fetcher::default(), wleds, &settings, 1).await;
+        assert!(failures.is_empty(), "Backup failed");
+
+        let hostnames: Vec<_> = reports
+            .iter()
+            .map(|r| r.hostname.clone().unwrap())
+            .collect();
+        assert_eq!(hostnames, vec!["WLED", "WLED-d3e4f5", "wled-127-0-0-1"]);
+        validate_response_file(dir.path().join("WLED_cfg.json"), &cfg_body("WLED"));
+        validate_response_file(dir.path().join("WLED-d3e4f5_cfg.json"), &cfg_body("WLED"));
+        validate_response_file(
+            dir.path().join("wled-127-0-0-1_cfg.json"),
+            &cfg_body("wled"),
+        );
+
+        for handle in servers {
+            handle.join().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_sanitizes_hostname() {
+        let server = mock_wled_server("127.0.0.1:126", &cfg_body("../evil"), Some(PRESETS_BODY));
+
+        let dir = tempdir().unwrap();
+        let out_dir = dir.path().join("out");
+        let settings = BackupSettings::new(Layout::new(LayoutKind::PerDevice, &out_dir));
+        let BackupRun { reports, failures } = backup_wleds(
+            &Fetcher::default(),
+            vec![mock_device("evil", "127.0.0.1", 126)],
+            &settings,
+            1,
+        )
+        .await;
+        assert!(failures.is_empty(), "Backup failed");
+
+        assert_eq!(reports[0].hostname.as_deref(), Some(".._evil"));
+        assert!(out_dir.join(".._evil").join("cfg.json").exists());
+        assert!(!dir.path().join("evil").exists());
+
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_on_collision() {
+        let servers = vec![
+            mock_wled_server("127.0.0.1:124", &cfg_body("WLED"), Some(PRESETS_BODY)),
+            mock_wled_server("127.0.0.1:125", &cfg_body("WLED"), Some(PRESETS_BODY)),
+            mock_wled_server("127.0.0.2:124", &cfg_body("WLED"), Some(PRESETS_BODY)),
+        ];
+        let wleds = |ip: &str| {
+            vec![
+                mock_device("wled-1", "127.0.0.1", 124),
+                mock_device("wled-2", ip, if ip == "127.0.0.1" { 125 } else { 124 }),
+            ]
+        };
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.on_collision = Collision::Error;
+        let BackupRun { reports, failures } =
+            backup_wleds(&Fetcher::default(), wleds("127.0.0.1"), &settings, 1).await;
+        assert!(!failures.is_empty());
+        assert!(reports[0].success);
+        let error = reports[1].error.clone().unwrap();
+        assert!(error.contains("already named WLED"), "{error}");
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.on_collision = Collision::SuffixIp;
+        let BackupRun { reports, failures } =
+            backup_wleds(&Fetcher::default(), wleds("127.0.0.2"), &settings, 1).await;
+        assert!(failures.is_empty(), "Backup failed");
+        assert_eq!(reports[1].hostname.as_deref(), Some("WLED-127-0-0-2"));
+
+        for handle in servers {
+            handle.join().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_returns_error() {
+        // Start server in a background thread. Use different ports to avoid conflicts.
+        let servers = vec![mock_wled_server(
+            "127.0.0.1:81",
+            &cfg_body("testwled"),
+            Some(PRESETS_BODY),
+        )];
+
+        // Prepare mock WLED device
+        let wleds = vec![
+            mock_device("mdns_name_port", "127.0.0.1", 8081), // Not served, so will fail.
+            mock_device("mdns_name", "127.0.0.1", 81),
+        ];
+
+        // Use a temp directory
+        let dir = tempdir().unwrap();
+        let out_dir = dir.path().to_path_buf();
+
+        // Perform the backup.
+        let BackupRun { reports, failures } = backup_wleds(
+            &Fetcher::default(),
+            wleds,
+            &BackupSettings::new(Layout::new(LayoutKind::Flat, &out_dir)),
+            1,
+        )
+        .await;
+
+        assert!(!failures.is_empty(), "Backup failed, as it should have.");
+
+        assert_eq!(reports.len(), 2);
+        assert!(!reports[0].success);
+        assert!(reports[0].error.is_some());
+        assert!(reports[1].success);
+        assert_eq!(reports[1].hostname.as_deref(), Some("testwled"));
+        assert_eq!(
+            reports[1].files,
+            vec![
+                "testwled_cfg.json",
+                "testwled_presets.json",
+                "testwled_meta.json"
+            ]
+        );
+        assert!(reports[1].bytes > 0);
+
+        // Check that the file exists for teh value correctly served.
+        validate_response_files(&out_dir, "testwled");
+
+        // Shutdown the server
+        for handle in servers {
+            handle.join().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_skip_presets() {
+        let servers = vec![mock_wled_server(
+            "127.0.0.1:92",
+            &cfg_body("testwled"),
+            None,
+        )];
+
+        let dir = tempdir().unwrap();
+        let out_dir = dir.path().to_path_buf();
+
+        let mut device = Device::new("testwled", vec![], 92);
+        device.options.skip_presets = true;
+        let backup_result = backup_wled(
+            &Fetcher::default(),
+            &IpAddr::V4("127.0.0.1".parse::<Ipv4Addr>().unwrap()),
+            &device,
+            &BackupSettings::new(Layout::new(LayoutKind::Flat, &out_dir)),
+            &SavedNames::default(),
+        )
+        .await;
+
+        assert!(backup_result.is_ok(), "Backup failed");
+        validate_response_file(out_dir.join("testwled_cfg.json"), &cfg_body("testwled"));
+        assert!(!out_dir.join("testwled_presets.json").exists());
+
+        for handle in servers {
+            handle.join().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_parallel() {
+        let servers = vec![
+            mock_wled_server("127.0.0.1:93", &cfg_body("testwled_a"), Some(PRESETS_BODY)),
+            mock_wled_server("127.0.0.1:94", &cfg_body("testwled_b"), Some(PRESETS_BODY)),
+            mock_wled_server("127.0.0.1:95", &cfg_body("testwled_c"), Some(PRESETS_BODY)),
+        ];
+
+        let wleds = vec![
+            mock_device("mdns_a", "127.0.0.1", 93),
+            mock_device("mdns_b", "127.0.0.1", 94),
+            mock_device("mdns_c", "127.0.0.1", 95),
+            mock_device("mdns_missing", "127.0.0.1", 8082), // Not served, so will fail.
+        ];
+
+        let dir = tempdir().unwrap();
+        let out_dir = dir.path().to_path_buf();
+
+        let BackupRun { failures, .. } = backup_wleds(
+            &Fetcher::default(),
+            wleds,
+            &BackupSettings::new(Layout::new(LayoutKind::Flat, &out_dir)),
+            3,
+        )
+        .await;
+        assert!(!failures.is_empty(), "Backup failed, as it should have.");
+
+        validate_response_files(&out_dir, "testwled_a");
+        validate_response_files(&out_dir, "testwled_b");
+        validate_response_files(&out_dir, "testwled_c");
+
+        for handle in servers {
+            handle.join().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_per_device_layout() {
+        let servers = vec![mock_wled_server(
+            "127.0.0.1:99",
+            &cfg_body("testwled"),
+            Some(PRESETS_BODY),
+        )];
+
+        let dir = tempdir().unwrap();
+        let layout = Layout::new(LayoutKind::PerDevice, dir.path());
+
+        let backup_result = backup_wled(
+            &Fetcher::default(),
+            &IpAddr::V4("127.0.0.1".parse::<Ipv4Addr>().unwrap()),
+            &Device::new("testwled", vec![], 99),
+            &BackupSettings::new(layout),
+            &SavedNames::default(),
+        )
+        .await;
+
+        assert!(backup_result.is_ok(), "Backup failed");
+        let device_dir = dir.path().join("testwled");
+        validate_response_file(device_dir.join("cfg.json"), &cfg_body("testwled"));
+        validate_response_file(device_dir.join("presets.json"), PRESETS_BODY);
+
+        for handle in servers {
+            handle.join().unwrap();
+        }
+    }
+
+    async fn backup_localhost(port: u16, settings: &BackupSettings) -> BackupStatus {
+        backup_wled(
+            &Fetcher::default(),
+            &IpAddr::V4(Ipv4Addr::LOCALHOST),
+            &Device::new("testwled", vec![], port),
+            settings,
+            &SavedNames::default(),
+        )
+        .await
+        .unwrap()
+        .status
+    }
+
+    fn modified(path: &std::path::Path) -> std::time::SystemTime {
+        fs::metadata(path).unwrap().modified().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_unchanged_not_rewritten() {
+        let dir = tempdir().unwrap();
+        let settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        let cfg_path = dir.path().join("testwled_cfg.json");
+
+        let server = mock_wled_server("127.0.0.1:100", &cfg_body("testwled"), Some(PRESETS_BODY));
+        assert_eq!(backup_localhost(100, &settings).await, BackupStatus::Saved);
+        server.join().unwrap();
+        let first_write = modified(&cfg_path);
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let server = mock_wled_server("127.0.0.1:100", &cfg_body("testwled"), Some(PRESETS_BODY));
+        assert_eq!(
+            backup_localhost(100, &settings).await,
+            BackupStatus::Unchanged
+        );
+        server.join().unwrap();
+        assert_eq!(modified(&cfg_path), first_write);
+
+        // Changed presets are saved, unchanged cfg is still left alone.
+        let server = mock_wled_server(
+            "127.0.0.1:100",
+            &cfg_body("testwled"),
+            Some(r#"{"1":{"n":"New"}}"#),
+        );
+        assert_eq!(backup_localhost(100, &settings).await, BackupStatus::Saved);
+        server.join().unwrap();
+        assert_eq!(modified(&cfg_path), first_write);
+        validate_response_file(
+            dir.path().join("testwled_presets.json"),
+            r#"{"1":{"n":"New"}}"#,
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_force_rewrites() {
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.force = true;
+
+        let server = mock_wled_server("127.0.0.1:101", &cfg_body("testwled"), Some(PRESETS_BODY));
+        backup_localhost(101, &settings).await;
+        server.join().unwrap();
+
+        let server = mock_wled_server("127.0.0.1:101", &cfg_body("testwled"), Some(PRESETS_BODY));
+        assert_eq!(backup_localhost(101, &settings).await, BackupStatus::Saved);
+        server.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_backup_wled_unchanged_skips_device_set() {
+        let dir = tempdir().unwrap();
+        let mut layout = Layout::new(LayoutKind::PerDevice, dir.path());
+
+        layout.timestamp = Some("20261015T000000Z".to_string());
+        let server = mock_wled_server("127.0.0.1:102", &cfg_body("testwled"), Some(PRESETS_BODY));
+        backup_localhost(102, &BackupSettings::new(layout.clone())).await;
+        server.join().unwrap();
+
+        layout.timestamp = Some("20261016T000000Z".to_string());
+        let server = mock_wled_server("127.0.0.1:102", &cfg_body("testwled"), Some(PRESETS_BODY));
+        let status = backup_localhost(102, &BackupSettings::new(layout.clone())).await;
+        server.join().unwrap();
+
+        assert_eq!(status, BackupStatus::Unchanged);
+        assert!(!dir.path().join("testwled/20261016T000000Z").exists());
+        assert_eq!(
+            fs::read_link(dir.path().join("testwled/latest")).unwrap(),
+            std::path::PathBuf::from("20261015T000000Z")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_large_presets() {
+        // Big enough to be downloaded in chunks, with a progress bar.
+        let presets: String = (0..2000)
+            .map(|i| format!(r#""{i}":{{"n":"preset {i}","on":true,"bri":128}}"#))
+            .collect::<Vec<_>>()
+            .join(",");
+        let presets = format!("{{{presets}}}");
+        assert!(presets.len() as u64 > crate::progress::LARGE_DOWNLOAD);
+        let handle = mock_wled_server("127.0.0.1:146", &cfg_body("testwled"), Some(&presets));
+
+        let dir = tempdir().unwrap();
+        let settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        let status = backup_localhost(146, &settings).await;
+        handle.join().unwrap();
+
+        assert_eq!(status, BackupStatus::Saved);
+        validate_response_file(dir.path().join("testwled_presets.json"), &presets);
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_endpoints() {
+        let handle = mock_routes_server(
+            "127.0.0.1:103",
+            &[
+                ("/cfg.json", &cfg_body("testwled")),
+                ("/presets.json", PRESETS_BODY),
+                ("/json/info", r#"{"ver":"0.15.0","uptime":12}"#),
+                ("/json/state", r#"{"on":true}"#),
+            ],
+        );
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.endpoints = vec![Endpoint::Info, Endpoint::State];
+
+        let status = backup_localhost(103, &settings).await;
+        handle.join().unwrap();
+
+        assert_eq!(status, BackupStatus::Saved);
+        validate_response_files(dir.path(), "testwled");
+        validate_response_file(
+            dir.path().join("testwled_info.json"),
+            r#"{"ver":"0.15.0","uptime":12}"#,
+        );
+        validate_response_file(dir.path().join("testwled_state.json"), r#"{"on":true}"#);
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_only() {
+        let handle = mock_routes_server(
+            "127.0.0.1:149",
+            &[
+                ("/cfg.json", &cfg_body("testwled")),
+                ("/presets.json", PRESETS_BODY),
+                ("/edit?download=/ledmap.json", r#"{"map":[0,1]}"#),
+                ("/json/state", r#"{"on":true}"#),
+            ],
+        );
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.only = vec![Part::Cfg, Part::State];
+        settings.secrets = SecretHandling::Separate;
+        backup_localhost(149, &settings).await;
+        handle.join().unwrap();
+
+        let mut saved: Vec<String> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        saved.sort();
+        assert_eq!(
+            saved,
+            [
+                "testwled_cfg.json".to_string(),
+                "testwled_meta.json".to_string(),
+                format!("testwled_{SECRETS_FILE}"),
+                "testwled_state.json".to_string(),
+            ]
+        );
+
+        // The secrets saved before are kept when cfg.json isn't saved.
+        let secrets = dir.path().join(format!("testwled_{SECRETS_FILE}"));
+        fs::write(&secrets, r#"{"nw":{"ins":[{"psk":"secret"}]}}"#).unwrap();
+        let handle = mock_routes_server(
+            "127.0.0.1:149",
+            &[
+                ("/cfg.json", &cfg_body("testwled")),
+                ("/presets.json", PRESETS_BODY),
+            ],
+        );
+        settings.only = vec![Part::Presets];
+        backup_localhost(149, &settings).await;
+        handle.join().unwrap();
+        assert!(dir.path().join("testwled_presets.json").exists());
+        validate_response_file(secrets, r#"{"nw":{"ins":[{"psk":"secret"}]}}"#);
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_ledmaps() {
+        let handle = mock_routes_server(
+            "127.0.0.1:105",
+            &[
+                ("/cfg.json", &cfg_body("testwled")),
+                ("/presets.json", PRESETS_BODY),
+                ("/edit?download=/ledmap.json", r#"{"map":[0,1]}"#),
+                ("/edit?download=/ledmap3.json", r#"{"map":[1,0]}"#),
+            ],
+        );
+
+        let dir = tempdir().unwrap();
+        let settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        backup_localhost(105, &settings).await;
+        handle.join().unwrap();
+
+        validate_response_files(dir.path(), "testwled");
+        validate_response_file(dir.path().join("testwled_ledmap.json"), r#"{"map":[0,1]}"#);
+        validate_response_file(dir.path().join("testwled_ledmap3.json"), r#"{"map":[1,0]}"#);
+        assert!(!dir.path().join("testwled_ledmap1.json").exists());
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_palettes() {
+        let handle = mock_routes_server(
+            "127.0.0.1:106",
+            &[
+                ("/cfg.json", &cfg_body("testwled")),
+                ("/presets.json", PRESETS_BODY),
+                ("/palette0.json", r#"{"palette":[0,"ff0000"]}"#),
+                ("/palette7.json", r#"{"palette":[0,"00ff00"]}"#),
+            ],
+        );
+
+        let dir = tempdir().unwrap();
+        let settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        backup_localhost(106, &settings).await;
+        handle.join().unwrap();
+
+        validate_response_files(dir.path(), "testwled");
+        validate_response_file(
+            dir.path().join("testwled_palette0.json"),
+            r#"{"palette":[0,"ff0000"]}"#,
+        );
+        validate_response_file(
+            dir.path().join("testwled_palette7.json"),
+            r#"{"palette":[0,"00ff00"]}"#,
+        );
+        assert!(!dir.path().join("testwled_palette1.json").exists());
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_full_fs() {
+        let listing = r#"[
+            {"type":"file","name":"/cfg.json","size":10},
+            {"type":"file","name":"/presets.json","size":10},
+            {"type":"file","name":"/wsec.json","size":10},
+            {"type":"file","name":"/usermod.json","size":10},
+            {"type":"dir","name":"/extra","size":0}
+        ]"#;
+        let handle = mock_routes_server(
+            "127.0.0.1:108",
+            &[
+                ("/cfg.json", &cfg_body("testwled")),
+                ("/presets.json", PRESETS_BODY),
+                ("/edit?list=/", listing),
+                ("/edit?download=/wsec.json", "wsec data"),
+                ("/edit?download=/usermod.json", "usermod data"),
+            ],
+        );
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.full_fs = true;
+        backup_localhost(108, &settings).await;
+        handle.join().unwrap();
+
+        validate_response_files(dir.path(), "testwled");
+        validate_response_file(dir.path().join("testwled_wsec.json"), "wsec data");
+        validate_response_file(dir.path().join("testwled_usermod.json"), "usermod data");
+        assert!(!dir.path().join("testwled_extra").exists());
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_dry_run() {
+        let listing = r#"[
+            {"type":"file","name":"/cfg.json","size":10},
+            {"type":"file","name":"/presets.json","size":10},
+            {"type":"file","name":"/ledmap1.json","size":10},
+            {"type":"file","name":"/usermod.json","size":10}
+        ]"#;
+        let handle = mock_routes_server(
+            "127.0.0.1:147",
+            &[
+                ("/cfg.json", &cfg_body("testwled")),
+                ("/edit?list=/", listing),
+            ],
+        );
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.dry_run = true;
+        settings.compression = Some(Compression::Gzip);
+        settings.secrets = SecretHandling::Separate;
+        let backup = backup_wled(
+            &Fetcher::default(),
+            &IpAddr::V4(Ipv4Addr::LOCALHOST),
+            &Device::new("testwled", vec![], 147),
+            &settings,
+            &SavedNames::default(),
+        )
+        .await
+        .unwrap();
+        handle.join().unwrap();
+
+        let planned: Vec<String> = backup
+            .planned
+            .iter()
+            .map(|path| settings.layout.display_path(path).to_string())
+            .collect();
+        assert_eq!(
+            planned,
+            [
+                "testwled_cfg.json.gz",
+                "testwled_presets.json.gz",
+                "testwled_ledmap1.json.gz",
+                "testwled_meta.json.gz",
+                &format!("testwled_{SECRETS_FILE}.gz"),
+            ]
+        );
+        assert!(backup.saved.is_empty());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_ipv6() {
+        let server = mock_wled_server("[::1]:117", &cfg_body("testwled"), Some(PRESETS_BODY));
+
+        let dir = tempdir().unwrap();
+        let result = backup_wled(
+            &Fetcher::default(),
+            &IpAddr::V6(std::net::Ipv6Addr::LOCALHOST),
+            &Device::new("testwled", vec![], 117),
+            &BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path())),
+            &SavedNames::default(),
+        )
+        .await;
+        assert!(result.is_ok(), "Backup failed");
+        server.join().unwrap();
+
+        validate_response_files(dir.path(), "testwled");
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_falls_back_to_next_address() {
+        let server = mock_wled_server("127.0.0.1:118", &cfg_body("testwled"), Some(PRESETS_BODY));
+
+        // Nothing listens on 127.0.0.2, so the backup moves on to 127.0.0.1.
+        let mut wled = mock_device("mdns_name", "127.0.0.2", 118);
+        wled.addresses.push(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        let dir = tempdir().unwrap();
+        let settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        let BackupRun { reports, failures } =
+            backup_wleds(&Fetcher::default(), vec![wled], &settings, 1).await;
+        assert!(failures.is_empty(), "Backup failed");
+        server.join().unwrap();
+
+        assert_eq!(reports[0].address, "127.0.0.1");
+        validate_response_files(dir.path(), "testwled");
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_next_address_keeps_name() {
+        // 127.0.0.1 serves cfg.json but no presets, so it fails after the
+        // name is claimed.
+        let servers = vec![
+            mock_routes_server("127.0.0.1:173", &[("/cfg.json", &cfg_body("WLED"))]),
+            mock_wled_server("127.0.0.2:173", &cfg_body("WLED"), Some(PRESETS_BODY)),
+        ];
+        let mut wled = mock_device("wled", "127.0.0.1", 173);
+        wled.addresses.push("127.0.0.2".parse().unwrap());
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.on_collision = Collision::Error;
+        let BackupRun { reports, failures } =
+            backup_wleds(&Fetcher::default(), vec![wled], &settings, 1).await;
+        assert!(failures.is_empty(), "Backup failed");
+        for handle in servers {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(reports[0].address, "127.0.0.2");
+        assert_eq!(reports[0].hostname.as_deref(), Some("WLED"));
+        validate_response_files(dir.path(), "WLED");
+    }
+
+    #[tokio::test]
+    async fn test_try_addresses_reports_every_failure() {
+        let addresses: Vec<IpAddr> =
+            vec!["127.0.0.2".parse().unwrap(), "127.0.0.3".parse().unwrap()];
+        let mut tried = vec![];
+        let (address, result) = try_addresses(&addresses, async |ip| -> Result<(), _> {
+            tried.push(*ip);
+            Err(BackupError::Invalid(format!("refused by {ip}")))
+        })
+        .await;
+
+        assert_eq!(tried, addresses);
+        assert_eq!(address, Some(addresses[1]));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "127.0.0.2: refused by 127.0.0.2; 127.0.0.3: refused by 127.0.0.3"
+        );
+
+        let (address, result) = try_addresses(&[], async |_| Ok(())).await;
+        assert_eq!(address, None);
+        assert!(matches!(result, Err(BackupError::NoAddresses)));
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_error_kind() {
+        // Nothing answers on port 131.
+        let dir = tempdir().unwrap();
+        let settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        let wleds = vec![mock_device("missing", "127.0.0.1", 131)];
+        let BackupRun { reports, failures } =
+            backup_wleds(&Fetcher::default(), wleds, &settings, 1).await;
+
+        assert!(matches!(failures[..], [(_, BackupError::Http { .. })]));
+        assert_eq!(reports[0].error_kind.as_deref(), Some("http"));
+        assert!(
+            reports[0]
+                .error
+                .as_deref()
+                .unwrap()
+                .starts_with("http://127.0.0.1:131/cfg.json")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_no_address() {
+        let dir = tempdir().unwrap();
+        let settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        let mut wled = mock_device("porch", "127.0.0.1", 80);
+        wled.addresses.clear();
+        let BackupRun { reports, failures } =
+            backup_wleds(&Fetcher::default(), vec![wled], &settings, 1).await;
+
+        assert_eq!(reports[0].status(), DeviceStatus::NoAddress);
+        assert!(matches!(failures[..], [(_, BackupError::NoAddresses)]));
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_fail_fast() {
+        // Nothing answers on port 131.
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        let wleds = || {
+            vec![
+                mock_device("porch", "127.0.0.1", 131),
+                mock_device("garden", "127.0.0.1", 131),
+            ]
+        };
+
+        // Every failure is kept.
+        let BackupRun { reports, failures } =
+            backup_wleds(&Fetcher::default(), wleds(), &settings, 1).await;
+        let names: Vec<&str> = failures.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["porch", "garden"]);
+        assert!(
+            reports
+                .iter()
+                .all(|report| report.error_kind.as_deref() == Some("http"))
+        );
+
+        settings.fail_fast = true;
+        let BackupRun { reports, failures } =
+            backup_wleds(&Fetcher::default(), wleds(), &settings, 1).await;
+        assert!(matches!(
+            failures[..],
+            [(_, BackupError::Http { .. }), (_, BackupError::Aborted)]
+        ));
+        assert_eq!(reports[1].name, "garden");
+        assert!(!reports[1].success);
+        assert_eq!(reports[1].error_kind.as_deref(), Some("aborted"));
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_manifest() {
+        let info = r#"{"ver":"0.15.0","mac":"AA:BB:CC:DD:EE:FF"}"#;
+        let server = mock_routes_server(
+            "127.0.0.1:136",
+            &[
+                ("/cfg.json", &cfg_body("testwled")),
+                ("/presets.json", PRESETS_BODY),
+                ("/json/info", info),
+            ],
+        );
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.archive = Some(ArchiveFormat::Zip);
+        settings.archive_scope = ArchiveScope::Run;
+        let wleds = vec![mock_device("testwled", "127.0.0.1", 136)];
+        let BackupRun { failures, .. } =
+            backup_wleds(&Fetcher::default(), wleds, &settings, 1).await;
+        server.join().unwrap();
+        assert!(failures.is_empty());
+
+        let manifest = RunManifest::load(&dir.path().join("MANIFEST.json")).unwrap();
+        let paths: Vec<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "testwled_cfg.json",
+                "testwled_meta.json",
+                "testwled_presets.json",
+                "wled-backup.zip"
+            ]
+        );
+
+        let cfg = &manifest.files[0];
+        assert_eq!(cfg.size, cfg_body("testwled").len() as u64);
+        let source = cfg.source.as_ref().unwrap();
+        assert_eq!(source.device, "testwled");
+        assert_eq!(source.address, "127.0.0.1");
+        assert_eq!(source.mac.as_deref(), Some("aabbccddeeff"));
+        assert_eq!(source.version.as_deref(), Some("0.15.0"));
+        assert_eq!(manifest.files[3].source, None);
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_stream() {
+        let info = r#"{"ver":"0.15.0","mac":"AA:BB:CC:DD:EE:FF"}"#;
+        let server = mock_routes_server(
+            "127.0.0.1:170",
+            &[
+                ("/cfg.json", &cfg_body("testwled")),
+                ("/presets.json", PRESETS_BODY),
+                ("/json/info", info),
+            ],
+        );
+
+        let dir = tempdir().unwrap();
+        let copy = tempdir().unwrap();
+        let mut layout = Layout::new(LayoutKind::Flat, dir.path());
+        layout.timestamp = Some("20240501T120000Z".to_string());
+        let dest = Destination::Local {
+            path: copy.path().to_path_buf(),
+        };
+        let now = chrono::Utc::now();
+        let target = StreamTarget::new(dest, Default::default(), &layout, now).unwrap();
+        let mut settings = BackupSettings::new(layout);
+        settings.stream = Some(target);
+        let wleds = vec![mock_device("testwled", "127.0.0.1", 170)];
+        let BackupRun { reports, failures } =
+            backup_wleds(&Fetcher::default(), wleds, &settings, 1).await;
+        server.join().unwrap();
+        assert!(failures.is_empty());
+
+        // Nothing is saved in out_dir: it all went to the destination.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        let set = copy.path().join("20240501T120000Z");
+        let presets = fs::read_to_string(set.join("testwled_presets.json")).unwrap();
+        assert_eq!(presets, PRESETS_BODY);
+
+        let manifest = copy.path().join("MANIFEST-20240501T120000Z.json");
+        let manifest = RunManifest::load(&manifest).unwrap();
+        let paths: Vec<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "20240501T120000Z/testwled_cfg.json",
+                "20240501T120000Z/testwled_meta.json",
+                "20240501T120000Z/testwled_presets.json",
+            ]
+        );
+        let presets = &manifest.files[2];
+        assert_eq!(presets.sha256, sha256_hex(PRESETS_BODY.as_bytes()));
+        let source = presets.source.as_ref().unwrap();
+        assert_eq!(source.mac.as_deref(), Some("aabbccddeeff"));
+        assert_eq!(reports[0].files.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_verify() {
+        let presets: &[&str] = &[PRESETS_BODY, r#"{"0":{},"1":{"n":"Warm"}}"#];
+        let cfg = cfg_body("testwled");
+        let server = mock_sequence_server(
+            "127.0.0.1:138",
+            &[("/cfg.json", &[&cfg]), ("/presets.json", presets)],
+        );
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.verify = true;
+        settings.secrets = SecretHandling::Redact;
+        let result = backup_wled(
+            &Fetcher::default(),
+            &IpAddr::V4(Ipv4Addr::LOCALHOST),
+            &Device::new("testwled", vec![], 138),
+            &settings,
+            &SavedNames::default(),
+        )
+        .await;
+        let error = result.err().unwrap();
+        assert!(
+            error
+                .to_string()
+                .contains("presets.json read back differently"),
+            "{error}"
+        );
+
+        // The second time, presets.json reads back the same.
+        assert_eq!(backup_localhost(138, &settings).await, BackupStatus::Saved);
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_filter() {
+        let servers = vec![
+            mock_wled_server("127.0.0.1:114", &cfg_body("garden-1"), Some(PRESETS_BODY)),
+            mock_wled_server("127.0.0.1:115", &cfg_body("garden-bench"), None),
+            mock_wled_server("127.0.0.1:116", &cfg_body("porch"), None),
+        ];
+        let wleds = vec![
+            // Included by its cfg.json name.
+            mock_device("wled-1234", "127.0.0.1", 114),
+            // Excluded by its cfg.json name.
+            mock_device("wled-5678", "127.0.0.1", 115),
+            // Not included.
+            mock_device("wled-9abc", "127.0.0.1", 116),
+            // Excluded by name, so never contacted.
+            mock_device("test-bench", "127.0.0.1", 8084),
+        ];
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.filter = DeviceFilter {
+            include: vec!["garden-*".to_string()],
+            exclude: vec!["*-bench".to_string()],
+            ..Default::default()
+        };
+
+        let BackupRun { reports, failures } =
+            backup_wleds(&Fetcher::default(), wleds, &settings, 1).await;
+        assert!(failures.is_empty(), "Backup failed");
+        for handle in servers {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].name, "wled-1234");
+        validate_response_files(dir.path(), "garden-1");
+        assert!(!dir.path().join("garden-bench_cfg.json").exists());
+        assert!(!dir.path().join("porch_cfg.json").exists());
+    }
+
+    #[tokio::test]
+    async fn test_backup_wled_device_archive() {
+        let server = mock_wled_server("127.0.0.1:109", &cfg_body("testwled"), Some(PRESETS_BODY));
+
+        let dir = tempdir().unwrap();
+        let mut settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        settings.archive = Some(ArchiveFormat::Zip);
+        backup_localhost(109, &settings).await;
+        server.join().unwrap();
+
+        validate_response_files(dir.path(), "testwled");
+        let archive = fs::File::open(dir.path().join("testwled_backup.zip")).unwrap();
+        let zip = zip::ZipArchive::new(archive).unwrap();
+        let mut names: Vec<String> = zip.file_names().map(|n| n.unwrap().to_string()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["cfg.json", "manifest.json", "meta.json", "presets.json"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backup_wleds_run_archive() {
+        let servers = vec![
+            mock_wled_server("127.0.0.1:110", &cfg_body("porch"), Some(PRESETS_BODY)),
+            mock_wled_server("127.0.0.1:111", &cfg_body("garden"), Some(PRESETS_BODY)),
+        ];
+        let wleds = vec![
+            mock_device("porch", "127.0.0.1", 110),
+            mock_device("garden", "127.0.0.1", 111),
+        ];
+
+        let dir = tempdir().unwrap();
+        let mut layout = Layout::new(LayoutKind::Flat, dir.path());
+        layout.timestamp = Some("20261015T030405Z".to_string());
+        let mut settings = BackupSettings::new(layout);
+        settings.archive = Some(ArchiveFormat::Zip);
+        settings.archive_scope = ArchiveScope::Run;
+
+        let BackupRun { failures, .. } =
+            backup_wleds(&Fetcher::default(), wleds, &settings, 2).await;
+        assert!(failures.is_empty(), "Backup failed");
+        for handle in servers {
+            handle.join().unwrap();
+        }
+
+        let archive = fs::File::open(dir.path().join("20261015T030405Z.zip")).unwrap();
+        let zip = zip::ZipArchive::new(archive).unwrap();
+        let names: Vec<String> = zip.file_names().map(|n| n.unwrap().to_string()).collect();
+        assert!(names.contains(&"garden/cfg.json".to_string()), "{names:?}");
+        assert!(
+            names.contains(&"porch/presets.json".to_string()),
+            "{names:?}"
+        );
+        assert!(
+            !dir.path()
+                .join("20261015T030405Z")
+                .join("porch_backup.zip")
+                .exists()
+        );
+    }
+
+    #[test]
+    fn test_parse_fs_listing() {
+        let listing = br#"[
+            {"type":"file","name":"/cfg.json","size":10},
+            {"type":"file","name":"ledmap.json","size":10},
+            {"type":"dir","name":"/sub","size":0},
+            {"type":"file","name":"/sub/nested.json","size":10},
+            {"type":"file","name":"/..","size":10}
+        ]"#;
+        assert_eq!(
+            parse_fs_listing(listing).unwrap(),
+            vec!["cfg.json".to_string(), "ledmap.json".to_string()]
+        );
+        assert!(parse_fs_listing(b"not json").is_err());
+        assert!(parse_fs_listing(br#"[{"type":"file"}]"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_version() {
+        let handle = mock_routes_server(
+            "127.0.0.1:113",
+            &[("/json/info", r#"{"ver":"0.15.0","leds":{"count":30}}"#)],
+        );
+
+        let version = fetch_version(
+            &Fetcher::default(),
+            &IpAddr::V4(Ipv4Addr::LOCALHOST),
+            113,
+            &DeviceOptions::default(),
+        )
+        .await;
+        assert_eq!(version.unwrap(), "0.15.0");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_ledmap_file_names() {
+        let names = ledmap_file_names();
+        assert_eq!(names.len(), 10);
+        assert_eq!(names[0], "ledmap.json");
+        assert_eq!(names[9], "ledmap9.json");
+    }
+
+    #[test]
+    fn test_endpoint_paths() {
+        assert_eq!(Endpoint::Eff.url_path(), "/json/eff");
+        assert_eq!(Endpoint::Pal.file_name(), "pal.json");
+    }
+}
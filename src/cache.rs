@@ -0,0 +1,301 @@
+use crate::device::{Device, DeviceOptions, normalize_mac};
+use crate::discovery::{Discovery, Found, probe};
+use crate::error::BoxError;
+use crate::http::Fetcher;
+use crate::layout::write_atomic;
+use chrono::{DateTime, Utc};
+use futures::future::LocalBoxFuture;
+use futures::{FutureExt, StreamExt, stream};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+use tracing::info;
+
+/// In out_dir, where --cache-devices remembers the WLEDs found.
+pub const DEVICE_CACHE_FILE: &str = "devices.json";
+
+/// How many cached devices are checked at once.
+const VERIFY_JOBS: usize = 16;
+
+/// How many runs in a row a cached device can go unfound before it's
+/// forgotten, such as once it's retired.
+pub const MAX_MISSED_RUNS: u32 = 3;
+
+/// Where a WLED was found last time.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedDevice {
+    pub name: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mdns_name: Option<String>,
+
+    /// When it was last found, in RFC 3339 form.
+    #[serde(default)]
+    pub last_seen: String,
+
+    /// How many runs in a row haven't found it since.
+    #[serde(default)]
+    pub missed: u32,
+}
+
+impl CachedDevice {
+    /// `device`, found at `now`.
+    pub fn new(device: &Device, now: DateTime<Utc>) -> Self {
+        CachedDevice {
+            name: device.name.clone(),
+            addresses: device.addresses.clone(),
+            port: device.port,
+            mac: device.mac.clone(),
+            mdns_name: device.mdns_name.clone(),
+            last_seen: now.to_rfc3339(),
+            missed: 0,
+        }
+    }
+
+    /// Is this the device, by name or MAC?
+    pub fn is(&self, device: &Device) -> bool {
+        device.has_name(&self.name)
+            || self
+                .mac
+                .as_deref()
+                .zip(device.mac.as_deref())
+                .is_some_and(|(cached, found)| normalize_mac(cached) == normalize_mac(found))
+    }
+}
+
+/// The WLEDs found on earlier runs, so later ones can go straight to them.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceCache {
+    pub devices: Vec<CachedDevice>,
+}
+
+impl DeviceCache {
+    /// Read the cache at `path`, which is empty if there's none yet.
+    pub fn load(path: &Path) -> Result<DeviceCache, BoxError> {
+        match std::fs::read(path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)
+                .map_err(|e| format!("Invalid {}: {e}", path.display()))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(DeviceCache::default()),
+            Err(e) => Err(format!("Failed to read {}: {e}", path.display()).into()),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), BoxError> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        write_atomic(path, &serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Remember where `devices` were found, at `now`. Devices not found
+    /// this time are still looked for, until [`MAX_MISSED_RUNS`] runs in a
+    /// row have missed them.
+    pub fn update(&mut self, devices: &[Device], now: DateTime<Utc>) {
+        self.devices
+            .iter_mut()
+            .for_each(|cached| cached.missed += 1);
+        for device in devices.iter().filter(|d| !d.addresses.is_empty()) {
+            self.devices.retain(|cached| !cached.is(device));
+            self.devices.push(CachedDevice::new(device, now));
+        }
+        self.devices.retain(|cached| {
+            let gone = cached.missed >= MAX_MISSED_RUNS;
+            if gone {
+                info!(
+                    "Forgetting {}, last found {}",
+                    cached.name, cached.last_seen
+                );
+            }
+            !gone
+        });
+        self.devices.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    /// Whether every cached device is among `devices`, with an address.
+    pub fn found_all(&self, devices: &[Device]) -> bool {
+        self.devices.iter().all(|cached| {
+            devices
+                .iter()
+                .any(|device| !device.addresses.is_empty() && cached.is(device))
+        })
+    }
+}
+
+/// Check that a cached device is still where it was, by asking each of its
+/// addresses for /json/info, reaching it with `options` as a backup would. A
+/// WLED with another MAC has taken its place.
+async fn verify(
+    fetcher: &Fetcher,
+    cached: &CachedDevice,
+    options: &DeviceOptions,
+    timeout: Duration,
+) -> Option<Device> {
+    let mut addresses = vec![];
+    let mut mac = cached.mac.clone();
+    for ip in cached.addresses.iter() {
+        let Some(found) = probe(fetcher, *ip, cached.port, options, timeout).await else {
+            continue;
+        };
+        let moved = cached
+            .mac
+            .as_deref()
+            .zip(found.mac.as_deref())
+            .is_some_and(|(cached, found)| normalize_mac(cached) != normalize_mac(found));
+        if !moved {
+            addresses.push(*ip);
+            mac = mac.or(found.mac);
+        }
+    }
+    if addresses.is_empty() {
+        info!("{} isn't where it was last time", cached.name);
+        return None;
+    }
+
+    let mut device = Device::new(&cached.name, addresses, cached.port);
+    device.mac = mac;
+    device.mdns_name = cached.mdns_name.clone();
+    device.options = options.clone();
+    Some(device)
+}
+
+/// Try the devices found on earlier runs where they were found, each with
+/// the options to reach it by.
+pub struct Cached {
+    pub devices: Vec<(CachedDevice, DeviceOptions)>,
+    pub timeout: Duration,
+}
+
+impl Discovery for Cached {
+    fn describe(&self) -> Option<String> {
+        Some(format!("Trying {} cached devices", self.devices.len()))
+    }
+
+    fn discover<'a>(&'a self, fetcher: &'a Fetcher) -> LocalBoxFuture<'a, Found> {
+        async {
+            Ok(stream::iter(self.devices.iter())
+                .map(|(cached, options)| verify(fetcher, cached, options, self.timeout))
+                .buffered(VERIFY_JOBS)
+                .filter_map(|device| async { device })
+                .collect()
+                .await)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mock_device, mock_routes_server};
+
+    fn cached(name: &str, ip: &str, port: u16, mac: &str) -> CachedDevice {
+        CachedDevice {
+            name: name.to_string(),
+            addresses: vec![ip.parse().unwrap()],
+            port,
+            mac: Some(mac.to_string()),
+            mdns_name: None,
+            last_seen: String::new(),
+            missed: 0,
+        }
+    }
+
+    #[test]
+    fn test_device_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(DEVICE_CACHE_FILE);
+        let mut cache = DeviceCache::load(&path).unwrap();
+        assert_eq!(cache, DeviceCache::default());
+
+        let mut porch = mock_device("porch", "127.0.0.1", 80);
+        porch.mac = Some("a0b1c2d3e4f5".to_string());
+        let garden = mock_device("garden", "127.0.0.2", 80);
+        cache.update(&[porch.clone(), garden.clone()], Utc::now());
+        cache.save(&path).unwrap();
+        assert_eq!(DeviceCache::load(&path).unwrap(), cache);
+        assert!(cache.found_all(&[garden.clone(), porch.clone()]));
+        assert!(!cache.found_all(std::slice::from_ref(&garden)));
+
+        // Porch moved, and is known by its MAC under another name.
+        let mut moved = mock_device("wled-porch", "127.0.0.3", 80);
+        moved.mac = Some("A0:B1:C2:D3:E4:F5".to_string());
+        assert!(cache.found_all(&[garden.clone(), moved.clone()]));
+        cache.update(std::slice::from_ref(&moved), Utc::now());
+        let names: Vec<&str> = cache.devices.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["garden", "wled-porch"]);
+    }
+
+    #[test]
+    fn test_device_cache_forgets_retired() {
+        let mut cache = DeviceCache::default();
+        let porch = mock_device("porch", "127.0.0.1", 80);
+        let garden = mock_device("garden", "127.0.0.2", 80);
+        let first = Utc::now();
+        cache.update(&[porch.clone(), garden.clone()], first);
+
+        // Garden is retired: it's still looked for a while, then forgotten,
+        // so the cache can be found in full again.
+        for missed in 1..MAX_MISSED_RUNS {
+            cache.update(std::slice::from_ref(&porch), Utc::now());
+            assert_eq!(cache.devices[0].name, "garden");
+            assert_eq!(cache.devices[0].missed, missed);
+            assert_eq!(cache.devices[0].last_seen, first.to_rfc3339());
+            assert!(!cache.found_all(std::slice::from_ref(&porch)));
+        }
+        cache.update(std::slice::from_ref(&porch), Utc::now());
+        let names: Vec<&str> = cache.devices.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, ["porch"]);
+        assert_eq!(cache.devices[0].missed, 0);
+        assert!(cache.found_all(std::slice::from_ref(&porch)));
+    }
+
+    #[tokio::test]
+    async fn test_cached_discover() {
+        let info = r#"{"brand":"WLED","name":"Porch","mac":"a0b1c2d3e4f5","ver":"0.15.0"}"#;
+        let server = mock_routes_server(
+            "127.0.0.1:167",
+            &[("/json/info", info), ("/wled/hall/json/info", info)],
+        );
+        let proxied = DeviceOptions {
+            base_path: "/wled/hall".to_string(),
+            ..Default::default()
+        };
+        let source = Cached {
+            devices: vec![
+                (
+                    cached("porch", "127.0.0.1", 167, "a0:b1:c2:d3:e4:f5"),
+                    Default::default(),
+                ),
+                (
+                    cached("garden", "127.0.0.1", 167, "0a1b2c3d4e5f"),
+                    Default::default(),
+                ),
+                // Nothing answers on port 131.
+                (
+                    cached("attic", "127.0.0.1", 131, "b0b1c2d3e4f5"),
+                    Default::default(),
+                ),
+                // Only reachable by its path behind a proxy.
+                (
+                    cached("hall", "127.0.0.1", 167, "a0b1c2d3e4f5"),
+                    proxied.clone(),
+                ),
+            ],
+            timeout: Duration::from_millis(500),
+        };
+        let found = source.discover(&Fetcher::default()).await.unwrap();
+        server.join().unwrap();
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].name, "porch");
+        assert_eq!(found[0].port, 167);
+        assert_eq!(found[0].mac.as_deref(), Some("a0:b1:c2:d3:e4:f5"));
+        assert_eq!(found[1].name, "hall");
+        assert_eq!(found[1].options, proxied);
+    }
+}
@@ -0,0 +1,299 @@
+use crate::control::STATE_DIR;
+use crate::crypt::{Decryption, find_saved, read_file};
+use crate::diff::file_names;
+use crate::layout::{LATEST, Layout, LayoutKind};
+use crate::library::LIBRARY_DIR;
+use crate::manifest::{FileCheck, FileStatus, RunManifest, latest_manifest, verify};
+use crate::ota::{FIRMWARE_DIR, PRE_UPDATE_DIR};
+use crate::restore::PRE_RESTORE_DIR;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+/// What's wrong with one device's backup, if anything.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceCheck {
+    pub device: String,
+
+    /// When its files were last fetched, in RFC 3339 form.
+    pub last_backup: Option<String>,
+
+    pub problems: Vec<String>,
+}
+
+/// The result of checking a backup directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BackupCheck {
+    pub devices: Vec<DeviceCheck>,
+
+    /// The files listed in the manifest, re-hashed.
+    pub files: Vec<FileCheck>,
+
+    /// Problems with the backup as a whole, such as an unreadable manifest.
+    pub problems: Vec<String>,
+}
+
+impl BackupCheck {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+            && self.devices.iter().all(|device| device.problems.is_empty())
+            && self.files.iter().all(|file| file.status == FileStatus::Ok)
+    }
+}
+
+/// The host names with backups under out_dir.
+pub fn saved_devices(layout: &Layout) -> std::io::Result<Vec<String>> {
+    let mut devices = std::collections::BTreeSet::new();
+    match layout.kind {
+        LayoutKind::Flat => {
+            let latest = layout.out_dir.join(LATEST);
+            let dir = if latest.is_dir() {
+                latest
+            } else {
+                layout.out_dir.clone()
+            };
+            let names = file_names(&dir).map_err(std::io::Error::other)?;
+            for name in names.keys() {
+                let device = match layout.official_names {
+                    true => name
+                        .strip_prefix("wled_cfg_")
+                        .or_else(|| name.strip_prefix("wled_presets_"))
+                        .and_then(|name| name.strip_suffix(".json")),
+                    false => name
+                        .strip_suffix("_cfg.json")
+                        .or_else(|| name.strip_suffix("_presets.json")),
+                };
+                devices.extend(device.map(str::to_string));
+            }
+        }
+        LayoutKind::PerDevice => {
+            for entry in fs::read_dir(&layout.out_dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                // Snapshots taken before restores and updates, the preset
+                // library, downloaded firmware and saved states aren't
+                // backups to check.
+                let skip = name.starts_with('.')
+                    || [
+                        PRE_RESTORE_DIR,
+                        PRE_UPDATE_DIR,
+                        LIBRARY_DIR,
+                        FIRMWARE_DIR,
+                        STATE_DIR,
+                    ]
+                    .contains(&name.as_str());
+                if entry.file_type()?.is_dir() && !skip {
+                    devices.insert(name);
+                }
+            }
+        }
+    }
+    Ok(devices.into_iter().collect())
+}
+
+/// When each device's files were last fetched, from every manifest in
+/// out_dir.
+fn fetch_times(out_dir: &Path) -> BTreeMap<String, DateTime<Utc>> {
+    let mut times = BTreeMap::new();
+    let manifests = fs::read_dir(out_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with("MANIFEST") && name.ends_with(".json")
+        });
+    for manifest in manifests.filter_map(|path| RunManifest::load(&path).ok()) {
+        for source in manifest.files.into_iter().filter_map(|file| file.source) {
+            let Ok(fetched) = DateTime::parse_from_rfc3339(&source.fetched) else {
+                continue;
+            };
+            let fetched = fetched.with_timezone(&Utc);
+            let time = times.entry(source.device).or_insert(fetched);
+            *time = fetched.max(*time);
+        }
+    }
+    times
+}
+
+fn check_device(
+    layout: &Layout,
+    device: &str,
+    fetched: Option<DateTime<Utc>>,
+    max_age: Option<Duration>,
+    decryption: Option<&Decryption>,
+    now: DateTime<Utc>,
+) -> DeviceCheck {
+    let mut problems = vec![];
+    let mut modified = None;
+    for file in ["cfg.json", "presets.json"] {
+        let path = find_saved(layout.find_file(device, file));
+        if !path.exists() {
+            problems.push(format!("missing {file}"));
+            continue;
+        }
+        if file == "cfg.json" {
+            modified = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .map(DateTime::<Utc>::from);
+        }
+        let parsed = read_file(&path, decryption).and_then(|contents| {
+            serde_json::from_slice::<serde_json::Value>(&contents)
+                .map_err(|e| format!("{file} isn't valid JSON: {e}").into())
+        });
+        if let Err(result) = parsed {
+            problems.push(result.to_string());
+        }
+    }
+
+    // Files matching the device aren't rewritten, so the manifest says when
+    // they were last fetched better than the files' times do.
+    let last_backup = fetched.or(modified);
+    if let (Some(max_age), Some(last_backup)) = (max_age, last_backup)
+        && now - last_backup > max_age
+    {
+        let hours = (now - last_backup).num_hours();
+        problems.push(format!("last backed up {hours} hours ago"));
+    }
+
+    DeviceCheck {
+        device: device.to_string(),
+        last_backup: last_backup.map(|time| time.to_rfc3339()),
+        problems,
+    }
+}
+
+/// Check the backups under out_dir: that every device has a cfg.json and
+/// presets.json that parse, that the files in the manifest (the newest in
+/// out_dir, if not given) are unchanged, and, given `max_age`, that no
+/// device's newest backup is older.
+pub fn check_backups(
+    layout: &Layout,
+    manifest: Option<&Path>,
+    max_age: Option<Duration>,
+    decryption: Option<&Decryption>,
+    now: DateTime<Utc>,
+) -> BackupCheck {
+    let mut check = BackupCheck::default();
+
+    let manifest_path =
+        manifest.map_or_else(|| latest_manifest(&layout.out_dir), Path::to_path_buf);
+    if manifest.is_some() || manifest_path.exists() {
+        match verify(&manifest_path) {
+            Ok(files) => check.files = files,
+            Err(result) => check.problems.push(result.to_string()),
+        }
+    }
+
+    let devices = match saved_devices(layout) {
+        Ok(devices) => devices,
+        Err(result) => {
+            let out_dir = layout.out_dir.display();
+            check
+                .problems
+                .push(format!("Failed to read {out_dir}: {result}"));
+            return check;
+        }
+    };
+    if devices.is_empty() {
+        let out_dir = layout.out_dir.display();
+        check.problems.push(format!("No backups in {out_dir}"));
+    }
+
+    let fetched = fetch_times(&layout.out_dir);
+    check.devices = devices
+        .iter()
+        .map(|device| {
+            let fetched = fetched.get(device).copied();
+            check_device(layout, device, fetched, max_age, decryption, now)
+        })
+        .collect();
+    check
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::FileSource;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_check_backups() {
+        let dir = tempdir().unwrap();
+        let out_dir = dir.path();
+        fs::write(out_dir.join("porch_cfg.json"), "{}").unwrap();
+        fs::write(out_dir.join("porch_presets.json"), "{}").unwrap();
+        fs::write(out_dir.join("garage_cfg.json"), "<html>").unwrap();
+
+        let now: DateTime<Utc> = "2026-10-15T12:00:00Z".parse().unwrap();
+        let mut manifest = RunManifest {
+            created: "2026-10-15T03:00:00+00:00".to_string(),
+            files: vec![],
+        };
+        let source = FileSource {
+            device: "porch".to_string(),
+            fetched: "2026-10-15T03:00:00+00:00".to_string(),
+            ..Default::default()
+        };
+        manifest
+            .add(out_dir, &out_dir.join("porch_cfg.json"), Some(source))
+            .unwrap();
+        manifest.write(&out_dir.join("MANIFEST.json")).unwrap();
+
+        let layout = Layout::new(LayoutKind::Flat, out_dir);
+        let check = check_backups(&layout, None, Some(Duration::hours(24)), None, now);
+        assert!(check.problems.is_empty());
+        assert_eq!(check.files[0].status, FileStatus::Ok);
+        assert_eq!(check.devices.len(), 2);
+
+        let garage = &check.devices[0];
+        assert_eq!(garage.device, "garage");
+        assert_eq!(garage.problems.len(), 2, "{:?}", garage.problems);
+        assert!(garage.problems[0].contains("cfg.json isn't valid JSON"));
+        assert_eq!(garage.problems[1], "missing presets.json");
+
+        let porch = &check.devices[1];
+        assert_eq!(
+            porch.last_backup.as_deref(),
+            Some("2026-10-15T03:00:00+00:00")
+        );
+        assert!(porch.problems.is_empty());
+        assert!(!check.is_ok());
+
+        // Too old, and tampered with.
+        fs::write(out_dir.join("porch_cfg.json"), "[]").unwrap();
+        let check = check_backups(&layout, None, Some(Duration::hours(6)), None, now);
+        assert_eq!(check.files[0].status, FileStatus::Changed);
+        assert_eq!(
+            check.devices[1].problems,
+            vec!["last backed up 9 hours ago"]
+        );
+    }
+
+    #[test]
+    fn test_check_backups_per_device() {
+        let dir = tempdir().unwrap();
+        let layout = Layout::new(LayoutKind::PerDevice, dir.path());
+        let now = Utc::now();
+        assert_eq!(
+            check_backups(&layout, None, None, None, now).problems.len(),
+            1
+        );
+
+        fs::create_dir(dir.path().join("porch")).unwrap();
+        fs::write(dir.path().join("porch/cfg.json"), "{}").unwrap();
+        fs::write(dir.path().join("porch/presets.json"), "{}").unwrap();
+        let check = check_backups(&layout, None, Some(Duration::hours(1)), None, now);
+        assert!(check.is_ok(), "{check:?}");
+        assert_eq!(check.devices[0].device, "porch");
+
+        // A manifest that was asked for must exist.
+        let missing = dir.path().join("MANIFEST.json");
+        let check = check_backups(&layout, Some(&missing), None, None, now);
+        assert!(!check.is_ok());
+    }
+}
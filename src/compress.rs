@@ -0,0 +1,133 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// How saved JSON files are compressed.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// .json.zst
+    Zstd,
+    /// .json.gz
+    Gzip,
+}
+
+const ALL: [Compression; 2] = [Compression::Zstd, Compression::Gzip];
+
+impl Compression {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Zstd => ".zst",
+            Compression::Gzip => ".gz",
+        }
+    }
+
+    pub fn compress(&self, contents: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => zstd::encode_all(contents, 0),
+            Compression::Gzip => {
+                let mut gz = flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+                gz.write_all(contents)?;
+                gz.finish()
+            }
+        }
+    }
+
+    pub fn decompress(&self, contents: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => zstd::decode_all(contents),
+            Compression::Gzip => {
+                let mut decompressed = vec![];
+                flate2::read::GzDecoder::new(contents).read_to_end(&mut decompressed)?;
+                Ok(decompressed)
+            }
+        }
+    }
+
+    /// Is a file of this name compressed when saved? Only JSON is, so
+    /// archives and other files keep their names.
+    pub fn applies_to(name: &str) -> bool {
+        name.ends_with(".json")
+    }
+
+    /// The name a file is saved under, such as porch_cfg.json.zst.
+    pub fn saved_name(compression: Option<Compression>, name: &str) -> String {
+        match compression {
+            Some(compression) if Compression::applies_to(name) => {
+                format!("{name}{}", compression.extension())
+            }
+            _ => name.to_string(),
+        }
+    }
+
+    /// The path a file is saved to.
+    pub fn saved_path(compression: Option<Compression>, path: &Path) -> PathBuf {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        path.with_file_name(Compression::saved_name(compression, &name))
+    }
+
+    /// Split a saved file's name into the JSON file's name and how it was
+    /// compressed, as in ("porch_cfg.json", Some(Zstd)).
+    pub fn split_name(name: &str) -> (&str, Option<Compression>) {
+        for compression in ALL {
+            if let Some(json) = name.strip_suffix(compression.extension())
+                && Compression::applies_to(json)
+            {
+                return (json, Some(compression));
+            }
+        }
+        (name, None)
+    }
+
+    /// Every name a JSON file may have been saved under.
+    pub fn saved_names(name: &str) -> Vec<String> {
+        std::iter::once(name.to_string())
+            .chain(ALL.iter().map(|c| Compression::saved_name(Some(*c), name)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let contents = br#"{"0":{},"1":{"n":"Warm"}}"#.repeat(100);
+        for compression in ALL {
+            let compressed = compression.compress(&contents).unwrap();
+            assert!(compressed.len() < contents.len() / 10, "{compression:?}");
+            assert_eq!(compression.decompress(&compressed).unwrap(), contents);
+            assert!(compression.decompress(b"not compressed").is_err());
+        }
+    }
+
+    #[test]
+    fn test_names() {
+        let zstd = Some(Compression::Zstd);
+        assert_eq!(
+            Compression::saved_name(zstd, "porch_cfg.json"),
+            "porch_cfg.json.zst"
+        );
+        assert_eq!(Compression::saved_name(zstd, "backup.zip"), "backup.zip");
+        assert_eq!(
+            Compression::saved_name(None, "porch_cfg.json"),
+            "porch_cfg.json"
+        );
+
+        assert_eq!(
+            Compression::split_name("porch_cfg.json.zst"),
+            ("porch_cfg.json", zstd)
+        );
+        assert_eq!(
+            Compression::split_name("cfg.json.gz"),
+            ("cfg.json", Some(Compression::Gzip))
+        );
+        assert_eq!(
+            Compression::split_name("backup.tar.gz"),
+            ("backup.tar.gz", None)
+        );
+        assert_eq!(
+            Compression::saved_names("cfg.json"),
+            vec!["cfg.json", "cfg.json.zst", "cfg.json.gz"]
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use crate::backup::DeviceHttp;
+use crate::device::DeviceOptions;
+use crate::error::BackupError;
+use crate::http::Fetcher;
+use crate::layout::write_atomic;
+use crate::preset::MAX_PRESET;
+use serde_json::{Value, json};
+use std::net::IpAddr;
+use std::path::Path;
+use tracing::info;
+
+/// Under out_dir, where state snapshot saves what each WLED is showing.
+pub const STATE_DIR: &str = "state";
+
+/// Parse a JSON state to send, which must be an object such as
+/// {"on":false}.
+pub fn parse_state(s: &str) -> Result<Value, String> {
+    match serde_json::from_str::<Value>(s) {
+        Ok(state) if state.is_object() => Ok(state),
+        Ok(_) => Err("The state must be a JSON object, such as {\"on\":false}".to_string()),
+        Err(e) => Err(format!("Invalid JSON: {e}")),
+    }
+}
+
+/// Send `state` to a WLED's /json/state, as the web UI does to change what
+/// it shows.
+pub async fn send_state(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    state: &Value,
+) -> Result<(), BackupError> {
+    let http = DeviceHttp::new(fetcher, ip, port, options);
+    http.unlock().await?;
+    http.post_json("/json/state", state.to_string()).await?;
+    info!("sent {state} to {ip}:{port}");
+    Ok(())
+}
+
+/// The state that makes a WLED reboot.
+pub fn reboot_state() -> Value {
+    json!({"rb": true})
+}
+
+/// What a WLED is showing right now, from /json/state.
+pub async fn fetch_state(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+) -> Result<Value, BackupError> {
+    let http = DeviceHttp::new(fetcher, ip, port, options);
+    let state: Value = serde_json::from_slice(&http.get("/json/state").await?)
+        .map_err(|e| BackupError::Invalid(format!("Invalid /json/state: {e}")))?;
+    match state.is_object() {
+        true => Ok(state),
+        false => Err(BackupError::Invalid(
+            "Invalid /json/state: not a JSON object".to_string(),
+        )),
+    }
+}
+
+/// Save a WLED's state to `path`, as JSON.
+pub fn save_state(path: &Path, state: &Value) -> Result<(), BackupError> {
+    let contents = serde_json::to_vec_pretty(state).expect("JSON values serialize");
+    let io_error = |source| BackupError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(io_error)?;
+    }
+    write_atomic(path, &contents).map_err(io_error)
+}
+
+/// Read a state saved by [`save_state`].
+pub fn load_state(path: &Path) -> Result<Value, BackupError> {
+    let contents = std::fs::read(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => {
+            BackupError::Invalid(format!("No saved state at {}", path.display()))
+        }
+        _ => BackupError::Invalid(format!("Failed to read {}: {e}", path.display())),
+    })?;
+    let state: Value = serde_json::from_slice(&contents)
+        .map_err(|e| BackupError::Invalid(format!("Invalid {}: {e}", path.display())))?;
+    match state.is_object() {
+        true => Ok(state),
+        false => Err(BackupError::Invalid(format!(
+            "Invalid {}: not a JSON object",
+            path.display()
+        ))),
+    }
+}
+
+/// What to send to a WLED to bring back a state fetched from it. The preset
+/// and playlist it reports are only what it was running, so they're dropped,
+/// except that a running playlist is started again.
+pub fn restorable_state(state: &Value) -> Value {
+    let mut state = state.clone();
+    if let Some(fields) = state.as_object_mut() {
+        let playlist = fields
+            .get("pl")
+            .and_then(Value::as_i64)
+            .filter(|pl| *pl > 0);
+        fields.remove("ps");
+        fields.remove("pl");
+        if let Some(playlist) = playlist {
+            fields.insert("ps".to_string(), json!(playlist));
+        }
+    }
+    state
+}
+
+/// What to send to /json/state to save what a WLED is showing as preset
+/// `id`, named `name`, with its brightness and segment bounds.
+pub fn save_preset_state(id: u16, name: &str) -> Result<Value, String> {
+    if !(1..=MAX_PRESET).contains(&id) {
+        return Err(format!(
+            "There's no preset {id}: presets are 1 to {MAX_PRESET}"
+        ));
+    }
+    Ok(json!({"psave": id, "n": name, "ib": true, "sb": true}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mock_recording_server, mock_routes_server};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse_state() {
+        assert_eq!(parse_state(r#"{"on":false}"#), Ok(json!({"on": false})));
+        assert!(parse_state("[1]").is_err());
+        assert!(parse_state("{on:false}").is_err());
+    }
+
+    #[test]
+    fn test_save_preset_state() {
+        assert_eq!(
+            save_preset_state(250, "Snapshot"),
+            Ok(json!({"psave": 250, "n": "Snapshot", "ib": true, "sb": true}))
+        );
+        assert!(save_preset_state(0, "Snapshot").is_err());
+    }
+
+    #[test]
+    fn test_restorable_state() {
+        let state = json!({"on": true, "bri": 128, "ps": 3, "pl": -1, "seg": [{"id": 0}]});
+        assert_eq!(
+            restorable_state(&state),
+            json!({"on": true, "bri": 128, "seg": [{"id": 0}]})
+        );
+        let state = json!({"on": true, "ps": 4, "pl": 7});
+        assert_eq!(restorable_state(&state), json!({"on": true, "ps": 7}));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_state() {
+        let routes = [("/json/state", r#"{"on":true,"bri":128,"ps":3}"#)];
+        let server = mock_routes_server("127.0.0.1:166", &routes);
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let state = fetch_state(&Fetcher::default(), &ip, 166, &DeviceOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(state, json!({"on": true, "bri": 128, "ps": 3}));
+        server.join().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("porch.json");
+        save_state(&path, &state).unwrap();
+        assert_eq!(load_state(&path).unwrap(), state);
+        assert!(load_state(&dir.path().join("garden.json")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_state() {
+        let server = mock_recording_server("127.0.0.1:163");
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let state = json!({"on": false});
+        send_state(
+            &Fetcher::default(),
+            &ip,
+            163,
+            &DeviceOptions::default(),
+            &state,
+        )
+        .await
+        .unwrap();
+
+        let seen = server.join().unwrap();
+        let seen: Vec<(&str, &str, &str)> = seen
+            .iter()
+            .map(|(method, url, body)| (method.as_str(), url.as_str(), body.as_str()))
+            .collect();
+        assert_eq!(seen, [("POST", "/json/state", r#"{"on":false}"#)]);
+    }
+}
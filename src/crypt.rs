@@ -0,0 +1,241 @@
+use crate::compress::Compression;
+use age::secrecy::{ExposeSecret, SecretString};
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Added to the names of encrypted files, as in porch_cfg.json.age.
+pub const AGE_EXTENSION: &str = ".age";
+
+/// Where the encrypted copy of `path` is saved.
+pub fn age_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(AGE_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Is `path` an encrypted file?
+pub fn is_age_path(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(AGE_EXTENSION)
+}
+
+/// Read a passphrase from the first line of a file.
+pub fn read_passphrase(
+    path: &Path,
+) -> Result<SecretString, Box<dyn std::error::Error + Send + Sync>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+    let passphrase = contents.lines().next().unwrap_or_default();
+    if passphrase.is_empty() {
+        return Err(format!("{} has no passphrase", path.display()).into());
+    }
+    Ok(SecretString::from(passphrase.to_string()))
+}
+
+/// Who saved files are encrypted for.
+#[derive(Clone)]
+pub enum Encryption {
+    /// age public keys, such as "age1...". Any of their private keys can
+    /// decrypt the files.
+    Recipients(Vec<age::x25519::Recipient>),
+    Passphrase(SecretString),
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Encryption::Recipients(recipients) => {
+                let keys: Vec<String> = recipients.iter().map(|r| r.to_string()).collect();
+                f.debug_tuple("Recipients").field(&keys).finish()
+            }
+            Encryption::Passphrase(_) => f.write_str("Passphrase(..)"),
+        }
+    }
+}
+
+impl PartialEq for Encryption {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Encryption::Recipients(a), Encryption::Recipients(b)) => a == b,
+            (Encryption::Passphrase(a), Encryption::Passphrase(b)) => {
+                a.expose_secret() == b.expose_secret()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Encryption {}
+
+impl Encryption {
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, age::EncryptError> {
+        let encryptor = match self {
+            Encryption::Recipients(recipients) => age::Encryptor::with_recipients(
+                recipients.iter().map(|r| r as &dyn age::Recipient),
+            )?,
+            Encryption::Passphrase(passphrase) => {
+                age::Encryptor::with_user_passphrase(passphrase.clone())
+            }
+        };
+
+        let mut encrypted = vec![];
+        let mut writer = encryptor.wrap_output(&mut encrypted)?;
+        writer.write_all(plaintext)?;
+        writer.finish()?;
+        Ok(encrypted)
+    }
+}
+
+/// Keys to decrypt saved files with.
+#[derive(Default)]
+pub struct Decryption {
+    identities: Vec<Box<dyn age::Identity>>,
+}
+
+impl Decryption {
+    /// Load the private keys in an age identity file, such as one made by
+    /// age-keygen.
+    pub fn add_identity_file(
+        &mut self,
+        path: &Path,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let file = age::IdentityFile::from_file(path.to_string_lossy().into_owned())
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        self.identities.extend(file.into_identities()?);
+        Ok(())
+    }
+
+    pub fn add_identity(&mut self, identity: age::x25519::Identity) {
+        self.identities.push(Box::new(identity));
+    }
+
+    pub fn add_passphrase(&mut self, passphrase: SecretString) {
+        self.identities
+            .push(Box::new(age::scrypt::Identity::new(passphrase)));
+    }
+
+    pub fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>, age::DecryptError> {
+        let decryptor = age::Decryptor::new_buffered(encrypted)?;
+        let mut reader = decryptor.decrypt(self.identities.iter().map(|i| i.as_ref()))?;
+        let mut plaintext = vec![];
+        reader.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+}
+
+/// Read a saved file, decrypting it if its name ends in .age and then
+/// decompressing it if it ends in .json.zst or .json.gz.
+pub fn read_file(
+    path: &Path,
+    decryption: Option<&Decryption>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut contents =
+        std::fs::read(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+
+    let name = path.to_string_lossy();
+    let name = match name.strip_suffix(AGE_EXTENSION) {
+        Some(name) => {
+            let decryption = decryption.ok_or_else(|| {
+                format!(
+                    "{} is encrypted; give --identity or --passphrase-file",
+                    path.display()
+                )
+            })?;
+            contents = decryption
+                .decrypt(&contents)
+                .map_err(|e| format!("Failed to decrypt {}: {e}", path.display()))?;
+            name
+        }
+        None => &name,
+    };
+
+    match Compression::split_name(name) {
+        (_, Some(compression)) => Ok(compression
+            .decompress(&contents)
+            .map_err(|e| format!("Failed to decompress {}: {e}", path.display()))?),
+        (_, None) => Ok(contents),
+    }
+}
+
+/// `path`, or the compressed or encrypted copy of it that was saved, if only
+/// that exists.
+pub fn find_saved(path: PathBuf) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    Compression::saved_names(&name)
+        .iter()
+        .map(|name| path.with_file_name(name))
+        .flat_map(|saved| {
+            let encrypted = age_path(&saved);
+            [saved, encrypted]
+        })
+        .find(|saved| saved.exists())
+        .unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_encrypt_to_recipient() {
+        let identity = age::x25519::Identity::generate();
+        let encryption = Encryption::Recipients(vec![identity.to_public()]);
+        let encrypted = encryption.encrypt(b"psk").unwrap();
+        assert!(encrypted.starts_with(b"age-encryption.org/v1"));
+
+        let mut decryption = Decryption::default();
+        assert!(decryption.decrypt(&encrypted).is_err());
+        decryption.add_identity(identity);
+        assert_eq!(decryption.decrypt(&encrypted).unwrap(), b"psk");
+    }
+
+    #[test]
+    fn test_read_file() {
+        let dir = tempdir().unwrap();
+        let identity = age::x25519::Identity::generate();
+        let key_path = dir.path().join("key.txt");
+        std::fs::write(&key_path, identity.to_string().expose_secret()).unwrap();
+
+        let path = dir.path().join("porch_cfg.json");
+        let encryption = Encryption::Recipients(vec![identity.to_public()]);
+        std::fs::write(age_path(&path), encryption.encrypt(b"cfg").unwrap()).unwrap();
+        assert_eq!(find_saved(path.clone()), age_path(&path));
+
+        let error = read_file(&age_path(&path), None).unwrap_err();
+        assert!(error.to_string().contains("is encrypted"), "{error}");
+
+        let mut decryption = Decryption::default();
+        decryption.add_identity_file(&key_path).unwrap();
+        assert_eq!(
+            read_file(&age_path(&path), Some(&decryption)).unwrap(),
+            b"cfg"
+        );
+
+        std::fs::write(&path, "plain").unwrap();
+        assert_eq!(find_saved(path.clone()), path);
+        assert_eq!(read_file(&path, None).unwrap(), b"plain");
+
+        // Compressed, and then encrypted.
+        let path = dir.path().join("porch_presets.json");
+        let compressed = Compression::Zstd.compress(b"presets").unwrap();
+        let saved = age_path(&dir.path().join("porch_presets.json.zst"));
+        std::fs::write(&saved, encryption.encrypt(&compressed).unwrap()).unwrap();
+        assert_eq!(find_saved(path.clone()), saved);
+        assert_eq!(read_file(&saved, Some(&decryption)).unwrap(), b"presets");
+    }
+
+    #[test]
+    fn test_read_passphrase() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("passphrase");
+        std::fs::write(&path, "correct horse\n").unwrap();
+        assert_eq!(
+            read_passphrase(&path).unwrap().expose_secret(),
+            "correct horse"
+        );
+
+        std::fs::write(&path, "").unwrap();
+        assert!(read_passphrase(&path).is_err());
+    }
+}
@@ -0,0 +1,531 @@
+use crate::error::BoxError;
+use crate::http::BasicAuth;
+use mdns_sd::ServiceInfo;
+use serde::Deserialize;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::warn;
+
+const DEFAULT_PORT: u16 = 80;
+
+/// Per-device settings, from the device inventory file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceOptions {
+    /// Give up on each HTTP request to the device after this long.
+    pub timeout: Option<Duration>,
+
+    /// Don't download presets.json.
+    pub skip_presets: bool,
+
+    /// Settings PIN, sent before downloading cfg.json or uploading a backup.
+    pub pin: Option<String>,
+
+    /// Credentials for a reverse proxy in front of the device.
+    pub auth: Option<BasicAuth>,
+
+    pub scheme: Scheme,
+
+    /// Path the device's URLs start with behind a reverse proxy, such as
+    /// "/wled/porch", or "" for none.
+    pub base_path: String,
+
+    /// Host name to put in URLs instead of the address, so that TLS
+    /// certificates and name-based proxies work.
+    pub url_host: Option<String>,
+
+    /// Extra headers sent with every request, such as a proxy's token.
+    pub headers: Vec<(String, String)>,
+}
+
+impl DeviceOptions {
+    /// The URL the device's paths go under, such as
+    /// "https://home.example.com:443/wled/porch".
+    pub fn base_url(&self, ip: &IpAddr, port: u16) -> String {
+        let authority = match &self.url_host {
+            Some(host) => format!("{host}:{port}"),
+            None => SocketAddr::new(*ip, port).to_string(),
+        };
+        format!("{}://{authority}{}", self.scheme.as_str(), self.base_path)
+    }
+
+    /// `request`, with the device's credentials and extra headers.
+    pub fn apply(&self, mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if let Some(auth) = &self.auth {
+            request = request.basic_auth(&auth.user, auth.password.as_ref());
+        }
+        for (name, value) in self.headers.iter() {
+            request = request.header(name, value);
+        }
+        request
+    }
+}
+
+/// How to talk to a device: plain HTTP, as WLED serves, or HTTPS through a
+/// reverse proxy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    #[default]
+    Http,
+    Https,
+}
+
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Scheme::Http => DEFAULT_PORT,
+            Scheme::Https => 443,
+        }
+    }
+}
+
+/// Which address family to try first, when a device has both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressPreference {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+/// IPv6 link-local addresses (fe80::/10) can't be used without a scope ID.
+fn is_link_local(ip: &IpAddr) -> bool {
+    matches!(ip, IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80)
+}
+
+/// Lower case, without separators, so "A0:B1:..." matches "a0b1...".
+pub fn normalize_mac(mac: &str) -> String {
+    mac.chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// A WLED to back up, however it was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    /// Name used when reporting on the device: the mDNS host name, or the
+    /// host as given on the command line.
+    pub name: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+
+    /// Other names the device may be discovered under.
+    pub aliases: Vec<String>,
+
+    /// MAC address, from the mDNS TXT record, if discovered.
+    pub mac: Option<String>,
+
+    /// Full mDNS service name, such as "wled-porch._wled._tcp.local.", if
+    /// discovered.
+    pub mdns_name: Option<String>,
+    pub options: DeviceOptions,
+}
+
+impl Device {
+    pub fn new(name: &str, addresses: Vec<IpAddr>, port: u16) -> Self {
+        Device {
+            name: name.to_string(),
+            addresses,
+            port,
+            aliases: vec![],
+            mac: None,
+            mdns_name: None,
+            options: DeviceOptions::default(),
+        }
+    }
+
+    /// Order the addresses to try: the preferred family first, and IPv6
+    /// link-local addresses last.
+    pub fn order_addresses(&mut self, preference: AddressPreference) {
+        self.addresses.sort_by_key(|ip| {
+            let preferred = match preference {
+                AddressPreference::Any => true,
+                AddressPreference::Ipv4 => ip.is_ipv4(),
+                AddressPreference::Ipv6 => ip.is_ipv6(),
+            };
+            (is_link_local(ip), !preferred)
+        });
+    }
+
+    /// Do the two devices share an address and port?
+    pub fn same_endpoint(&self, other: &Device) -> bool {
+        self.port == other.port && self.addresses.iter().any(|a| other.addresses.contains(a))
+    }
+
+    /// Are both devices known to have the same MAC address?
+    pub fn same_mac(&self, other: &Device) -> bool {
+        match (&self.mac, &other.mac) {
+            (Some(a), Some(b)) => normalize_mac(a) == normalize_mac(b),
+            _ => false,
+        }
+    }
+
+    /// Does this device go by the given name, ignoring case and any
+    /// mDNS ".local." suffix?
+    pub fn has_name(&self, name: &str) -> bool {
+        let short = |n: &str| {
+            n.trim_end_matches('.')
+                .trim_end_matches(".local")
+                .to_lowercase()
+        };
+        let name = short(name);
+        short(&self.name) == name || self.aliases.iter().any(|a| short(a) == name)
+    }
+}
+
+impl From<&ServiceInfo> for Device {
+    fn from(info: &ServiceInfo) -> Self {
+        let mut device = Device::new(
+            info.get_hostname(),
+            info.get_addresses().iter().cloned().collect(),
+            info.get_port(),
+        );
+        device.mac = info.get_property_val_str("mac").map(|mac| mac.to_string());
+        device.mdns_name = Some(info.get_fullname().to_string());
+        device
+    }
+}
+
+/// A device given on the command line as `host`, `host:port`, `ip`, `ip:port`,
+/// or `[ipv6]:port`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSpec {
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for DeviceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(DeviceSpec {
+                host: addr.ip().to_string(),
+                port: addr.port(),
+            });
+        }
+
+        // A bare IPv6 address has colons, but no port.
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(DeviceSpec {
+                host: ip.to_string(),
+                port: DEFAULT_PORT,
+            });
+        }
+
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|_| format!("Invalid port in device '{s}'"))?;
+                (host, port)
+            }
+            None => (s, DEFAULT_PORT),
+        };
+
+        if host.is_empty() {
+            return Err(format!("Missing host in device '{s}'"));
+        }
+
+        Ok(DeviceSpec {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for DeviceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl DeviceSpec {
+    /// Look up the addresses for the host, which may be a DNS name.
+    pub async fn resolve(&self) -> Result<Device, BoxError> {
+        let mut addresses = vec![];
+        for addr in tokio::net::lookup_host((self.host.as_str(), self.port)).await? {
+            if !addresses.contains(&addr.ip()) {
+                addresses.push(addr.ip());
+            }
+        }
+
+        if addresses.is_empty() {
+            return Err(format!("No addresses found for {}", self.host).into());
+        }
+
+        Ok(Device::new(&self.host, addresses, self.port))
+    }
+
+    /// Like [`DeviceSpec::resolve`], but if it fails and `retry` is given,
+    /// wait that long and try once more, in case DNS was only slow. Backups
+    /// already under way carry on meanwhile.
+    pub async fn resolve_retrying(&self, retry: Option<Duration>) -> Result<Device, BoxError> {
+        match (self.resolve().await, retry) {
+            (Err(result), Some(retry)) => {
+                warn!(
+                    "Failed to resolve {self}: {result}; trying again in {}s",
+                    retry.as_secs()
+                );
+                tokio::time::sleep(retry).await;
+                self.resolve().await
+            }
+            (resolved, _) => resolved,
+        }
+    }
+}
+
+/// Add devices to a list, skipping any already present at the same address,
+/// and adding the addresses of any with the same MAC to the device already
+/// present. Devices in the list without addresses yet take them from a device
+/// of the same name.
+pub fn merge_devices(devices: &mut Vec<Device>, more: Vec<Device>) {
+    for device in more {
+        if let Some(same) = devices.iter_mut().find(|d| d.same_endpoint(&device)) {
+            same.mac = same.mac.take().or(device.mac);
+            same.mdns_name = same.mdns_name.take().or(device.mdns_name);
+            continue;
+        }
+
+        if let Some(same) = devices.iter_mut().find(|d| d.same_mac(&device)) {
+            for ip in device.addresses {
+                if !same.addresses.contains(&ip) {
+                    same.addresses.push(ip);
+                }
+            }
+            continue;
+        }
+
+        if let Some(unresolved) = devices
+            .iter_mut()
+            .find(|d| d.addresses.is_empty() && d.has_name(&device.name))
+        {
+            unresolved.addresses = device.addresses;
+            unresolved.port = device.port;
+            unresolved.mac = device.mac;
+            unresolved.mdns_name = device.mdns_name;
+            continue;
+        }
+
+        devices.push(device);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock_service_info;
+    use std::net::Ipv4Addr;
+
+    fn spec(host: &str, port: u16) -> DeviceSpec {
+        DeviceSpec {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_device_spec_parse() {
+        assert_eq!("192.168.5.20".parse(), Ok(spec("192.168.5.20", 80)));
+        assert_eq!("192.168.5.20:8080".parse(), Ok(spec("192.168.5.20", 8080)));
+        assert_eq!("wled-porch.lan".parse(), Ok(spec("wled-porch.lan", 80)));
+        assert_eq!(
+            "wled-porch.lan:8080".parse(),
+            Ok(spec("wled-porch.lan", 8080))
+        );
+        assert_eq!("fe80::1".parse(), Ok(spec("fe80::1", 80)));
+        assert_eq!("[fe80::1]:8080".parse(), Ok(spec("fe80::1", 8080)));
+    }
+
+    #[test]
+    fn test_device_options_base_url() {
+        let v4: IpAddr = "192.168.1.20".parse().unwrap();
+        let v6: IpAddr = "fd00::20".parse().unwrap();
+        let options = DeviceOptions::default();
+        assert_eq!(options.base_url(&v4, 80), "http://192.168.1.20:80");
+        assert_eq!(options.base_url(&v6, 8080), "http://[fd00::20]:8080");
+
+        let options = DeviceOptions {
+            scheme: Scheme::Https,
+            base_path: "/wled/porch".to_string(),
+            url_host: Some("home.example.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.base_url(&v4, 443),
+            "https://home.example.com:443/wled/porch"
+        );
+    }
+
+    #[test]
+    fn test_device_spec_parse_errors() {
+        assert!("wled-porch.lan:http".parse::<DeviceSpec>().is_err());
+        assert!("wled-porch.lan:99999".parse::<DeviceSpec>().is_err());
+        assert!(":80".parse::<DeviceSpec>().is_err());
+        assert!("".parse::<DeviceSpec>().is_err());
+    }
+
+    #[test]
+    fn test_device_spec_display() {
+        assert_eq!(spec("wled-porch.lan", 80).to_string(), "wled-porch.lan:80");
+        assert_eq!(spec("fe80::1", 80).to_string(), "[fe80::1]:80");
+    }
+
+    #[tokio::test]
+    async fn test_device_spec_resolve() {
+        let device = spec("127.0.0.1", 8080).resolve().await.unwrap();
+        assert_eq!(
+            device,
+            Device::new("127.0.0.1", vec![IpAddr::V4(Ipv4Addr::LOCALHOST)], 8080)
+        );
+
+        let retry = Some(Duration::ZERO);
+        assert_eq!(
+            spec("127.0.0.1", 8080)
+                .resolve_retrying(retry)
+                .await
+                .unwrap(),
+            device
+        );
+        assert!(
+            spec("wled-porch.invalid", 80)
+                .resolve_retrying(retry)
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_device_from_service_info() {
+        let device = Device::from(&mock_service_info("mdns_name", "127.0.0.1", 80));
+        assert_eq!(device.name, "mdns_name");
+        assert_eq!(device.addresses, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        assert_eq!(device.port, 80);
+        assert_eq!(device.mac, None);
+    }
+
+    #[test]
+    fn test_device_from_service_info_mac() {
+        let info = ServiceInfo::new(
+            "_wled._tcp.local.",
+            "porch",
+            "porch.local.",
+            "127.0.0.1",
+            80,
+            &[("mac", "a0b1c2d3e4f5")][..],
+        )
+        .unwrap();
+        assert_eq!(Device::from(&info).mac, Some("a0b1c2d3e4f5".to_string()));
+    }
+
+    #[test]
+    fn test_order_addresses() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let addresses = vec![ip("fe80::1"), ip("fd00::1"), ip("192.168.1.20")];
+
+        let mut device = Device::new("porch", addresses.clone(), 80);
+        device.order_addresses(AddressPreference::Any);
+        assert_eq!(
+            device.addresses,
+            vec![ip("fd00::1"), ip("192.168.1.20"), ip("fe80::1")]
+        );
+
+        device.order_addresses(AddressPreference::Ipv4);
+        assert_eq!(
+            device.addresses,
+            vec![ip("192.168.1.20"), ip("fd00::1"), ip("fe80::1")]
+        );
+
+        device.order_addresses(AddressPreference::Ipv6);
+        assert_eq!(
+            device.addresses,
+            vec![ip("fd00::1"), ip("192.168.1.20"), ip("fe80::1")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_devices() {
+        let mut devices = vec![spec("127.0.0.1", 80).resolve().await.unwrap()];
+        merge_devices(
+            &mut devices,
+            vec![
+                Device::from(&mock_service_info("same", "127.0.0.1", 80)),
+                Device::from(&mock_service_info("other_port", "127.0.0.1", 8080)),
+            ],
+        );
+
+        let names: Vec<&str> = devices.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["127.0.0.1", "other_port"]);
+    }
+
+    #[test]
+    fn test_merge_devices_by_mac() {
+        let mut scanned = Device::new("127.0.0.1", vec![IpAddr::V4(Ipv4Addr::LOCALHOST)], 80);
+        scanned.mac = Some("a0b1c2d3e4f5".to_string());
+        let mut devices = vec![Device::new(
+            "127.0.0.1",
+            vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            80,
+        )];
+
+        // The same endpoint, now with a MAC, and then the same MAC elsewhere.
+        let mut advertised = Device::from(&mock_service_info("porch.local.", "::1", 80));
+        advertised.mac = Some("A0:B1:C2:D3:E4:F5".to_string());
+        merge_devices(&mut devices, vec![scanned, advertised]);
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].mac.as_deref(), Some("a0b1c2d3e4f5"));
+        assert_eq!(
+            devices[0].addresses,
+            vec![IpAddr::V4(Ipv4Addr::LOCALHOST), "::1".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_merge_devices_fills_in_addresses_by_name() {
+        let mut porch = Device::new("porch", vec![], 80);
+        porch.aliases = vec!["wled-porch".to_string()];
+        let mut devices = vec![porch];
+
+        merge_devices(
+            &mut devices,
+            vec![Device::from(&mock_service_info(
+                "wled-porch.local.",
+                "127.0.0.1",
+                8080,
+            ))],
+        );
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "porch");
+        assert_eq!(devices[0].addresses, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        assert_eq!(devices[0].port, 8080);
+    }
+
+    #[test]
+    fn test_device_has_name() {
+        let mut device = Device::new("Porch", vec![], 80);
+        device.aliases = vec!["wled-porch".to_string()];
+
+        assert!(device.has_name("porch"));
+        assert!(device.has_name("porch.local."));
+        assert!(device.has_name("WLED-Porch.local."));
+        assert!(!device.has_name("garden"));
+    }
+}
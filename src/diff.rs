@@ -0,0 +1,475 @@
+use crate::backup::{DeviceHttp, get_hostname_from_cfg};
+use crate::compress::Compression;
+use crate::crypt::{AGE_EXTENSION, Decryption, find_saved, read_file};
+use crate::device::DeviceOptions;
+use crate::error::BackupError;
+use crate::http::Fetcher;
+use crate::layout::{Layout, sanitize_name};
+use crate::model::WledCfg;
+use crate::redact::{SECRETS_FILE, inject_secrets, is_secret_path, take_secrets};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum FileChange {
+    Added {
+        file: String,
+    },
+    Removed {
+        file: String,
+    },
+    /// The file's contents differ. If both versions are JSON, `fields` says
+    /// how.
+    Changed {
+        file: String,
+        fields: Vec<FieldChange>,
+    },
+}
+
+/// A difference between two JSON documents, at a path of object keys and
+/// array indexes such as "nw.ins.0.ssid".
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum FieldChange {
+    Added {
+        path: String,
+        new: Value,
+    },
+    Removed {
+        path: String,
+        old: Value,
+    },
+    Changed {
+        path: String,
+        old: Value,
+        new: Value,
+    },
+}
+
+/// Passwords and keys are compared, but not shown, even inside a value
+/// added or removed whole.
+fn shown(path: &[String], value: &Value) -> Value {
+    fn mask(path: &mut Vec<String>, value: &mut Value) {
+        if is_secret_path(path) {
+            *value = Value::String("(secret)".to_string());
+            return;
+        }
+        let children: Vec<(String, &mut Value)> = match value {
+            Value::Object(map) => map.iter_mut().map(|(k, v)| (k.clone(), v)).collect(),
+            Value::Array(items) => items
+                .iter_mut()
+                .enumerate()
+                .map(|(i, v)| (i.to_string(), v))
+                .collect(),
+            _ => return,
+        };
+        for (key, child) in children {
+            path.push(key);
+            mask(path, child);
+            path.pop();
+        }
+    }
+
+    let mut value = value.clone();
+    mask(&mut path.to_vec(), &mut value);
+    value
+}
+
+fn diff_values(path: &mut Vec<String>, old: &Value, new: &Value, changes: &mut Vec<FieldChange>) {
+    let children: Vec<(String, Option<&Value>, Option<&Value>)> = match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            let keys: std::collections::BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            keys.into_iter()
+                .map(|key| (key.clone(), old.get(key), new.get(key)))
+                .collect()
+        }
+        (Value::Array(old), Value::Array(new)) => (0..old.len().max(new.len()))
+            .map(|i| (i.to_string(), old.get(i), new.get(i)))
+            .collect(),
+        (old, new) if old != new => {
+            changes.push(FieldChange::Changed {
+                path: path.join("."),
+                old: shown(path, old),
+                new: shown(path, new),
+            });
+            return;
+        }
+        _ => return,
+    };
+
+    for (key, old, new) in children {
+        path.push(key);
+        match (old, new) {
+            (Some(old), Some(new)) => diff_values(path, old, new, changes),
+            (Some(old), None) => changes.push(FieldChange::Removed {
+                path: path.join("."),
+                old: shown(path, old),
+            }),
+            (None, Some(new)) => changes.push(FieldChange::Added {
+                path: path.join("."),
+                new: shown(path, new),
+            }),
+            (None, None) => unreachable!("key came from one of the values"),
+        }
+        path.pop();
+    }
+}
+
+/// The fields added, removed and changed between two JSON documents.
+pub fn diff_json(old: &Value, new: &Value) -> Vec<FieldChange> {
+    let mut changes = vec![];
+    diff_values(&mut vec![], old, new, &mut changes);
+    changes
+}
+
+/// How two versions of a file differ, field by field if both are JSON.
+fn diff_contents(old: &[u8], new: &[u8]) -> Vec<FieldChange> {
+    match (
+        serde_json::from_slice::<Value>(old),
+        serde_json::from_slice::<Value>(new),
+    ) {
+        (Ok(old), Ok(new)) => diff_json(&old, &new),
+        _ => vec![],
+    }
+}
+
+/// The files in `dir`, by name without any .age, .zst or .gz, mapped to
+/// their actual names.
+pub(crate) fn file_names(
+    dir: &Path,
+) -> Result<BTreeMap<String, String>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut names = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let name = file_name.strip_suffix(AGE_EXTENSION).unwrap_or(&file_name);
+            let (name, _) = Compression::split_name(name);
+            names.insert(name.to_string(), file_name.clone());
+        }
+    }
+    Ok(names)
+}
+
+/// Compare the backup files in two directories, by file name and contents.
+/// Encrypted and compressed files are compared by what they decrypt and
+/// decompress to.
+pub fn diff_dirs(
+    old: &Path,
+    new: &Path,
+    decryption: Option<&Decryption>,
+) -> Result<Vec<FileChange>, Box<dyn std::error::Error + Send + Sync>> {
+    let old_names = file_names(old)?;
+    let new_names = file_names(new)?;
+
+    let mut changes = vec![];
+    let names: std::collections::BTreeSet<&String> =
+        old_names.keys().chain(new_names.keys()).collect();
+    for name in names {
+        match (old_names.get(name), new_names.get(name)) {
+            (Some(_), None) => changes.push(FileChange::Removed { file: name.clone() }),
+            (None, Some(_)) => changes.push(FileChange::Added { file: name.clone() }),
+            (Some(old_file), Some(new_file)) => {
+                let old_contents = read_file(&old.join(old_file), decryption)?;
+                let new_contents = read_file(&new.join(new_file), decryption)?;
+                if old_contents != new_contents {
+                    changes.push(FileChange::Changed {
+                        file: name.clone(),
+                        fields: diff_contents(&old_contents, &new_contents),
+                    });
+                }
+            }
+            (None, None) => unreachable!("name came from one of the directories"),
+        }
+    }
+
+    Ok(changes)
+}
+
+/// How a device differs from its backup, for --output json.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct DeviceChanges {
+    pub device: String,
+    pub changes: Vec<FileChange>,
+
+    /// Why the device couldn't be compared.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A device's saved cfg.json or presets.json, with any secrets saved beside
+/// it merged back in.
+fn read_saved(
+    layout: &Layout,
+    hostname: &str,
+    file: &str,
+    decryption: Option<&Decryption>,
+) -> Result<Option<Value>, BackupError> {
+    let invalid = |e: &dyn std::fmt::Display| BackupError::Invalid(e.to_string());
+    let path = find_saved(layout.find_file(hostname, file));
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let mut contents = read_file(&path, decryption).map_err(|e| invalid(&e))?;
+    let secrets = find_saved(layout.find_file(hostname, SECRETS_FILE));
+    if file == "cfg.json" && secrets.exists() {
+        let secrets = read_file(&secrets, decryption).map_err(|e| invalid(&e))?;
+        contents = inject_secrets(&contents, &secrets).map_err(|e| invalid(&e))?;
+    }
+    let saved = serde_json::from_slice(&contents)
+        .map_err(|e| invalid(&format!("Invalid {}: {e}", path.display())))?;
+    Ok(Some(saved))
+}
+
+/// Compare a device's current cfg.json and presets.json with its newest
+/// backup under `layout`. Returns its host name and what changed since, as
+/// if the backup were the old directory and the device the new one. If the
+/// backup was saved without secrets, the device's are ignored too.
+pub async fn diff_live(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    layout: &Layout,
+    decryption: Option<&Decryption>,
+) -> Result<(String, Vec<FileChange>), BackupError> {
+    let http = DeviceHttp::new(fetcher, ip, port, options);
+    http.unlock().await?;
+    let cfg_contents = http.get("/cfg.json").await?;
+    let cfg = WledCfg::parse(&cfg_contents).map_err(|e| BackupError::ParseCfg(e.to_string()))?;
+    let hostname = sanitize_name(get_hostname_from_cfg(&cfg)?);
+
+    let mut files = vec![("cfg.json", cfg_contents)];
+    if !options.skip_presets {
+        files.push(("presets.json", http.get("/presets.json").await?));
+    }
+
+    let mut changes = vec![];
+    for (file, contents) in files {
+        let mut live: Value = serde_json::from_slice(&contents)
+            .map_err(|e| BackupError::Invalid(format!("Invalid {file}: {e}")))?;
+        let Some(saved) = read_saved(layout, &hostname, file, decryption)? else {
+            changes.push(FileChange::Added {
+                file: file.to_string(),
+            });
+            continue;
+        };
+
+        if file == "cfg.json"
+            && take_secrets(&mut saved.clone()) == Value::Object(Default::default())
+        {
+            take_secrets(&mut live);
+        }
+        let fields = diff_json(&saved, &live);
+        if !fields.is_empty() {
+            changes.push(FileChange::Changed {
+                file: file.to_string(),
+                fields,
+            });
+        }
+    }
+    Ok((hostname, changes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypt::Encryption;
+    use crate::layout::LayoutKind;
+    use crate::test_util::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_diff_json() {
+        let old = json!({
+            "id": {"name": "porch"},
+            "nw": {"ins": [{"ssid": "home", "psk": "hunter2"}]},
+            "hw": {"led": {"total": 30}},
+        });
+        let new = json!({
+            "id": {"name": "porch"},
+            "nw": {"ins": [{"ssid": "home", "psk": "hunter3"}, {"ssid": "guest"}]},
+            "hw": {},
+            "light": {"gc": {"bri": 1}},
+        });
+        assert_eq!(
+            diff_json(&old, &new),
+            vec![
+                FieldChange::Removed {
+                    path: "hw.led".to_string(),
+                    old: json!({"total": 30}),
+                },
+                FieldChange::Added {
+                    path: "light".to_string(),
+                    new: json!({"gc": {"bri": 1}}),
+                },
+                FieldChange::Changed {
+                    path: "nw.ins.0.psk".to_string(),
+                    old: json!("(secret)"),
+                    new: json!("(secret)"),
+                },
+                FieldChange::Added {
+                    path: "nw.ins.1".to_string(),
+                    new: json!({"ssid": "guest"}),
+                },
+            ]
+        );
+        assert_eq!(diff_json(&old, &old), vec![]);
+        assert_eq!(
+            diff_json(&json!({}), &json!({"ap": {"psk": "x", "ssid": "AP"}})),
+            vec![FieldChange::Added {
+                path: "ap".to_string(),
+                new: json!({"psk": "(secret)", "ssid": "AP"}),
+            }]
+        );
+
+        // A value replaced by one of another type.
+        assert_eq!(
+            diff_json(&json!({"a": [1]}), &json!({"a": 1})),
+            vec![FieldChange::Changed {
+                path: "a".to_string(),
+                old: json!([1]),
+                new: json!(1),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_dirs_fields() {
+        let old = tempdir().unwrap();
+        let new = tempdir().unwrap();
+        fs::write(
+            old.path().join("porch_presets.json"),
+            r#"{"1":{"n":"Warm"}}"#,
+        )
+        .unwrap();
+        fs::write(
+            new.path().join("porch_presets.json"),
+            r#"{"1":{"n":"Cool"}}"#,
+        )
+        .unwrap();
+
+        let changes = diff_dirs(old.path(), new.path(), None).unwrap();
+        assert_eq!(
+            changes,
+            vec![FileChange::Changed {
+                file: "porch_presets.json".to_string(),
+                fields: vec![FieldChange::Changed {
+                    path: "1.n".to_string(),
+                    old: json!("Warm"),
+                    new: json!("Cool"),
+                }],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_dirs() {
+        let old = tempdir().unwrap();
+        let new = tempdir().unwrap();
+
+        fs::write(old.path().join("same_cfg.json"), "same").unwrap();
+        fs::write(new.path().join("same_cfg.json"), "same").unwrap();
+        fs::write(old.path().join("changed_cfg.json"), "before").unwrap();
+        fs::write(new.path().join("changed_cfg.json"), "after").unwrap();
+        fs::write(old.path().join("removed_cfg.json"), "gone").unwrap();
+        fs::write(new.path().join("added_cfg.json"), "new").unwrap();
+
+        let changes = diff_dirs(old.path(), new.path(), None).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                FileChange::Added {
+                    file: "added_cfg.json".to_string()
+                },
+                FileChange::Changed {
+                    file: "changed_cfg.json".to_string(),
+                    fields: vec![]
+                },
+                FileChange::Removed {
+                    file: "removed_cfg.json".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_dirs_missing_dir() {
+        let old = tempdir().unwrap();
+        assert!(diff_dirs(old.path(), &old.path().join("missing"), None).is_err());
+    }
+
+    #[test]
+    fn test_diff_dirs_encrypted_and_compressed() {
+        let old = tempdir().unwrap();
+        let new = tempdir().unwrap();
+        let identity = age::x25519::Identity::generate();
+        let encryption = Encryption::Recipients(vec![identity.to_public()]);
+
+        // Encrypting the same contents twice gives different files.
+        let encrypted = |contents: &str| encryption.encrypt(contents.as_bytes()).unwrap();
+        fs::write(old.path().join("same_cfg.json.age"), encrypted("same")).unwrap();
+        fs::write(new.path().join("same_cfg.json.age"), encrypted("same")).unwrap();
+        fs::write(old.path().join("changed_cfg.json"), "before").unwrap();
+        fs::write(new.path().join("changed_cfg.json.age"), encrypted("after")).unwrap();
+        let compressed = Compression::Zstd.compress(b"same").unwrap();
+        fs::write(old.path().join("zipped_cfg.json"), "same").unwrap();
+        fs::write(new.path().join("zipped_cfg.json.zst"), compressed).unwrap();
+
+        let mut decryption = Decryption::default();
+        decryption.add_identity(identity);
+        let changes = diff_dirs(old.path(), new.path(), Some(&decryption)).unwrap();
+        assert_eq!(
+            changes,
+            vec![FileChange::Changed {
+                file: "changed_cfg.json".to_string(),
+                fields: vec![]
+            }]
+        );
+
+        assert!(diff_dirs(old.path(), new.path(), None).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_diff_live() {
+        let cfg = r#"{"id":{"name":"testwled"},"ap":{"psk":"wled1234"},"hw":{"led":{"total":60}}}"#;
+        let server = mock_wled_server("127.0.0.1:137", cfg, Some(PRESETS_BODY));
+
+        // Saved with --redact-secrets and --pretty, with fewer LEDs.
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("testwled_cfg.json"),
+            "{\n  \"ap\": {},\n  \"hw\": {\"led\": {\"total\": 30}},\n  \"id\": {\"name\": \"testwled\"}\n}\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("testwled_presets.json"), PRESETS_BODY).unwrap();
+
+        let layout = Layout::new(LayoutKind::Flat, dir.path());
+        let ip = "127.0.0.1".parse().unwrap();
+        let options = DeviceOptions::default();
+        let (hostname, changes) = diff_live(&Fetcher::default(), &ip, 137, &options, &layout, None)
+            .await
+            .unwrap();
+        server.join().unwrap();
+
+        assert_eq!(hostname, "testwled");
+        assert_eq!(
+            changes,
+            vec![FileChange::Changed {
+                file: "cfg.json".to_string(),
+                fields: vec![FieldChange::Changed {
+                    path: "hw.led.total".to_string(),
+                    old: json!(30),
+                    new: json!(60),
+                }],
+            }]
+        );
+    }
+}
@@ -0,0 +1,586 @@
+use crate::backup::{DeviceHttp, try_addresses};
+use crate::device::{Device, DeviceOptions, DeviceSpec, normalize_mac};
+use crate::error::BoxError;
+use crate::filter::Subnet;
+use crate::http::Fetcher;
+use crate::model::{WledInfo, WledNodes};
+use crate::progress;
+use futures::future::LocalBoxFuture;
+use futures::{FutureExt, StreamExt, stream};
+use mdns_sd::{IfKind, ServiceDaemon, ServiceEvent};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// How often a search's countdown is redrawn.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Devices found by a discovery source, or why it couldn't search.
+pub type Found = Result<Vec<Device>, Box<dyn std::error::Error + Send + Sync>>;
+
+/// A way of finding WLEDs.
+pub trait Discovery {
+    /// What the search does, for progress messages. None if it's quick enough
+    /// not to mention.
+    fn describe(&self) -> Option<String>;
+
+    fn discover<'a>(&'a self, fetcher: &'a Fetcher) -> LocalBoxFuture<'a, Found>;
+
+    /// Like [`Discovery::discover`], but passes each device to `found` as
+    /// soon as it's found. By default, they're all passed once the search is
+    /// over. An open-ended search stops early when `found` breaks.
+    fn discover_each<'a>(
+        &'a self,
+        fetcher: &'a Fetcher,
+        found: &'a mut dyn FnMut(Device) -> ControlFlow<()>,
+    ) -> LocalBoxFuture<'a, Result<(), BoxError>> {
+        async move {
+            for device in self.discover(fetcher).await? {
+                let _ = found(device);
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    /// Whether the source searches for as long as it's given, rather than
+    /// for devices it was told of, so can stop once what's expected is found.
+    fn open_ended(&self) -> bool {
+        false
+    }
+}
+
+/// Run `search`, which blocks, on its own thread, passing each device it
+/// finds on to `found` as it goes. If `found` breaks, the search is told to
+/// stop.
+async fn search_blocking(
+    search: impl FnOnce(&mut dyn FnMut(&Device), &AtomicBool) -> Result<(), BoxError> + Send + 'static,
+    found: &mut dyn FnMut(Device) -> ControlFlow<()>,
+) -> Result<(), BoxError> {
+    let (sender, mut receiver) = futures::channel::mpsc::unbounded();
+    let stop = Arc::new(AtomicBool::new(false));
+    let search = tokio::task::spawn_blocking({
+        let stop = stop.clone();
+        move || {
+            let mut send = |device: &Device| {
+                let _ = sender.unbounded_send(device.clone());
+            };
+            search(&mut send, &stop)
+        }
+    });
+    while let Some(device) = receiver.next().await {
+        if found(device).is_break() {
+            stop.store(true, Ordering::SeqCst);
+        }
+    }
+    search.await?
+}
+
+/// Which discovery sources search for WLEDs when none are given.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiscoveryKind {
+    /// mDNS (_wled._tcp) advertisements
+    #[default]
+    Mdns,
+    /// WLED's own UDP node announcements and sync notifications
+    Udp,
+    /// Probe every address in the --scan networks
+    Scan,
+    /// Ask the --nodes WLEDs which devices they know about
+    Nodes,
+}
+
+/// Largest network --scan will probe: a /16 for IPv4, or a /112 for IPv6.
+const MAX_SCAN_HOST_BITS: u32 = 16;
+
+/// How many hosts to probe at once when scanning.
+const SCAN_JOBS: usize = 64;
+
+/// Which network interfaces to search. All of them, if none are given.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MdnsOptions {
+    /// Interface names, such as "eth0".
+    pub interfaces: Vec<String>,
+    /// Addresses of local interfaces.
+    pub bind_ips: Vec<IpAddr>,
+}
+
+impl MdnsOptions {
+    fn if_kinds(&self) -> Vec<IfKind> {
+        self.interfaces
+            .iter()
+            .map(|name| IfKind::Name(name.clone()))
+            .chain(self.bind_ips.iter().map(|ip| IfKind::Addr(*ip)))
+            .collect()
+    }
+}
+
+pub fn discover_wleds(search_duration: std::time::Duration, options: &MdnsOptions) -> Vec<Device> {
+    let mut wleds = vec![];
+    let mut found = |device: &Device| wleds.push(device.clone());
+    discover_wleds_each(
+        search_duration,
+        options,
+        &mut found,
+        &AtomicBool::new(false),
+    );
+    wleds
+}
+
+/// Like [`discover_wleds`], but passes each device to `found` as soon as
+/// it's found, and ends early once `stop` is set.
+pub fn discover_wleds_each(
+    search_duration: std::time::Duration,
+    options: &MdnsOptions,
+    found: &mut dyn FnMut(&Device),
+    stop: &AtomicBool,
+) {
+    let mut wleds = HashMap::new();
+
+    // Create a daemon
+    let mdns = ServiceDaemon::new().expect("Failed to create daemon");
+
+    let if_kinds = options.if_kinds();
+    if !if_kinds.is_empty() {
+        mdns.disable_interface(IfKind::All)
+            .expect("Failed to disable interfaces");
+        mdns.enable_interface(if_kinds)
+            .expect("Failed to enable interfaces");
+    }
+
+    // Browse for a service type.
+    let service_type = "_wled._tcp.local.";
+    let receiver = mdns.browse(service_type).expect("Failed to browse");
+
+    // The search goes on until nothing new has been heard for the duration.
+    let countdown = progress::countdown("Searching", search_duration);
+    let mut quiet_since = Instant::now();
+    while !stop.load(Ordering::SeqCst) {
+        let event = match receiver.recv_timeout(TICK) {
+            Ok(event) => event,
+            Err(_) if quiet_since.elapsed() < search_duration && !receiver.is_disconnected() => {
+                progress::set_elapsed(&countdown, quiet_since.elapsed());
+                continue;
+            }
+            Err(_) => break,
+        };
+        quiet_since = Instant::now();
+        if let ServiceEvent::ServiceResolved(info) = event {
+            // Sometimes we get multiple responses for the same device. We use the
+            // HashMap as we way to deduplicate them based on MAC, or hostname
+            // if the device doesn't advertise its MAC, as devices can share a
+            // name.
+            let key = info
+                .get_property_val_str("mac")
+                .map(normalize_mac)
+                .unwrap_or_else(|| info.get_hostname().to_string());
+            if let Entry::Vacant(entry) = wleds.entry(key) {
+                info!("Discovered: {}", info.get_fullname());
+                debug!(
+                    "addresses: {:?}, port: {}",
+                    info.get_addresses(),
+                    info.get_port()
+                );
+                found(entry.insert(Device::from(&info)));
+            }
+        }
+    }
+    countdown.finish_and_clear();
+}
+
+/// A device given on the command line.
+pub struct Static {
+    pub spec: DeviceSpec,
+    /// If the host doesn't resolve, how long to wait before trying once
+    /// more.
+    pub retry: Option<Duration>,
+}
+
+impl Discovery for Static {
+    fn describe(&self) -> Option<String> {
+        None
+    }
+
+    fn discover<'a>(&'a self, _fetcher: &'a Fetcher) -> LocalBoxFuture<'a, Found> {
+        async {
+            let device = self
+                .spec
+                .resolve_retrying(self.retry)
+                .await
+                .map_err(|e| format!("Failed to resolve {}: {e}", self.spec))?;
+            Ok(vec![device])
+        }
+        .boxed_local()
+    }
+}
+
+/// Search with mDNS.
+pub struct Mdns {
+    pub duration: Duration,
+    pub options: MdnsOptions,
+}
+
+impl Discovery for Mdns {
+    fn describe(&self) -> Option<String> {
+        Some(format!("Searching for {} seconds", self.duration.as_secs()))
+    }
+
+    fn discover<'a>(&'a self, _fetcher: &'a Fetcher) -> LocalBoxFuture<'a, Found> {
+        async { Ok(discover_wleds(self.duration, &self.options)) }.boxed_local()
+    }
+
+    fn discover_each<'a>(
+        &'a self,
+        _fetcher: &'a Fetcher,
+        found: &'a mut dyn FnMut(Device) -> ControlFlow<()>,
+    ) -> LocalBoxFuture<'a, Result<(), BoxError>> {
+        let (duration, options) = (self.duration, self.options.clone());
+        let search = move |found: &mut dyn FnMut(&Device), stop: &AtomicBool| {
+            discover_wleds_each(duration, &options, found, stop);
+            Ok(())
+        };
+        search_blocking(search, found).boxed_local()
+    }
+
+    fn open_ended(&self) -> bool {
+        true
+    }
+}
+
+/// WLED's node discovery port, where devices announce themselves about once a
+/// minute.
+const NODES_PORT: u16 = 65506;
+
+/// WLED's sync notifier port, where devices announce state changes, if
+/// sending notifications is enabled.
+const NOTIFIER_PORT: u16 = 21324;
+
+/// The WLED address and name in a UDP packet, if it's a WLED announcement.
+fn parse_announcement(port: u16, packet: &[u8], from: IpAddr) -> Option<(IpAddr, String)> {
+    match port {
+        // 255, 1, 4 byte IP, 32 byte name, then node type, ID and version.
+        NODES_PORT if packet.len() >= 38 && packet[0] == 255 && packet[1] == 1 => {
+            let ip = Ipv4Addr::new(packet[2], packet[3], packet[4], packet[5]);
+            let ip = if ip.is_unspecified() { from } else { ip.into() };
+            let name = &packet[6..38];
+            let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+            Some((ip, String::from_utf8_lossy(name).trim().to_string()))
+        }
+        // A sync notification starts with 0, and carries no name.
+        NOTIFIER_PORT if packet.len() >= 24 && packet[0] == 0 => Some((from, String::new())),
+        _ => None,
+    }
+}
+
+/// Listen for WLED's own UDP announcements.
+pub struct Udp {
+    pub duration: Duration,
+}
+
+impl Udp {
+    fn listen(&self) -> Found {
+        let mut wleds = vec![];
+        let mut found = |device: &Device| wleds.push(device.clone());
+        Udp::listen_each(self.duration, &mut found, &AtomicBool::new(false))?;
+        Ok(wleds)
+    }
+
+    /// Listen for `duration`, or until `stop` is set, passing each WLED
+    /// heard to `found` the first time it's heard.
+    fn listen_each(
+        duration: Duration,
+        found: &mut dyn FnMut(&Device),
+        stop: &AtomicBool,
+    ) -> Result<(), BoxError> {
+        let sockets = [NODES_PORT, NOTIFIER_PORT]
+            .into_iter()
+            .map(|port| {
+                let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))
+                    .map_err(|e| format!("Failed to listen on UDP port {port}: {e}"))?;
+                socket.set_read_timeout(Some(Duration::from_millis(50)))?;
+                Ok((port, socket))
+            })
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error + Send + Sync>>>()?;
+
+        let mut heard: Vec<IpAddr> = vec![];
+        let started = Instant::now();
+        let countdown = progress::countdown("Listening", duration);
+        let mut packet = [0u8; 1500];
+        while started.elapsed() < duration && !stop.load(Ordering::SeqCst) {
+            progress::set_elapsed(&countdown, started.elapsed());
+            for (port, socket) in sockets.iter() {
+                let Ok((len, from)) = socket.recv_from(&mut packet) else {
+                    continue;
+                };
+                let Some((ip, name)) = parse_announcement(*port, &packet[..len], from.ip()) else {
+                    continue;
+                };
+                if heard.contains(&ip) {
+                    continue;
+                }
+
+                info!("Discovered: {ip} {name}");
+                debug!("announced on UDP port {port}");
+                heard.push(ip);
+                found(&Device::new(&ip.to_string(), vec![ip], 80));
+            }
+        }
+        countdown.finish_and_clear();
+
+        Ok(())
+    }
+}
+
+impl Discovery for Udp {
+    fn describe(&self) -> Option<String> {
+        Some(format!(
+            "Listening for UDP announcements for {} seconds",
+            self.duration.as_secs()
+        ))
+    }
+
+    fn discover<'a>(&'a self, _fetcher: &'a Fetcher) -> LocalBoxFuture<'a, Found> {
+        async { self.listen() }.boxed_local()
+    }
+
+    fn discover_each<'a>(
+        &'a self,
+        _fetcher: &'a Fetcher,
+        found: &'a mut dyn FnMut(Device) -> ControlFlow<()>,
+    ) -> LocalBoxFuture<'a, Result<(), BoxError>> {
+        let duration = self.duration;
+        let search = move |found: &mut dyn FnMut(&Device), stop: &AtomicBool| {
+            Udp::listen_each(duration, found, stop)
+        };
+        search_blocking(search, found).boxed_local()
+    }
+
+    fn open_ended(&self) -> bool {
+        true
+    }
+}
+
+/// Ask a host for /json/info, and return it as a device if it's a WLED.
+/// `options` gives how to reach it, such as behind a proxy.
+pub(crate) async fn probe(
+    fetcher: &Fetcher,
+    ip: IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    timeout: Duration,
+) -> Option<Device> {
+    let url = format!("{}/json/info", options.base_url(&ip, port));
+    let response = options
+        .apply(fetcher.client.get(&url))
+        .timeout(timeout)
+        .send()
+        .await
+        .ok()?;
+    let bytes = response.error_for_status().ok()?.bytes().await.ok()?;
+    let info: WledInfo = serde_json::from_slice(&bytes).ok()?;
+    if info.brand.as_deref() != Some("WLED") {
+        return None;
+    }
+
+    let mut device = Device::new(&ip.to_string(), vec![ip], port);
+    device.mac = info.mac;
+    info!(
+        "Discovered: {ip} ({})",
+        info.name.as_deref().unwrap_or("unnamed")
+    );
+    Some(device)
+}
+
+/// Find WLEDs without mDNS, by probing every host in the networks. Hosts that
+/// don't answer within `timeout` are skipped.
+pub async fn scan_subnets(
+    fetcher: &Fetcher,
+    subnets: &[Subnet],
+    port: u16,
+    timeout: Duration,
+) -> Vec<Device> {
+    let hosts = subnets.iter().flat_map(|subnet| subnet.hosts());
+    let options = DeviceOptions::default();
+    let mut wleds: Vec<Device> = stream::iter(hosts)
+        .map(|ip| probe(fetcher, ip, port, &options, timeout))
+        .buffer_unordered(SCAN_JOBS)
+        .filter_map(|device| async { device })
+        .collect()
+        .await;
+
+    wleds.sort_by_key(|device| device.addresses[0]);
+    wleds.dedup_by_key(|device| device.addresses[0]);
+    wleds
+}
+
+/// Probe every address in some networks.
+pub struct Scan {
+    pub subnets: Vec<Subnet>,
+    pub port: u16,
+    pub timeout: Duration,
+}
+
+impl Discovery for Scan {
+    fn describe(&self) -> Option<String> {
+        Some(format!("Scanning {} networks", self.subnets.len()))
+    }
+
+    fn discover<'a>(&'a self, fetcher: &'a Fetcher) -> LocalBoxFuture<'a, Found> {
+        async {
+            if let Some(subnet) = self
+                .subnets
+                .iter()
+                .find(|subnet| subnet.host_bits() > MAX_SCAN_HOST_BITS)
+            {
+                return Err(format!("{subnet} is too big to scan").into());
+            }
+            Ok(scan_subnets(fetcher, &self.subnets, self.port, self.timeout).await)
+        }
+        .boxed_local()
+    }
+}
+
+/// Parse /json/nodes into devices, all on the default port.
+fn parse_nodes(contents: &[u8]) -> Result<Vec<Device>, Box<dyn std::error::Error + Send + Sync>> {
+    let nodes: WledNodes =
+        serde_json::from_slice(contents).map_err(|e| format!("Invalid /json/nodes: {e}"))?;
+
+    Ok(nodes
+        .nodes
+        .iter()
+        .filter_map(|node| node.ip.parse::<IpAddr>().ok())
+        .map(|ip| Device::new(&ip.to_string(), vec![ip], 80))
+        .collect())
+}
+
+/// Find the WLEDs a seed device knows about through WLED's own UDP node
+/// discovery, by asking it for /json/nodes. The seed is included.
+pub async fn query_nodes(
+    fetcher: &Fetcher,
+    seed: Device,
+) -> Result<Vec<Device>, Box<dyn std::error::Error + Send + Sync>> {
+    let (_, contents) = try_addresses(&seed.addresses, async |ip| {
+        DeviceHttp::new(fetcher, ip, seed.port, &seed.options)
+            .get("/json/nodes")
+            .await
+    })
+    .await;
+
+    let nodes = parse_nodes(&contents?)?;
+    for node in nodes.iter() {
+        info!("Discovered: {} (node of {})", node.name, seed.name);
+    }
+    Ok(std::iter::once(seed).chain(nodes).collect())
+}
+
+/// Ask a WLED for its node list.
+pub struct Nodes {
+    pub seed: DeviceSpec,
+}
+
+impl Discovery for Nodes {
+    fn describe(&self) -> Option<String> {
+        Some(format!("Asking {} for its nodes", self.seed))
+    }
+
+    fn discover<'a>(&'a self, fetcher: &'a Fetcher) -> LocalBoxFuture<'a, Found> {
+        async {
+            let seed = self
+                .seed
+                .resolve()
+                .await
+                .map_err(|e| format!("Failed to resolve {}: {e}", self.seed))?;
+            query_nodes(fetcher, seed)
+                .await
+                .map_err(|e| format!("Failed to list the nodes of {}: {e}", self.seed).into())
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock_routes_server;
+
+    #[tokio::test]
+    async fn test_scan_subnets() {
+        let info = r#"{"brand":"WLED","name":"Porch","mac":"a0b1c2d3e4f5","ver":"0.15.0"}"#;
+        let wled = mock_routes_server("127.0.0.1:119", &[("/json/info", info)]);
+        let other = mock_routes_server("127.0.0.2:119", &[("/json/info", r#"{"brand":"X"}"#)]);
+
+        let subnets = [
+            "127.0.0.1/32".parse().unwrap(),
+            "127.0.0.2/31".parse().unwrap(),
+        ];
+        let wleds = scan_subnets(
+            &Fetcher::default(),
+            &subnets,
+            119,
+            Duration::from_millis(500),
+        )
+        .await;
+
+        assert_eq!(wleds.len(), 1);
+        assert_eq!(wleds[0].name, "127.0.0.1");
+        assert_eq!(wleds[0].port, 119);
+        assert_eq!(wleds[0].mac.as_deref(), Some("a0b1c2d3e4f5"));
+
+        wled.join().unwrap();
+        other.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_query_nodes() {
+        let nodes = r#"{"nodes":[
+            {"name":"Porch","type":32,"ip":"192.168.1.20","age":0,"vid":2405180},
+            {"name":"Garden","type":82,"ip":"192.168.1.21","age":1,"vid":2405180},
+            {"name":"Broken","type":32,"ip":"","age":1,"vid":2405180}
+        ]}"#;
+        let server = mock_routes_server("127.0.0.1:120", &[("/json/nodes", nodes)]);
+
+        let seed = Device::new("seed", vec!["127.0.0.1".parse().unwrap()], 120);
+        let devices = query_nodes(&Fetcher::default(), seed).await.unwrap();
+        let names: Vec<&str> = devices.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["seed", "192.168.1.20", "192.168.1.21"]);
+        assert_eq!(devices[1].port, 80);
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_parse_announcement() {
+        let from: IpAddr = "192.168.1.99".parse().unwrap();
+
+        let mut node = vec![255, 1, 192, 168, 1, 20];
+        node.extend(b"Porch");
+        node.resize(44, 0);
+        assert_eq!(
+            parse_announcement(NODES_PORT, &node, from),
+            Some(("192.168.1.20".parse().unwrap(), "Porch".to_string()))
+        );
+
+        node[2..6].fill(0);
+        assert_eq!(parse_announcement(NODES_PORT, &node, from).unwrap().0, from);
+
+        let sync = [0u8; 41];
+        assert_eq!(
+            parse_announcement(NOTIFIER_PORT, &sync, from),
+            Some((from, String::new()))
+        );
+
+        assert_eq!(parse_announcement(NODES_PORT, &[255, 1, 192], from), None);
+        assert_eq!(parse_announcement(NOTIFIER_PORT, &[4u8; 41], from), None);
+    }
+
+    #[test]
+    fn test_parse_nodes_invalid() {
+        assert!(parse_nodes(b"{}").is_err());
+        assert!(parse_nodes(b"not json").is_err());
+    }
+}
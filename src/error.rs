@@ -0,0 +1,149 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+/// Any error, from a library that doesn't have its own type.
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Why backing up a device failed.
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    /// A discovery source couldn't search.
+    #[error("{0}")]
+    Discovery(BoxError),
+
+    /// The device couldn't be reached, or answered with an error.
+    #[error("{url}: {source}")]
+    Http {
+        /// The device's address and port.
+        device: String,
+        url: String,
+        source: BoxError,
+    },
+
+    /// cfg.json wasn't valid, or had no name to save the backup under.
+    #[error("{0}")]
+    ParseCfg(String),
+
+    /// Another file from the device wasn't what WLED serves, such as a
+    /// captive portal's login page instead of presets.json.
+    #[error("{0}")]
+    Invalid(String),
+
+    /// Another device in the run already has the name, and --on-collision
+    /// error was given.
+    #[error("another device in this run is already named {0}")]
+    Collision(String),
+
+    /// Saving a file failed, for example because the disk is full.
+    #[error("Failed to write {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    /// Encrypting a file failed.
+    #[error("Failed to encrypt {}: {source}", path.display())]
+    Encrypt { path: PathBuf, source: BoxError },
+
+    /// Writing an archive failed.
+    #[error("Failed to write {}: {source}", path.display())]
+    Archive { path: PathBuf, source: BoxError },
+
+    /// The device has no addresses to try.
+    #[error("No addresses")]
+    NoAddresses,
+
+    /// Every one of the device's addresses failed.
+    #[error("{}", .0.iter().map(|(ip, e)| format!("{ip}: {e}")).collect::<Vec<_>>().join("; "))]
+    Addresses(Vec<(IpAddr, BackupError)>),
+
+    /// Not tried, because an earlier device failed and --fail-fast was
+    /// given.
+    #[error("Not backed up: an earlier device failed")]
+    Aborted,
+
+    /// Streaming a file to backup --stream's destination failed.
+    #[error("Failed to upload {path}: {source}")]
+    Upload { path: String, source: BoxError },
+
+    /// The run didn't finish within --deadline-secs.
+    #[error("Deadline of {0} seconds exceeded")]
+    Deadline(u64),
+}
+
+impl BackupError {
+    /// A short name for the kind of error, for scripts reading the JSON
+    /// report: "http" if the device was unreachable, "io" if saving failed,
+    /// and so on.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BackupError::Discovery(_) => "discovery",
+            BackupError::Http { .. } => "http",
+            BackupError::ParseCfg(_) => "parse_cfg",
+            BackupError::Invalid(_) => "invalid",
+            BackupError::Collision(_) => "collision",
+            BackupError::Io { .. } | BackupError::Archive { .. } => "io",
+            BackupError::Encrypt { .. } => "encrypt",
+            BackupError::Upload { .. } => "upload",
+            BackupError::NoAddresses => "no_addresses",
+            BackupError::Addresses(errors) => {
+                errors.last().map_or("no_addresses", |(_, e)| e.kind())
+            }
+            BackupError::Aborted => "aborted",
+            BackupError::Deadline(_) => "deadline",
+        }
+    }
+
+    /// An I/O error while writing `path`.
+    pub fn io(path: impl Into<PathBuf>) -> impl FnOnce(std::io::Error) -> BackupError {
+        let path = path.into();
+        move |source| BackupError::Io { path, source }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn http(url: &str) -> BackupError {
+        BackupError::Http {
+            device: "127.0.0.1:80".to_string(),
+            url: url.to_string(),
+            source: "connection refused".into(),
+        }
+    }
+
+    #[test]
+    fn test_backup_error_display_and_kind() {
+        let error = http("http://127.0.0.1:80/cfg.json");
+        assert_eq!(
+            error.to_string(),
+            "http://127.0.0.1:80/cfg.json: connection refused"
+        );
+        assert_eq!(error.kind(), "http");
+
+        let error = BackupError::io("/backup/porch_cfg.json")(std::io::Error::other("disk full"));
+        assert_eq!(
+            error.to_string(),
+            "Failed to write /backup/porch_cfg.json: disk full"
+        );
+        assert_eq!(error.kind(), "io");
+
+        let error = BackupError::Addresses(vec![
+            ("fd00::1".parse().unwrap(), http("a")),
+            (
+                "10.0.0.1".parse().unwrap(),
+                BackupError::ParseCfg("bad".to_string()),
+            ),
+        ]);
+        assert_eq!(
+            error.to_string(),
+            "fd00::1: a: connection refused; 10.0.0.1: bad"
+        );
+        assert_eq!(error.kind(), "parse_cfg");
+
+        let error = BackupError::Deadline(120);
+        assert_eq!(error.to_string(), "Deadline of 120 seconds exceeded");
+        assert_eq!(error.kind(), "deadline");
+    }
+}
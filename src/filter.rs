@@ -0,0 +1,304 @@
+use crate::device::Device;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IP network in CIDR notation, such as 192.168.10.0/24 or fd00::/8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subnet {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s
+            .split_once('/')
+            .ok_or_else(|| format!("{s} is not in address/prefix form"))?;
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("{addr} is not an IP address"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = prefix
+            .parse()
+            .ok()
+            .filter(|p| *p <= max)
+            .ok_or_else(|| format!("{prefix} is not a prefix length from 0 to {max}"))?;
+        Ok(Subnet { addr, prefix })
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Subnet {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (net, ip, width) = match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                (u32::from(net) as u128, u32::from(*ip) as u128, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(*ip), 128),
+            _ => return false,
+        };
+
+        // Compare the network bits, all of which are shifted away for /0.
+        let host_bits = width - self.prefix as u32;
+        net.checked_shr(host_bits).unwrap_or(0) == ip.checked_shr(host_bits).unwrap_or(0)
+    }
+
+    /// How many bits of an address pick the host.
+    pub fn host_bits(&self) -> u32 {
+        let width = if self.addr.is_ipv4() { 32 } else { 128 };
+        width - self.prefix as u32
+    }
+
+    /// Every host address in the network. For IPv4 networks bigger than /31,
+    /// the network and broadcast addresses are left out.
+    pub fn hosts(&self) -> impl Iterator<Item = IpAddr> + use<> {
+        let host_bits = self.host_bits();
+        let size = 1u128.checked_shl(host_bits).unwrap_or(0).wrapping_sub(1);
+        let (first, last) = match self.addr {
+            IpAddr::V4(net) => {
+                let first = u32::from(net) as u128 & !size;
+                if host_bits > 1 {
+                    (first + 1, first + size - 1)
+                } else {
+                    (first, first + size)
+                }
+            }
+            IpAddr::V6(net) => {
+                let first = u128::from(net) & !size;
+                (first, first + size)
+            }
+        };
+
+        let v4 = self.addr.is_ipv4();
+        (first..=last).map(move |n| {
+            if v4 {
+                IpAddr::from(std::net::Ipv4Addr::from(n as u32))
+            } else {
+                IpAddr::from(std::net::Ipv6Addr::from(n))
+            }
+        })
+    }
+}
+
+/// Which devices to back up, by name or address.
+#[derive(clap::Args, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceFilter {
+    /// Only back up devices whose host name or cfg.json name matches this
+    /// glob, such as "garden-*". May be repeated
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Skip devices whose host name or cfg.json name matches this glob. May
+    /// be repeated
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Only back up devices with an address in this network, such as
+    /// 192.168.10.0/24. May be repeated
+    #[arg(long, value_name = "CIDR")]
+    pub include_net: Vec<Subnet>,
+
+    /// Skip addresses in this network. May be repeated
+    #[arg(long, value_name = "CIDR")]
+    pub exclude_net: Vec<Subnet>,
+}
+
+/// Match a name against a glob, where `*` matches any run of characters and
+/// `?` any one character. Case is ignored, as for host names.
+pub fn glob_match(glob: &str, name: &str) -> bool {
+    let glob: Vec<char> = glob.to_lowercase().chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+
+    // Where to resume after the last `*`: its index in glob, and in name.
+    let mut star: Option<(usize, usize)> = None;
+    let (mut g, mut n) = (0, 0);
+    while n < name.len() {
+        match glob.get(g) {
+            Some('*') => {
+                star = Some((g, n));
+                g += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                g += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_g, star_n)) => {
+                    star = Some((star_g, star_n + 1));
+                    g = star_g + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    glob[g..].iter().all(|c| *c == '*')
+}
+
+/// Drop the mDNS ".local." suffix, so globs match the plain host name.
+fn short_name(name: &str) -> &str {
+    name.trim_end_matches('.').trim_end_matches(".local")
+}
+
+impl DeviceFilter {
+    /// Is there a name filter? Network filters are applied separately.
+    pub fn is_set(&self) -> bool {
+        !self.include.is_empty() || !self.exclude.is_empty()
+    }
+
+    /// May the device be contacted at this address?
+    pub fn allows_address(&self, ip: &IpAddr) -> bool {
+        (self.include_net.is_empty() || self.include_net.iter().any(|net| net.contains(ip)))
+            && !self.exclude_net.iter().any(|net| net.contains(ip))
+    }
+
+    /// Drop addresses outside the allowed networks, and then devices left
+    /// without any. Returns the names of the dropped devices.
+    pub fn filter_networks(&self, devices: &mut Vec<Device>) -> Vec<String> {
+        let mut dropped = vec![];
+        devices.retain_mut(|device| {
+            device.addresses.retain(|ip| self.allows_address(ip));
+            if device.addresses.is_empty() {
+                dropped.push(device.name.clone());
+            }
+            !device.addresses.is_empty()
+        });
+        dropped
+    }
+
+    /// Is any of the names excluded?
+    pub fn excludes(&self, names: &[&str]) -> bool {
+        names
+            .iter()
+            .any(|name| self.exclude.iter().any(|g| glob_match(g, short_name(name))))
+    }
+
+    /// Should a device going by these names be backed up?
+    pub fn allows(&self, names: &[&str]) -> bool {
+        let included = self.include.is_empty()
+            || names
+                .iter()
+                .any(|name| self.include.iter().any(|g| glob_match(g, short_name(name))));
+        included && !self.excludes(names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("garden-*", "garden-1"));
+        assert!(glob_match("garden-*", "Garden-"));
+        assert!(glob_match("*-bench", "test-bench"));
+        assert!(glob_match("wled-??", "wled-ab"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("garden-*", "porch"));
+        assert!(!glob_match("wled-??", "wled-abc"));
+        assert!(!glob_match("*a*b", "xxaxxbxx"));
+    }
+
+    #[test]
+    fn test_device_filter() {
+        let filter = DeviceFilter {
+            include: vec!["garden-*".to_string()],
+            exclude: vec!["*-bench".to_string()],
+            ..Default::default()
+        };
+        assert!(filter.allows(&["garden-1.local."]));
+        assert!(filter.allows(&["wled-1234", "garden-2"]));
+        assert!(!filter.allows(&["porch"]));
+        assert!(!filter.allows(&["garden-bench"]));
+
+        assert!(DeviceFilter::default().allows(&["porch"]));
+        assert!(!DeviceFilter::default().is_set());
+    }
+
+    fn subnet(s: &str) -> Subnet {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_subnet_parse() {
+        assert_eq!(
+            subnet("192.168.10.0/24"),
+            Subnet {
+                addr: ip("192.168.10.0"),
+                prefix: 24,
+            }
+        );
+        assert_eq!(subnet("fd00::/8").to_string(), "fd00::/8");
+        assert!("192.168.10.0".parse::<Subnet>().is_err());
+        assert!("192.168.10.0/33".parse::<Subnet>().is_err());
+        assert!("garden/24".parse::<Subnet>().is_err());
+    }
+
+    #[test]
+    fn test_subnet_contains() {
+        let net = subnet("192.168.10.0/24");
+        assert!(net.contains(&ip("192.168.10.20")));
+        assert!(!net.contains(&ip("192.168.11.20")));
+        assert!(!net.contains(&ip("fd00::1")));
+
+        assert!(subnet("10.0.0.0/8").contains(&ip("10.255.1.2")));
+        assert!(subnet("0.0.0.0/0").contains(&ip("8.8.8.8")));
+        assert!(subnet("192.168.10.20/32").contains(&ip("192.168.10.20")));
+        assert!(!subnet("192.168.10.20/32").contains(&ip("192.168.10.21")));
+
+        assert!(subnet("fd00::/8").contains(&ip("fd12::1")));
+        assert!(!subnet("fd00::/8").contains(&ip("fe80::1")));
+        assert!(subnet("::/0").contains(&ip("fe80::1")));
+    }
+
+    #[test]
+    fn test_subnet_hosts() {
+        let hosts: Vec<IpAddr> = subnet("192.168.10.5/30").hosts().collect();
+        assert_eq!(hosts, vec![ip("192.168.10.5"), ip("192.168.10.6")]);
+        assert_eq!(subnet("192.168.10.0/24").hosts().count(), 254);
+        assert_eq!(
+            subnet("192.168.10.20/32").hosts().collect::<Vec<_>>(),
+            vec![ip("192.168.10.20")]
+        );
+        assert_eq!(subnet("10.0.0.0/31").hosts().count(), 2);
+        assert_eq!(
+            subnet("fd00::/127").hosts().collect::<Vec<_>>(),
+            vec![ip("fd00::"), ip("fd00::1")]
+        );
+        assert_eq!(subnet("fd00::/8").host_bits(), 120);
+    }
+
+    #[test]
+    fn test_filter_networks() {
+        let filter = DeviceFilter {
+            include_net: vec![subnet("192.168.10.0/24")],
+            exclude_net: vec![subnet("192.168.10.128/25")],
+            ..Default::default()
+        };
+
+        let mut devices = vec![
+            Device::new("porch", vec![ip("10.0.0.5"), ip("192.168.10.5")], 80),
+            Device::new("remote", vec![ip("10.1.0.5")], 80),
+            Device::new("excluded", vec![ip("192.168.10.200")], 80),
+        ];
+        let dropped = filter.filter_networks(&mut devices);
+
+        assert_eq!(dropped, vec!["remote", "excluded"]);
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].addresses, vec![ip("192.168.10.5")]);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::device::{Device, normalize_mac};
+use crate::report::DeviceReport;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// The devices that should be backed up on every run, by name or MAC.
+///
+/// ```toml
+/// devices = ["porch", "garden", "a0:b1:c2:d3:e4:f5"]
+/// ```
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Fleet {
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+/// How a run differed from the fleet.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FleetDrift {
+    /// Fleet devices that weren't backed up.
+    pub missing: Vec<String>,
+    /// Devices found that aren't in the fleet.
+    pub unknown: Vec<String>,
+}
+
+/// Does a fleet entry name this device, by name, alias, host name from
+/// cfg.json, or MAC?
+fn matches(entry: &str, device: &Device, report: Option<&DeviceReport>) -> bool {
+    let hostname = report.and_then(|r| r.hostname.as_deref());
+    let entry_mac = normalize_mac(entry);
+    device.has_name(entry)
+        || hostname.is_some_and(|h| h.eq_ignore_ascii_case(entry))
+        || device
+            .mac
+            .as_deref()
+            .is_some_and(|mac| entry_mac.len() == 12 && normalize_mac(mac) == entry_mac)
+}
+
+impl Fleet {
+    pub fn load(path: &Path) -> Result<Fleet, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        Self::parse(&contents)
+            .map_err(|e| format!("Failed to parse {}: {e}", path.display()).into())
+    }
+
+    pub fn parse(contents: &str) -> Result<Fleet, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    /// Whether every fleet device is among those found with an address.
+    pub fn found_all(&self, devices: &[Device]) -> bool {
+        self.devices.iter().all(|entry| {
+            devices
+                .iter()
+                .any(|device| !device.addresses.is_empty() && matches(entry, device, None))
+        })
+    }
+
+    /// Compare the devices found, and how their backups went, to the fleet.
+    pub fn drift(&self, devices: &[Device], reports: &[DeviceReport]) -> FleetDrift {
+        let report_for = |device: &Device| reports.iter().find(|r| r.name == device.name);
+
+        let missing = self
+            .devices
+            .iter()
+            .filter(|entry| {
+                !devices.iter().any(|device| {
+                    let report = report_for(device);
+                    report.is_some_and(|r| r.success) && matches(entry, device, report)
+                })
+            })
+            .cloned()
+            .collect();
+
+        let unknown = devices
+            .iter()
+            .filter(|device| {
+                !self
+                    .devices
+                    .iter()
+                    .any(|entry| matches(entry, device, report_for(device)))
+            })
+            .map(|device| device.name.clone())
+            .collect();
+
+        FleetDrift { missing, unknown }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock_device;
+
+    fn report(name: &str, hostname: &str, success: bool) -> DeviceReport {
+        DeviceReport {
+            name: name.to_string(),
+            hostname: Some(hostname.to_string()),
+            success,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_fleet_parse() {
+        let fleet = Fleet::parse(r#"devices = ["porch", "a0:b1:c2:d3:e4:f5"]"#).unwrap();
+        assert_eq!(fleet.devices, vec!["porch", "a0:b1:c2:d3:e4:f5"]);
+        assert!(Fleet::parse(r#"device = ["porch"]"#).is_err());
+    }
+
+    #[test]
+    fn test_fleet_found_all() {
+        let fleet = Fleet::parse(r#"devices = ["porch", "A0:B1:C2:D3:E4:F5"]"#).unwrap();
+        let mut by_mac = mock_device("wled-1234", "127.0.0.2", 80);
+        by_mac.mac = Some("a0b1c2d3e4f5".to_string());
+        let porch = mock_device("porch", "127.0.0.1", 80);
+        assert!(!fleet.found_all(std::slice::from_ref(&porch)));
+        assert!(fleet.found_all(&[porch.clone(), by_mac.clone()]));
+
+        let mut unresolved = porch;
+        unresolved.addresses.clear();
+        assert!(!fleet.found_all(&[unresolved, by_mac]));
+    }
+
+    #[test]
+    fn test_fleet_drift() {
+        let fleet =
+            Fleet::parse(r#"devices = ["porch", "Garden", "A0:B1:C2:D3:E4:F5", "attic", "shed"]"#)
+                .unwrap();
+
+        let mut by_mac = mock_device("wled-1234", "127.0.0.3", 80);
+        by_mac.mac = Some("a0b1c2d3e4f5".to_string());
+        let devices = vec![
+            mock_device("porch.local.", "127.0.0.1", 80),
+            mock_device("wled-5678", "127.0.0.2", 80),
+            by_mac,
+            mock_device("bench", "127.0.0.4", 80),
+            mock_device("shed", "127.0.0.5", 80),
+        ];
+        let reports = vec![
+            report("porch.local.", "porch", true),
+            report("wled-5678", "garden", true),
+            report("wled-1234", "kitchen", true),
+            report("bench", "bench", true),
+            report("shed", "shed", false),
+        ];
+
+        assert_eq!(
+            fleet.drift(&devices, &reports),
+            FleetDrift {
+                missing: vec!["attic".to_string(), "shed".to_string()],
+                unknown: vec!["bench".to_string()],
+            }
+        );
+    }
+}
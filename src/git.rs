@@ -0,0 +1,154 @@
+use crate::error::BoxError;
+use crate::lock::LOCK_FILE;
+use crate::report::{DeviceReport, Summary};
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// A commit message for a backup run: the summary, then a line for each
+/// device saying which files changed, or why it failed.
+pub fn commit_message(backups: &[DeviceReport], summary: &Summary) -> String {
+    let mut backups: Vec<&DeviceReport> = backups.iter().collect();
+    backups.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut message = format!("Backup: {summary}\n\n");
+    for backup in backups {
+        let line = match (&backup.error, backup.unchanged) {
+            (Some(error), _) => format!("FAILED: {error}"),
+            (None, true) => "unchanged".to_string(),
+            (None, false) => backup.files.join(", "),
+        };
+        message.push_str(&format!("{}: {line}\n", backup.name));
+    }
+    message
+}
+
+/// Run git in `dir`, failing if it does.
+fn git(dir: &Path, args: &[&str]) -> Result<Output, BoxError> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run git: {e}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("git {} failed: {}", args[0], stderr.trim()).into());
+    }
+    Ok(output)
+}
+
+/// Files every run rewrites with its time and the devices' uptime, so they
+/// change even when no device did.
+const VOLATILE: [&str; 2] = [
+    ":(exclude,glob)**/*meta.json*",
+    ":(exclude,glob)MANIFEST*.json*",
+];
+
+/// Commit everything changed under `dir`, which must be in a git repository,
+/// and push if asked. Changes elsewhere in the repository are left alone.
+/// Returns false if there was nothing to commit, which includes when only
+/// the run's times and uptimes changed.
+pub fn commit_backups(dir: &Path, message: &str, push: bool) -> Result<bool, BoxError> {
+    git(dir, &["rev-parse", "--is-inside-work-tree"])
+        .map_err(|_| format!("{} is not in a git repository", dir.display()))?;
+    let exclude_lock = format!(":(exclude){LOCK_FILE}");
+    git(dir, &["add", "--all", "--", ".", &exclude_lock])?;
+
+    let staged = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["diff", "--cached", "--quiet", "--", "."])
+        .args(VOLATILE)
+        .status()
+        .map_err(|e| format!("Failed to run git: {e}"))?;
+    if staged.success() {
+        return Ok(false);
+    }
+
+    git(dir, &["commit", "--quiet", "--message", message, "--", "."])?;
+    if push {
+        git(dir, &["push", "--quiet"])?;
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn report(name: &str) -> DeviceReport {
+        DeviceReport {
+            name: name.to_string(),
+            success: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_commit_message() {
+        let backups = vec![
+            DeviceReport {
+                files: vec![
+                    "porch_cfg.json".to_string(),
+                    "porch_presets.json".to_string(),
+                ],
+                ..report("porch")
+            },
+            DeviceReport {
+                success: false,
+                error: Some("http://10.0.0.9/cfg.json: timed out".to_string()),
+                ..report("attic")
+            },
+            DeviceReport {
+                unchanged: true,
+                ..report("garage")
+            },
+        ];
+        let summary = Summary::new(&backups, 0);
+        assert_eq!(
+            commit_message(&backups, &summary),
+            "Backup: 1 succeeded, 1 failed, 1 skipped\n\
+             \n\
+             attic: FAILED: http://10.0.0.9/cfg.json: timed out\n\
+             garage: unchanged\n\
+             porch: porch_cfg.json, porch_presets.json\n"
+        );
+    }
+
+    #[test]
+    fn test_commit_backups() {
+        let dir = tempdir().unwrap();
+        let repo = dir.path();
+        assert!(commit_backups(repo, "Backup", false).is_err());
+
+        git(repo, &["init", "--quiet"]).unwrap();
+        git(repo, &["config", "user.name", "Test"]).unwrap();
+        git(repo, &["config", "user.email", "test@example.com"]).unwrap();
+
+        // Only changes in the backup directory are committed.
+        let out_dir = repo.join("backups");
+        fs::create_dir(&out_dir).unwrap();
+        fs::write(out_dir.join("porch_cfg.json"), "{}").unwrap();
+        fs::write(repo.join("notes.txt"), "not a backup").unwrap();
+        fs::write(out_dir.join(LOCK_FILE), "1").unwrap();
+        assert!(commit_backups(&out_dir, "Backup: 1 succeeded", false).unwrap());
+        assert!(!commit_backups(&out_dir, "Backup: 1 succeeded", false).unwrap());
+
+        // A run that only changed the times isn't committed.
+        fs::write(out_dir.join("porch_meta.json"), r#"{"uptime_secs":60}"#).unwrap();
+        fs::write(out_dir.join("MANIFEST.json"), "{}").unwrap();
+        fs::create_dir(out_dir.join("garden")).unwrap();
+        fs::write(out_dir.join("garden").join("meta.json"), "{}").unwrap();
+        assert!(!commit_backups(&out_dir, "Backup: 1 skipped", false).unwrap());
+
+        let log = git(repo, &["log", "--format=%s", "--name-only"]).unwrap();
+        let log = String::from_utf8_lossy(&log.stdout);
+        assert_eq!(log.trim(), "Backup: 1 succeeded\n\nbackups/porch_cfg.json");
+
+        // Pushing with nowhere to push to fails.
+        fs::write(out_dir.join("porch_cfg.json"), "[]").unwrap();
+        assert!(commit_backups(&out_dir, "Backup", true).is_err());
+    }
+}
@@ -0,0 +1,445 @@
+use crate::device::DeviceOptions;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// HTTP Basic credentials, for devices behind an authenticating reverse
+/// proxy.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct BasicAuth {
+    pub user: String,
+    pub password: Option<String>,
+}
+
+impl std::fmt::Debug for BasicAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("user", &self.user)
+            .field("password", &self.password.as_ref().map(|_| "(secret)"))
+            .finish()
+    }
+}
+
+/// Sent with every request, unless --user-agent gives another. GitHub's API
+/// refuses requests without one.
+pub const DEFAULT_USER_AGENT: &str = concat!("wled_backup/", env!("CARGO_PKG_VERSION"));
+
+/// Give a WLED its settings PIN from `options`, if it has one, so it serves
+/// cfg.json and accepts uploads. WLED remembers the PIN for a while, for
+/// every client, rather than handing out a cookie.
+pub async fn unlock(
+    client: &reqwest::Client,
+    base_url: &str,
+    options: &DeviceOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(pin) = &options.pin else {
+        return Ok(());
+    };
+    let url = format!("{base_url}/json/state");
+    let body = serde_json::json!({ "pin": pin }).to_string();
+    let request = client
+        .post(&url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body);
+    options
+        .apply(request)
+        .send()
+        .await?
+        .error_for_status()
+        .map_err(|e| format!("Failed to send the settings PIN: {e}"))?;
+    Ok(())
+}
+
+/// How often to retry a failed request, and how long to wait before the first
+/// retry. The wait doubles after each attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn none() -> Self {
+        RetryPolicy {
+            retries: 0,
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Delay before the given retry, counting from 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        self.delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+    }
+}
+
+/// How to build the shared HTTP client.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientOptions {
+    /// Give up if connecting, or waiting for the next data, takes longer.
+    pub timeout: Option<Duration>,
+
+    /// Accept any TLS certificate, such as a reverse proxy's self-signed one.
+    pub insecure_tls: bool,
+
+    /// PEM files of CA certificates to trust, besides the system's.
+    pub ca_certs: Vec<PathBuf>,
+
+    /// Sent instead of [`DEFAULT_USER_AGENT`].
+    pub user_agent: Option<String>,
+
+    /// Proxy for every request, such as "socks5h://jump:1080", instead of
+    /// the one named by HTTP_PROXY, HTTPS_PROXY or ALL_PROXY, if any.
+    pub proxy: Option<String>,
+}
+
+/// Shared HTTP client, used for every request to every device so connections
+/// are pooled.
+#[derive(Debug, Clone)]
+pub struct Fetcher {
+    pub client: reqwest::Client,
+    pub retry: RetryPolicy,
+}
+
+impl Default for Fetcher {
+    fn default() -> Self {
+        Fetcher {
+            client: reqwest::Client::builder()
+                .user_agent(DEFAULT_USER_AGENT)
+                .build()
+                .expect("Failed to create HTTP client"),
+            retry: RetryPolicy::none(),
+        }
+    }
+}
+
+impl Fetcher {
+    /// Build a fetcher whose client is set up as `options` say.
+    pub fn new(
+        options: &ClientOptions,
+        retry: RetryPolicy,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let user_agent = options.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT);
+        let mut builder = reqwest::Client::builder().user_agent(user_agent);
+        if let Some(timeout) = options.timeout {
+            builder = builder.connect_timeout(timeout).read_timeout(timeout);
+        }
+        for path in options.ca_certs.iter() {
+            let pem = std::fs::read(path)
+                .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+            let certificate = reqwest::Certificate::from_pem(&pem)
+                .map_err(|e| format!("Invalid certificate in {}: {e}", path.display()))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        builder = builder.danger_accept_invalid_certs(options.insecure_tls);
+        if let Some(proxy) = &options.proxy {
+            // NO_PROXY still applies, as it does to the environment's proxy.
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(|e| format!("Invalid proxy {proxy}: {e}"))?
+                .no_proxy(reqwest::NoProxy::from_env());
+            builder = builder.proxy(proxy);
+        }
+
+        Ok(Fetcher {
+            client: builder.build()?,
+            retry,
+        })
+    }
+
+    /// GET a URL from a device, with its `options`' timeout, credentials and
+    /// headers, retrying connection failures and server errors. Other
+    /// responses, including 404s, are returned as is.
+    pub async fn get(
+        &self,
+        url: &str,
+        options: &DeviceOptions,
+    ) -> Result<reqwest::Response, Box<dyn std::error::Error + Send + Sync>> {
+        let attempts = self.retry.retries + 1;
+        let mut attempt = 1;
+
+        loop {
+            let mut request = options.apply(self.client.get(url));
+            if let Some(timeout) = options.timeout {
+                request = request.timeout(timeout);
+            }
+
+            let error: Box<dyn std::error::Error + Send + Sync> = match request.send().await {
+                Ok(response) if response.status().is_server_error() => {
+                    format!("{url}: HTTP {}", response.status()).into()
+                }
+                Ok(response) => return Ok(response),
+                Err(e) => e.into(),
+            };
+
+            if attempt >= attempts {
+                return Err(if attempts > 1 {
+                    format!("{error} (after {attempts} attempts)").into()
+                } else {
+                    error
+                });
+            }
+
+            tokio::time::sleep(self.retry.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// GET a file that may not exist. Returns None for a 404, and an error
+    /// for any other unsuccessful response.
+    pub async fn get_optional(
+        &self,
+        url: &str,
+        options: &DeviceOptions,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error + Send + Sync>> {
+        let response = self.get(url, options).await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.bytes().await?.to_vec()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use tiny_http::{Response, Server};
+
+    // Fail the first `failures` requests with a 500, then succeed.
+    fn flaky_server(addr: &str, failures: usize) -> thread::JoinHandle<()> {
+        let server = Server::http(addr).unwrap();
+        thread::spawn(move || {
+            for i in 0..=failures {
+                if let Ok(request) = server.recv() {
+                    let response = if i < failures {
+                        Response::from_string("busy").with_status_code(500)
+                    } else {
+                        Response::from_string("ok")
+                    };
+                    let _ = request.respond(response);
+                }
+            }
+        })
+    }
+
+    fn fetcher(retries: u32) -> Fetcher {
+        Fetcher {
+            client: reqwest::Client::new(),
+            retry: RetryPolicy {
+                retries,
+                delay: Duration::from_millis(1),
+            },
+        }
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = RetryPolicy {
+            retries: 3,
+            delay: Duration::from_millis(100),
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_get_retries_server_errors() {
+        let server = flaky_server("127.0.0.1:96", 2);
+
+        let response = fetcher(2)
+            .get("http://127.0.0.1:96/cfg.json", &DeviceOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_gives_up_with_attempt_count() {
+        let server = flaky_server("127.0.0.1:97", 2);
+
+        let result = fetcher(1)
+            .get("http://127.0.0.1:97/cfg.json", &DeviceOptions::default())
+            .await;
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("HTTP 500"), "{error}");
+        assert!(error.ends_with("(after 2 attempts)"), "{error}");
+
+        // Let the server finish.
+        let _ = reqwest::get("http://127.0.0.1:97/").await;
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_read_timeout() {
+        // Accepts the connection, but never answers.
+        let server = Server::http("127.0.0.1:98").unwrap();
+        let handle = thread::spawn(move || {
+            let request = server.recv().unwrap();
+            thread::sleep(Duration::from_millis(500));
+            drop(request);
+        });
+
+        let options = ClientOptions {
+            timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let fetcher = Fetcher::new(&options, RetryPolicy::none()).unwrap();
+        let result = fetcher
+            .get("http://127.0.0.1:98/cfg.json", &DeviceOptions::default())
+            .await;
+        assert!(result.is_err());
+
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_optional() {
+        let server = crate::test_util::mock_routes_server("127.0.0.1:104", &[("/found", "data")]);
+
+        let fetcher = fetcher(0);
+        let found = fetcher
+            .get_optional("http://127.0.0.1:104/found", &DeviceOptions::default())
+            .await;
+        assert_eq!(found.unwrap(), Some(b"data".to_vec()));
+        let missing = fetcher
+            .get_optional("http://127.0.0.1:104/missing", &DeviceOptions::default())
+            .await;
+        assert_eq!(missing.unwrap(), None);
+
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unlock_and_auth() {
+        let server = Server::http("127.0.0.1:152").unwrap();
+        let handle = thread::spawn(move || {
+            let mut seen = vec![];
+            for _ in 0..2 {
+                let mut request = server.recv().unwrap();
+                let mut body = String::new();
+                request.as_reader().read_to_string(&mut body).unwrap();
+                let header = |name: &'static str| {
+                    request
+                        .headers()
+                        .iter()
+                        .find(|header| header.field.equiv(name))
+                        .map(|header| header.value.to_string())
+                };
+                let headers = [
+                    header("Authorization"),
+                    header("X-Proxy-Token"),
+                    header("User-Agent"),
+                ];
+                seen.push((request.url().to_string(), body, headers));
+                let _ = request.respond(Response::from_string("{}"));
+            }
+            seen
+        });
+
+        let auth = BasicAuth {
+            user: "wled".to_string(),
+            password: Some("hunter2".to_string()),
+        };
+        let options = DeviceOptions {
+            pin: Some("1234".to_string()),
+            auth: Some(auth.clone()),
+            headers: vec![("X-Proxy-Token".to_string(), "abc".to_string())],
+            ..Default::default()
+        };
+        let client = ClientOptions {
+            user_agent: Some("test-agent".to_string()),
+            ..Default::default()
+        };
+        let fetcher = Fetcher::new(&client, RetryPolicy::none()).unwrap();
+        unlock(&fetcher.client, "http://127.0.0.1:152", &options)
+            .await
+            .unwrap();
+        fetcher
+            .get("http://127.0.0.1:152/cfg.json", &options)
+            .await
+            .unwrap();
+
+        let headers = [
+            Some("Basic d2xlZDpodW50ZXIy".to_string()),
+            Some("abc".to_string()),
+            Some("test-agent".to_string()),
+        ];
+        assert_eq!(
+            handle.join().unwrap(),
+            [
+                (
+                    "/json/state".to_string(),
+                    r#"{"pin":"1234"}"#.to_string(),
+                    headers.clone()
+                ),
+                ("/cfg.json".to_string(), String::new(), headers),
+            ]
+        );
+        assert!(!format!("{auth:?}").contains("hunter2"));
+    }
+
+    #[test]
+    fn test_fetcher_ca_certs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ca.pem");
+        std::fs::write(&path, "not a certificate").unwrap();
+        let options = ClientOptions {
+            insecure_tls: true,
+            ca_certs: vec![path],
+            ..Default::default()
+        };
+        let error = Fetcher::new(&options, RetryPolicy::none()).unwrap_err();
+        assert!(
+            error.to_string().starts_with("Invalid certificate in "),
+            "{error}"
+        );
+
+        let options = ClientOptions {
+            ca_certs: vec![dir.path().join("missing.pem")],
+            ..Default::default()
+        };
+        let error = Fetcher::new(&options, RetryPolicy::none()).unwrap_err();
+        assert!(error.to_string().starts_with("Failed to read "), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_fetcher_proxy() {
+        // A plain HTTP proxy is sent the whole URL.
+        let server = Server::http("127.0.0.1:154").unwrap();
+        let handle = thread::spawn(move || {
+            let request = server.recv().unwrap();
+            let url = request.url().to_string();
+            let _ = request.respond(Response::from_string("{}"));
+            url
+        });
+
+        let options = ClientOptions {
+            proxy: Some("http://127.0.0.1:154".to_string()),
+            ..Default::default()
+        };
+        let fetcher = Fetcher::new(&options, RetryPolicy::none()).unwrap();
+        let response = fetcher
+            .get("http://192.0.2.10/json/info", &DeviceOptions::default())
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(handle.join().unwrap(), "http://192.0.2.10/json/info");
+
+        let options = ClientOptions {
+            proxy: Some("not a proxy".to_string()),
+            ..Default::default()
+        };
+        assert!(Fetcher::new(&options, RetryPolicy::none()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_get_connection_refused() {
+        let result = fetcher(0)
+            .get("http://127.0.0.1:8083/cfg.json", &DeviceOptions::default())
+            .await;
+        let error = result.unwrap_err().to_string();
+        assert!(!error.contains("attempts"), "{error}");
+    }
+}
@@ -0,0 +1,341 @@
+use crate::device::{Device, DeviceOptions, DeviceSpec, Scheme};
+use crate::http::BasicAuth;
+use crate::notify::NotifyConfig;
+use reqwest::header::{HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// A device inventory file, listing the fleet to back up.
+///
+/// ```toml
+/// [devices.porch]
+/// host = "192.168.5.20"
+/// port = 8080
+/// aliases = ["wled-porch"]
+/// timeout_secs = 10
+/// skip_presets = true
+/// pin = "1234"
+///
+/// # Behind a reverse proxy that asks for a password.
+/// [devices.attic]
+/// host = "attic.example.com"
+/// http_user = "wled"
+/// http_password = "hunter2"
+///
+/// # At https://home.example.com/wled/porch/, through a reverse proxy.
+/// [devices.shed]
+/// host = "home.example.com"
+/// scheme = "https"
+/// base_path = "/wled/shed"
+///
+/// # Sent with every request to the device, such as a proxy's token.
+/// [devices.shed.headers]
+/// CF-Access-Client-Id = "0123.access"
+/// CF-Access-Client-Secret = "abcdef"
+///
+/// # No host, so found by searching for "garden" (or an alias).
+/// [devices.garden]
+///
+/// # Where to send notifications; see [`NotifyConfig`].
+/// [notify]
+/// ntfy = "https://ntfy.sh/my-wled-backups"
+/// ```
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Inventory {
+    #[serde(default)]
+    pub devices: BTreeMap<String, InventoryDevice>,
+    #[serde(default)]
+    pub notify: NotifyConfig,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct InventoryDevice {
+    /// IP address or DNS name. Searched for by name if not given.
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub aliases: Vec<String>,
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub skip_presets: bool,
+    /// Settings PIN.
+    pub pin: Option<String>,
+    /// HTTP Basic credentials, for a reverse proxy in front of the device.
+    pub http_user: Option<String>,
+    pub http_password: Option<String>,
+    /// "https" for a device behind a TLS reverse proxy.
+    pub scheme: Option<Scheme>,
+    /// Path the device is served under by a reverse proxy.
+    pub base_path: Option<String>,
+    /// Extra HTTP headers, by name.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// "wled/porch/" as "/wled/porch", ready to put paths after.
+fn normalize_base_path(path: &str) -> String {
+    let path = path.trim_matches('/');
+    match path.is_empty() {
+        true => String::new(),
+        false => format!("/{path}"),
+    }
+}
+
+impl InventoryDevice {
+    /// The host name to put in URLs, for a device behind a proxy: TLS
+    /// certificates and path-based proxies go by the name, not the address.
+    fn url_host(&self) -> Option<String> {
+        let host = self.host.as_ref()?;
+        let proxied = self.scheme == Some(Scheme::Https) || self.base_path.is_some();
+        (proxied && host.parse::<std::net::IpAddr>().is_err()).then(|| host.clone())
+    }
+
+    /// The extra headers, checked so a typo is reported up front rather
+    /// than failing every request.
+    fn headers(&self) -> Result<Vec<(String, String)>, String> {
+        let mut headers = vec![];
+        for (name, value) in self.headers.iter() {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("Invalid header name {name:?}"))?;
+            HeaderValue::from_str(value).map_err(|_| format!("Invalid value for header {name}"))?;
+            headers.push((name.clone(), value.clone()));
+        }
+        Ok(headers)
+    }
+}
+
+impl Inventory {
+    pub fn load(path: &Path) -> Result<Inventory, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        Self::parse(&contents)
+            .map_err(|e| format!("Failed to parse {}: {e}", path.display()).into())
+    }
+
+    pub fn parse(contents: &str) -> Result<Inventory, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    /// Does any device need to be found by searching?
+    pub fn needs_discovery(&self) -> bool {
+        self.devices.values().any(|d| d.host.is_none())
+    }
+
+    /// Turn the inventory into devices. Devices without a host are returned
+    /// without addresses, to be filled in by discovery. A host that doesn't
+    /// resolve is tried once more after `retry`, if given.
+    pub async fn to_devices(&self, retry: Option<Duration>) -> (Vec<Device>, Vec<String>) {
+        let mut devices = vec![];
+        let mut errors = vec![];
+
+        for (name, entry) in self.devices.iter() {
+            let headers = match entry.headers() {
+                Ok(headers) => headers,
+                Err(result) => {
+                    errors.push(format!("{name}: {result}"));
+                    continue;
+                }
+            };
+            let scheme = entry.scheme.unwrap_or_default();
+            let port = entry.port.unwrap_or(scheme.default_port());
+            let mut device = match &entry.host {
+                Some(host) => {
+                    let spec = DeviceSpec {
+                        host: host.clone(),
+                        port,
+                    };
+                    match spec.resolve_retrying(retry).await {
+                        Ok(device) => device,
+                        Err(result) => {
+                            errors.push(format!("{name}: {result}"));
+                            continue;
+                        }
+                    }
+                }
+                None => Device::new(name, vec![], port),
+            };
+
+            device.name = name.clone();
+            device.aliases = entry.aliases.clone();
+            device.options = DeviceOptions {
+                timeout: entry.timeout_secs.map(Duration::from_secs),
+                skip_presets: entry.skip_presets,
+                pin: entry.pin.clone(),
+                auth: entry.http_user.clone().map(|user| BasicAuth {
+                    user,
+                    password: entry.http_password.clone(),
+                }),
+                scheme,
+                base_path: entry
+                    .base_path
+                    .as_deref()
+                    .map(normalize_base_path)
+                    .unwrap_or_default(),
+                url_host: entry.url_host(),
+                headers,
+            };
+            devices.push(device);
+        }
+
+        (devices, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use tempfile::tempdir;
+
+    const SAMPLE: &str = r#"
+        [devices.porch]
+        host = "127.0.0.1"
+        port = 8080
+        aliases = ["wled-porch"]
+        timeout_secs = 10
+        skip_presets = true
+        pin = "1234"
+        http_user = "wled"
+        http_password = "hunter2"
+
+        [devices.garden]
+
+        [notify]
+        slack = "https://hooks.slack.com/services/T0/B0/x"
+    "#;
+
+    #[test]
+    fn test_inventory_parse() {
+        let inventory = Inventory::parse(SAMPLE).unwrap();
+        assert_eq!(inventory.devices.len(), 2);
+        assert_eq!(
+            inventory.devices["porch"],
+            InventoryDevice {
+                host: Some("127.0.0.1".to_string()),
+                port: Some(8080),
+                aliases: vec!["wled-porch".to_string()],
+                timeout_secs: Some(10),
+                skip_presets: true,
+                pin: Some("1234".to_string()),
+                http_user: Some("wled".to_string()),
+                http_password: Some("hunter2".to_string()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(inventory.devices["garden"], InventoryDevice::default());
+        assert!(inventory.needs_discovery());
+        assert_eq!(inventory.notify.backends().len(), 1);
+    }
+
+    #[test]
+    fn test_inventory_parse_unknown_field() {
+        assert!(Inventory::parse("[devices.porch]\nhots = \"1.2.3.4\"").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_inventory_to_devices() {
+        let inventory = Inventory::parse(SAMPLE).unwrap();
+        let (devices, errors) = inventory.to_devices(None).await;
+        assert!(errors.is_empty());
+
+        // BTreeMap, so sorted by name.
+        assert_eq!(devices[0], Device::new("garden", vec![], 80));
+
+        let porch = &devices[1];
+        assert_eq!(porch.name, "porch");
+        assert_eq!(porch.addresses, vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        assert_eq!(porch.port, 8080);
+        assert_eq!(porch.aliases, vec!["wled-porch".to_string()]);
+        assert_eq!(porch.options.timeout, Some(Duration::from_secs(10)));
+        assert!(porch.options.skip_presets);
+        assert_eq!(porch.options.pin.as_deref(), Some("1234"));
+        assert_eq!(
+            porch.options.auth,
+            Some(BasicAuth {
+                user: "wled".to_string(),
+                password: Some("hunter2".to_string()),
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_inventory_proxied() {
+        let inventory = Inventory::parse(
+            r#"
+            [devices.shed]
+            host = "localhost"
+            scheme = "https"
+            base_path = "wled/shed/"
+
+            [devices.porch]
+            host = "127.0.0.1"
+            base_path = "/porch"
+            "#,
+        )
+        .unwrap();
+        let (devices, errors) = inventory.to_devices(None).await;
+        assert!(errors.is_empty());
+
+        let porch = &devices[0];
+        assert_eq!(porch.port, 80);
+        assert_eq!(porch.options.url_host, None);
+        assert_eq!(
+            porch.options.base_url(&porch.addresses[0], porch.port),
+            "http://127.0.0.1:80/porch"
+        );
+
+        let shed = &devices[1];
+        assert_eq!(shed.port, 443);
+        assert_eq!(
+            shed.options.base_url(&shed.addresses[0], shed.port),
+            "https://localhost:443/wled/shed"
+        );
+
+        assert!(Inventory::parse("[devices.shed]\nscheme = \"ftp\"").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_inventory_headers() {
+        let inventory = Inventory::parse(
+            r#"
+            [devices.shed]
+            host = "127.0.0.1"
+            headers = { Authorization = "Bearer abc", X-Proxy = "1" }
+            "#,
+        )
+        .unwrap();
+        let (devices, errors) = inventory.to_devices(None).await;
+        assert!(errors.is_empty());
+        assert_eq!(
+            devices[0].options.headers,
+            [
+                ("Authorization".to_string(), "Bearer abc".to_string()),
+                ("X-Proxy".to_string(), "1".to_string())
+            ]
+        );
+
+        let inventory = Inventory::parse("[devices.shed.headers]\n\"Bad Name\" = \"1\"").unwrap();
+        let (devices, errors) = inventory.to_devices(None).await;
+        assert!(devices.is_empty());
+        assert_eq!(errors, ["shed: Invalid header name \"Bad Name\""]);
+    }
+
+    #[tokio::test]
+    async fn test_inventory_to_devices_unresolvable() {
+        let inventory = Inventory::parse("[devices.bad]\nhost = \"no-such-host.invalid\"").unwrap();
+        let (devices, errors) = inventory.to_devices(None).await;
+        assert!(devices.is_empty());
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("bad: "));
+    }
+
+    #[test]
+    fn test_inventory_load_missing_file() {
+        let dir = tempdir().unwrap();
+        assert!(Inventory::load(&dir.path().join("devices.toml")).is_err());
+    }
+}
@@ -0,0 +1,469 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// ISO 8601 basic format, which sorts by time and avoids ':' in file names.
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Name of the symlink pointing at the newest backup set.
+pub const LATEST: &str = "latest";
+
+pub fn timestamp_name(time: DateTime<Utc>) -> String {
+    time.format(TIMESTAMP_FORMAT).to_string()
+}
+
+/// Parse the time back out of a backup set directory name.
+pub fn parse_timestamp_name(name: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(name, TIMESTAMP_FORMAT)
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Make a device name safe to use in file and directory names. Path
+/// separators, characters Windows doesn't allow and control characters become
+/// '_', so a name like "../evil" can't escape out_dir.
+pub fn sanitize_name(name: &str) -> String {
+    let mut safe: String = name
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows drops trailing dots and spaces, and "." and ".." aren't names.
+    while safe.ends_with(['.', ' ']) {
+        safe.pop();
+        safe.push('_');
+    }
+    if safe.is_empty() {
+        safe.push('_');
+    }
+
+    let stem = safe.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        safe.insert(stem.len(), '_');
+    }
+    safe
+}
+
+/// How backup files are arranged under out_dir.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LayoutKind {
+    /// out_dir/<hostname>_cfg.json
+    #[default]
+    Flat,
+    /// out_dir/<hostname>/cfg.json
+    PerDevice,
+}
+
+/// Where the files for each device are saved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub kind: LayoutKind,
+    pub out_dir: PathBuf,
+
+    /// Name of the backup set, when each run is saved separately.
+    pub timestamp: Option<String>,
+
+    /// Name cfg.json and presets.json the way the WLED web UI's backup
+    /// buttons do, so they can be restored through it.
+    pub official_names: bool,
+}
+
+impl Layout {
+    pub fn new(kind: LayoutKind, out_dir: &Path) -> Self {
+        Layout {
+            kind,
+            out_dir: out_dir.to_path_buf(),
+            timestamp: None,
+            official_names: false,
+        }
+    }
+
+    /// Directory holding a device's timestamped backup sets, and the link to
+    /// the newest.
+    pub fn sets_dir(&self, hostname: &str) -> PathBuf {
+        match self.kind {
+            LayoutKind::Flat => self.out_dir.clone(),
+            LayoutKind::PerDevice => self.out_dir.join(sanitize_name(hostname)),
+        }
+    }
+
+    /// Directory holding a device's files.
+    pub fn device_dir(&self, hostname: &str) -> PathBuf {
+        let dir = self.sets_dir(hostname);
+        match &self.timestamp {
+            Some(timestamp) => dir.join(timestamp),
+            None => dir,
+        }
+    }
+
+    /// File name for one of a device's files, such as "cfg.json".
+    pub fn file_name(&self, hostname: &str, file: &str) -> String {
+        let hostname = sanitize_name(hostname);
+        if self.official_names && (file == "cfg.json" || file == "presets.json") {
+            let stem = file.trim_end_matches(".json");
+            return format!("wled_{stem}_{hostname}.json");
+        }
+
+        match self.kind {
+            LayoutKind::Flat => format!("{hostname}_{file}"),
+            LayoutKind::PerDevice => file.to_string(),
+        }
+    }
+
+    pub fn file_path(&self, hostname: &str, file: &str) -> PathBuf {
+        self.device_dir(hostname)
+            .join(self.file_name(hostname, file))
+    }
+
+    /// Where the previous backup of a file is: the newest backup set if runs
+    /// are timestamped, else the file about to be overwritten.
+    pub fn previous_file_path(&self, hostname: &str, file: &str) -> PathBuf {
+        match self.timestamp {
+            Some(_) => Layout {
+                timestamp: Some(LATEST.to_string()),
+                ..self.clone()
+            }
+            .file_path(hostname, file),
+            None => self.file_path(hostname, file),
+        }
+    }
+
+    /// Do all devices share each timestamped backup set?
+    pub fn shares_sets(&self) -> bool {
+        self.kind == LayoutKind::Flat && self.timestamp.is_some()
+    }
+
+    /// Path relative to out_dir, for messages.
+    pub fn display_path<'a>(&self, path: &'a Path) -> std::path::Display<'a> {
+        path.strip_prefix(&self.out_dir).unwrap_or(path).display()
+    }
+
+    /// Create the directory a device's files are saved in.
+    pub fn create_device_dir(&self, hostname: &str) -> std::io::Result<()> {
+        fs::create_dir_all(self.device_dir(hostname))
+    }
+
+    /// Called once a device is backed up, to point its latest link at the new
+    /// backup set.
+    pub fn device_done(&self, hostname: &str) -> std::io::Result<()> {
+        match (&self.timestamp, self.kind) {
+            (Some(timestamp), LayoutKind::PerDevice) => {
+                update_latest(&self.sets_dir(hostname), timestamp)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Called once every device is backed up, to point out_dir/latest at the
+    /// new backup set.
+    pub fn run_done(&self) -> std::io::Result<()> {
+        match (&self.timestamp, self.kind) {
+            (Some(timestamp), LayoutKind::Flat) if self.out_dir.join(timestamp).is_dir() => {
+                update_latest(&self.out_dir, timestamp)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Find a saved file to restore from: the newest backup set if backups
+    /// are timestamped, else the file saved directly in place.
+    pub fn find_file(&self, hostname: &str, file: &str) -> PathBuf {
+        let latest = Layout {
+            timestamp: Some(LATEST.to_string()),
+            ..self.clone()
+        }
+        .file_path(hostname, file);
+
+        if self.timestamp.is_none() && latest.exists() {
+            latest
+        } else {
+            self.file_path(hostname, file)
+        }
+    }
+}
+
+/// Where a file is written before being renamed into place: `<name>.tmp`.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// Write a file to `<name>.tmp`, and rename it over `path` once it's
+/// complete, so a failed write never leaves a truncated file in place of the
+/// previous backup.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp = temp_path(path);
+    let written = File::create(&tmp)
+        .and_then(|mut out| {
+            out.write_all(contents)?;
+            out.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    written
+}
+
+/// Point `dir/latest` at the backup set `dir/<name>`, replacing any older link.
+#[cfg(unix)]
+fn update_latest(dir: &Path, name: &str) -> std::io::Result<()> {
+    // Create the new link beside the old one, and rename over it, so there is
+    // always a valid link. The link is relative, so backups can be moved.
+    let tmp_link = dir.join(format!(".{LATEST}.tmp"));
+    let _ = fs::remove_file(&tmp_link);
+    std::os::unix::fs::symlink(name, &tmp_link)?;
+    fs::rename(&tmp_link, dir.join(LATEST))
+}
+
+#[cfg(not(unix))]
+fn update_latest(_dir: &Path, _name: &str) -> std::io::Result<()> {
+    tracing::warn!("{LATEST} link is only supported on unix");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use tempfile::tempdir;
+
+    const TIMESTAMP: &str = "20261015T030405Z";
+
+    fn layout(kind: LayoutKind, timestamp: Option<&str>) -> Layout {
+        Layout {
+            kind,
+            out_dir: PathBuf::from("/backup"),
+            timestamp: timestamp.map(|t| t.to_string()),
+            official_names: false,
+        }
+    }
+
+    #[test]
+    fn test_timestamp_name() {
+        let time = Utc.with_ymd_and_hms(2026, 10, 15, 3, 4, 5).unwrap();
+        assert_eq!(timestamp_name(time), TIMESTAMP);
+    }
+
+    #[test]
+    fn test_parse_timestamp_name() {
+        let time = Utc.with_ymd_and_hms(2026, 10, 15, 3, 4, 5).unwrap();
+        assert_eq!(parse_timestamp_name(TIMESTAMP), Some(time));
+        assert_eq!(parse_timestamp_name(LATEST), None);
+        assert_eq!(parse_timestamp_name("porch_cfg.json"), None);
+    }
+
+    #[test]
+    fn test_layout_file_path() {
+        let cases = [
+            (LayoutKind::Flat, None, "/backup/porch_cfg.json"),
+            (
+                LayoutKind::Flat,
+                Some(TIMESTAMP),
+                "/backup/20261015T030405Z/porch_cfg.json",
+            ),
+            (LayoutKind::PerDevice, None, "/backup/porch/cfg.json"),
+            (
+                LayoutKind::PerDevice,
+                Some(TIMESTAMP),
+                "/backup/porch/20261015T030405Z/cfg.json",
+            ),
+        ];
+
+        for (kind, timestamp, expected) in cases {
+            assert_eq!(
+                layout(kind, timestamp).file_path("porch", "cfg.json"),
+                PathBuf::from(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn test_layout_official_names() {
+        let mut layout = layout(LayoutKind::Flat, None);
+        layout.official_names = true;
+        assert_eq!(
+            layout.file_path("porch", "cfg.json"),
+            PathBuf::from("/backup/wled_cfg_porch.json")
+        );
+        assert_eq!(
+            layout.file_name("porch", "presets.json"),
+            "wled_presets_porch.json"
+        );
+        assert_eq!(
+            layout.file_name("porch", "ledmap.json"),
+            "porch_ledmap.json"
+        );
+
+        layout.kind = LayoutKind::PerDevice;
+        assert_eq!(
+            layout.file_path("porch", "cfg.json"),
+            PathBuf::from("/backup/porch/wled_cfg_porch.json")
+        );
+    }
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(sanitize_name("porch"), "porch");
+        assert_eq!(sanitize_name("Living Room"), "Living Room");
+        assert_eq!(sanitize_name("../evil"), ".._evil");
+        assert_eq!(sanitize_name("..\\..\\evil"), ".._.._evil");
+        assert_eq!(sanitize_name("liv/room"), "liv_room");
+        assert_eq!(sanitize_name("/etc/passwd"), "_etc_passwd");
+        assert_eq!(sanitize_name("a:b*c?d\"e<f>g|h"), "a_b_c_d_e_f_g_h");
+        assert_eq!(sanitize_name("tab\there\n"), "tab_here");
+        assert_eq!(sanitize_name(".."), "._");
+        assert_eq!(sanitize_name("."), "_");
+        assert_eq!(sanitize_name("  "), "_");
+        assert_eq!(sanitize_name("porch."), "porch_");
+        assert_eq!(sanitize_name("CON"), "CON_");
+        assert_eq!(sanitize_name("com1.json"), "com1_.json");
+        assert_eq!(sanitize_name("CONSOLE"), "CONSOLE");
+    }
+
+    #[test]
+    fn test_layout_sanitizes_names() {
+        assert_eq!(
+            layout(LayoutKind::Flat, None).file_path("../evil", "cfg.json"),
+            PathBuf::from("/backup/.._evil_cfg.json")
+        );
+        assert_eq!(
+            layout(LayoutKind::PerDevice, None).file_path("../evil", "cfg.json"),
+            PathBuf::from("/backup/.._evil/cfg.json")
+        );
+        assert_eq!(
+            layout(LayoutKind::PerDevice, Some(TIMESTAMP)).file_path("..", "cfg.json"),
+            PathBuf::from("/backup/._/20261015T030405Z/cfg.json")
+        );
+    }
+
+    #[test]
+    fn test_layout_previous_file_path() {
+        assert_eq!(
+            layout(LayoutKind::Flat, None).previous_file_path("porch", "cfg.json"),
+            PathBuf::from("/backup/porch_cfg.json")
+        );
+        assert_eq!(
+            layout(LayoutKind::PerDevice, Some(TIMESTAMP)).previous_file_path("porch", "cfg.json"),
+            PathBuf::from("/backup/porch/latest/cfg.json")
+        );
+    }
+
+    #[test]
+    fn test_layout_display_path() {
+        let layout = layout(LayoutKind::PerDevice, None);
+        let path = layout.file_path("porch", "cfg.json");
+        assert_eq!(layout.display_path(&path).to_string(), "porch/cfg.json");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_layout_flat_latest() {
+        let dir = tempdir().unwrap();
+        let mut layout = Layout {
+            kind: LayoutKind::Flat,
+            out_dir: dir.path().to_path_buf(),
+            timestamp: Some(TIMESTAMP.to_string()),
+            official_names: false,
+        };
+
+        // Nothing saved, so nothing to link to.
+        layout.run_done().unwrap();
+        assert!(fs::symlink_metadata(dir.path().join(LATEST)).is_err());
+
+        layout.create_device_dir("porch").unwrap();
+        layout.device_done("porch").unwrap();
+        layout.run_done().unwrap();
+        assert_eq!(
+            fs::read_link(dir.path().join(LATEST)).unwrap(),
+            PathBuf::from(TIMESTAMP)
+        );
+
+        // A newer run replaces the link.
+        layout.timestamp = Some("20261016T000000Z".to_string());
+        layout.create_device_dir("porch").unwrap();
+        layout.run_done().unwrap();
+        assert_eq!(
+            fs::read_link(dir.path().join(LATEST)).unwrap(),
+            PathBuf::from("20261016T000000Z")
+        );
+        assert!(!dir.path().join(".latest.tmp").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_layout_per_device_latest() {
+        let dir = tempdir().unwrap();
+        let layout = Layout {
+            kind: LayoutKind::PerDevice,
+            out_dir: dir.path().to_path_buf(),
+            timestamp: Some(TIMESTAMP.to_string()),
+            official_names: false,
+        };
+
+        layout.create_device_dir("porch").unwrap();
+        fs::write(layout.file_path("porch", "cfg.json"), "cfg").unwrap();
+        layout.device_done("porch").unwrap();
+        layout.run_done().unwrap();
+
+        assert_eq!(
+            fs::read_link(dir.path().join("porch").join(LATEST)).unwrap(),
+            PathBuf::from(TIMESTAMP)
+        );
+        assert!(fs::symlink_metadata(dir.path().join(LATEST)).is_err());
+
+        // Restore reads through the device's latest link.
+        let reader = Layout {
+            timestamp: None,
+            ..layout
+        };
+        assert_eq!(
+            reader.find_file("porch", "cfg.json"),
+            dir.path().join("porch").join(LATEST).join("cfg.json")
+        );
+        assert_eq!(
+            fs::read_to_string(reader.find_file("porch", "cfg.json")).unwrap(),
+            "cfg"
+        );
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("porch_cfg.json");
+        assert_eq!(temp_path(&path), dir.path().join("porch_cfg.json.tmp"));
+
+        fs::write(&path, "old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!temp_path(&path).exists());
+
+        // A failed write leaves the old file alone.
+        let missing = dir.path().join("missing").join("cfg.json");
+        assert!(write_atomic(&missing, b"new").is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    }
+
+    #[test]
+    fn test_layout_find_file_untimestamped() {
+        let dir = tempdir().unwrap();
+        let layout = Layout::new(LayoutKind::Flat, dir.path());
+        assert_eq!(
+            layout.find_file("porch", "cfg.json"),
+            dir.path().join("porch_cfg.json")
+        );
+    }
+}
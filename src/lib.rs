@@ -0,0 +1,511 @@
+//! Find WLED devices, and back up and restore their configuration.
+//!
+//! The `wled_backup` command line tool is built on this crate. Other tools can
+//! use [`discover`], [`backup_device`] and [`restore_device`] directly, or the
+//! modules for finer control.
+
+pub mod archive;
+pub mod audit;
+pub mod backup;
+pub mod cache;
+pub mod check;
+pub mod compress;
+pub mod control;
+pub mod crypt;
+pub mod device;
+pub mod diff;
+pub mod discovery;
+pub mod error;
+pub mod filter;
+pub mod fleet;
+pub mod git;
+pub mod http;
+pub mod inventory;
+pub mod layout;
+pub mod library;
+pub mod lock;
+pub mod manifest;
+pub mod meta;
+pub mod metrics;
+pub mod model;
+pub mod mqtt;
+pub mod notify;
+pub mod ota;
+pub mod preset;
+pub mod progress;
+pub mod redact;
+pub mod report;
+pub mod restore;
+pub mod retention;
+pub mod schedule;
+pub mod settings;
+pub mod storage;
+pub mod systemd;
+pub mod template;
+#[cfg(test)]
+mod test_util;
+
+use backup::{
+    BackupSettings, DeviceBackup, SavedNames, backup_wled, get_hostname_from_cfg, try_addresses,
+};
+use crypt::{Decryption, find_saved, read_file};
+use device::{Device, DeviceOptions, merge_devices};
+use discovery::Discovery;
+use error::BackupError;
+use http::Fetcher;
+use layout::{Layout, timestamp_name};
+use meta::{DeviceMeta, META_FILE};
+use model::WledCfg;
+use restore::{RestorePlan, RestoreSettings, SavedFiles, Snapshot};
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::path::Path;
+use tracing::{error, info, warn};
+
+/// Errors from the public API.
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Find WLEDs with each of the sources in turn, merging devices found more
+/// than once. Also returns why any source failed.
+pub async fn discover(
+    fetcher: &Fetcher,
+    sources: &[Box<dyn Discovery>],
+) -> (Vec<Device>, Vec<BackupError>) {
+    let mut devices = vec![];
+    let errors = discover_each(fetcher, sources, &mut devices, |_| false, |_| {}).await;
+    (devices, errors)
+}
+
+/// Like [`discover`], but merges what's found into `devices`, which may hold
+/// some already, such as an inventory's, and passes each device to `found`
+/// as soon as it has an address, so work on it can start while the search
+/// goes on. Each device is passed once, even if it's found again later.
+/// Open-ended searches, such as mDNS, end early once `done` says everything
+/// expected has been found.
+pub async fn discover_each(
+    fetcher: &Fetcher,
+    sources: &[Box<dyn Discovery>],
+    devices: &mut Vec<Device>,
+    done: impl Fn(&[Device]) -> bool,
+    mut found: impl FnMut(&Device),
+) -> Vec<BackupError> {
+    let mut passed = vec![];
+    let mut pass_ready = |devices: &Vec<Device>| {
+        passed.resize(devices.len(), false);
+        for (device, passed) in devices.iter().zip(passed.iter_mut()) {
+            if !*passed && !device.addresses.is_empty() {
+                *passed = true;
+                found(device);
+            }
+        }
+    };
+    pass_ready(devices);
+
+    let mut errors = vec![];
+    for source in sources.iter() {
+        if source.open_ended() && done(devices) {
+            info!("Found every WLED expected, so not searching for more");
+            break;
+        }
+        if let Some(description) = source.describe() {
+            info!("{description}...");
+        }
+        let mut merge = |device| {
+            merge_devices(devices, vec![device]);
+            pass_ready(devices);
+            match done(devices) {
+                true => ControlFlow::Break(()),
+                false => ControlFlow::Continue(()),
+            }
+        };
+        if let Err(result) = source.discover_each(fetcher, &mut merge).await {
+            error!("FAILED: {result}");
+            errors.push(BackupError::Discovery(result));
+        }
+    }
+    errors
+}
+
+/// Back up one device, trying each of its addresses until one works.
+pub async fn backup_device(
+    fetcher: &Fetcher,
+    device: &Device,
+    settings: &BackupSettings,
+) -> Result<DeviceBackup, BackupError> {
+    let names = SavedNames::default();
+    let (_, result) = try_addresses(&device.addresses, async |ip| {
+        backup_wled(fetcher, ip, device, settings, &names).await
+    })
+    .await;
+    result
+}
+
+/// Back up the WLED at `address` into out_dir/pre-restore/, before restoring
+/// to it. [`Snapshot::save_last`] records it for `restore --rollback`.
+/// `options` gives the device's settings PIN and credentials.
+pub async fn snapshot_device(
+    fetcher: &Fetcher,
+    out_dir: &Path,
+    address: &SocketAddr,
+    options: &DeviceOptions,
+) -> Result<Snapshot, Error> {
+    let timestamp = timestamp_name(chrono::Utc::now());
+    let ip = address.ip();
+    let mut device = Device::new(&ip.to_string(), vec![ip], address.port());
+    device.options = options.clone();
+    let mut settings = BackupSettings::new(Snapshot::layout(out_dir, &timestamp));
+    settings.force = true;
+
+    let backup = backup_device(fetcher, &device, &settings).await?;
+    Ok(Snapshot {
+        hostname: backup.hostname,
+        address: *address,
+        timestamp,
+    })
+}
+
+/// Upload the backup saved under `hostname` to the WLED at `address`, and reboot
+/// it. LED maps and palettes are restored too, if they were saved. Secrets
+/// are merged back from `settings.secrets`, or else from the secrets file
+/// saved with the backup, if there is one. Encrypted files are decrypted with
+/// `decryption`. `options` gives the device's settings PIN and credentials,
+/// if it needs them. Refuses if the WLED's MAC or name isn't the backed up
+/// device's, unless `settings.force` is set.
+pub async fn restore_device(
+    client: &reqwest::Client,
+    layout: &Layout,
+    hostname: &str,
+    decryption: Option<&Decryption>,
+    address: &SocketAddr,
+    options: &DeviceOptions,
+    settings: &RestoreSettings,
+) -> Result<(), Error> {
+    let (ip, port) = (&address.ip(), address.port());
+    let saved = saved_files(layout, hostname, settings.secrets.as_deref());
+    check_target(
+        client,
+        layout,
+        hostname,
+        decryption,
+        address,
+        options,
+        settings.force,
+    )
+    .await?;
+    let wait = settings.reboot_timeout;
+    restore::restore_wled(client, ip, port, options, &saved, decryption, wait).await
+}
+
+/// What restore_device would upload, and the name of the WLED it would go
+/// to, without uploading anything.
+pub async fn plan_restore_device(
+    client: &reqwest::Client,
+    layout: &Layout,
+    hostname: &str,
+    decryption: Option<&Decryption>,
+    address: &SocketAddr,
+    options: &DeviceOptions,
+    settings: &RestoreSettings,
+) -> Result<RestorePlan, Error> {
+    let (ip, port) = (&address.ip(), address.port());
+    let saved = saved_files(layout, hostname, settings.secrets.as_deref());
+    check_target(
+        client,
+        layout,
+        hostname,
+        decryption,
+        address,
+        options,
+        settings.force,
+    )
+    .await?;
+    restore::plan_restore(client, ip, port, options, &saved, decryption).await
+}
+
+/// The name in the cfg.json saved under `hostname`.
+fn saved_name(
+    layout: &Layout,
+    hostname: &str,
+    decryption: Option<&Decryption>,
+) -> Result<String, Error> {
+    let path = saved_files(layout, hostname, None).cfg;
+    let cfg = WledCfg::parse(&read_file(&path, decryption)?)
+        .map_err(|e| format!("Invalid {}: {e}", path.display()))?;
+    Ok(get_hostname_from_cfg(&cfg)?.to_string())
+}
+
+/// Check the WLED at `address` looks like the device the backup saved under
+/// `hostname` came from. A different MAC or name is an error unless `force`
+/// is set, and anything else, such as newer firmware, only a warning. So is
+/// being unable to check, such as when the WLED doesn't answer.
+async fn check_target(
+    client: &reqwest::Client,
+    layout: &Layout,
+    hostname: &str,
+    decryption: Option<&Decryption>,
+    address: &SocketAddr,
+    options: &DeviceOptions,
+    force: bool,
+) -> Result<(), Error> {
+    // Backups from before meta.json was saved only have the name in cfg.json
+    // to go by.
+    let path = find_saved(layout.find_file(hostname, META_FILE));
+    let meta = match path.exists() {
+        true => DeviceMeta::load(&path, decryption),
+        false => saved_name(layout, hostname, decryption).map(|hostname| DeviceMeta {
+            hostname,
+            ..Default::default()
+        }),
+    };
+    let (ip, port) = (&address.ip(), address.port());
+    let mismatches = match meta {
+        Ok(meta) => restore::target_mismatches(client, ip, port, options, &meta).await,
+        Err(result) => Err(result),
+    };
+    let mismatches = match mismatches {
+        Ok(mismatches) => mismatches,
+        Err(result) if force => {
+            warn!("Couldn't check {address} against the backup: {result}");
+            return Ok(());
+        }
+        Err(result) => {
+            return Err(format!(
+                "couldn't check {address} is where {hostname} was backed up from: {result} (--force restores anyway)"
+            )
+            .into());
+        }
+    };
+
+    let (refusals, warnings): (Vec<_>, Vec<_>) = mismatches
+        .into_iter()
+        .partition(|m| m.other_device() && !force);
+    for mismatch in warnings {
+        warn!("{address} may not be where {hostname} was backed up from: {mismatch}");
+    }
+    match refusals.first() {
+        Some(mismatch) => Err(format!(
+            "{address} isn't where {hostname} was backed up from: {mismatch} (--force restores anyway)"
+        )
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// The files of the backup saved under `hostname`.
+fn saved_files(layout: &Layout, hostname: &str, secrets: Option<&Path>) -> SavedFiles {
+    let find = |file: &str| find_saved(layout.find_file(hostname, file));
+    let saved_secrets = find(redact::SECRETS_FILE);
+    SavedFiles {
+        cfg: find("cfg.json"),
+        presets: find("presets.json"),
+        secrets: secrets
+            .map(Path::to_path_buf)
+            .or(saved_secrets.exists().then_some(saved_secrets)),
+        extra: backup::ledmap_file_names()
+            .into_iter()
+            .chain(backup::palette_file_names())
+            .map(|file| (find(&file), file))
+            .filter(|(path, _)| path.exists())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::LayoutKind;
+    use crate::test_util::*;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn test_discover_each() {
+        let source = || -> Box<dyn Discovery> {
+            Box::new(discovery::Static {
+                spec: "127.0.0.1:80".parse().unwrap(),
+                retry: None,
+            })
+        };
+        let mut devices = vec![
+            Device::new("porch", vec![], 80),
+            Device::new("garden", vec!["127.0.0.2".parse().unwrap()], 80),
+        ];
+        let mut found = vec![];
+        let sources = [source(), source()];
+        let errors = discover_each(
+            &Fetcher::default(),
+            &sources,
+            &mut devices,
+            |_| false,
+            |device| found.push(device.name.clone()),
+        )
+        .await;
+
+        assert!(errors.is_empty());
+        assert_eq!(found, ["garden", "127.0.0.1"]);
+        assert_eq!(devices.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_discover_each_done() {
+        let sources: [Box<dyn Discovery>; 2] = [
+            Box::new(discovery::Static {
+                spec: "127.0.0.1:80".parse().unwrap(),
+                retry: None,
+            }),
+            Box::new(discovery::Udp {
+                duration: std::time::Duration::from_secs(60),
+            }),
+        ];
+        let mut devices = vec![];
+        let started = std::time::Instant::now();
+        let errors = discover_each(
+            &Fetcher::default(),
+            &sources,
+            &mut devices,
+            |devices| !devices.is_empty(),
+            |_| {},
+        )
+        .await;
+
+        assert!(errors.is_empty());
+        assert_eq!(devices.len(), 1);
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_backup_device_falls_back_to_next_address() {
+        let server = mock_wled_server("127.0.0.1:129", &cfg_body("testwled"), Some(PRESETS_BODY));
+
+        // Nothing answers at 127.0.0.2.
+        let device = Device::new(
+            "testwled",
+            vec!["127.0.0.2".parse().unwrap(), "127.0.0.1".parse().unwrap()],
+            129,
+        );
+        let dir = tempdir().unwrap();
+        let settings = BackupSettings::new(Layout::new(LayoutKind::Flat, dir.path()));
+        let backup = backup_device(&Fetcher::default(), &device, &settings)
+            .await
+            .unwrap();
+
+        assert_eq!(backup.hostname, "testwled");
+        validate_response_files(dir.path(), "testwled");
+
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_device() {
+        let server = mock_wled_server("127.0.0.1:156", &cfg_body("testwled"), Some(PRESETS_BODY));
+
+        let dir = tempdir().unwrap();
+        let address = "127.0.0.1:156".parse().unwrap();
+        let options = DeviceOptions::default();
+        let snapshot = snapshot_device(&Fetcher::default(), dir.path(), &address, &options)
+            .await
+            .unwrap();
+        assert_eq!(snapshot.hostname, "testwled");
+        assert_eq!(snapshot.address, address);
+
+        // Saved under pre-restore/, where the rollback finds it.
+        let set = dir
+            .path()
+            .join("pre-restore/testwled")
+            .join(&snapshot.timestamp);
+        validate_response_file(set.join("cfg.json"), &cfg_body("testwled"));
+        let layout = Snapshot::layout(dir.path(), &snapshot.timestamp);
+        let saved = saved_files(&layout, "testwled", None);
+        assert_eq!(saved.presets, set.join("presets.json"));
+
+        assert_eq!(Snapshot::load_last(dir.path()).unwrap(), vec![]);
+        let taken = vec![snapshot];
+        Snapshot::save_last(&taken, dir.path()).unwrap();
+        assert_eq!(Snapshot::load_last(dir.path()).unwrap(), taken);
+        Snapshot::save_last(&[], dir.path()).unwrap();
+        assert_eq!(Snapshot::load_last(dir.path()).unwrap(), vec![]);
+
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_refuses_other_device() {
+        let info = r#"{"ver":"0.15.0","mac":"112233445566","name":"garden"}"#;
+        let cfg = cfg_body("garden");
+        let routes = [("/json/info", info), ("/cfg.json", cfg.as_str())];
+        let server = mock_routes_server("127.0.0.1:157", &routes);
+
+        let dir = tempdir().unwrap();
+        let layout = Layout::new(LayoutKind::Flat, dir.path());
+        std::fs::write(dir.path().join("porch_cfg.json"), cfg_body("porch")).unwrap();
+        std::fs::write(dir.path().join("porch_presets.json"), PRESETS_BODY).unwrap();
+        let meta = DeviceMeta {
+            hostname: "porch".to_string(),
+            mac: Some("aabbccddeeff".to_string()),
+            ..Default::default()
+        };
+        std::fs::write(
+            dir.path().join("porch_meta.json"),
+            serde_json::to_vec(&meta).unwrap(),
+        )
+        .unwrap();
+
+        let client = reqwest::Client::new();
+        let address = "127.0.0.1:157".parse().unwrap();
+        let options = DeviceOptions::default();
+        let mut settings = RestoreSettings::default();
+        let plan = plan_restore_device(
+            &client, &layout, "porch", None, &address, &options, &settings,
+        );
+        let result = plan.await.unwrap_err().to_string();
+        assert!(result.contains("its MAC is 112233445566"), "{result}");
+
+        settings.force = true;
+        let plan = plan_restore_device(
+            &client, &layout, "porch", None, &address, &options, &settings,
+        );
+        assert_eq!(plan.await.unwrap().device, "garden");
+
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_refuses_unchecked() {
+        let info = r#"{"ver":"0.15.0","name":"garden"}"#;
+        let cfg = cfg_body("garden");
+        let routes = [("/json/info", info), ("/cfg.json", cfg.as_str())];
+        let server = mock_routes_server("127.0.0.1:172", &routes);
+
+        // Without meta.json, the name in the saved cfg.json is checked.
+        let dir = tempdir().unwrap();
+        let layout = Layout::new(LayoutKind::Flat, dir.path());
+        std::fs::write(dir.path().join("porch_cfg.json"), cfg_body("porch")).unwrap();
+        std::fs::write(dir.path().join("porch_presets.json"), PRESETS_BODY).unwrap();
+
+        let client = reqwest::Client::new();
+        let options = DeviceOptions::default();
+        let settings = RestoreSettings::default();
+        let address = "127.0.0.1:172".parse().unwrap();
+        let plan = plan_restore_device(
+            &client, &layout, "porch", None, &address, &options, &settings,
+        );
+        let result = plan.await.unwrap_err().to_string();
+        assert!(result.contains("it's named garden"), "{result}");
+        server.join().unwrap();
+
+        // A WLED that can't be checked isn't restored to either. Nothing
+        // answers on port 131.
+        let address = "127.0.0.1:131".parse().unwrap();
+        let plan = plan_restore_device(
+            &client, &layout, "porch", None, &address, &options, &settings,
+        );
+        let result = plan.await.unwrap_err().to_string();
+        assert!(
+            result.starts_with("couldn't check 127.0.0.1:131"),
+            "{result}"
+        );
+        assert!(result.ends_with("(--force restores anyway)"), "{result}");
+
+        let check = check_target(&client, &layout, "porch", None, &address, &options, true);
+        assert!(check.await.is_ok());
+    }
+}
@@ -0,0 +1,275 @@
+use crate::error::BoxError;
+use crate::layout::write_atomic;
+use crate::manifest::sha256_hex;
+use crate::model::Preset;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Under out_dir, where the library is kept unless --library-dir says
+/// otherwise.
+pub const LIBRARY_DIR: &str = "library";
+
+/// In the library directory, which library presets were put on which WLEDs.
+pub const APPLIED_FILE: &str = "applied.json";
+
+/// A directory of named presets, one per <name>.json, kept apart from any
+/// WLED.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Library {
+    pub dir: PathBuf,
+}
+
+/// A library preset put on a WLED.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Applied {
+    pub name: String,
+    pub version: String,
+    /// When, in RFC 3339 form.
+    pub applied: String,
+}
+
+/// For each WLED, the library presets put on it, by preset number.
+pub type AppliedPresets = BTreeMap<String, BTreeMap<u16, Applied>>;
+
+/// A library preset, and the WLEDs it was put on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LibraryEntry {
+    pub name: String,
+    pub version: String,
+    pub installed: Vec<Installed>,
+}
+
+/// Where a library preset was put.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Installed {
+    pub device: String,
+    pub id: u16,
+    /// Whether the WLED has the library's current version.
+    pub current: bool,
+}
+
+/// A library preset to put on a WLED as preset `id`, given as "NAME=ID".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement {
+    pub name: String,
+    pub id: u16,
+}
+
+impl FromStr for Placement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, id) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("Invalid preset '{s}': expected NAME=ID"))?;
+        check_name(name)?;
+        let id = id.parse::<crate::preset::PresetRange>()?;
+        if id.first != id.last {
+            return Err(format!("Invalid preset '{s}': expected NAME=ID"));
+        }
+        Ok(Placement {
+            name: name.to_string(),
+            id: id.first,
+        })
+    }
+}
+
+/// Library names are file names, so keep them to letters, digits, '-', '_'
+/// and '.'.
+pub fn check_name(name: &str) -> Result<(), String> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || "-_.".contains(c);
+    if name.is_empty() || name.starts_with('.') || !name.chars().all(valid) {
+        return Err(format!(
+            "Invalid library name '{name}': use letters, digits, '-', '_' and '.'"
+        ));
+    }
+    if format!("{name}.json") == APPLIED_FILE {
+        return Err(format!("'{name}' is reserved"));
+    }
+    Ok(())
+}
+
+/// A short hash of a library preset's file, which changes whenever the
+/// preset does.
+fn version(contents: &[u8]) -> String {
+    sha256_hex(contents)[..12].to_string()
+}
+
+impl Library {
+    pub fn new(dir: &Path) -> Self {
+        Library {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{name}.json"))
+    }
+
+    /// Save `preset` as `name`, replacing any preset of that name. Returns
+    /// its version.
+    pub fn add(&self, name: &str, preset: &Preset) -> Result<String, BoxError> {
+        check_name(name)?;
+        let contents = serde_json::to_vec_pretty(preset)?;
+        fs::create_dir_all(&self.dir)?;
+        write_atomic(&self.path(name), &contents)?;
+        Ok(version(&contents))
+    }
+
+    /// The preset saved as `name`, and its version.
+    pub fn load(&self, name: &str) -> Result<(Preset, String), BoxError> {
+        check_name(name)?;
+        let path = self.path(name);
+        let contents = fs::read(&path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => format!("There's no {name} in the library"),
+            _ => format!("Failed to read {}: {e}", path.display()),
+        })?;
+        let preset = serde_json::from_slice(&contents)
+            .map_err(|e| format!("Invalid {}: {e}", path.display()))?;
+        Ok((preset, version(&contents)))
+    }
+
+    /// Which library presets were put on which WLEDs.
+    pub fn applied(&self) -> Result<AppliedPresets, BoxError> {
+        let path = self.dir.join(APPLIED_FILE);
+        match fs::read(&path) {
+            Ok(contents) => Ok(serde_json::from_slice(&contents)
+                .map_err(|e| format!("Invalid {}: {e}", path.display()))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(AppliedPresets::new()),
+            Err(e) => Err(format!("Failed to read {}: {e}", path.display()).into()),
+        }
+    }
+
+    /// Note that version `version` of `name` was put on `device` as preset
+    /// `id`.
+    pub fn record(
+        &self,
+        device: &str,
+        id: u16,
+        name: &str,
+        version: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), BoxError> {
+        let mut applied = self.applied()?;
+        applied.entry(device.to_string()).or_default().insert(
+            id,
+            Applied {
+                name: name.to_string(),
+                version: version.to_string(),
+                applied: now.to_rfc3339(),
+            },
+        );
+        fs::create_dir_all(&self.dir)?;
+        let contents = serde_json::to_vec_pretty(&applied)?;
+        write_atomic(&self.dir.join(APPLIED_FILE), &contents)?;
+        Ok(())
+    }
+
+    /// Every preset in the library, by name, with where each was put.
+    pub fn list(&self) -> Result<Vec<LibraryEntry>, BoxError> {
+        let applied = self.applied()?;
+        let mut entries = vec![];
+        let files = match fs::read_dir(&self.dir) {
+            Ok(files) => files,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+            Err(e) => return Err(format!("{}: {e}", self.dir.display()).into()),
+        };
+        for file in files {
+            let file_name = file?.file_name().to_string_lossy().into_owned();
+            let Some(name) = file_name.strip_suffix(".json") else {
+                continue;
+            };
+            if check_name(name).is_err() {
+                continue;
+            }
+            let (_, version) = self.load(name)?;
+            let installed = applied
+                .iter()
+                .flat_map(|(device, presets)| {
+                    presets
+                        .iter()
+                        .filter(|(_, preset)| preset.name == name)
+                        .map(|(id, preset)| Installed {
+                            device: device.clone(),
+                            id: *id,
+                            current: preset.version == version,
+                        })
+                })
+                .collect();
+            entries.push(LibraryEntry {
+                name: name.to_string(),
+                version,
+                installed,
+            });
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_library() {
+        let dir = tempdir().unwrap();
+        let library = Library::new(&dir.path().join("library"));
+        assert_eq!(library.list().unwrap(), []);
+
+        let ocean: Preset = serde_json::from_value(json!({"n": "Ocean", "bri": 128})).unwrap();
+        let old = library.add("ocean-wave", &ocean).unwrap();
+        let now = "2026-10-15T03:00:00Z".parse().unwrap();
+        library.record("porch", 5, "ocean-wave", &old, now).unwrap();
+
+        let brighter: Preset = serde_json::from_value(json!({"n": "Ocean", "bri": 255})).unwrap();
+        let new = library.add("ocean-wave", &brighter).unwrap();
+        assert_ne!(old, new);
+        library
+            .record("garden", 7, "ocean-wave", &new, now)
+            .unwrap();
+        assert_eq!(library.load("ocean-wave").unwrap(), (brighter, new.clone()));
+
+        assert_eq!(
+            library.list().unwrap(),
+            [LibraryEntry {
+                name: "ocean-wave".to_string(),
+                version: new,
+                installed: vec![
+                    Installed {
+                        device: "garden".to_string(),
+                        id: 7,
+                        current: true,
+                    },
+                    Installed {
+                        device: "porch".to_string(),
+                        id: 5,
+                        current: false,
+                    },
+                ],
+            }]
+        );
+        assert!(library.load("fire").is_err());
+    }
+
+    #[test]
+    fn test_placement() {
+        assert_eq!(
+            "ocean-wave=5".parse::<Placement>(),
+            Ok(Placement {
+                name: "ocean-wave".to_string(),
+                id: 5,
+            })
+        );
+        assert!("ocean-wave".parse::<Placement>().is_err());
+        assert!("../x=5".parse::<Placement>().is_err());
+        assert!("applied=5".parse::<Placement>().is_err());
+        assert!("ocean=0".parse::<Placement>().is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+/// Held in out_dir while a backup runs, so runs don't overlap.
+pub const LOCK_FILE: &str = ".wled_backup.lock";
+
+/// A lock whose process can't be checked is taken over after this long.
+const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum LockError {
+    #[error("another backup is running ({}{})", path.display(), pid.map(|pid| format!(", process {pid}")).unwrap_or_default())]
+    Held { path: PathBuf, pid: Option<u32> },
+
+    #[error("Failed to lock {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+/// Is the process that wrote a lock gone? Where /proc shows running
+/// processes that is checked, else the lock is stale once it's a day old.
+fn is_stale(path: &Path, pid: Option<u32>) -> bool {
+    let proc = Path::new("/proc");
+    let modified = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    if let Some(pid) = pid
+        && proc.join("self").exists()
+    {
+        // A restarted container often gives the new run the old one's PID,
+        // and any other process with it started after the lock was written.
+        return pid == std::process::id()
+            || !proc.join(pid.to_string()).exists()
+            || modified
+                .zip(process_started(pid))
+                .is_some_and(|(modified, started)| modified + Duration::from_secs(1) < started);
+    }
+    modified
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age > STALE_AFTER)
+}
+
+/// When process `pid` started, from /proc. Accurate to about a second.
+fn process_started(pid: u32) -> Option<SystemTime> {
+    // The command name may hold spaces, so fields are counted after it.
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    let ticks: u64 = stat
+        .rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(19)?
+        .parse()
+        .ok()?;
+    let boot: u64 = fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    // Linux counts process times in hundredths of a second.
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(boot) + Duration::from_millis(ticks * 10))
+}
+
+/// An exclusive lock on a backup directory, released when dropped.
+#[derive(Debug)]
+pub struct RunLock {
+    path: PathBuf,
+}
+
+impl RunLock {
+    /// Lock `dir`, taking over a lock left by a process that has exited.
+    pub fn acquire(dir: &Path) -> Result<RunLock, LockError> {
+        let path = dir.join(LOCK_FILE);
+        let io = |source| LockError::Io {
+            path: path.clone(),
+            source,
+        };
+
+        // Twice: once more after removing a stale lock.
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    writeln!(file, "{}", std::process::id()).map_err(io)?;
+                    return Ok(RunLock { path });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let pid = fs::read_to_string(&path)
+                        .ok()
+                        .and_then(|contents| contents.trim().parse().ok());
+                    if !is_stale(&path, pid) {
+                        return Err(LockError::Held { path, pid });
+                    }
+                    warn!("Removing stale lock {}", path.display());
+                    match fs::remove_file(&path) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(io(e)),
+                        _ => {}
+                    }
+                }
+                Err(e) => return Err(io(e)),
+            }
+        }
+        Err(LockError::Held { path, pid: None })
+    }
+
+    /// Lock `dir`, waiting up to `timeout` for another run to finish.
+    pub async fn wait(dir: &Path, timeout: Duration) -> Result<RunLock, LockError> {
+        let started = Instant::now();
+        let mut waiting = false;
+        loop {
+            match RunLock::acquire(dir) {
+                Err(LockError::Held { .. }) if started.elapsed() < timeout => {
+                    if !waiting {
+                        info!("Waiting for another backup to finish");
+                        waiting = true;
+                    }
+                    tokio::time::sleep(Duration::from_secs(1).min(timeout)).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_run_lock() {
+        let dir = tempdir().unwrap();
+        let lock = RunLock::acquire(dir.path()).unwrap();
+        let contents = fs::read_to_string(dir.path().join(LOCK_FILE)).unwrap();
+        assert_eq!(contents.trim(), std::process::id().to_string());
+
+        drop(lock);
+        assert!(!dir.path().join(LOCK_FILE).exists());
+
+        // Held by a process that's still running.
+        fs::write(dir.path().join(LOCK_FILE), "1\n").unwrap();
+        let error = RunLock::acquire(dir.path()).unwrap_err();
+        assert!(
+            matches!(error, LockError::Held { pid: Some(1), .. }),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_run_lock_stale() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(LOCK_FILE);
+
+        // No such process.
+        fs::write(&path, "4000000000\n").unwrap();
+        drop(RunLock::acquire(dir.path()).unwrap());
+
+        // This process, so one that had the PID before, as in a restarted
+        // container.
+        fs::write(&path, format!("{}\n", std::process::id())).unwrap();
+        drop(RunLock::acquire(dir.path()).unwrap());
+
+        // Written before the process with its PID started.
+        fs::write(&path, "1\n").unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        let _lock = RunLock::acquire(dir.path()).unwrap();
+
+        // A lock being written is left alone.
+        fs::write(&path, "").unwrap();
+        assert!(RunLock::acquire(dir.path()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_lock_wait() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join(LOCK_FILE), "1\n").unwrap();
+        let waited = RunLock::wait(dir.path(), Duration::from_millis(10)).await;
+        assert!(matches!(waited, Err(LockError::Held { .. })));
+
+        fs::remove_file(dir.path().join(LOCK_FILE)).unwrap();
+        assert!(RunLock::wait(dir.path(), Duration::ZERO).await.is_ok());
+    }
+}
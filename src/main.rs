@@ -1,467 +1,5047 @@
-use clap::Parser;
-use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
-use serde_json::Value;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Write, copy};
-use std::net::IpAddr;
-use std::path::PathBuf;
+use clap::builder::PossibleValuesParser;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use clap_complete::Shell;
+use futures::StreamExt;
+use std::io::{IsTerminal, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::{self, time::ChronoLocal};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{Layer, Registry};
+use wled_backup::archive::{ArchiveFormat, ArchiveScope};
+use wled_backup::audit::{
+    DeviceFindings, DeviceVersion, LATEST_RELEASE_URL, Version, audit_versions, latest_release,
+    security_findings,
+};
+use wled_backup::backup::{
+    BackupRun, BackupSettings, Collision, Endpoint, Part, backup_stream, fetch_cfg, fetch_info,
+    fetch_version, filtered_out, try_addresses,
+};
+use wled_backup::cache::{Cached, DEVICE_CACHE_FILE, DeviceCache};
+use wled_backup::check::{check_backups, saved_devices};
+use wled_backup::compress::Compression;
+use wled_backup::control;
+use wled_backup::crypt::{Decryption, Encryption, find_saved, read_file, read_passphrase};
+use wled_backup::device::{AddressPreference, Device, DeviceOptions, DeviceSpec, merge_devices};
+use wled_backup::diff::{DeviceChanges, FieldChange, FileChange, diff_dirs, diff_live};
+use wled_backup::discovery::{
+    Discovery, DiscoveryKind, Mdns, MdnsOptions, Nodes, Scan, Static, Udp,
+};
+use wled_backup::error::{BackupError, BoxError};
+use wled_backup::filter::{DeviceFilter, Subnet};
+use wled_backup::fleet::Fleet;
+use wled_backup::git;
+use wled_backup::http::{BasicAuth, ClientOptions, Fetcher, RetryPolicy};
+use wled_backup::inventory::Inventory;
+use wled_backup::layout::{self, Layout, LayoutKind};
+use wled_backup::library::{LIBRARY_DIR, Library, Placement};
+use wled_backup::lock::{LockError, RunLock};
+use wled_backup::manifest::FileStatus;
+use wled_backup::meta::{DeviceMeta, META_FILE};
+use wled_backup::metrics::{self, RunMetrics};
+use wled_backup::model::{Presets, WledInfo};
+use wled_backup::mqtt::{self, MqttSettings};
+use wled_backup::notify::{NotifyConfig, NotifyWhen, Ping, RunNotice, send_ping, send_webhook};
+use wled_backup::ota::{self, Asset, FIRMWARE_DIR, Firmware, RELEASES_URL, Release};
+use wled_backup::preset::{self, PresetChange, PresetFile, PresetRange, SlotMap};
+use wled_backup::progress;
+use wled_backup::redact::SecretHandling;
+use wled_backup::report::{
+    ActionReport, DeviceDetails, DeviceReport, DeviceSummary, ExportFormat, Failure, OutputFormat,
+    RestoreReport, RunReport, Summary, UploadReport, print_json, set_output_format,
+};
+use wled_backup::restore::{
+    Export, PRE_RESTORE_DIR, RestorePlan, RestoreSettings, Snapshot, plan_export, restore_export,
+};
+use wled_backup::retention::{self, RetentionPolicy};
+use wled_backup::schedule::{Cron, Schedule, parse_duration};
+use wled_backup::settings::Settings;
+use wled_backup::storage::{
+    self, Destination, S3Options, SftpOptions, StorageOptions, StreamTarget, WebDavOptions,
+};
+use wled_backup::systemd;
+use wled_backup::template;
+use wled_backup::{
+    backup_device, discover_each, plan_restore_device, restore_device, say, snapshot_device,
+};
 
 /// Backup WLED presets from discovered devices.
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// Directory to save backups in
-    #[arg(short, long, default_value = ".")]
+    #[arg(short, long, default_value = ".", global = true)]
     out_dir: PathBuf,
 
-    /// Search duration in seconds
-    #[arg(short, long, default_value_t = 4)]
-    search_secs: u64,
-}
+    /// Search duration in seconds
+    #[arg(short, long, default_value_t = 4, global = true)]
+    search_secs: u64,
+
+    /// How backup files are arranged in out_dir
+    #[arg(long, value_enum, default_value_t = LayoutKind::Flat, global = true)]
+    layout: LayoutKind,
+
+    /// Name cfg.json and presets.json as the WLED web UI's backup buttons do
+    /// (wled_cfg_<name>.json), for restoring through the stock UI
+    #[arg(long, global = true)]
+    official_format: bool,
+
+    /// Only search for WLEDs on this network interface, such as eth0. May be
+    /// repeated
+    #[arg(long, value_name = "NAME", global = true)]
+    interface: Vec<String>,
+
+    /// Only search for WLEDs on the interface with this local address. May be
+    /// repeated
+    #[arg(long, value_name = "IP", global = true)]
+    bind_ip: Vec<IpAddr>,
+
+    /// Try a device's IPv4 addresses before its IPv6 ones
+    #[arg(long, global = true, conflicts_with = "prefer_ipv6")]
+    prefer_ipv4: bool,
+
+    /// Try a device's IPv6 addresses before its IPv4 ones
+    #[arg(long, global = true)]
+    prefer_ipv6: bool,
+
+    /// If a device's host name doesn't resolve, wait this long and try once
+    /// more before giving up on it
+    #[arg(long, value_name = "SECS", global = true)]
+    retry_resolve_secs: Option<u64>,
+
+    /// Print results as text, or as one JSON document for scripts
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text, global = true)]
+    output: OutputFormat,
+
+    /// Log more detail: -v for debugging messages, -vv for everything
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Log less: -q for only warnings and failures, -qq for only failures
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    quiet: u8,
+
+    /// Also append the log to this file
+    #[arg(long, value_name = "FILE", global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Log as JSON lines, one per message: to --log-file if given, else to
+    /// stderr
+    #[arg(long, global = true)]
+    log_json: bool,
+
+    /// WLED to use instead of searching, as host[:port]. May be repeated
+    #[arg(short, long = "device", value_name = "HOST[:PORT]", global = true)]
+    devices: Vec<DeviceSpec>,
+
+    /// Also search for WLEDs when devices are given with --device, --config,
+    /// --scan or --nodes
+    #[arg(long, global = true)]
+    discover: bool,
+
+    /// Remember the WLEDs found in out_dir/devices.json, and try them there
+    /// first next time, only searching if one has moved
+    #[arg(long, global = true)]
+    cache_devices: bool,
+
+    /// How to search for WLEDs, when none are given or with --discover. Comma
+    /// separated, to combine several
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "mdns",
+        global = true
+    )]
+    discovery: Vec<DiscoveryKind>,
+
+    /// Find WLEDs by probing every address in this network, such as
+    /// 192.168.1.0/24, for networks that block mDNS. May be repeated
+    #[arg(long, value_name = "CIDR", global = true)]
+    scan: Vec<Subnet>,
+
+    /// How long to wait for each scanned address to answer
+    #[arg(long, default_value_t = 500, global = true)]
+    scan_timeout_ms: u64,
+
+    /// Use this WLED, and every WLED it lists in /json/nodes, found by WLED's
+    /// own UDP node discovery. May be repeated
+    #[arg(long, value_name = "HOST[:PORT]", global = true)]
+    nodes: Vec<DeviceSpec>,
+
+    /// Device inventory file (TOML) listing the WLEDs to use instead of searching
+    #[arg(short, long, value_name = "FILE", global = true)]
+    config: Option<PathBuf>,
+
+    /// How many times to retry a failed HTTP request
+    #[arg(long, default_value_t = 2, global = true)]
+    retries: u32,
+
+    /// Delay before the first retry, doubling after each retry
+    #[arg(long, default_value_t = 500, global = true)]
+    retry_delay_ms: u64,
+
+    /// Give up on an HTTP request if connecting or reading stalls this long
+    #[arg(long, default_value_t = 30, global = true)]
+    http_timeout_secs: u64,
+
+    /// Settings PIN to send to devices that have one, unless the device
+    /// inventory gives one
+    #[arg(long, env = "WLED_PIN", hide_env_values = true, global = true)]
+    pin: Option<String>,
+
+    /// User name for HTTP Basic authentication, for devices behind an
+    /// authenticating reverse proxy, unless the device inventory gives one
+    #[arg(long, value_name = "USER", global = true)]
+    http_user: Option<String>,
+
+    /// Password for HTTP Basic authentication, with --http-user
+    #[arg(
+        long,
+        value_name = "PASSWORD",
+        env = "WLED_HTTP_PASSWORD",
+        hide_env_values = true,
+        global = true,
+        requires = "http_user"
+    )]
+    http_password: Option<String>,
+
+    /// Accept any TLS certificate from devices behind HTTPS reverse proxies,
+    /// such as a self-signed one
+    #[arg(long, global = true)]
+    insecure_tls: bool,
+
+    /// PEM file of a CA certificate to trust for devices behind HTTPS
+    /// reverse proxies. May be repeated
+    #[arg(long, value_name = "FILE", global = true)]
+    ca_cert: Vec<PathBuf>,
+
+    /// User-Agent header for every request [default: wled_backup/<version>]
+    #[arg(long, global = true)]
+    user_agent: Option<String>,
+
+    /// Send every request through this proxy, such as socks5h://jump:1080 or
+    /// http://proxy:3128, instead of $HTTP_PROXY, $HTTPS_PROXY or $ALL_PROXY
+    #[arg(long, value_name = "URL", global = true)]
+    proxy: Option<String>,
+
+    /// Give up on the whole run after this long
+    #[arg(long, global = true)]
+    deadline_secs: Option<u64>,
+
+    /// age identity file with the private key to decrypt encrypted backups
+    /// with, for restore and diff. May be repeated
+    #[arg(long, value_name = "FILE", global = true)]
+    identity: Vec<PathBuf>,
+
+    /// File holding the passphrase to decrypt encrypted backups with, for
+    /// restore and diff
+    #[arg(long, value_name = "FILE", global = true)]
+    passphrase_file: Option<PathBuf>,
+
+    /// What to do, defaults to backup
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Parser, Debug, Clone, PartialEq)]
+struct BackupArgs {
+    /// How many devices to back up at once
+    #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+
+    /// Start no more backups once a device fails, instead of backing up the
+    /// rest and reporting every failure
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Save each run in its own <timestamp> directory, and point a latest
+    /// link at the newest. Per device, with --layout per-device
+    #[arg(short, long)]
+    timestamped: bool,
+
+    /// Save files even if they match the previous backup
+    #[arg(short, long)]
+    force: bool,
+
+    /// Find the devices and their names, and print the files that would be
+    /// saved, but save, upload and send nothing
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Also save these JSON API endpoints, comma separated
+    #[arg(short, long, value_enum, value_delimiter = ',')]
+    endpoints: Vec<Endpoint>,
+
+    /// Save only these parts of each backup, comma separated, leaving the
+    /// rest as they were
+    #[arg(long, value_enum, value_delimiter = ',', conflicts_with = "full_fs")]
+    only: Vec<Part>,
+
+    /// Save every file on the device's filesystem, such as usermod configs
+    #[arg(long)]
+    full_fs: bool,
+
+    /// Save cfg.json and presets.json indented, with sorted keys, so backups
+    /// diff readably
+    #[arg(long)]
+    pretty: bool,
+
+    /// Fetch cfg.json and presets.json again after saving them, and fail the
+    /// device if they differ, catching a download cut short
+    #[arg(long)]
+    verify: bool,
+
+    /// Commit the changed files to git, if out_dir is in a git repository,
+    /// with what changed on each device in the message
+    #[arg(long)]
+    git_commit: bool,
+
+    /// Also push the commit
+    #[arg(long, requires = "git_commit")]
+    git_push: bool,
+
+    /// Also copy each run's files here: a directory, s3://bucket/prefix,
+    /// sftp://user@nas/path or webdavs://user@host/path, each run under its
+    /// own <timestamp>/ prefix. Give more than once to copy to each
+    #[arg(long, value_name = "URL")]
+    dest: Vec<Destination>,
+
+    /// Send each file to the --dest as it downloads, hashing it on the way,
+    /// instead of saving it in out_dir and copying it after. Needs a single
+    /// directory, s3:// or webdav --dest. presets.json isn't checked first
+    #[arg(
+        long,
+        requires = "dest",
+        conflicts_with_all = [
+            "pretty", "verify", "git_commit", "redact_secrets", "separate_secrets", "compress",
+            "encrypt_recipient", "encrypt_passphrase_file", "archive",
+        ]
+    )]
+    stream: bool,
+
+    /// S3 endpoint, for a store other than AWS, such as http://minio:9000
+    #[arg(long, value_name = "URL")]
+    s3_endpoint: Option<String>,
+
+    /// [default: $AWS_REGION, or us-east-1]
+    #[arg(long)]
+    s3_region: Option<String>,
+
+    /// [default: $AWS_ACCESS_KEY_ID]
+    #[arg(long)]
+    s3_access_key_id: Option<String>,
+
+    /// [default: $AWS_SECRET_ACCESS_KEY]
+    #[arg(long)]
+    s3_secret_access_key: Option<String>,
+
+    /// Private key for an sftp:// --dest [default: ssh's keys and agent]
+    #[arg(long, value_name = "PATH")]
+    sftp_identity: Option<PathBuf>,
+
+    /// Password for the user of a webdav:// or webdavs:// --dest, such as a
+    /// Nextcloud app password
+    #[arg(long, env = "WLED_WEBDAV_PASSWORD", hide_env_values = true)]
+    webdav_password: Option<String>,
+
+    /// POST a JSON summary of each run here: how many devices succeeded and
+    /// failed, how long it took, and the errors
+    #[arg(long, value_name = "URL")]
+    webhook_url: Option<String>,
+
+    /// A healthchecks.io ping URL: pinged with /start as the run begins, and
+    /// afterwards with the summary, at /fail if any device failed
+    #[arg(long, value_name = "URL")]
+    healthcheck_url: Option<String>,
+
+    /// Send a notification to this ntfy topic URL, such as
+    /// https://ntfy.sh/my-wled-backups
+    #[arg(long, value_name = "URL")]
+    ntfy: Option<String>,
+
+    /// Send a notification to this Slack incoming webhook
+    #[arg(long, value_name = "URL")]
+    slack_webhook: Option<String>,
+
+    /// Send a notification to this Discord webhook
+    #[arg(long, value_name = "URL")]
+    discord_webhook: Option<String>,
+
+    /// When to send notifications to ntfy, Slack or Discord, given here or
+    /// in the [notify] section of --config [default: failure]
+    #[arg(long, value_name = "WHEN")]
+    notify_when: Option<NotifyWhen>,
+
+    /// Publish each device's backup status, last backup time and size to
+    /// this MQTT broker, as mqtt://[USER:PASSWORD@]HOST[:PORT], with Home
+    /// Assistant discovery configs so they show up as sensors
+    #[arg(long, value_name = "URL")]
+    mqtt_url: Option<String>,
+
+    /// Topic prefix for the status messages
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        default_value = "wled_backup",
+        requires = "mqtt_url"
+    )]
+    mqtt_prefix: String,
+
+    /// Home Assistant's MQTT discovery prefix
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        default_value = "homeassistant",
+        requires = "mqtt_url"
+    )]
+    mqtt_discovery_prefix: String,
+
+    /// Write Prometheus metrics about the run, and when each device was last
+    /// backed up, to this file, for node_exporter's textfile collector
+    #[arg(long, value_name = "FILE")]
+    metrics_file: Option<PathBuf>,
+
+    /// Push the same metrics to this Prometheus Pushgateway
+    #[arg(long, value_name = "URL")]
+    pushgateway_url: Option<String>,
+
+    /// If another backup to out_dir is running, wait up to this long for it
+    /// to finish, instead of exiting with code 4 straight away
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    lock_wait_secs: u64,
+
+    /// Leave WiFi, access point, MQTT, Hue and OTA passwords out of
+    /// cfg.json, for backups kept somewhere shared, like a git repo
+    #[arg(long, conflicts_with = "separate_secrets")]
+    redact_secrets: bool,
+
+    /// Move the passwords out of cfg.json into <hostname>_secrets.json, to
+    /// be kept apart from the backup and merged back on restore
+    #[arg(long)]
+    separate_secrets: bool,
+
+    /// Compress saved JSON files, as .json.zst or .json.gz
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    compress: Option<Compression>,
+
+    /// Encrypt every saved file with age to this public key (age1...),
+    /// adding .age to its name. May be repeated
+    #[arg(long, value_name = "KEY")]
+    encrypt_recipient: Vec<age::x25519::Recipient>,
+
+    /// Encrypt every saved file with age, using the passphrase in this file
+    #[arg(long, value_name = "FILE", conflicts_with = "encrypt_recipient")]
+    encrypt_passphrase_file: Option<PathBuf>,
+
+    /// Also bundle the saved files, with a manifest, into archives
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    archive: Option<ArchiveFormat>,
+
+    /// Make one archive per device, or one for the whole run
+    #[arg(long, value_enum, default_value_t = ArchiveScope::Device, requires = "archive")]
+    archive_scope: ArchiveScope,
+
+    /// Fail if fewer than N devices are found, after backing up those that are
+    #[arg(long, value_name = "N")]
+    expect: Option<usize>,
+
+    /// Report which devices listed in this file weren't backed up, and which
+    /// devices found aren't listed
+    #[arg(long, value_name = "FILE")]
+    fleet: Option<PathBuf>,
+
+    /// What to do when a device has the same host name as another device
+    /// backed up in the run
+    #[arg(long, value_enum, default_value_t = Collision::SuffixMac)]
+    on_collision: Collision,
+
+    #[command(flatten)]
+    filter: DeviceFilter,
+
+    /// Delete old backup sets after a fully successful run
+    #[command(flatten)]
+    retention: RetentionPolicy,
+}
+
+impl Default for BackupArgs {
+    /// The arguments used when no subcommand is given.
+    fn default() -> Self {
+        BackupArgs::parse_from(["backup"])
+    }
+}
+
+/// Parse the command line, with the environment and `settings` providing
+/// defaults. With no subcommand, the backup arguments come from them too.
+fn parse_args<I, T>(settings: &Settings, argv: I) -> Result<Args, clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let command = settings
+        .apply(Args::command())
+        .map_err(|e| Args::command().error(clap::error::ErrorKind::InvalidValue, e))?;
+    let mut args = Args::from_arg_matches(&command.clone().try_get_matches_from(argv)?)?;
+    if args.command.is_none()
+        && let Some(backup) = command.find_subcommand("backup")
+    {
+        let matches = backup.clone().try_get_matches_from(["backup"])?;
+        args.command = Some(Command::Backup(BackupArgs::from_arg_matches(&matches)?));
+    }
+    Ok(args)
+}
+
+#[derive(clap::Args, Debug, Clone, PartialEq)]
+struct DaemonArgs {
+    /// Back up straight away, and then this long after each backup started,
+    /// such as 6h or 1d
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    #[arg(required_unless_present = "cron", conflicts_with = "cron")]
+    interval: Option<chrono::Duration>,
+
+    /// Back up at the times given by a cron expression, in local time, such
+    /// as "0 3 * * *" for 3am every day
+    #[arg(long, value_name = "EXPR")]
+    cron: Option<Cron>,
+
+    #[command(flatten)]
+    backup: BackupArgs,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+enum Command {
+    /// Discover WLEDs and back up their cfg.json and presets.json
+    Backup(BackupArgs),
+
+    /// Keep running, and back up on a schedule until stopped with SIGTERM or
+    /// Ctrl+C
+    Daemon(DaemonArgs),
+
+    /// Print systemd service and timer units that run backup, with the other
+    /// options given, on a schedule
+    InstallSystemd {
+        /// How often to back up, such as 6h or 1d
+        #[arg(long, value_name = "DURATION", default_value = "1d", value_parser = parse_duration)]
+        interval: chrono::Duration,
+
+        /// Write the units to ~/.config/systemd/user instead of printing them
+        #[arg(long)]
+        install: bool,
+
+        #[command(flatten)]
+        backup: BackupArgs,
+    },
+
+    /// Discover WLEDs and list their addresses, MACs and firmware versions,
+    /// without backing anything up
+    List,
+
+    /// Discover WLEDs and print a spreadsheet of their addresses, MACs,
+    /// firmware, chips, LED counts and Wi-Fi signal
+    Inventory {
+        /// Defaults to csv, or json with --output json
+        #[arg(long, value_enum)]
+        format: Option<ExportFormat>,
+    },
+
+    /// Check the discovered WLEDs for problems
+    Audit {
+        #[command(subcommand)]
+        audit: AuditCommand,
+    },
+
+    /// Copy single presets off a WLED, or onto one, leaving its other presets
+    /// alone
+    Preset {
+        #[command(subcommand)]
+        preset: PresetCommand,
+    },
+
+    /// Keep a library of named presets, apart from any WLED, and put them on
+    /// WLEDs
+    Library {
+        /// Library directory, instead of out_dir/library
+        #[arg(long, value_name = "DIR", global = true)]
+        library_dir: Option<PathBuf>,
+
+        #[command(subcommand)]
+        library: LibraryCommand,
+    },
+
+    /// Send a JSON state to the WLEDs found, such as {"on":false} to turn
+    /// them all off
+    Cmd {
+        /// State to send to each WLED's /json/state
+        #[arg(long, value_name = "JSON", value_parser = control::parse_state)]
+        json: serde_json::Value,
+
+        #[command(flatten)]
+        filter: DeviceFilter,
+
+        /// Print which WLEDs it would be sent to, but send nothing
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Reboot the WLEDs found, after asking
+    Reboot {
+        #[command(flatten)]
+        filter: DeviceFilter,
+
+        /// Reboot without asking first
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Save what the WLEDs found are showing right now, to bring it back
+    /// after a power cycle or restore
+    State {
+        /// State directory, instead of out_dir/state
+        #[arg(long, value_name = "DIR", global = true)]
+        state_dir: Option<PathBuf>,
+
+        #[command(subcommand)]
+        state: StateCommand,
+    },
+
+    /// Update the firmware of the WLEDs found, backing each up first, and
+    /// check each comes back running it. Stops at the first failure
+    Ota {
+        /// Firmware image, such as WLED_0.15.0_ESP32.bin
+        #[arg(long, value_name = "FILE", required_unless_present = "release")]
+        firmware: Option<PathBuf>,
+
+        /// Download this official WLED release, such as 0.15.0 or latest, and
+        /// give each WLED the image for its board
+        #[arg(long, value_name = "VERSION", conflicts_with_all = ["firmware", "version"])]
+        release: Option<String>,
+
+        /// Where downloaded releases are kept, instead of out_dir/firmware
+        #[arg(long, value_name = "DIR", requires = "release")]
+        firmware_cache: Option<PathBuf>,
+
+        #[command(flatten)]
+        filter: DeviceFilter,
+
+        /// How many WLEDs to update at once
+        #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        rolling: u16,
+
+        /// WLED version the firmware installs, when its file name doesn't say
+        #[arg(long)]
+        version: Option<String>,
+
+        /// How long to wait for each WLED to come back running the firmware
+        #[arg(long, value_name = "SECS", default_value_t = 180)]
+        reboot_timeout_secs: u64,
+
+        /// Install the firmware even on WLEDs with another chip than its
+        /// file name says it's for
+        #[arg(long)]
+        force: bool,
+
+        /// Don't back up each WLED into out_dir/pre-update/ before updating it
+        #[arg(long)]
+        no_backup: bool,
+
+        /// Print which WLEDs would be updated, but change nothing
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Merge a piece of cfg.json, such as NTP or MQTT settings, into the
+    /// settings of the WLEDs given with --device or --config. Some settings
+    /// only take effect once a WLED reboots
+    ApplyTemplate {
+        /// JSON file holding just the settings to change
+        #[arg(long, value_name = "FILE")]
+        template: PathBuf,
+
+        /// Change every WLED discovered, when none are given
+        #[arg(long)]
+        all: bool,
+
+        /// Print what would change on each WLED, but change nothing
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Upload a backup from out_dir to a WLED, and reboot it
+    Restore {
+        /// Host name the backup was saved under
+        #[arg(required_unless_present_any = ["from", "rollback", "all"])]
+        name: Option<String>,
+
+        /// Restore every device with a backup, in out_dir or the backup set
+        /// directory given by --from, to the address it was backed up from
+        #[arg(long, conflicts_with_all = ["name", "ip", "secrets", "rollback"])]
+        all: bool,
+
+        /// With --all, how many devices to restore at once
+        #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+        batch: u16,
+
+        /// With --all, how long to wait between batches, so the Wi-Fi isn't
+        /// swamped by devices rebooting
+        #[arg(long, default_value_t = 10)]
+        pause_secs: u64,
+
+        /// With --all, keep going after a device fails
+        #[arg(long = "continue")]
+        keep_going: bool,
+
+        /// Restore files exported by the WLED web UI's "Backup & Restore" page
+        /// (or a zip of them) instead of a saved backup
+        #[arg(long, value_name = "FILE", conflicts_with = "name")]
+        from: Vec<PathBuf>,
+
+        /// IP address of the WLED to restore to
+        #[arg(long, required_unless_present_any = ["rollback", "all"])]
+        ip: Option<IpAddr>,
+
+        /// HTTP port of the WLED to restore to
+        #[arg(long, default_value_t = 80)]
+        port: u16,
+
+        /// Secrets file to merge into cfg.json, if it isn't beside the backup
+        #[arg(long, value_name = "FILE", conflicts_with = "from")]
+        secrets: Option<PathBuf>,
+
+        /// Print the device that would be restored, and the files that
+        /// would be uploaded to it, but upload nothing
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Don't back up the WLED into out_dir/pre-restore/ before
+        /// restoring to it
+        #[arg(long)]
+        no_snapshot: bool,
+
+        /// How long to wait for the WLED to come back after rebooting, before
+        /// checking it kept the restored files
+        #[arg(long, default_value_t = 120)]
+        reboot_timeout_secs: u64,
+
+        /// Don't wait for the WLED to come back, or check the restored files
+        #[arg(long)]
+        no_wait: bool,
+
+        /// Restore even if the WLED's MAC or name isn't the backed up
+        /// device's
+        #[arg(long, conflicts_with = "from")]
+        force: bool,
+
+        /// Undo the last restore, by restoring the backup taken just before
+        /// it. Goes to the address restored to, unless --ip is given. After
+        /// --all, rolls back every WLED restored to
+        #[arg(long, conflicts_with_all = ["name", "from", "secrets", "no_snapshot"])]
+        rollback: bool,
+    },
+
+    /// Delete old timestamped backup sets
+    Prune(RetentionPolicy),
+
+    /// Compare the files in two backup directories, or each device with its
+    /// newest backup
+    Diff {
+        /// Older backup directory
+        #[arg(required_unless_present = "live")]
+        old: Option<PathBuf>,
+
+        /// Newer backup directory
+        #[arg(required_unless_present = "live")]
+        new: Option<PathBuf>,
+
+        /// Fetch each device's cfg.json and presets.json and compare them with
+        /// its newest backup in out_dir, exiting 1 if any device has changed
+        #[arg(long, conflicts_with_all = ["old", "new"])]
+        live: bool,
+    },
+
+    /// Check that every device's backup is complete and parses, and re-hash
+    /// the files listed in the run's MANIFEST.json
+    Verify {
+        /// Manifest to check [default: the newest in out_dir]
+        manifest: Option<PathBuf>,
+
+        /// Backup directory to check [default: out_dir]
+        #[arg(long, value_name = "DIR")]
+        dir: Option<PathBuf>,
+
+        /// Also report devices last backed up longer ago than this, such as
+        /// 36h or 7d
+        #[arg(long, value_name = "AGE", value_parser = parse_duration)]
+        max_age: Option<chrono::Duration>,
+    },
+
+    /// Print a shell completion script. With --config, the inventory's
+    /// device names and hosts are completed too
+    Completions { shell: Shell },
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+enum AuditCommand {
+    /// Report each WLED's firmware version, and those that differ from the
+    /// rest or are out of date, exiting 1 if any are
+    Versions {
+        /// Also report devices older than this release, such as 0.15.0
+        #[arg(long, value_name = "VERSION")]
+        min_version: Option<Version>,
+
+        /// Also report devices older than the latest WLED release on GitHub
+        #[arg(long)]
+        latest: bool,
+    },
+
+    /// Report risky settings in each WLED's cfg.json, such as an unlocked
+    /// OTA, an open access point or MQTT without a password, exiting 1 if any
+    /// are found
+    Security,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+enum LibraryCommand {
+    /// Add a preset to the library, from a file saved by preset export or
+    /// from the WLED given with --device, replacing any of the same name
+    Add {
+        /// Name to keep it under, such as ocean-wave
+        name: String,
+
+        /// File holding the preset
+        #[arg(long, value_name = "FILE", required_unless_present = "id")]
+        from: Option<PathBuf>,
+
+        /// Preset number on the WLED given with --device
+        #[arg(long, conflicts_with = "from", value_parser = clap::value_parser!(u16).range(1..=250))]
+        id: Option<u16>,
+    },
+
+    /// List the library's presets, their versions, and the WLEDs they were
+    /// put on
+    List,
+
+    /// Put library presets on the WLEDs given with --device or --config
+    Apply {
+        /// Presets to put on each WLED, as NAME=ID
+        #[arg(required = true, value_name = "NAME=ID")]
+        presets: Vec<Placement>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+enum StateCommand {
+    /// Save each WLED's live /json/state to <state-dir>/<name>.json, or as a
+    /// preset on the WLED itself
+    Snapshot {
+        #[command(flatten)]
+        filter: DeviceFilter,
+
+        /// Preset number to save the state as on each WLED, instead of to a
+        /// file
+        #[arg(long, value_name = "ID", value_parser = clap::value_parser!(u16).range(1..=250))]
+        preset: Option<u16>,
+    },
+
+    /// Send each WLED the state state snapshot saved for it, so it shows
+    /// again what it was showing
+    Restore {
+        #[command(flatten)]
+        filter: DeviceFilter,
+
+        /// Print which WLEDs have a saved state to send, but send nothing
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq)]
+enum PresetCommand {
+    /// Save one preset from the WLED given with --device, or a playlist with
+    /// the presets it plays
+    Export {
+        /// Preset number
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..=250))]
+        id: u16,
+
+        /// File to save the preset to, instead of printing it
+        #[arg(long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+
+    /// Save a preset from a file as one preset on the WLED given with
+    /// --device, replacing whatever preset had that number. A playlist's
+    /// presets are saved under their own numbers
+    Import {
+        /// Preset number to save it as
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..=250))]
+        id: u16,
+
+        /// File saved by preset export
+        #[arg(long, value_name = "FILE")]
+        from: PathBuf,
+
+        /// Replace the WLED's presets where a playlist's presets go, even if
+        /// they're different presets
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Copy presets from one WLED, or a saved presets.json, to others, so
+    /// they all have the same, along with the presets copied playlists play.
+    /// Presets the source doesn't have are deleted
+    Sync {
+        /// WLED to copy from, as host[:port], or a presets.json from a backup
+        #[arg(long, value_name = "HOST[:PORT]|FILE")]
+        from: String,
+
+        /// WLEDs to copy to
+        #[arg(long, value_name = "HOST[:PORT]", num_args = 1.., required = true)]
+        to: Vec<DeviceSpec>,
+
+        /// Preset numbers to copy, such as 1-10. Comma separated
+        #[arg(long, value_name = "IDS", value_delimiter = ',', required = true)]
+        ids: Vec<PresetRange>,
+
+        /// Copy a preset to another number on the targets, such as 3=13. May
+        /// be repeated
+        #[arg(long, value_name = "FROM=TO")]
+        map: Vec<SlotMap>,
+
+        /// Print what would change on each WLED, but change nothing
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/// Offer the inventory's hosts for --device, and its device names and
+/// aliases for the options and arguments that take a device name, in `command`
+/// and all of its subcommands.
+fn complete_devices(command: clap::Command, inventory: &Inventory) -> clap::Command {
+    let hosts: Vec<String> = inventory
+        .devices
+        .values()
+        .filter_map(|device| device.host.clone())
+        .collect();
+    let names: Vec<String> = inventory
+        .devices
+        .iter()
+        .flat_map(|(name, device)| std::iter::once(name).chain(device.aliases.iter()))
+        .cloned()
+        .collect();
+    command
+        .mut_args(|arg| match arg.get_id().as_str() {
+            "devices" if !hosts.is_empty() => {
+                arg.value_parser(PossibleValuesParser::new(hosts.clone()))
+            }
+            "include" | "exclude" | "name" if !names.is_empty() => {
+                arg.value_parser(PossibleValuesParser::new(names.clone()))
+            }
+            _ => arg,
+        })
+        .mut_subcommands(|command| complete_devices(command, inventory))
+}
+
+/// The completion script for `shell`, for the program named `bin`.
+fn completion_script(shell: Shell, bin: &str, inventory: Option<&Inventory>) -> Vec<u8> {
+    let mut command = Args::command();
+    if let Some(inventory) = inventory {
+        command = complete_devices(command, inventory);
+    }
+    let mut script = vec![];
+    clap_complete::generate(shell, &mut command, bin, &mut script);
+    script
+}
+
+fn run_completions(args: &Args, shell: Shell) {
+    let inventory = args
+        .config
+        .as_ref()
+        .map(|path| match Inventory::load(path) {
+            Ok(inventory) => inventory,
+            Err(result) => {
+                error!("FAILED: {result}");
+                std::process::exit(1);
+            }
+        });
+    // Complete the program as it was run, such as wled-backup.
+    let bin = std::env::args_os()
+        .next()
+        .map(PathBuf::from)
+        .and_then(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "wled-backup".to_string());
+    let script = completion_script(shell, &bin, inventory.as_ref());
+    std::io::stdout()
+        .write_all(&script)
+        .expect("Failed to write the completion script");
+}
+
+/// How much to log, from the -v and -q counts.
+fn log_level(verbose: u8, quiet: u8) -> LevelFilter {
+    match i16::from(verbose) - i16::from(quiet) {
+        ..=-2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+/// Log to stderr with the time, level and device of each message, and to
+/// --log-file if given. Libraries' messages are only logged when they're
+/// warnings, unless everything is asked for.
+fn init_logging(args: &Args) -> std::io::Result<()> {
+    let level = log_level(args.verbose, args.quiet);
+    let libraries = match level {
+        LevelFilter::TRACE => LevelFilter::TRACE,
+        _ => level.min(LevelFilter::WARN),
+    };
+    let filter = Targets::new()
+        .with_target("wled_backup", level)
+        .with_default(libraries);
+
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = vec![];
+    let console = fmt::layer()
+        .with_writer(progress::log_writer)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_timer(ChronoLocal::new("%H:%M:%S%.3f".to_string()))
+        .with_target(false);
+    layers.push(match args.log_json && args.log_file.is_none() {
+        true => console.json().with_filter(filter.clone()).boxed(),
+        false => console.with_filter(filter.clone()).boxed(),
+    });
+
+    if let Some(path) = &args.log_file {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let file = fmt::layer()
+            .with_writer(std::sync::Mutex::new(file))
+            .with_ansi(false)
+            .with_target(false);
+        layers.push(match args.log_json {
+            true => file.json().with_filter(filter).boxed(),
+            false => file.with_filter(filter).boxed(),
+        });
+    }
+
+    tracing_subscriber::registry().with(layers).init();
+    Ok(())
+}
+
+/// The backup layout --layout and --official-format ask for, in out_dir.
+fn make_layout(args: &Args) -> Layout {
+    let mut layout = Layout::new(args.layout, &args.out_dir);
+    layout.official_names = args.official_format;
+    layout
+}
+
+/// Keys from --identity and --passphrase-file, if any were given.
+fn make_decryption(args: &Args) -> Option<Decryption> {
+    if args.identity.is_empty() && args.passphrase_file.is_none() {
+        return None;
+    }
+
+    let mut decryption = Decryption::default();
+    let passphrase = args.passphrase_file.as_deref().map(read_passphrase);
+    let loaded = args
+        .identity
+        .iter()
+        .try_for_each(|path| decryption.add_identity_file(path))
+        .and_then(|_| passphrase.transpose());
+    if let Ok(Some(passphrase)) = loaded {
+        decryption.add_passphrase(passphrase);
+    } else if let Err(result) = loaded {
+        error!("FAILED: {result}");
+        std::process::exit(1);
+    }
+    Some(decryption)
+}
+
+/// How --encrypt-recipient or --encrypt-passphrase-file asked for backups to
+/// be encrypted.
+fn make_encryption(backup_args: &BackupArgs) -> Result<Option<Encryption>, BoxError> {
+    if let Some(path) = &backup_args.encrypt_passphrase_file {
+        return Ok(Some(Encryption::Passphrase(read_passphrase(path)?)));
+    }
+    Ok((!backup_args.encrypt_recipient.is_empty())
+        .then(|| Encryption::Recipients(backup_args.encrypt_recipient.clone())))
+}
+
+/// Collect the devices to work on, from --config, --device, --scan, --nodes
+/// and/or discovery. Also returns how many listed devices couldn't be
+/// resolved or found.
+async fn find_devices(args: &Args, fetcher: &Fetcher) -> (Vec<Device>, usize) {
+    match find_devices_each(args, fetcher, &Expected::default(), false, |_| {}).await {
+        Ok(found) => found,
+        Err(result) => {
+            error!("FAILED: {result}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// What a run expects to find, so the search can end once it has.
+#[derive(Debug, Default)]
+struct Expected<'a> {
+    count: Option<usize>,
+    fleet: Option<&'a Fleet>,
+}
+
+/// Like [`find_devices`], but passes each device to `found`, ready to use,
+/// as soon as it's found, so work on it can start while the search goes on.
+/// A `dry_run` doesn't update the device cache. Fails if the inventory or
+/// the discovery options are unusable.
+async fn find_devices_each(
+    args: &Args,
+    fetcher: &Fetcher,
+    expected: &Expected<'_>,
+    dry_run: bool,
+    mut found: impl FnMut(Device),
+) -> Result<(Vec<Device>, usize), String> {
+    let mut devices = vec![];
+    let mut unresolved = 0;
+    let mut needs_discovery = args.discover;
+
+    if let Some(config) = &args.config {
+        let inventory = Inventory::load(config).map_err(|e| e.to_string())?;
+
+        let (inventory_devices, errors) = inventory.to_devices(resolve_retry(args)).await;
+        for error in errors.iter() {
+            error!("FAILED to resolve {error}");
+            unresolved += 1;
+        }
+        merge_devices(&mut devices, inventory_devices);
+        needs_discovery |= inventory.needs_discovery();
+    }
+
+    let given = !args.devices.is_empty()
+        || args.config.is_some()
+        || !args.scan.is_empty()
+        || !args.nodes.is_empty();
+    let search = !given || needs_discovery;
+    let mut sources = discovery_sources(args, search)?;
+
+    let cache_path = args.out_dir.join(DEVICE_CACHE_FILE);
+    let cache = (args.cache_devices && search).then(|| {
+        DeviceCache::load(&cache_path).unwrap_or_else(|result| {
+            warn!("Ignoring the device cache: {result}");
+            DeviceCache::default()
+        })
+    });
+    let preference = if args.prefer_ipv4 {
+        AddressPreference::Ipv4
+    } else if args.prefer_ipv6 {
+        AddressPreference::Ipv6
+    } else {
+        AddressPreference::Any
+    };
+    let defaults = device_options(args);
+    let prepare = |device: &mut Device| {
+        device.order_addresses(preference);
+        let options = &mut device.options;
+        options.pin = options.pin.take().or_else(|| defaults.pin.clone());
+        options.auth = options.auth.take().or_else(|| defaults.auth.clone());
+    };
+
+    // Cached devices are checked with the options they'd be backed up with:
+    // the inventory's for the device, if it's listed, and the defaults.
+    if let Some(cache) = cache.as_ref().filter(|cache| !cache.devices.is_empty()) {
+        let devices = cache
+            .devices
+            .iter()
+            .map(|cached| {
+                let mut device = match devices.iter().find(|device| cached.is(device)) {
+                    Some(listed) => listed.clone(),
+                    None => Device::new(&cached.name, vec![], cached.port),
+                };
+                prepare(&mut device);
+                (cached.clone(), device.options)
+            })
+            .collect();
+        sources.insert(
+            0,
+            Box::new(Cached {
+                devices,
+                timeout: Duration::from_millis(args.scan_timeout_ms),
+            }),
+        );
+    }
+
+    // Searching only for listed devices, for what the run expects, or for
+    // cached devices that moved, ends as soon as they've all been found.
+    let listed_only = given && !args.discover;
+    let cached = cache.as_ref().filter(|cache| !cache.devices.is_empty());
+    let done = |devices: &[Device]| {
+        let resolved = devices.iter().filter(|d| !d.addresses.is_empty()).count();
+        (listed_only || expected.count.is_some() || expected.fleet.is_some() || cached.is_some())
+            && resolved == devices.len()
+            && expected.count.is_none_or(|count| resolved >= count)
+            && expected.fleet.is_none_or(|fleet| fleet.found_all(devices))
+            && cached.is_none_or(|cache| cache.found_all(devices))
+    };
+    let errors = discover_each(fetcher, &sources, &mut devices, done, |device| {
+        let mut device = device.clone();
+        prepare(&mut device);
+        found(device);
+    })
+    .await;
+    unresolved += errors.len();
+    devices.iter_mut().for_each(prepare);
+
+    // A dry run writes nothing, the cache included.
+    if let Some(mut cache) = cache.filter(|_| !dry_run) {
+        cache.update(&devices, chrono::Utc::now());
+        if let Err(result) = cache.save(&cache_path) {
+            warn!("Failed to save the device cache: {result}");
+        }
+    }
+
+    // Inventory devices without a host, which discovery didn't find.
+    devices.retain(|device| {
+        if device.addresses.is_empty() {
+            error!("FAILED to find {}", device.name);
+            unresolved += 1;
+        }
+        !device.addresses.is_empty()
+    });
+
+    Ok((devices, unresolved))
+}
+
+/// How long to wait before resolving a host name again, if at all.
+fn resolve_retry(args: &Args) -> Option<Duration> {
+    args.retry_resolve_secs.map(Duration::from_secs)
+}
+
+/// The settings PIN and credentials given on the command line, for devices
+/// the inventory doesn't give them for.
+fn device_options(args: &Args) -> DeviceOptions {
+    DeviceOptions {
+        pin: args.pin.clone(),
+        auth: args.http_user.clone().map(|user| BasicAuth {
+            user,
+            password: args.http_password.clone(),
+        }),
+        ..Default::default()
+    }
+}
+
+/// The discovery sources to use: --device, --scan and --nodes if given, and
+/// the --discovery sources too if `search` is set. Devices found by more than
+/// one are merged.
+fn discovery_sources(args: &Args, search: bool) -> Result<Vec<Box<dyn Discovery>>, String> {
+    let mut sources: Vec<Box<dyn Discovery>> = vec![];
+    for spec in args.devices.iter() {
+        sources.push(Box::new(Static {
+            spec: spec.clone(),
+            retry: resolve_retry(args),
+        }));
+    }
+    if !args.scan.is_empty() {
+        sources.push(Box::new(Scan {
+            subnets: args.scan.clone(),
+            port: 80,
+            timeout: Duration::from_millis(args.scan_timeout_ms),
+        }));
+    }
+    for seed in args.nodes.iter() {
+        sources.push(Box::new(Nodes { seed: seed.clone() }));
+    }
+
+    if !search {
+        return Ok(sources);
+    }
+    let duration = Duration::from_secs(args.search_secs);
+    for kind in args.discovery.iter() {
+        match kind {
+            DiscoveryKind::Mdns => sources.push(Box::new(Mdns {
+                duration,
+                options: MdnsOptions {
+                    interfaces: args.interface.clone(),
+                    bind_ips: args.bind_ip.clone(),
+                },
+            })),
+            DiscoveryKind::Udp => sources.push(Box::new(Udp { duration })),
+            DiscoveryKind::Scan if args.scan.is_empty() => {
+                return Err("--discovery scan needs the networks to scan, with --scan".to_string());
+            }
+            DiscoveryKind::Nodes if args.nodes.is_empty() => {
+                return Err("--discovery nodes needs a WLED to ask, with --nodes".to_string());
+            }
+            // Already added.
+            DiscoveryKind::Scan | DiscoveryKind::Nodes => {}
+        }
+    }
+    Ok(sources)
+}
+
+/// Run a future, failing the run if it doesn't finish within --deadline-secs
+/// of starting.
+async fn with_deadline<F: Future>(args: &Args, started: Instant, future: F) -> F::Output {
+    match within_deadline(args, started, future).await {
+        Ok(output) => output,
+        Err(result) => {
+            error!("FAILED: {result}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run a future, or give up on it once --deadline-secs have passed since
+/// starting.
+async fn within_deadline<F: Future>(
+    args: &Args,
+    started: Instant,
+    future: F,
+) -> Result<F::Output, BackupError> {
+    let Some(deadline_secs) = args.deadline_secs else {
+        return Ok(future.await);
+    };
+
+    let deadline = started + Duration::from_secs(deadline_secs);
+    tokio::time::timeout_at(deadline.into(), future)
+        .await
+        .map_err(|_| BackupError::Deadline(deadline_secs))
+}
+
+/// Where S3 is and how to sign in to it, from the options or else the
+/// standard AWS environment variables.
+fn s3_options(backup_args: &BackupArgs) -> Result<S3Options, String> {
+    let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+    let access_key_id = backup_args
+        .s3_access_key_id
+        .clone()
+        .or_else(|| env("AWS_ACCESS_KEY_ID"));
+    let secret_access_key = backup_args
+        .s3_secret_access_key
+        .clone()
+        .or_else(|| env("AWS_SECRET_ACCESS_KEY"));
+    let (Some(access_key_id), Some(secret_access_key)) = (access_key_id, secret_access_key) else {
+        return Err(
+            "No S3 credentials: set --s3-access-key-id and --s3-secret-access-key, \
+                    or AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY"
+                .to_string(),
+        );
+    };
+    Ok(S3Options {
+        endpoint: backup_args.s3_endpoint.clone(),
+        region: backup_args
+            .s3_region
+            .clone()
+            .or_else(|| env("AWS_REGION"))
+            .or_else(|| env("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_string()),
+        access_key_id,
+        secret_access_key,
+        session_token: env("AWS_SESSION_TOKEN"),
+    })
+}
+
+/// Back up the devices, returning the exit code.
+async fn run_backup(
+    args: &Args,
+    started: Instant,
+    fetcher: &Fetcher,
+    backup_args: &BackupArgs,
+) -> i32 {
+    let dry_run = backup_args.dry_run;
+    if !args.out_dir.exists()
+        && !dry_run
+        && let Err(result) = std::fs::create_dir_all(&args.out_dir)
+    {
+        error!("FAILED to create {:?}: {result}", args.out_dir);
+        return 1;
+    }
+
+    // Overlapping runs would write the same files. A dry run writes nothing.
+    let wait = Duration::from_secs(backup_args.lock_wait_secs);
+    let _lock = match dry_run {
+        true => None,
+        false => match RunLock::wait(&args.out_dir, wait).await {
+            Ok(lock) => Some(lock),
+            Err(result @ LockError::Held { .. }) => {
+                error!("FAILED: {result}");
+                return 4;
+            }
+            Err(result) => {
+                error!("FAILED: {result}");
+                return 1;
+            }
+        },
+    };
+
+    // Find out about missing credentials before the run, not after it.
+    let uses_s3 = backup_args
+        .dest
+        .iter()
+        .any(|dest| matches!(dest, Destination::S3 { .. }));
+    let s3 = match uses_s3 && !dry_run {
+        true => match s3_options(backup_args) {
+            Ok(s3) => s3,
+            Err(result) => {
+                error!("FAILED: {result}");
+                return 1;
+            }
+        },
+        false => S3Options::default(),
+    };
+    let storage_options = StorageOptions {
+        s3,
+        sftp: SftpOptions {
+            identity: backup_args.sftp_identity.clone(),
+            retry: RetryPolicy {
+                retries: args.retries,
+                delay: Duration::from_millis(args.retry_delay_ms),
+            },
+        },
+        webdav: WebDavOptions {
+            password: backup_args.webdav_password.clone(),
+        },
+    };
+
+    let fleet = match backup_args.fleet.as_deref().map(Fleet::load).transpose() {
+        Ok(fleet) => fleet,
+        Err(result) => {
+            error!("FAILED: {result}");
+            return 1;
+        }
+    };
+
+    if let Some(url) = &backup_args.healthcheck_url
+        && !dry_run
+    {
+        ping(fetcher, url, Ping::Start, "").await;
+    }
+
+    info!("Saving backups to {:?}", args.out_dir);
+
+    let mut layout = make_layout(args);
+    if backup_args.timestamped {
+        layout.timestamp = Some(layout::timestamp_name(chrono::Utc::now()));
+    }
+
+    let stream = match (backup_args.stream, backup_args.dest.as_slice()) {
+        (false, _) => None,
+        (true, [dest]) => {
+            let now = chrono::Utc::now();
+            match StreamTarget::new(dest.clone(), storage_options.clone(), &layout, now) {
+                Ok(target) => Some(target),
+                Err(result) => {
+                    error!("FAILED: {result}");
+                    return 1;
+                }
+            }
+        }
+        (true, _) => {
+            error!("FAILED: --stream sends to a single --dest");
+            return 1;
+        }
+    };
+
+    let mut settings = BackupSettings::new(layout.clone());
+    settings.force = backup_args.force;
+    settings.endpoints = backup_args.endpoints.clone();
+    settings.only = backup_args.only.clone();
+    settings.full_fs = backup_args.full_fs;
+    settings.archive = backup_args.archive;
+    settings.archive_scope = backup_args.archive_scope;
+    settings.filter = backup_args.filter.clone();
+    settings.on_collision = backup_args.on_collision;
+    settings.pretty = backup_args.pretty;
+    settings.verify = backup_args.verify;
+    settings.dry_run = dry_run;
+    settings.fail_fast = backup_args.fail_fast;
+    settings.compression = backup_args.compress;
+    settings.encryption = match make_encryption(backup_args) {
+        Ok(encryption) => encryption,
+        Err(result) => {
+            error!("FAILED: {result}");
+            return 1;
+        }
+    };
+    settings.stream = stream;
+    settings.secrets = if backup_args.separate_secrets {
+        SecretHandling::Separate
+    } else if backup_args.redact_secrets {
+        SecretHandling::Redact
+    } else {
+        SecretHandling::Keep
+    };
+
+    // Back up each device as soon as it's found, while the search goes on.
+    let (sender, receiver) = futures::channel::mpsc::unbounded();
+    let filter = &backup_args.filter;
+    let expected = Expected {
+        count: backup_args.expect,
+        fleet: fleet.as_ref(),
+    };
+    let finding = find_devices_each(args, fetcher, &expected, dry_run, move |device| {
+        let mut wleds = vec![device];
+        for name in filter.filter_networks(&mut wleds) {
+            warn!("Skipping {name}: no address in an included network");
+        }
+        for wled in wleds {
+            let _ = sender.unbounded_send(wled);
+        }
+    });
+    let backup = backup_stream(fetcher, receiver, &settings, backup_args.jobs.into());
+    // A run that runs out of time still reports, notifies and pings, with
+    // the deadline as its failure.
+    let (finding, mut run) =
+        match within_deadline(args, started, async { futures::join!(finding, backup) }).await {
+            Ok(done) => done,
+            Err(result) => {
+                error!("FAILED: {result}");
+                let run = BackupRun {
+                    failures: vec![("run".to_string(), result)],
+                    ..Default::default()
+                };
+                (Ok((vec![], 0)), run)
+            }
+        };
+    // Likewise a search that couldn't start.
+    let unsearched = finding.is_err();
+    let (mut wleds, unresolved) = finding.unwrap_or_else(|result| {
+        error!("FAILED: {result}");
+        let failure = ("run".to_string(), BackupError::Invalid(result));
+        run.failures.push(failure);
+        (vec![], 0)
+    });
+    // Those left out were warned about as they were found.
+    filter.filter_networks(&mut wleds);
+
+    let too_few = match backup_args.expect {
+        Some(expect) if wleds.len() < expect => {
+            error!("FAILED: found {} WLEDs, expected {expect}", wleds.len());
+            true
+        }
+        _ => false,
+    };
+
+    let devices = wleds.iter().map(DeviceSummary::from).collect();
+    let found = wleds;
+    let backups = run.reports;
+    let failures: Vec<Failure> = run
+        .failures
+        .iter()
+        .map(|(name, error)| Failure::new(name, error))
+        .collect();
+
+    // A streamed run saved nothing in out_dir.
+    if !dry_run && !backup_args.stream {
+        if let Err(result) = layout.run_done() {
+            error!("FAILED to update {}: {result}", layout::LATEST);
+        }
+        if let Some(timestamp) = &layout.timestamp {
+            info!("Saved backup set {timestamp}");
+        }
+    }
+
+    // Copy whatever was saved, even if some devices failed, to each
+    // destination in turn, whether or not the others fail. Streamed files
+    // are there already.
+    let mut uploads = vec![];
+    let copies = !dry_run && !backup_args.stream;
+    for dest in backup_args.dest.iter().filter(|_| copies) {
+        let uploaded = storage::upload_run(&fetcher.client, dest, &storage_options, &layout).await;
+        let mut upload = UploadReport {
+            destination: dest.to_string(),
+            ..Default::default()
+        };
+        match uploaded {
+            Ok(count) => {
+                info!("Uploaded {count} files to {dest}");
+                upload.success = true;
+                upload.files = count;
+            }
+            Err(result) => {
+                error!("FAILED to upload to {dest}: {result}");
+                upload.error = Some(result.to_string());
+            }
+        }
+        uploads.push(upload);
+    }
+    let upload_failed = uploads.iter().any(|upload| !upload.success);
+
+    let summary = Summary::new(&backups, unresolved);
+    let drift = fleet.map(|fleet| fleet.drift(&found, &backups));
+    let commit_message = git::commit_message(&backups, &summary);
+    let mut notice = RunNotice::new(&backups, summary, 0, started.elapsed());
+    let mqtt_messages = mqtt_messages(args, backup_args, &backups, &found);
+    let run_metrics = RunMetrics::new(
+        found.len(),
+        &backups,
+        summary,
+        started.elapsed(),
+        chrono::Utc::now(),
+    );
+    match args.output {
+        OutputFormat::Text => {
+            let mut rows = vec![["DEVICE", "STATUS"].map(String::from).to_vec()];
+            for backup in backups.iter() {
+                rows.push(vec![backup.name.clone(), backup.status().to_string()]);
+            }
+            if backups.len() > 1 {
+                say!("");
+                print!("{}", format_table(&rows));
+            }
+            if dry_run {
+                for backup in backups.iter() {
+                    for file in backup.files.iter() {
+                        say!("{}: would save {file}", backup.name);
+                    }
+                }
+            }
+            say!("{summary}");
+            for failure in failures.iter() {
+                say!("FAILED: {}: {}", failure.device, failure.error);
+            }
+            if let Some(drift) = &drift {
+                for name in drift.missing.iter() {
+                    say!("MISSING from this run: {name}");
+                }
+                for name in drift.unknown.iter() {
+                    say!("NEW device, not in the fleet: {name}");
+                }
+            }
+            for upload in uploads.iter() {
+                match &upload.error {
+                    None => say!("{}: copied {} files", upload.destination, upload.files),
+                    Some(error) => say!("{}: FAILED: {error}", upload.destination),
+                }
+            }
+        }
+        OutputFormat::Json => print_json(&RunReport {
+            devices,
+            backups,
+            summary,
+            fleet: drift,
+            uploads,
+            failures,
+        }),
+    }
+
+    let mut exit_code = summary.exit_code();
+    let timed_out = run
+        .failures
+        .iter()
+        .any(|(_, error)| matches!(error, BackupError::Deadline(_)));
+    if timed_out || unsearched || (exit_code == 0 && (!run.failures.is_empty() || too_few)) {
+        exit_code = 1;
+    }
+    if dry_run {
+        return exit_code;
+    }
+    if exit_code == 0
+        && backup_args.retention.is_set()
+        && let Err(result) = prune_backups(args, &backup_args.retention)
+    {
+        error!("FAILED to prune: {result}");
+        exit_code = 1;
+    }
+
+    if upload_failed {
+        exit_code = exit_code.max(1);
+    }
+
+    // Commit whatever was saved, even if some devices failed.
+    if backup_args.git_commit {
+        match git::commit_backups(&args.out_dir, &commit_message, backup_args.git_push) {
+            Ok(true) => info!("Committed the changes to git"),
+            Ok(false) => info!("No changes to commit to git"),
+            Err(result) => {
+                error!("FAILED to commit to git: {result}");
+                exit_code = exit_code.max(1);
+            }
+        }
+    }
+
+    if let Some(url) = &backup_args.webhook_url {
+        notice.exit_code = exit_code;
+        if let Err(result) = send_webhook(&fetcher.client, url, &notice).await {
+            error!("FAILED to notify {url}: {result}");
+        }
+    }
+    if let Some(url) = &backup_args.healthcheck_url {
+        let outcome = if exit_code == 0 {
+            Ping::Success
+        } else {
+            Ping::Fail
+        };
+        ping(fetcher, url, outcome, &commit_message).await;
+    }
+    let notify = notify_config(args, backup_args);
+    if notify.should_notify(exit_code) {
+        let title = match exit_code {
+            0 => "WLED backup finished",
+            _ => "WLED backup failed",
+        };
+        for backend in notify.backends() {
+            let sent = backend.send(&fetcher.client, title, &commit_message, exit_code != 0);
+            if let Err(result) = sent.await {
+                error!("FAILED to notify {}: {result}", backend.name());
+            }
+        }
+    }
+    if let Some(url) = &backup_args.mqtt_url
+        && let Err(result) = mqtt::publish(url, &mqtt_messages).await
+    {
+        error!("FAILED to publish to MQTT: {result}");
+    }
+    if let Some(path) = &backup_args.metrics_file
+        && let Err(result) = metrics::write_textfile(path, &run_metrics)
+    {
+        error!("FAILED to write {}: {result}", path.display());
+    }
+    if let Some(url) = &backup_args.pushgateway_url
+        && let Err(result) = metrics::push(&fetcher.client, url, &run_metrics).await
+    {
+        error!("FAILED to push metrics to {url}: {result}");
+    }
+    if exit_code == 0 {
+        info!("Finished");
+    }
+    exit_code
+}
+
+/// The MQTT messages for each device backed up, if --mqtt-url was given.
+fn mqtt_messages(
+    args: &Args,
+    backup_args: &BackupArgs,
+    backups: &[DeviceReport],
+    found: &[Device],
+) -> Vec<mqtt::Message> {
+    let Some(url) = &backup_args.mqtt_url else {
+        return vec![];
+    };
+    let settings = MqttSettings {
+        url: url.clone(),
+        prefix: backup_args.mqtt_prefix.clone(),
+        discovery_prefix: backup_args.mqtt_discovery_prefix.clone(),
+    };
+    let now = chrono::Utc::now();
+    backups
+        .iter()
+        .flat_map(|backup| {
+            let mac = found
+                .iter()
+                .find(|device| device.name == backup.name)
+                .and_then(|device| device.mac.as_deref());
+            mqtt::device_messages(&settings, backup, mac, &args.out_dir, now)
+        })
+        .collect()
+}
+
+/// Where to send notifications: the inventory's [notify] section, with the
+/// command line's options taking precedence.
+fn notify_config(args: &Args, backup_args: &BackupArgs) -> NotifyConfig {
+    // find_devices has already reported an inventory that doesn't load.
+    let config = args
+        .config
+        .as_ref()
+        .and_then(|path| Inventory::load(path).ok())
+        .map(|inventory| inventory.notify)
+        .unwrap_or_default();
+    config.merge(NotifyConfig {
+        ntfy: backup_args.ntfy.clone(),
+        slack: backup_args.slack_webhook.clone(),
+        discord: backup_args.discord_webhook.clone(),
+        when: backup_args.notify_when,
+    })
+}
+
+/// Ping a healthchecks.io check. A check that can't be pinged doesn't fail
+/// the backup; it will alert on its own.
+async fn ping(fetcher: &Fetcher, url: &str, ping: Ping, body: &str) {
+    if let Err(result) = send_ping(&fetcher.client, url, ping, body).await {
+        error!("FAILED to ping {url}: {result}");
+    }
+}
+
+/// Print or install systemd units that run this command line, with backup in
+/// place of install-systemd.
+fn run_install_systemd(interval: chrono::Duration, install: bool) {
+    let mut args = vec![];
+    for arg in std::env::args_os() {
+        match arg.into_string() {
+            Ok(arg) => args.push(arg),
+            Err(arg) => {
+                error!(
+                    "FAILED: {} isn't valid UTF-8, so can't go in a unit file",
+                    arg.to_string_lossy()
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+    if let Ok(exe) = std::env::current_exe() {
+        args[0] = exe.to_string_lossy().into_owned();
+    }
+    let own_options = [("--interval", true), ("--install", false)];
+    let command = systemd::backup_command(&args, "install-systemd", &own_options);
+    // Secrets go in a file only the user can read, not in the unit.
+    let (command, secrets) = systemd::split_secrets(&command);
+    let working_dir = std::env::current_dir().expect("Failed to find the current directory");
+
+    let dir = systemd::user_unit_dir();
+    let env_file = dir.as_ref().map(|dir| dir.join(systemd::ENVIRONMENT_FILE));
+    if !secrets.is_empty() && env_file.is_none() {
+        error!("FAILED: HOME isn't set");
+        std::process::exit(1);
+    }
+    let env_file = env_file.filter(|_| !secrets.is_empty());
+
+    let units = [
+        (
+            "service",
+            systemd::service_unit(&command, &working_dir, env_file.as_deref()),
+        ),
+        ("timer", systemd::timer_unit(interval)),
+    ];
+    if !install {
+        for (kind, unit) in units.iter() {
+            println!("# {}.{kind}\n{unit}", systemd::UNIT_NAME);
+        }
+        if !secrets.is_empty() {
+            let env = systemd::environment_file(&secrets);
+            println!(
+                "# {} (readable only by you)\n{env}",
+                systemd::ENVIRONMENT_FILE
+            );
+        }
+        return;
+    }
+
+    let Some(dir) = dir else {
+        error!("FAILED: HOME isn't set");
+        std::process::exit(1);
+    };
+    for (kind, unit) in units.iter() {
+        let path = dir.join(format!("{}.{kind}", systemd::UNIT_NAME));
+        if let Err(result) = std::fs::create_dir_all(&dir).and_then(|_| std::fs::write(&path, unit))
+        {
+            error!("FAILED to write {}: {result}", path.display());
+            std::process::exit(1);
+        }
+        info!("Saved {}", path.display());
+    }
+    if let Some(path) = &env_file {
+        let env = systemd::environment_file(&secrets);
+        if let Err(result) = systemd::write_private(path, &env) {
+            error!("FAILED to write {}: {result}", path.display());
+            std::process::exit(1);
+        }
+        info!("Saved {}", path.display());
+    }
+    say!(
+        "Start it with: systemctl --user daemon-reload && systemctl --user enable --now {}.timer",
+        systemd::UNIT_NAME
+    );
+}
+
+/// Resolves on SIGTERM, or SIGINT (Ctrl+C).
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to handle SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Back up on a schedule until stopped. A backup in progress when the signal
+/// to stop comes is finished first.
+async fn run_daemon(args: &Args, fetcher: &Fetcher, daemon_args: &DaemonArgs) {
+    let schedule = match (daemon_args.interval, &daemon_args.cron) {
+        (Some(interval), _) => Schedule::Every(interval),
+        (None, Some(cron)) => Schedule::Cron(cron.clone()),
+        (None, None) => unreachable!("clap requires --interval or --cron"),
+    };
+    let format = "%Y-%m-%d %H:%M:%S %Z";
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut previous = None;
+    loop {
+        let Some(next) = schedule.next_run(previous, chrono::Local::now()) else {
+            error!("FAILED: the schedule never runs");
+            std::process::exit(1);
+        };
+        info!("Next backup at {}", next.format(format));
+        let wait = (next - chrono::Local::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = &mut shutdown => {
+                info!("Stopping");
+                return;
+            }
+        }
+
+        let now = chrono::Local::now();
+        previous = Some(now);
+        info!("Starting backup at {}", now.format(format));
+        let backup = run_backup(args, Instant::now(), fetcher, &daemon_args.backup);
+        tokio::pin!(backup);
+        let stopping = tokio::select! {
+            exit_code = &mut backup => {
+                info!("Backup finished with exit code {exit_code}");
+                false
+            }
+            _ = &mut shutdown => {
+                warn!("Stopping once this backup finishes");
+                true
+            }
+        };
+        if stopping {
+            let exit_code = backup.await;
+            info!("Backup finished with exit code {exit_code}");
+            return;
+        }
+    }
+}
+
+fn run_prune(args: &Args, policy: &RetentionPolicy) {
+    if !policy.is_set() {
+        error!("FAILED: give --keep-last and/or --keep-days");
+        std::process::exit(1);
+    }
+
+    if let Err(result) = prune_backups(args, policy) {
+        error!("FAILED to prune: {result}");
+        std::process::exit(1);
+    }
+}
+
+/// Remove the backups `policy` doesn't keep.
+fn prune_backups(args: &Args, policy: &RetentionPolicy) -> std::io::Result<()> {
+    let layout = make_layout(args);
+    let removed = retention::prune(&layout, policy, chrono::Utc::now())?;
+    for path in removed.iter() {
+        info!("Pruned {}", layout.display_path(path));
+    }
+    Ok(())
+}
+
+/// Lay out rows as columns, padded to the widest cell in each.
+fn format_table(rows: &[Vec<String>]) -> String {
+    let mut widths = vec![];
+    for row in rows.iter() {
+        widths.resize(widths.len().max(row.len()), 0);
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in rows.iter() {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Rows of comma separated values, quoted where needed.
+fn format_csv(rows: &[Vec<String>]) -> String {
+    let mut csv = String::new();
+    for row in rows.iter() {
+        let cells: Vec<String> = row
+            .iter()
+            .map(|cell| match cell.contains([',', '"', '\n', '\r']) {
+                true => format!("\"{}\"", cell.replace('"', "\"\"")),
+                false => cell.clone(),
+            })
+            .collect();
+        csv.push_str(&cells.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+async fn run_inventory(args: &Args, fetcher: &Fetcher, format: Option<ExportFormat>) {
+    let (wleds, _unresolved) = find_devices(args, fetcher).await;
+
+    let mut devices = vec![];
+    for wled in wleds.iter() {
+        let (address, info) = try_addresses(&wled.addresses, async |ip| {
+            fetch_info(fetcher, ip, wled.port, &wled.options).await
+        })
+        .await;
+        let info = info
+            .inspect_err(|result| warn!("{}: {result}", wled.name))
+            .ok();
+        devices.push(DeviceDetails::new(wled, address.as_ref(), info.as_ref()));
+    }
+
+    let format = format.unwrap_or(match args.output {
+        OutputFormat::Text => ExportFormat::Csv,
+        OutputFormat::Json => ExportFormat::Json,
+    });
+    match format {
+        ExportFormat::Csv => {
+            let mut rows = vec![DeviceDetails::HEADER.map(String::from).to_vec()];
+            rows.extend(devices.iter().map(DeviceDetails::cells));
+            print!("{}", format_csv(&rows));
+        }
+        ExportFormat::Json => print_json(&devices),
+    }
+}
+
+async fn run_list(args: &Args, fetcher: &Fetcher) {
+    let (wleds, _unresolved) = find_devices(args, fetcher).await;
+
+    let mut summaries = vec![];
+    let mut rows = vec![
+        ["NAME", "ADDRESSES", "PORT", "MAC", "VERSION"]
+            .map(String::from)
+            .to_vec(),
+    ];
+    for wled in wleds.iter() {
+        let addresses: Vec<String> = wled.addresses.iter().map(|ip| ip.to_string()).collect();
+        let (_, version) = try_addresses(&wled.addresses, async |ip| {
+            fetch_version(fetcher, ip, wled.port, &wled.options).await
+        })
+        .await;
+        let version = version.unwrap_or_else(|result| {
+            debug!("{}: {result}", wled.name);
+            "?".to_string()
+        });
+
+        rows.push(vec![
+            wled.name.clone(),
+            addresses.join(","),
+            wled.port.to_string(),
+            wled.mac.clone().unwrap_or_else(|| "-".to_string()),
+            version.clone(),
+        ]);
+        summaries.push(DeviceSummary {
+            version: Some(version),
+            ..DeviceSummary::from(wled)
+        });
+    }
+
+    match args.output {
+        OutputFormat::Text => print!("{}", format_table(&rows)),
+        OutputFormat::Json => print_json(&summaries),
+    }
+}
+
+async fn run_audit_versions(
+    args: &Args,
+    fetcher: &Fetcher,
+    min_version: Option<&Version>,
+    latest: bool,
+) {
+    let (wleds, unresolved) = find_devices(args, fetcher).await;
+
+    let mut failed = unresolved > 0;
+    let latest = match latest {
+        true => latest_release(fetcher, LATEST_RELEASE_URL)
+            .await
+            .inspect_err(|result| {
+                error!("FAILED: Couldn't find the latest WLED release: {result}");
+                failed = true;
+            })
+            .ok(),
+        false => None,
+    };
+
+    let mut devices = vec![];
+    for wled in wleds.iter() {
+        let (_, version) = try_addresses(&wled.addresses, async |ip| {
+            fetch_version(fetcher, ip, wled.port, &wled.options).await
+        })
+        .await;
+        let error = version.as_ref().err().map(|result| {
+            error!("FAILED: {}: {result}", wled.name);
+            failed = true;
+            result.to_string()
+        });
+        devices.push(DeviceVersion {
+            device: wled.name.clone(),
+            version: version.ok(),
+            error,
+            ..Default::default()
+        });
+    }
+    let audit = audit_versions(devices, min_version, latest.as_ref());
+
+    match args.output {
+        OutputFormat::Text => {
+            if let Some(latest) = &audit.latest {
+                say!("Latest WLED release: {latest}");
+            }
+            let mut rows = vec![["NAME", "VERSION", "STATUS"].map(String::from).to_vec()];
+            for device in audit.devices.iter() {
+                let status = match (&device.error, device.problems.is_empty()) {
+                    (Some(_), _) => "FAILED".to_string(),
+                    (None, true) => "ok".to_string(),
+                    (None, false) => device.problems.join("; "),
+                };
+                rows.push(vec![
+                    device.device.clone(),
+                    device.version.clone().unwrap_or_else(|| "?".to_string()),
+                    status,
+                ]);
+            }
+            print!("{}", format_table(&rows));
+        }
+        OutputFormat::Json => print_json(&audit),
+    }
+
+    if failed {
+        std::process::exit(2);
+    }
+    if !audit.is_ok() {
+        std::process::exit(1);
+    }
+}
+
+async fn run_audit_security(args: &Args, fetcher: &Fetcher) {
+    let (wleds, unresolved) = find_devices(args, fetcher).await;
+
+    let mut devices = vec![];
+    for wled in wleds.iter() {
+        let (_, cfg) = try_addresses(&wled.addresses, async |ip| {
+            fetch_cfg(fetcher, ip, wled.port, &wled.options).await
+        })
+        .await;
+        let device = match cfg {
+            Ok(cfg) => {
+                let findings = security_findings(&cfg, wled.options.pin.is_some());
+                match findings.is_empty() {
+                    true => say!("{}: no risky settings", wled.name),
+                    false => say!("{}: {} risky settings", wled.name, findings.len()),
+                }
+                if args.output == OutputFormat::Text {
+                    for finding in findings.iter() {
+                        println!("  {}", finding.message);
+                    }
+                }
+                DeviceFindings {
+                    device: wled.name.clone(),
+                    findings,
+                    error: None,
+                }
+            }
+            Err(result) => {
+                error!("FAILED: {}: {result}", wled.name);
+                DeviceFindings {
+                    device: wled.name.clone(),
+                    error: Some(result.to_string()),
+                    ..Default::default()
+                }
+            }
+        };
+        devices.push(device);
+    }
+
+    if args.output == OutputFormat::Json {
+        print_json(&devices);
+    }
+
+    if unresolved > 0 || devices.iter().any(|device| device.error.is_some()) {
+        std::process::exit(2);
+    }
+    if devices.iter().any(|device| !device.findings.is_empty()) {
+        std::process::exit(1);
+    }
+}
+
+/// The one WLED a command works on, given with --device or --config. Exits
+/// unless exactly one is found.
+async fn find_one_device(args: &Args, fetcher: &Fetcher) -> (Device, SocketAddr) {
+    let (mut devices, unresolved) = find_devices(args, fetcher).await;
+    if unresolved > 0 {
+        std::process::exit(1);
+    }
+    if devices.len() != 1 {
+        let found = devices.len();
+        error!("FAILED: Give exactly one WLED with --device, but {found} were found");
+        std::process::exit(1);
+    }
+    let device = devices.remove(0);
+    let address = SocketAddr::new(device.addresses[0], device.port);
+    (device, address)
+}
+
+async fn run_preset_export(args: &Args, fetcher: &Fetcher, id: u16, out: Option<&Path>) {
+    let (device, address) = find_one_device(args, fetcher).await;
+    let (ip, port) = (address.ip(), address.port());
+    let preset = match preset::export_preset(fetcher, &ip, port, &device.options, id).await {
+        Ok(preset) => preset,
+        Err(result) => {
+            error!("FAILED: {result}");
+            std::process::exit(1);
+        }
+    };
+
+    let contents = serde_json::to_string_pretty(&preset).unwrap();
+    match out {
+        Some(out) => {
+            if let Err(result) = layout::write_atomic(out, contents.as_bytes()) {
+                error!("FAILED: Failed to write {}: {result}", out.display());
+                std::process::exit(1);
+            }
+            info!("saved: preset {id} of {} to {}", device.name, out.display());
+        }
+        None => println!("{contents}"),
+    }
+}
+
+async fn run_preset_import(args: &Args, fetcher: &Fetcher, id: u16, from: &Path, force: bool) {
+    let file = match read_preset(from) {
+        Ok(file) => file,
+        Err(result) => {
+            error!("FAILED: {result}");
+            std::process::exit(1);
+        }
+    };
+
+    let (device, address) = find_one_device(args, fetcher).await;
+    let (ip, port) = (address.ip(), address.port());
+    let options = &device.options;
+    if let Err(result) = preset::import_file(fetcher, &ip, port, options, id, &file, force).await {
+        error!("FAILED: {result}");
+        std::process::exit(1);
+    }
+    info!("Finished");
+}
+
+/// Read a preset, or a playlist, from a file saved by preset export.
+fn read_preset(path: &Path) -> Result<PresetFile, String> {
+    let contents = std::fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let file: PresetFile =
+        serde_json::from_slice(&contents).map_err(|e| format!("{}: {e}", path.display()))?;
+    file.preset()
+        .map_err(|e| format!("{}: {e}", path.display()))?;
+    Ok(file)
+}
+
+async fn run_library_add(
+    args: &Args,
+    fetcher: &Fetcher,
+    library: &Library,
+    name: &str,
+    from: Option<&Path>,
+    id: Option<u16>,
+) {
+    let file = match (from, id) {
+        (Some(from), _) => read_preset(from),
+        (None, Some(id)) => {
+            let (device, address) = find_one_device(args, fetcher).await;
+            let (ip, port) = (address.ip(), address.port());
+            let preset = preset::export_preset(fetcher, &ip, port, &device.options, id);
+            preset.await.map_err(|e| e.to_string())
+        }
+        (None, None) => unreachable!("clap requires --from or --id"),
+    };
+    if let Ok(PresetFile::Playlist { .. }) = &file {
+        warn!("{name} is a playlist, and the library keeps only it, not the presets it plays");
+    }
+    let added = file.and_then(|file| {
+        let preset = file.preset().map_err(|e| e.to_string())?;
+        library.add(name, preset).map_err(|e| e.to_string())
+    });
+    match added {
+        Ok(version) => say!("{name}: added, version {version}"),
+        Err(result) => {
+            error!("FAILED: {result}");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn run_library_list(args: &Args, library: &Library) {
+    let entries = match library.list() {
+        Ok(entries) => entries,
+        Err(result) => {
+            error!("FAILED: {result}");
+            std::process::exit(1);
+        }
+    };
+
+    match args.output {
+        OutputFormat::Text => {
+            for entry in entries.iter() {
+                let installed: Vec<String> = entry
+                    .installed
+                    .iter()
+                    .map(|installed| match installed.current {
+                        true => format!("{}:{}", installed.device, installed.id),
+                        false => format!("{}:{} (old)", installed.device, installed.id),
+                    })
+                    .collect();
+                say!("{} {} {}", entry.name, entry.version, installed.join(", "));
+            }
+        }
+        OutputFormat::Json => print_json(&entries),
+    }
+}
+
+async fn run_library_apply(
+    args: &Args,
+    fetcher: &Fetcher,
+    library: &Library,
+    placements: &[Placement],
+) {
+    let mut presets = vec![];
+    for placement in placements.iter() {
+        match library.load(&placement.name) {
+            Ok((preset, version)) => presets.push((placement, preset, version)),
+            Err(result) => {
+                error!("FAILED: {result}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if args.devices.is_empty() && args.config.is_none() {
+        error!("FAILED: Give the WLEDs to put the presets on with --device or --config");
+        std::process::exit(1);
+    }
+    let (devices, unresolved) = find_devices(args, fetcher).await;
+    let mut failed = unresolved;
+    for device in devices.iter() {
+        let (ip, port) = (device.addresses[0], device.port);
+        for (placement, preset, version) in presets.iter() {
+            let (name, id) = (&placement.name, placement.id);
+            let import = preset::import_preset(fetcher, &ip, port, &device.options, id, preset);
+            let result = match import.await {
+                Ok(()) => library.record(&device.name, id, name, version, chrono::Utc::now()),
+                Err(result) => Err(result),
+            };
+            match result {
+                Ok(()) => say!("{}: {name} saved as preset {id}", device.name),
+                Err(result) => {
+                    error!("FAILED: {}: {name}: {result}", device.name);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// The presets to sync from: a saved presets.json, or else a WLED.
+async fn sync_source(args: &Args, fetcher: &Fetcher, from: &str) -> Result<Presets, String> {
+    let path = Path::new(from);
+    if path.is_file() {
+        let contents =
+            read_file(path, make_decryption(args).as_ref()).map_err(|e| e.to_string())?;
+        return Presets::parse(&contents).map_err(|e| format!("{from}: {e}"));
+    }
+
+    let spec: DeviceSpec = from.parse()?;
+    let device = spec.resolve().await.map_err(|e| format!("{spec}: {e}"))?;
+    let (ip, port) = (device.addresses[0], device.port);
+    let options = device_options(args);
+    let presets = preset::fetch_presets(fetcher, &ip, port, &options).await;
+    presets.map_err(|e| format!("{spec}: {e}"))
+}
+
+/// Make the presets numbered `ids` on one WLED match `source`.
+async fn sync_presets(
+    args: &Args,
+    fetcher: &Fetcher,
+    source: &Presets,
+    target: &DeviceSpec,
+    ids: &[PresetRange],
+    map: &[SlotMap],
+    dry_run: bool,
+) -> Result<Vec<PresetChange>, String> {
+    let device = target.resolve().await.map_err(|e| e.to_string())?;
+    let (ip, port) = (device.addresses[0], device.port);
+    let options = device_options(args);
+    let presets = preset::fetch_presets(fetcher, &ip, port, &options).await;
+    let changes = preset::plan_sync(source, &presets.map_err(|e| e.to_string())?, ids, map);
+    if !dry_run {
+        let sync = preset::apply_sync(fetcher, &ip, port, &options, &changes);
+        sync.await.map_err(|e| e.to_string())?;
+    }
+    Ok(changes)
+}
+
+async fn run_preset_sync(
+    args: &Args,
+    fetcher: &Fetcher,
+    from: &str,
+    to: &[DeviceSpec],
+    ids: &[PresetRange],
+    map: &[SlotMap],
+    dry_run: bool,
+) {
+    let source = match sync_source(args, fetcher, from).await {
+        Ok(source) => source,
+        Err(result) => {
+            error!("FAILED: {result}");
+            std::process::exit(1);
+        }
+    };
+
+    let mut failed = 0;
+    for target in to.iter() {
+        let changes = match sync_presets(args, fetcher, &source, target, ids, map, dry_run).await {
+            Ok(changes) => changes,
+            Err(result) => {
+                error!("FAILED: {target}: {result}");
+                failed += 1;
+                continue;
+            }
+        };
+        let list = |saved: bool| {
+            let ids: Vec<String> = changes
+                .iter()
+                .filter(|change| change.preset.is_some() == saved)
+                .map(|change| change.id.to_string())
+                .collect();
+            ids.join(", ")
+        };
+        let (saved, deleted) = (list(true), list(false));
+        let verb = if dry_run { "would " } else { "" };
+        match (saved.is_empty(), deleted.is_empty()) {
+            (true, true) => say!("{target}: already in sync"),
+            (false, true) => say!("{target}: {verb}save {saved}"),
+            (true, false) => say!("{target}: {verb}delete {deleted}"),
+            (false, false) => say!("{target}: {verb}save {saved}, and delete {deleted}"),
+        }
+    }
+
+    if failed > 0 {
+        std::process::exit(if failed == to.len() { 2 } else { 1 });
+    }
+}
+
+/// Print what a restore would do.
+fn print_restore_plan(args: &Args, backup: &str, plan: &RestorePlan) {
+    match args.output {
+        OutputFormat::Text => say!(
+            "Would restore {backup} to {} at {}, uploading {}, then reboot it",
+            plan.device,
+            plan.address,
+            plan.files.join(", ")
+        ),
+        OutputFormat::Json => print_json(plan),
+    }
+}
+
+/// Back up the WLED about to be restored to, so the restore can be rolled
+/// back, adding it to the run's `taken` snapshots, which are what --rollback
+/// goes back to. Fails rather than restore with no way back.
+async fn take_snapshot(
+    args: &Args,
+    fetcher: &Fetcher,
+    address: &SocketAddr,
+    options: &DeviceOptions,
+    taken: &Mutex<Vec<Snapshot>>,
+) -> Result<(), String> {
+    info!("Backing up {address} before restoring to it");
+    let snapshot = match snapshot_device(fetcher, &args.out_dir, address, options).await {
+        Ok(snapshot) => snapshot,
+        Err(result) => {
+            return Err(format!(
+                "couldn't back up {address} before restoring to it: {result} (--no-snapshot restores anyway)"
+            ));
+        }
+    };
+    info!(
+        "Saved {} to {}/{}/{}",
+        snapshot.hostname, PRE_RESTORE_DIR, snapshot.hostname, snapshot.timestamp
+    );
+
+    let mut taken = taken.lock().unwrap();
+    taken.retain(|other| other.hostname != snapshot.hostname);
+    taken.push(snapshot);
+    Snapshot::save_last(&taken, &args.out_dir)
+        .map_err(|e| format!("couldn't record the snapshot of {address}: {e}"))
+}
+
+async fn run_restore_export(
+    args: &Args,
+    fetcher: &Fetcher,
+    from: &[PathBuf],
+    address: &SocketAddr,
+    dry_run: bool,
+    snapshot: bool,
+    wait: Option<Duration>,
+) {
+    let export = match Export::load(from) {
+        Ok(export) => export,
+        Err(result) => {
+            error!("FAILED: {result}");
+            std::process::exit(1);
+        }
+    };
+
+    let client = &fetcher.client;
+    let (ip, port) = (&address.ip(), address.port());
+    let options = device_options(args);
+    if dry_run {
+        match plan_export(client, ip, port, &options, export).await {
+            Ok(plan) => print_restore_plan(args, "the WLED export", &plan),
+            Err(result) => {
+                error!("FAILED: {result}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let taken = Mutex::new(vec![]);
+    if snapshot && let Err(result) = take_snapshot(args, fetcher, address, &options, &taken).await {
+        error!("FAILED: {result}");
+        std::process::exit(1);
+    }
+
+    info!("Restoring WLED export to {address}");
+    if let Err(result) = restore_export(client, ip, port, &options, export, wait).await {
+        error!("FAILED: {result}");
+        std::process::exit(1);
+    }
+
+    info!("Finished");
+}
+
+/// Restore the backup saved under `name` to `address`: check it can be read
+/// and the WLED is the right one before touching anything, then snapshot the
+/// WLED into `snapshots`, unless it's None, then restore.
+async fn restore_backup(
+    args: &Args,
+    fetcher: &Fetcher,
+    layout: &Layout,
+    name: &str,
+    settings: &RestoreSettings,
+    address: &SocketAddr,
+    snapshots: Option<&Mutex<Vec<Snapshot>>>,
+) -> Result<(), wled_backup::Error> {
+    let client = &fetcher.client;
+    let decryption = make_decryption(args);
+    let decryption = decryption.as_ref();
+    let options = device_options(args);
+
+    plan_restore_device(
+        client, layout, name, decryption, address, &options, settings,
+    )
+    .await?;
+    if let Some(taken) = snapshots {
+        take_snapshot(args, fetcher, address, &options, taken).await?;
+    }
+
+    info!("Restoring {name} to {address}");
+    restore_device(
+        client, layout, name, decryption, address, &options, settings,
+    )
+    .await
+}
+
+async fn run_restore(
+    args: &Args,
+    fetcher: &Fetcher,
+    name: &str,
+    settings: &RestoreSettings,
+    address: &SocketAddr,
+    dry_run: bool,
+    snapshot: bool,
+) {
+    let layout = make_layout(args);
+    if dry_run {
+        let decryption = make_decryption(args);
+        let options = device_options(args);
+        let plan = plan_restore_device(
+            &fetcher.client,
+            &layout,
+            name,
+            decryption.as_ref(),
+            address,
+            &options,
+            settings,
+        );
+        match plan.await {
+            Ok(plan) => print_restore_plan(args, name, &plan),
+            Err(result) => {
+                error!("FAILED: {result}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let taken = Mutex::new(vec![]);
+    let snapshots = snapshot.then_some(&taken);
+    let restore = restore_backup(args, fetcher, &layout, name, settings, address, snapshots);
+    if let Err(result) = restore.await {
+        error!("FAILED: {result}");
+        std::process::exit(1);
+    }
+
+    info!("Finished");
+}
+
+/// How restore --all goes through the devices.
+struct Rollout {
+    /// Devices restored at once.
+    batch: usize,
+    /// Wait between batches, so the Wi-Fi isn't swamped by reboots.
+    pause: Duration,
+    /// Keep going after a device fails.
+    keep_going: bool,
+}
+
+/// Where to restore a device's backup to: the address it was backed up from.
+fn saved_address(args: &Args, layout: &Layout, name: &str) -> Result<SocketAddr, String> {
+    let path = find_saved(layout.find_file(name, META_FILE));
+    let meta = DeviceMeta::load(&path, make_decryption(args).as_ref())
+        .map_err(|e| format!("its address isn't known: {e}"))?;
+    let ip: IpAddr = meta.address.parse().map_err(|e| {
+        format!(
+            "Invalid address {} in {}: {e}",
+            meta.address,
+            path.display()
+        )
+    })?;
+    Ok(SocketAddr::new(ip, meta.port))
+}
+
+/// Restore every device with a backup in `from`, or out_dir, to the address
+/// it was backed up from, a batch at a time.
+async fn run_restore_all(
+    args: &Args,
+    fetcher: &Fetcher,
+    from: Option<&Path>,
+    settings: &RestoreSettings,
+    rollout: &Rollout,
+    dry_run: bool,
+    snapshot: bool,
+) {
+    let mut layout = make_layout(args);
+    if let Some(from) = from {
+        layout.out_dir = from.to_path_buf();
+    }
+    let names = match saved_devices(&layout) {
+        Ok(names) if names.is_empty() => {
+            error!("FAILED: No backups found in {}", layout.out_dir.display());
+            std::process::exit(1);
+        }
+        Ok(names) => names,
+        Err(result) => {
+            error!("FAILED: {}: {result}", layout.out_dir.display());
+            std::process::exit(1);
+        }
+    };
+
+    // Every device's snapshot is kept, so --rollback undoes the whole run.
+    let taken = Mutex::new(vec![]);
+    let snapshots = snapshot.then_some(&taken);
+    let restore = async |name: &String| {
+        let address = saved_address(args, &layout, name)?;
+        if dry_run {
+            let client = &fetcher.client;
+            let decryption = make_decryption(args);
+            let options = device_options(args);
+            let plan = plan_restore_device(
+                client,
+                &layout,
+                name,
+                decryption.as_ref(),
+                &address,
+                &options,
+                settings,
+            );
+            print_restore_plan(args, name, &plan.await?);
+        } else {
+            restore_backup(args, fetcher, &layout, name, settings, &address, snapshots).await?;
+        }
+        Ok::<_, wled_backup::Error>(address)
+    };
+
+    let mut reports = vec![];
+    let batches: Vec<&[String]> = names.chunks(rollout.batch).collect();
+    for (i, batch) in batches.iter().enumerate() {
+        if i > 0 && !dry_run {
+            info!("Waiting {}s before the next batch", rollout.pause.as_secs());
+            tokio::time::sleep(rollout.pause).await;
+        }
+        let results = futures::future::join_all(batch.iter().map(restore)).await;
+        for (name, result) in batch.iter().zip(results) {
+            let report = match result {
+                Ok(address) => RestoreReport {
+                    name: name.clone(),
+                    address: Some(address.to_string()),
+                    success: true,
+                    error: None,
+                },
+                Err(result) => {
+                    error!("FAILED: {name}: {result}");
+                    RestoreReport {
+                        name: name.clone(),
+                        address: None,
+                        success: false,
+                        error: Some(result.to_string()),
+                    }
+                }
+            };
+            reports.push(report);
+        }
+        if !rollout.keep_going && reports.iter().any(|report| !report.success) {
+            break;
+        }
+    }
+
+    let restored = reports.iter().filter(|report| report.success).count();
+    let failed = reports.len() - restored;
+    let skipped = names.len() - reports.len();
+    if !dry_run {
+        match args.output {
+            OutputFormat::Text => {
+                for report in reports.iter() {
+                    match &report.error {
+                        None => say!("{}: OK", report.name),
+                        Some(result) => say!("{}: FAILED: {result}", report.name),
+                    }
+                }
+                say!("{restored} restored, {failed} failed, {skipped} not attempted");
+            }
+            OutputFormat::Json => print_json(&reports),
+        }
+    }
+
+    if failed > 0 {
+        if skipped > 0 {
+            info!("Stopped after the first failure. Use --continue to restore the rest anyway");
+        }
+        std::process::exit(if restored == 0 { 2 } else { 1 });
+    }
+    info!("Finished");
+}
+
+/// Restore the backups taken before the last restore, to `address` or else
+/// where each was taken from. After restore --all, that's every WLED it
+/// restored to, in turn.
+async fn run_rollback(
+    args: &Args,
+    client: &reqwest::Client,
+    address: Option<SocketAddr>,
+    dry_run: bool,
+    force: bool,
+    wait: Option<Duration>,
+) {
+    let snapshots = match Snapshot::load_last(&args.out_dir) {
+        Ok(snapshots) if snapshots.is_empty() => {
+            error!("FAILED: Nothing to roll back: no restore since the last rollback");
+            std::process::exit(1);
+        }
+        Ok(snapshots) => snapshots,
+        Err(result) => {
+            error!("FAILED: {result}");
+            std::process::exit(1);
+        }
+    };
+    if address.is_some() && snapshots.len() > 1 {
+        error!(
+            "FAILED: The last restore was to {} WLEDs, so --ip can't say where each goes",
+            snapshots.len()
+        );
+        std::process::exit(1);
+    }
+
+    let options = device_options(args);
+    let settings = RestoreSettings {
+        secrets: None,
+        force,
+        reboot_timeout: wait,
+    };
+
+    // Those rolled back are forgotten, so a rollback that fails part way can
+    // be run again for the rest.
+    let mut left = snapshots.clone();
+    let mut failed = 0;
+    for snapshot in snapshots.iter() {
+        let address = address.unwrap_or(snapshot.address);
+        let layout = Snapshot::layout(&args.out_dir, &snapshot.timestamp);
+        let hostname = &snapshot.hostname;
+
+        if dry_run {
+            let plan = plan_restore_device(
+                client, &layout, hostname, None, &address, &options, &settings,
+            );
+            match plan.await {
+                Ok(plan) => {
+                    let backup = format!("{hostname} as it was at {}", snapshot.timestamp);
+                    print_restore_plan(args, &backup, &plan)
+                }
+                Err(result) => {
+                    error!("FAILED: {hostname}: {result}");
+                    failed += 1;
+                }
+            }
+            continue;
+        }
+
+        info!(
+            "Rolling {address} back to {hostname} as it was at {}",
+            snapshot.timestamp
+        );
+        let restore = restore_device(
+            client, &layout, hostname, None, &address, &options, &settings,
+        );
+        if let Err(result) = restore.await {
+            error!("FAILED: {hostname}: {result}");
+            failed += 1;
+            continue;
+        }
+        left.retain(|other| other != snapshot);
+        if let Err(result) = Snapshot::save_last(&left, &args.out_dir) {
+            warn!("Couldn't forget the rollback of {hostname}: {result}");
+        }
+    }
+
+    if failed > 0 {
+        if !dry_run && snapshots.len() > 1 {
+            say!(
+                "{} rolled back, {failed} failed: run --rollback again to retry",
+                snapshots.len() - failed
+            );
+        }
+        std::process::exit(1);
+    }
+    info!("Finished");
+}
+
+fn print_changes(changes: &[FileChange]) {
+    for change in changes.iter() {
+        match change {
+            FileChange::Added { file } => say!("added:   {file}"),
+            FileChange::Removed { file } => say!("removed: {file}"),
+            FileChange::Changed { file, fields } => {
+                say!("changed: {file}");
+                for field in fields.iter() {
+                    match field {
+                        FieldChange::Added { path, new } => say!("  + {path}: {new}"),
+                        FieldChange::Removed { path, old } => say!("  - {path}: {old}"),
+                        FieldChange::Changed { path, old, new } => {
+                            say!("  ~ {path}: {old} -> {new}")
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn run_diff(args: &Args, old: &Path, new: &Path) {
+    let changes = match diff_dirs(old, new, make_decryption(args).as_ref()) {
+        Ok(changes) => changes,
+        Err(result) => {
+            error!("FAILED: {result}");
+            std::process::exit(2);
+        }
+    };
+
+    match args.output {
+        OutputFormat::Text => print_changes(&changes),
+        OutputFormat::Json => print_json(&changes),
+    }
+
+    // Follow diff(1), and exit non-zero when there are differences.
+    if !changes.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+fn run_verify(
+    args: &Args,
+    manifest: Option<&Path>,
+    dir: Option<&Path>,
+    max_age: Option<chrono::Duration>,
+) {
+    let mut layout = make_layout(args);
+    if let Some(dir) = dir {
+        layout.out_dir = dir.to_path_buf();
+    }
+    let decryption = make_decryption(args);
+    let now = chrono::Utc::now();
+    let check = check_backups(&layout, manifest, max_age, decryption.as_ref(), now);
+
+    match args.output {
+        OutputFormat::Text => {
+            for problem in check.problems.iter() {
+                say!("FAILED: {problem}");
+            }
+            for file in check.files.iter() {
+                match file.status {
+                    FileStatus::Ok => say!("ok:      {}", file.path),
+                    FileStatus::Missing => say!("MISSING: {}", file.path),
+                    FileStatus::Changed => say!("CHANGED: {}", file.path),
+                }
+            }
+            for device in check.devices.iter() {
+                match device.problems.is_empty() {
+                    true => say!("{}: ok", device.device),
+                    false => say!("{}: FAILED: {}", device.device, device.problems.join("; ")),
+                }
+            }
+        }
+        OutputFormat::Json => print_json(&check),
+    }
+
+    if !check.is_ok() {
+        std::process::exit(1);
+    }
+}
+
+/// Compare each device with its newest backup. Exits 1 if any device has
+/// changed, and 2 if any couldn't be compared.
+async fn run_diff_live(args: &Args, fetcher: &Fetcher) {
+    let (wleds, unresolved) = find_devices(args, fetcher).await;
+    let layout = make_layout(args);
+    let decryption = make_decryption(args);
+
+    let mut devices = vec![];
+    for wled in wleds.iter() {
+        info!("Comparing {}", wled.name);
+        let (_, result) = try_addresses(&wled.addresses, async |ip| {
+            diff_live(
+                fetcher,
+                ip,
+                wled.port,
+                &wled.options,
+                &layout,
+                decryption.as_ref(),
+            )
+            .await
+        })
+        .await;
+        let device = match result {
+            Ok((hostname, changes)) => {
+                match changes.is_empty() {
+                    true => say!("{}: no changes since the backup of {hostname}", wled.name),
+                    false => {
+                        say!("{}: CHANGED since the backup of {hostname}", wled.name);
+                        if args.output == OutputFormat::Text {
+                            print_changes(&changes);
+                        }
+                    }
+                }
+                DeviceChanges {
+                    device: wled.name.clone(),
+                    changes,
+                    error: None,
+                }
+            }
+            Err(result) => {
+                error!("FAILED: {result}");
+                DeviceChanges {
+                    device: wled.name.clone(),
+                    changes: vec![],
+                    error: Some(result.to_string()),
+                }
+            }
+        };
+        devices.push(device);
+    }
+
+    if args.output == OutputFormat::Json {
+        print_json(&devices);
+    }
+
+    if unresolved > 0 || devices.iter().any(|device| device.error.is_some()) {
+        std::process::exit(2);
+    }
+    if devices.iter().any(|device| !device.changes.is_empty()) {
+        std::process::exit(1);
+    }
+}
+
+/// Find the devices to act on, leaving out those `filter` skips. Also
+/// returns how many listed devices couldn't be resolved or found.
+async fn select_devices(
+    args: &Args,
+    fetcher: &Fetcher,
+    filter: &DeviceFilter,
+) -> (Vec<Device>, usize) {
+    let (mut wleds, unresolved) = find_devices(args, fetcher).await;
+    for name in filter.filter_networks(&mut wleds) {
+        warn!("Skipping {name}: no address in an included network");
+    }
+    let mut selected = vec![];
+    for wled in wleds {
+        if filtered_out(fetcher, &wled.addresses[0], &wled, filter).await {
+            info!("Skipping {}: filtered out", wled.name);
+            continue;
+        }
+        selected.push(wled);
+    }
+    (selected, unresolved)
+}
+
+/// Print how sending to each device went, and exit 1 if any failed, or 2 if
+/// all did.
+fn finish_actions(args: &Args, reports: &[ActionReport], unresolved: usize) {
+    match args.output {
+        OutputFormat::Text => {
+            for report in reports.iter() {
+                match (&report.error, &report.version) {
+                    (None, Some(version)) => say!("{}: OK, running {version}", report.name),
+                    (None, None) => say!("{}: OK", report.name),
+                    (Some(result), _) => say!("{}: FAILED: {result}", report.name),
+                }
+            }
+        }
+        OutputFormat::Json => print_json(&reports),
+    }
+
+    let failed = unresolved + reports.iter().filter(|report| !report.success).count();
+    if failed > 0 && failed == unresolved + reports.len() {
+        std::process::exit(2);
+    }
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+async fn run_cmd(
+    args: &Args,
+    fetcher: &Fetcher,
+    state: &serde_json::Value,
+    filter: &DeviceFilter,
+    dry_run: bool,
+) {
+    let (wleds, unresolved) = select_devices(args, fetcher, filter).await;
+    if dry_run {
+        for wled in wleds.iter() {
+            say!("{}: would send {state}", wled.name);
+        }
+        return;
+    }
+
+    let reports = send_states(fetcher, &wleds, state).await;
+    finish_actions(args, &reports, unresolved);
+}
+
+/// Send `state` to every device at once, and report how each went.
+async fn send_states(
+    fetcher: &Fetcher,
+    wleds: &[Device],
+    state: &serde_json::Value,
+) -> Vec<ActionReport> {
+    act_on_all(wleds, async |ip, wled| {
+        control::send_state(fetcher, ip, wled.port, &wled.options, state).await
+    })
+    .await
+}
+
+/// Do `action` to every device at once, trying each of its addresses, and
+/// report how each went.
+async fn act_on_all(
+    wleds: &[Device],
+    action: impl AsyncFn(&IpAddr, &Device) -> Result<(), BackupError>,
+) -> Vec<ActionReport> {
+    let act = async |wled: &Device| {
+        try_addresses(&wled.addresses, async |ip| action(ip, wled).await).await
+    };
+    let results = futures::future::join_all(wleds.iter().map(act)).await;
+    let mut reports = vec![];
+    for (wled, (ip, result)) in wleds.iter().zip(results) {
+        if let Err(result) = &result {
+            error!("FAILED: {}: {result}", wled.name);
+        }
+        reports.push(ActionReport {
+            name: wled.name.clone(),
+            address: ip.map(|ip| ip.to_string()),
+            success: result.is_ok(),
+            error: result.err().map(|e| e.to_string()),
+            ..Default::default()
+        });
+    }
+    reports
+}
+
+/// Ask on the terminal whether to go ahead. Without a terminal to ask on,
+/// the answer is no.
+fn confirm(question: &str) -> bool {
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+    eprint!("{question} [y/N] ");
+    let _ = std::io::stderr().flush();
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+async fn run_reboot(args: &Args, fetcher: &Fetcher, filter: &DeviceFilter, yes: bool) {
+    let (wleds, unresolved) = select_devices(args, fetcher, filter).await;
+    if wleds.is_empty() && unresolved == 0 {
+        warn!("No WLEDs to reboot");
+        return;
+    }
+
+    let names: Vec<&str> = wleds.iter().map(|wled| wled.name.as_str()).collect();
+    let question = format!("Reboot {} WLEDs ({})?", wleds.len(), names.join(", "));
+    if !wleds.is_empty() && !yes && !confirm(&question) {
+        error!("FAILED: Not rebooting without --yes, or a yes when asked");
+        std::process::exit(1);
+    }
+
+    let reports = send_states(fetcher, &wleds, &control::reboot_state()).await;
+    finish_actions(args, &reports, unresolved);
+}
+
+/// Where state snapshot saves a device's state in `dir`.
+fn state_path(dir: &Path, wled: &Device) -> PathBuf {
+    dir.join(format!("{}.json", layout::sanitize_name(&wled.name)))
+}
+
+/// Save what each device found is showing, to a file in `dir` or, given
+/// `preset`, as that preset on the device.
+async fn run_state_snapshot(
+    args: &Args,
+    fetcher: &Fetcher,
+    dir: &Path,
+    filter: &DeviceFilter,
+    preset: Option<u16>,
+) {
+    let (wleds, unresolved) = select_devices(args, fetcher, filter).await;
+    let reports = act_on_all(&wleds, async |ip, wled| match preset {
+        Some(id) => {
+            let save = control::save_preset_state(id, "Snapshot").map_err(BackupError::Invalid)?;
+            control::send_state(fetcher, ip, wled.port, &wled.options, &save).await
+        }
+        None => {
+            let state = control::fetch_state(fetcher, ip, wled.port, &wled.options).await?;
+            let path = state_path(dir, wled);
+            control::save_state(&path, &state)?;
+            info!("saved {}'s state to {}", wled.name, path.display());
+            Ok(())
+        }
+    })
+    .await;
+    finish_actions(args, &reports, unresolved);
+}
+
+/// Send each device found the state saved for it in `dir`.
+async fn run_state_restore(
+    args: &Args,
+    fetcher: &Fetcher,
+    dir: &Path,
+    filter: &DeviceFilter,
+    dry_run: bool,
+) {
+    let (wleds, unresolved) = select_devices(args, fetcher, filter).await;
+    if dry_run {
+        for wled in wleds.iter() {
+            let path = state_path(dir, wled);
+            match control::load_state(&path) {
+                Ok(_) => say!("{}: would send {}", wled.name, path.display()),
+                Err(e) => warn!("{}: {e}", wled.name),
+            }
+        }
+        return;
+    }
+
+    let reports = act_on_all(&wleds, async |ip, wled| {
+        let state = control::restorable_state(&control::load_state(&state_path(dir, wled))?);
+        control::send_state(fetcher, ip, wled.port, &wled.options, &state).await
+    })
+    .await;
+    finish_actions(args, &reports, unresolved);
+}
+
+/// Where ota gets firmware from.
+enum FirmwareSource {
+    /// The image given with --firmware, for every device.
+    File(Firmware),
+    /// The image for each device's board from an official release,
+    /// downloaded into `cache`.
+    Release { release: Release, cache: PathBuf },
+}
+
+/// How ota updates each device.
+struct Update {
+    firmware: FirmwareSource,
+    force: bool,
+    wait: Duration,
+    /// The backup set to back devices up into first, unless --no-backup.
+    backup: Option<String>,
+}
+
+/// The image in `release` for a device's board.
+async fn release_asset<'a>(
+    fetcher: &Fetcher,
+    wled: &Device,
+    release: &'a Release,
+) -> Result<&'a Asset, wled_backup::Error> {
+    let (_, info) = try_addresses(&wled.addresses, async |ip| {
+        fetch_info(fetcher, ip, wled.port, &wled.options).await
+    })
+    .await;
+    Ok(release.asset_for(&info?)?)
+}
+
+/// Back up a device, then update its firmware. Returns the address updated,
+/// and /json/info from before and after.
+async fn update_device(
+    args: &Args,
+    fetcher: &Fetcher,
+    wled: &Device,
+    update: &Update,
+) -> Result<(IpAddr, WledInfo, WledInfo), wled_backup::Error> {
+    let downloaded;
+    let firmware = match &update.firmware {
+        FirmwareSource::File(firmware) => firmware,
+        FirmwareSource::Release { release, cache } => {
+            let asset = release_asset(fetcher, wled, release).await?;
+            downloaded = ota::cached_firmware(fetcher, release, asset, cache).await?;
+            &downloaded
+        }
+    };
+
+    if let Some(timestamp) = &update.backup {
+        info!("Backing up {} before updating it", wled.name);
+        let mut settings = BackupSettings::new(ota::backup_layout(&args.out_dir, timestamp));
+        settings.force = true;
+        backup_device(fetcher, wled, &settings)
+            .await
+            .map_err(|e| format!("Not updating, as the backup failed: {e}"))?;
+    }
+
+    info!("Updating {}", wled.name);
+    let ip = wled.addresses[0];
+    let (client, options) = (&fetcher.client, &wled.options);
+    let (force, wait) = (update.force, update.wait);
+    let (before, after) =
+        ota::update_wled(client, &ip, wled.port, options, firmware, force, wait).await?;
+    Ok((ip, before, after))
+}
+
+async fn run_ota(
+    args: &Args,
+    fetcher: &Fetcher,
+    filter: &DeviceFilter,
+    rolling: usize,
+    update: &Update,
+    dry_run: bool,
+) {
+    if let FirmwareSource::File(firmware) = &update.firmware
+        && firmware.version.is_none()
+    {
+        warn!(
+            "Can't tell which version {} installs, so only checking each WLED comes back (--version checks it)",
+            firmware.path.display()
+        );
+    }
+    let (wleds, unresolved) = select_devices(args, fetcher, filter).await;
+    if dry_run {
+        for wled in wleds.iter() {
+            match &update.firmware {
+                FirmwareSource::File(firmware) => {
+                    let version = firmware.version.as_deref().unwrap_or("the firmware");
+                    say!("{}: would update to {version}", wled.name);
+                }
+                FirmwareSource::Release { release, .. } => {
+                    match release_asset(fetcher, wled, release).await {
+                        Ok(asset) => say!("{}: would update with {}", wled.name, asset.name),
+                        Err(result) => say!("{}: can't update: {result}", wled.name),
+                    }
+                }
+            }
+        }
+        return;
+    }
+
+    let failed = std::sync::atomic::AtomicBool::new(false);
+    let update_one = async |wled: &Device| {
+        if failed.load(std::sync::atomic::Ordering::Relaxed) {
+            return None;
+        }
+        let report = match update_device(args, fetcher, wled, update).await {
+            Ok((ip, before, after)) => ActionReport {
+                name: wled.name.clone(),
+                address: Some(ip.to_string()),
+                success: true,
+                previous_version: before.ver,
+                version: after.ver,
+                error: None,
+            },
+            Err(result) => {
+                error!("FAILED: {}: {result}", wled.name);
+                failed.store(true, std::sync::atomic::Ordering::Relaxed);
+                ActionReport {
+                    name: wled.name.clone(),
+                    address: wled.addresses.first().map(|ip| ip.to_string()),
+                    success: false,
+                    error: Some(result.to_string()),
+                    ..Default::default()
+                }
+            }
+        };
+        Some(report)
+    };
+    let reports: Vec<ActionReport> = futures::stream::iter(wleds.iter())
+        .map(update_one)
+        .buffered(rolling)
+        .filter_map(std::future::ready)
+        .collect()
+        .await;
+
+    let skipped = wleds.len() - reports.len();
+    if skipped > 0 {
+        warn!("Stopped after a failure, leaving {skipped} WLEDs as they were");
+    }
+    finish_actions(args, &reports, unresolved + skipped);
+}
+
+async fn run_apply_template(
+    args: &Args,
+    fetcher: &Fetcher,
+    template: &Path,
+    all: bool,
+    dry_run: bool,
+) {
+    let template = match template::load_template(template) {
+        Ok(template) => template,
+        Err(result) => {
+            error!("FAILED: {result}");
+            std::process::exit(1);
+        }
+    };
+    let given = !args.devices.is_empty()
+        || args.config.is_some()
+        || !args.scan.is_empty()
+        || !args.nodes.is_empty()
+        || args.discover;
+    if !given && !all {
+        error!(
+            "FAILED: Give the WLEDs to change with --device or --config, or --all for every one discovered"
+        );
+        std::process::exit(1);
+    }
+
+    let (wleds, unresolved) = find_devices(args, fetcher).await;
+    let mut devices = vec![];
+    for wled in wleds.iter() {
+        let (_, result) = try_addresses(&wled.addresses, async |ip| {
+            template::apply_template(fetcher, ip, wled.port, &wled.options, &template, dry_run)
+                .await
+        })
+        .await;
+        let device = match result {
+            Ok(fields) => {
+                let changes = match fields.is_empty() {
+                    true => vec![],
+                    false => vec![FileChange::Changed {
+                        file: "cfg.json".to_string(),
+                        fields,
+                    }],
+                };
+                match (changes.is_empty(), dry_run) {
+                    (true, _) => say!("{}: already has the template's settings", wled.name),
+                    (false, true) => say!("{}: would change", wled.name),
+                    (false, false) => say!("{}: changed", wled.name),
+                }
+                if args.output == OutputFormat::Text {
+                    print_changes(&changes);
+                }
+                DeviceChanges {
+                    device: wled.name.clone(),
+                    changes,
+                    error: None,
+                }
+            }
+            Err(result) => {
+                error!("FAILED: {}: {result}", wled.name);
+                DeviceChanges {
+                    device: wled.name.clone(),
+                    changes: vec![],
+                    error: Some(result.to_string()),
+                }
+            }
+        };
+        devices.push(device);
+    }
+
+    if args.output == OutputFormat::Json {
+        print_json(&devices);
+    }
+
+    let failed = unresolved
+        + devices
+            .iter()
+            .filter(|device| device.error.is_some())
+            .count();
+    if failed > 0 && failed == unresolved + devices.len() {
+        std::process::exit(2);
+    }
+    if failed > 0 {
+        std::process::exit(1);
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let started = Instant::now();
+    let settings = Settings::load_default().unwrap_or_else(|result| {
+        eprintln!("FAILED: {result}");
+        std::process::exit(1);
+    });
+    let args = parse_args(&settings, std::env::args_os()).unwrap_or_else(|e| e.exit());
+    set_output_format(args.output);
+    // Bars would only get in the way of output going to a file or a script.
+    if args.output == OutputFormat::Text
+        && std::io::stdout().is_terminal()
+        && std::io::stderr().is_terminal()
+    {
+        progress::enable();
+    }
+    if let Err(result) = init_logging(&args) {
+        eprintln!("FAILED to open the log file: {result}");
+        std::process::exit(1);
+    }
+    let fetcher = Fetcher::new(
+        &ClientOptions {
+            timeout: Some(Duration::from_secs(args.http_timeout_secs)),
+            insecure_tls: args.insecure_tls,
+            ca_certs: args.ca_cert.clone(),
+            user_agent: args.user_agent.clone(),
+            proxy: args.proxy.clone(),
+        },
+        RetryPolicy {
+            retries: args.retries,
+            delay: Duration::from_millis(args.retry_delay_ms),
+        },
+    )
+    .unwrap_or_else(|result| {
+        error!("FAILED: {result}");
+        std::process::exit(1);
+    });
+
+    match args
+        .command
+        .clone()
+        .unwrap_or(Command::Backup(BackupArgs::default()))
+    {
+        Command::Backup(backup_args) => {
+            let exit_code = run_backup(&args, started, &fetcher, &backup_args).await;
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
+        }
+        Command::Daemon(daemon_args) => run_daemon(&args, &fetcher, &daemon_args).await,
+        Command::InstallSystemd {
+            interval, install, ..
+        } => run_install_systemd(interval, install),
+        Command::Inventory { format } => {
+            let inventory = run_inventory(&args, &fetcher, format);
+            with_deadline(&args, started, inventory).await
+        }
+        Command::Audit { audit } => match audit {
+            AuditCommand::Versions {
+                min_version,
+                latest,
+            } => {
+                let audit = run_audit_versions(&args, &fetcher, min_version.as_ref(), latest);
+                with_deadline(&args, started, audit).await
+            }
+            AuditCommand::Security => {
+                let audit = run_audit_security(&args, &fetcher);
+                with_deadline(&args, started, audit).await
+            }
+        },
+        Command::Cmd {
+            json,
+            filter,
+            dry_run,
+        } => {
+            let cmd = run_cmd(&args, &fetcher, &json, &filter, dry_run);
+            with_deadline(&args, started, cmd).await
+        }
+        Command::Reboot { filter, yes } => {
+            let reboot = run_reboot(&args, &fetcher, &filter, yes);
+            with_deadline(&args, started, reboot).await
+        }
+        Command::State { state_dir, state } => {
+            let dir = state_dir.unwrap_or_else(|| args.out_dir.join(control::STATE_DIR));
+            match state {
+                StateCommand::Snapshot { filter, preset } => {
+                    let snapshot = run_state_snapshot(&args, &fetcher, &dir, &filter, preset);
+                    with_deadline(&args, started, snapshot).await
+                }
+                StateCommand::Restore { filter, dry_run } => {
+                    let restore = run_state_restore(&args, &fetcher, &dir, &filter, dry_run);
+                    with_deadline(&args, started, restore).await
+                }
+            }
+        }
+        Command::Ota {
+            firmware,
+            release,
+            firmware_cache,
+            filter,
+            rolling,
+            version,
+            reboot_timeout_secs,
+            force,
+            no_backup,
+            dry_run,
+        } => {
+            let firmware = match (firmware, release) {
+                (Some(path), _) => Firmware::load(&path, version).map(FirmwareSource::File),
+                (None, Some(release)) => ota::fetch_release(&fetcher, RELEASES_URL, &release)
+                    .await
+                    .map(|release| FirmwareSource::Release {
+                        release,
+                        cache: firmware_cache.unwrap_or_else(|| args.out_dir.join(FIRMWARE_DIR)),
+                    })
+                    .map_err(|e| format!("Couldn't find WLED release {release}: {e}").into()),
+                (None, None) => unreachable!("clap requires --firmware or --release"),
+            };
+            let firmware = match firmware {
+                Ok(firmware) => firmware,
+                Err(result) => {
+                    error!("FAILED: {result}");
+                    std::process::exit(1);
+                }
+            };
+            let update = Update {
+                firmware,
+                force,
+                wait: Duration::from_secs(reboot_timeout_secs),
+                backup: (!no_backup).then(|| layout::timestamp_name(chrono::Utc::now())),
+            };
+            let ota = run_ota(&args, &fetcher, &filter, rolling.into(), &update, dry_run);
+            with_deadline(&args, started, ota).await
+        }
+        Command::ApplyTemplate {
+            template,
+            all,
+            dry_run,
+        } => {
+            let apply = run_apply_template(&args, &fetcher, &template, all, dry_run);
+            with_deadline(&args, started, apply).await
+        }
+        Command::Library {
+            library_dir,
+            library: command,
+        } => {
+            let dir = library_dir.unwrap_or_else(|| args.out_dir.join(LIBRARY_DIR));
+            let library = Library::new(&dir);
+            match command {
+                LibraryCommand::Add { name, from, id } => {
+                    let add =
+                        run_library_add(&args, &fetcher, &library, &name, from.as_deref(), id);
+                    with_deadline(&args, started, add).await
+                }
+                LibraryCommand::List => run_library_list(&args, &library),
+                LibraryCommand::Apply { presets } => {
+                    let apply = run_library_apply(&args, &fetcher, &library, &presets);
+                    with_deadline(&args, started, apply).await
+                }
+            }
+        }
+        Command::Preset { preset } => match preset {
+            PresetCommand::Export { id, out } => {
+                let export = run_preset_export(&args, &fetcher, id, out.as_deref());
+                with_deadline(&args, started, export).await
+            }
+            PresetCommand::Import { id, from, force } => {
+                let import = run_preset_import(&args, &fetcher, id, &from, force);
+                with_deadline(&args, started, import).await
+            }
+            PresetCommand::Sync {
+                from,
+                to,
+                ids,
+                map,
+                dry_run,
+            } => {
+                let sync = run_preset_sync(&args, &fetcher, &from, &to, &ids, &map, dry_run);
+                with_deadline(&args, started, sync).await
+            }
+        },
+        Command::List => {
+            let list = run_list(&args, &fetcher);
+            with_deadline(&args, started, list).await
+        }
+        Command::Restore {
+            name,
+            all,
+            batch,
+            pause_secs,
+            keep_going,
+            from,
+            ip,
+            port,
+            secrets,
+            dry_run,
+            reboot_timeout_secs,
+            no_wait,
+            force,
+            no_snapshot,
+            rollback,
+        } => {
+            let address = ip.map(|ip| SocketAddr::new(ip, port));
+            let snapshot = !no_snapshot;
+            let wait = (!no_wait).then(|| Duration::from_secs(reboot_timeout_secs));
+            match (name, address) {
+                _ if all => {
+                    if from.len() > 1 {
+                        error!("FAILED: --all restores from a single backup set directory");
+                        std::process::exit(1);
+                    }
+                    let settings = RestoreSettings {
+                        secrets: None,
+                        force,
+                        reboot_timeout: wait,
+                    };
+                    let rollout = Rollout {
+                        batch: batch.into(),
+                        pause: Duration::from_secs(pause_secs),
+                        keep_going,
+                    };
+                    let from = from.first().map(PathBuf::as_path);
+                    let restore = run_restore_all(
+                        &args, &fetcher, from, &settings, &rollout, dry_run, snapshot,
+                    );
+                    with_deadline(&args, started, restore).await
+                }
+                _ if rollback => {
+                    let client = &fetcher.client;
+                    let rollback = run_rollback(&args, client, address, dry_run, force, wait);
+                    with_deadline(&args, started, rollback).await
+                }
+                (Some(name), Some(address)) => {
+                    let settings = RestoreSettings {
+                        secrets,
+                        force,
+                        reboot_timeout: wait,
+                    };
+                    let restore = run_restore(
+                        &args, &fetcher, &name, &settings, &address, dry_run, snapshot,
+                    );
+                    with_deadline(&args, started, restore).await
+                }
+                (None, Some(address)) => {
+                    let restore = run_restore_export(
+                        &args, &fetcher, &from, &address, dry_run, snapshot, wait,
+                    );
+                    with_deadline(&args, started, restore).await
+                }
+                (_, None) => unreachable!("--ip is required unless --rollback is given"),
+            }
+        }
+        Command::Prune(policy) => run_prune(&args, &policy),
+        Command::Diff { old, new, live } => match (old, new) {
+            (Some(old), Some(new)) if !live => run_diff(&args, &old, &new),
+            _ => {
+                let diff = run_diff_live(&args, &fetcher);
+                with_deadline(&args, started, diff).await
+            }
+        },
+        Command::Verify {
+            manifest,
+            dir,
+            max_age,
+        } => run_verify(&args, manifest.as_deref(), dir.as_deref(), max_age),
+        Command::Completions { shell } => run_completions(&args, shell),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args_defaults() {
+        let args = Args::parse_from(["test"]);
+        assert_eq!(args.out_dir, PathBuf::from("."));
+        assert_eq!(args.search_secs, 4);
+        assert_eq!(args.verbose, 0);
+        assert_eq!(args.layout, LayoutKind::Flat);
+        assert_eq!(args.command, None);
+        assert!(args.devices.is_empty());
+        assert!(!args.discover);
+        assert_eq!(args.config, None);
+        assert_eq!(args.retries, 2);
+        assert_eq!(args.retry_delay_ms, 500);
+        assert_eq!(args.http_timeout_secs, 30);
+        assert_eq!(args.deadline_secs, None);
+    }
+
+    #[test]
+    fn test_args_timeouts() {
+        let args = Args::parse_from(["test", "--http-timeout-secs", "5", "--deadline-secs", "120"]);
+        assert_eq!(args.http_timeout_secs, 5);
+        assert_eq!(args.deadline_secs, Some(120));
+    }
+
+    #[tokio::test]
+    async fn test_within_deadline() {
+        let args = Args::parse_from(["test", "--deadline-secs", "0"]);
+        let late = within_deadline(&args, Instant::now(), std::future::pending::<()>()).await;
+        assert!(matches!(late, Err(BackupError::Deadline(0))));
+
+        let args = Args::parse_from(["test"]);
+        assert!(
+            within_deadline(&args, Instant::now(), async {})
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_backup_bad_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path().to_str().unwrap();
+        let missing = dir.path().join("missing.toml");
+        let missing = missing.to_str().unwrap();
+
+        // Each fails the run instead of ending the process, so a daemon
+        // keeps its schedule.
+        for argv in [
+            vec!["test", "--out-dir", out_dir, "backup", "--fleet", missing],
+            vec!["test", "--out-dir", out_dir, "--config", missing, "backup"],
+            vec![
+                "test",
+                "--out-dir",
+                out_dir,
+                "backup",
+                "--discovery",
+                "nodes",
+            ],
+        ] {
+            let args = Args::parse_from(argv);
+            let Some(Command::Backup(backup_args)) = &args.command else {
+                panic!("Expected backup");
+            };
+            let fetcher = Fetcher::default();
+            let exit_code = run_backup(&args, Instant::now(), &fetcher, backup_args).await;
+            assert_eq!(exit_code, 1);
+        }
+    }
+
+    #[test]
+    fn test_args_retries() {
+        let args = Args::parse_from(["test", "--retries", "5", "--retry-delay-ms", "100"]);
+        assert_eq!(args.retries, 5);
+        assert_eq!(args.retry_delay_ms, 100);
+    }
+
+    #[test]
+    fn test_args_config() {
+        let args = Args::parse_from(["test", "backup", "--config", "devices.toml"]);
+        assert_eq!(args.config, Some(PathBuf::from("devices.toml")));
+    }
+
+    #[test]
+    fn test_args_custom() {
+        let args = Args::parse_from(["test", "--out-dir", "mydir", "--search-secs", "10"]);
+        assert_eq!(args.out_dir, PathBuf::from("mydir"));
+        assert_eq!(args.search_secs, 10);
+    }
+
+    #[test]
+    fn test_args_global_flags_after_subcommand() {
+        let args = Args::parse_from(["test", "backup", "--out-dir", "mydir", "-s", "10", "-vv"]);
+        assert_eq!(args.out_dir, PathBuf::from("mydir"));
+        assert_eq!(args.search_secs, 10);
+        assert_eq!(args.verbose, 2);
+        assert_eq!(args.command, Some(Command::Backup(BackupArgs::default())));
+    }
+
+    #[test]
+    fn test_completions() {
+        let args = Args::parse_from(["test", "completions", "zsh"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Completions { shell: Shell::Zsh })
+        );
+
+        let script = completion_script(Shell::Bash, "wled-backup", None);
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("install-systemd"), "{script}");
+        assert!(!script.contains("porch"));
+
+        let inventory = Inventory::parse(
+            "[devices.porch]\nhost = \"192.168.5.20\"\naliases = [\"wled-porch\"]\n[devices.garden]",
+        )
+        .unwrap();
+        let script = completion_script(Shell::Bash, "wled-backup", Some(&inventory));
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("192.168.5.20"), "{script}");
+        assert!(script.contains("garden porch wled-porch"), "{script}");
+    }
+
+    #[test]
+    fn test_args_pin() {
+        let args = Args::parse_from([
+            "test",
+            "--pin",
+            "1234",
+            "--http-user",
+            "wled",
+            "--http-password",
+            "hunter2",
+        ]);
+        assert_eq!(
+            device_options(&args),
+            DeviceOptions {
+                pin: Some("1234".to_string()),
+                auth: Some(BasicAuth {
+                    user: "wled".to_string(),
+                    password: Some("hunter2".to_string()),
+                }),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            device_options(&Args::parse_from(["test"])),
+            DeviceOptions::default()
+        );
+        assert!(Args::try_parse_from(["test", "--http-password", "hunter2"]).is_err());
+    }
+
+    #[test]
+    fn test_args_settings() {
+        let settings = Settings::parse(
+            "out_dir = \"/backup/wled\"\nretries = 4\ntimestamped = true\nwebhook_url = \"http://mon/hook\"",
+        )
+        .unwrap();
+        let args = parse_args(&settings, ["test"]).unwrap();
+        assert_eq!(args.out_dir, PathBuf::from("/backup/wled"));
+        assert_eq!(args.retries, 4);
+        let Some(Command::Backup(backup_args)) = args.command else {
+            panic!("Expected backup");
+        };
+        assert!(backup_args.timestamped);
+        assert_eq!(backup_args.webhook_url.as_deref(), Some("http://mon/hook"));
+
+        let args = parse_args(&settings, ["test", "--retries", "1", "list"]).unwrap();
+        assert_eq!(args.retries, 1);
+        assert_eq!(args.command, Some(Command::List));
+
+        let settings = Settings::parse("out_directory = \"/backup\"").unwrap();
+        assert!(parse_args(&settings, ["test"]).is_err());
+    }
+
+    #[test]
+    fn test_args_secret_env() {
+        // SAFETY: no other test reads or sets WLED_PIN.
+        unsafe { std::env::set_var("WLED_PIN", "1234") };
+        let args = parse_args(&Settings::default(), ["test"]).unwrap();
+        unsafe { std::env::remove_var("WLED_PIN") };
+        assert_eq!(args.pin.as_deref(), Some("1234"));
+    }
 
-fn discover_wleds(search_duration: std::time::Duration) -> Vec<ServiceInfo> {
-    let mut wleds = HashMap::new();
+    #[test]
+    fn test_args_logging() {
+        let args = Args::parse_from(["test", "-q", "--log-file", "backup.log", "--log-json"]);
+        assert_eq!(args.quiet, 1);
+        assert_eq!(args.log_file, Some(PathBuf::from("backup.log")));
+        assert!(args.log_json);
 
-    // Create a daemon
-    let mdns = ServiceDaemon::new().expect("Failed to create daemon");
+        assert_eq!(log_level(0, 0), LevelFilter::INFO);
+        assert_eq!(log_level(1, 0), LevelFilter::DEBUG);
+        assert_eq!(log_level(3, 0), LevelFilter::TRACE);
+        assert_eq!(log_level(0, 1), LevelFilter::WARN);
+        assert_eq!(log_level(0, 2), LevelFilter::ERROR);
+        assert_eq!(log_level(1, 1), LevelFilter::INFO);
+    }
 
-    // Browse for a service type.
-    let service_type = "_wled._tcp.local.";
-    let receiver = mdns.browse(service_type).expect("Failed to browse");
+    #[test]
+    fn test_args_jobs() {
+        let args = Args::parse_from(["test", "backup", "--jobs", "8"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                jobs: 8,
+                ..Default::default()
+            }))
+        );
+        assert!(Args::try_parse_from(["test", "backup", "--jobs", "0"]).is_err());
+    }
 
-    while let Ok(event) = receiver.recv_timeout(search_duration) {
-        match event {
-            ServiceEvent::ServiceResolved(info) => {
-                // Sometimes we get multiple responses for the same device. We use the
-                // HashMap as we way to deduplicate them based on hostname.
-                if !wleds.contains_key(&info.get_hostname().to_string()) {
-                    wleds.insert(info.get_hostname().to_string(), info.clone());
-                    println!("Discovered: {}", info.get_fullname());
-                }
-            }
-            _other_event => {}
-        }
+    #[test]
+    fn test_args_fail_fast() {
+        assert!(!BackupArgs::default().fail_fast);
+        let args = Args::parse_from(["test", "backup", "--fail-fast"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                fail_fast: true,
+                ..Default::default()
+            }))
+        );
     }
 
-    wleds.into_values().collect()
-}
+    #[test]
+    fn test_args_timestamped() {
+        assert!(!BackupArgs::default().timestamped);
 
-fn get_hostname_from_cfg(cfg_json: &Value) -> Result<&str, Box<dyn std::error::Error>> {
-    let hostname = cfg_json
-        .get("id")
-        .ok_or_else(|| "Missing 'id' field in cfg.json")?
-        .get("name")
-        .ok_or_else(|| "Missing 'name' field in cfg.json")?
-        .as_str()
-        .ok_or_else(|| "Expected 'name' to be a string in cfg.json")?;
+        let args = Args::parse_from(["test", "backup", "--timestamped"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                timestamped: true,
+                ..Default::default()
+            }))
+        );
+    }
 
-    if hostname.trim().is_empty() {
-        return Err("Hostname is empty or contains only whitespace".into());
+    #[test]
+    fn test_args_layout() {
+        let args = Args::parse_from(["test", "--layout", "per-device"]);
+        assert_eq!(args.layout, LayoutKind::PerDevice);
+        assert!(Args::try_parse_from(["test", "--layout", "nested"]).is_err());
     }
 
-    Ok(hostname)
-}
+    #[test]
+    fn test_args_official_format() {
+        assert!(!make_layout(&Args::parse_from(["test"])).official_names);
+        let args = Args::parse_from([
+            "test",
+            "restore",
+            "porch",
+            "--ip",
+            "10.0.0.1",
+            "--official-format",
+        ]);
+        assert!(make_layout(&args).official_names);
+    }
 
-fn backup_wled(
-    ip: &IpAddr,
-    port: u16,
-    out_dir: &PathBuf,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let url_cfg = format!("http://{ip}:{port}/cfg.json");
-    let url_presets = format!("http://{ip}:{port}/presets.json");
+    #[test]
+    fn test_args_dry_run() {
+        assert!(!BackupArgs::default().dry_run);
+        let args = Args::parse_from(["test", "backup", "--dry-run"]);
+        let Some(Command::Backup(backup_args)) = args.command else {
+            panic!("Expected backup");
+        };
+        assert!(backup_args.dry_run);
+    }
 
-    let cfg_response_str = reqwest::blocking::get(url_cfg)?.text()?;
-    let cfg_json: Value = serde_json::from_str(&cfg_response_str)?;
+    #[test]
+    fn test_args_force() {
+        assert!(!BackupArgs::default().force);
+        let args = Args::parse_from(["test", "backup", "--force"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                force: true,
+                ..Default::default()
+            }))
+        );
+    }
 
-    let hostname = get_hostname_from_cfg(&cfg_json)?;
+    #[test]
+    fn test_args_endpoints() {
+        let args = Args::parse_from(["test", "backup", "--endpoints", "info,state", "-e", "pal"]);
+        let Some(Command::Backup(backup_args)) = args.command else {
+            panic!("Expected backup");
+        };
+        assert_eq!(
+            backup_args.endpoints,
+            vec![Endpoint::Info, Endpoint::State, Endpoint::Pal]
+        );
+        assert!(Args::try_parse_from(["test", "backup", "--endpoints", "nodes"]).is_err());
+    }
 
-    println!("  host name: {hostname}");
+    #[test]
+    fn test_args_only() {
+        let args = Args::parse_from(["test", "backup", "--only", "cfg,state"]);
+        let Some(Command::Backup(backup_args)) = args.command else {
+            panic!("Expected backup");
+        };
+        assert_eq!(backup_args.only, vec![Part::Cfg, Part::State]);
+        assert!(Args::try_parse_from(["test", "backup", "--only", "cfg", "--full-fs"]).is_err());
+    }
 
-    // Save out cfg.json
-    let cfg_file_name = format!("{hostname}_cfg.json");
-    let cfg_path = out_dir.join(cfg_file_name.clone());
-    let mut cfg_file = File::create(cfg_path.to_str().unwrap())?;
-    cfg_file.write_all(cfg_response_str.as_bytes())?;
-    cfg_file.flush()?;
-    println!("  saved: {cfg_file_name}");
+    #[test]
+    fn test_args_full_fs() {
+        assert!(!BackupArgs::default().full_fs);
+        let args = Args::parse_from(["test", "backup", "--full-fs"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                full_fs: true,
+                ..Default::default()
+            }))
+        );
+    }
 
-    // Save out presets.json
-    let presets_file_name = format!("{hostname}_presets.json");
-    let mut presets_response = reqwest::blocking::get(url_presets)?;
-    let presets_path = out_dir.join(presets_file_name.clone());
-    let mut presets_file = File::create(presets_path)?;
-    copy(&mut presets_response, &mut presets_file)?;
-    println!("  saved: {presets_file_name}");
+    #[test]
+    fn test_args_verify_backup() {
+        let args = Args::parse_from(["test", "backup", "--verify"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                verify: true,
+                ..Default::default()
+            }))
+        );
+    }
 
-    Ok(())
-}
+    #[test]
+    fn test_args_git() {
+        let args = Args::parse_from(["test", "backup", "--git-commit", "--git-push"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                git_commit: true,
+                git_push: true,
+                ..Default::default()
+            }))
+        );
+        assert!(Args::try_parse_from(["test", "backup", "--git-push"]).is_err());
+    }
 
-fn backup_wleds(
-    wleds: Vec<ServiceInfo>,
-    out_dir: &PathBuf,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut final_result = Ok(());
+    #[test]
+    fn test_args_stream() {
+        let args =
+            Args::try_parse_from(["test", "backup", "--stream", "--dest", "s3://backups/wled"])
+                .unwrap();
+        let Some(Command::Backup(backup_args)) = args.command else {
+            panic!("not a backup");
+        };
+        assert!(backup_args.stream);
 
-    for wled in wleds.iter() {
-        if let Some(ip) = wled.get_addresses().iter().next() {
-            println!("Backing up {}", wled.get_hostname());
-            if let Err(result) = backup_wled(&ip, wled.get_port(), out_dir) {
-                println!("  FAILED: {result}");
-                final_result = Err(result);
-            }
-            println!("  SUCCESS");
-        }
+        // Streaming needs somewhere to stream to, and files left as they are.
+        assert!(Args::try_parse_from(["test", "backup", "--stream"]).is_err());
+        assert!(
+            Args::try_parse_from(["test", "backup", "--stream", "--dest", "/mnt", "--pretty"])
+                .is_err()
+        );
     }
 
-    final_result
-}
+    #[test]
+    fn test_args_dest() {
+        let args = Args::parse_from([
+            "test",
+            "backup",
+            "--dest",
+            "s3://backups/wled",
+            "--s3-endpoint",
+            "http://minio:9000",
+            "--s3-access-key-id",
+            "key",
+            "--s3-secret-access-key",
+            "secret",
+            "--s3-region",
+            "eu-west-1",
+        ]);
+        let Some(Command::Backup(backup_args)) = args.command else {
+            panic!("not a backup: {:?}", args.command);
+        };
+        assert_eq!(
+            backup_args.dest,
+            vec![Destination::S3 {
+                bucket: "backups".to_string(),
+                prefix: "wled".to_string()
+            }]
+        );
+        let s3 = s3_options(&backup_args).unwrap();
+        assert_eq!(s3.endpoint.as_deref(), Some("http://minio:9000"));
+        assert_eq!(s3.region, "eu-west-1");
+        assert_eq!(s3.access_key_id, "key");
+        assert_eq!(s3.secret_access_key, "secret");
 
-fn main() {
-    let args = Args::parse();
+        assert!(Args::try_parse_from(["test", "backup", "--dest", "ftp://nas"]).is_err());
 
-    if !args.out_dir.exists() {
-        std::fs::create_dir_all(&args.out_dir).expect("Failed to create output directory");
+        let args = Args::parse_from([
+            "test",
+            "backup",
+            "--dest",
+            "/mnt/usb/wled",
+            "--dest",
+            "sftp://backup@nas/volume1/wled",
+            "--sftp-identity",
+            "/root/.ssh/nas",
+        ]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                dest: vec![
+                    Destination::Local {
+                        path: PathBuf::from("/mnt/usb/wled")
+                    },
+                    Destination::Sftp {
+                        user: Some("backup".to_string()),
+                        host: "nas".to_string(),
+                        port: None,
+                        path: "/volume1/wled".to_string(),
+                    }
+                ],
+                sftp_identity: Some(PathBuf::from("/root/.ssh/nas")),
+                ..Default::default()
+            }))
+        );
     }
 
-    println!(
-        "Saving backups to {:?}, searching for {} seconds...",
-        args.out_dir, args.search_secs
-    );
-
-    let wleds = discover_wleds(std::time::Duration::from_secs(args.search_secs));
+    #[test]
+    fn test_args_webhook() {
+        let args = Args::parse_from(["test", "backup", "--webhook-url", "http://mon/hook"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                webhook_url: Some("http://mon/hook".to_string()),
+                ..Default::default()
+            }))
+        );
 
-    if let Err(_result) = backup_wleds(wleds, &args.out_dir) {
-        std::process::exit(1);
+        let args = Args::parse_from([
+            "test",
+            "backup",
+            "--healthcheck-url",
+            "https://hc-ping.com/x",
+        ]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                healthcheck_url: Some("https://hc-ping.com/x".to_string()),
+                ..Default::default()
+            }))
+        );
     }
 
-    println!("Finished");
-}
+    #[test]
+    fn test_args_mqtt() {
+        let backup_args = BackupArgs::default();
+        assert_eq!(backup_args.mqtt_prefix, "wled_backup");
+        assert_eq!(backup_args.mqtt_discovery_prefix, "homeassistant");
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::json;
-    use std::fs;
-    use std::net::Ipv4Addr;
-    use std::thread;
-    use std::vec;
-    use tempfile::tempdir;
-    use tiny_http::{Response, Server};
-
-    // Mock ServiceInfo for testing
-    fn mock_service_info(name: &str, ip: &str, port: u16) -> ServiceInfo {
-        ServiceInfo::new("_wled._tcp.local.", name, name, ip, port, None).unwrap()
-    }
-
-    fn cfg_body(hostname: &str) -> String {
-        format!(r#"{{"id":{{"name":"{}"}}}}"#, hostname)
-    }
-
-    fn mock_wled_server(
-        addr: &str,
-        cfg_body: &str,
-        presets_body: Option<&str>,
-    ) -> thread::JoinHandle<()> {
-        // Start server in a background thread
-
-        let cfg_body = cfg_body.to_string();
-        let presets_body = presets_body.map(|s| s.to_string());
-
-        let server = Server::http(addr).unwrap();
-        let handle = thread::spawn(move || {
-            let max_requests = if presets_body.is_some() { 2 } else { 1 };
-
-            for _ in 0..max_requests {
-                if let Ok(request) = server.recv() {
-                    let url = request.url();
-                    let response = if url.ends_with("/cfg.json") {
-                        Response::from_string(cfg_body.clone())
-                        // .with_header("Content-Type: application/json".parse().unwrap())
-                    } else if url.ends_with("/presets.json") {
-                        if let Some(ref presets) = presets_body {
-                            Response::from_string(presets.clone())
-                            // .with_header("Content-Type: application/json".parse().unwrap())
-                        } else {
-                            Response::from_string("not found").with_status_code(404)
-                        }
-                    } else {
-                        Response::from_string("not found").with_status_code(404)
-                    };
-                    let _ = request.respond(response);
-                }
-            }
-        });
+        let args = Args::parse_from(["test", "backup", "--mqtt-url", "mqtt://broker"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                mqtt_url: Some("mqtt://broker".to_string()),
+                ..Default::default()
+            }))
+        );
+        assert!(Args::try_parse_from(["test", "backup", "--mqtt-prefix", "wled"]).is_err());
+    }
 
-        handle
+    #[test]
+    fn test_args_metrics() {
+        let args = Args::parse_from([
+            "test",
+            "backup",
+            "--metrics-file",
+            "/var/lib/node_exporter/wled_backup.prom",
+            "--pushgateway-url",
+            "http://pushgateway:9091",
+        ]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                metrics_file: Some(PathBuf::from("/var/lib/node_exporter/wled_backup.prom")),
+                pushgateway_url: Some("http://pushgateway:9091".to_string()),
+                ..Default::default()
+            }))
+        );
     }
 
-    fn validate_response_file(expected_file: PathBuf, expected_content: &str) {
-        assert!(expected_file.exists());
-        let contents = fs::read_to_string(expected_file).unwrap();
-        assert_eq!(contents, expected_content);
+    #[test]
+    fn test_args_notify() {
+        let args = Args::parse_from([
+            "test",
+            "backup",
+            "--ntfy",
+            "https://ntfy.sh/wled",
+            "--discord-webhook",
+            "https://discord.com/api/webhooks/1",
+            "--notify-when",
+            "always",
+        ]);
+        let Some(Command::Backup(backup_args)) = &args.command else {
+            panic!("{:?}", args.command);
+        };
+        let notify = notify_config(&args, backup_args);
+        assert_eq!(notify.backends().len(), 2);
+        assert!(notify.should_notify(0));
+        assert!(Args::try_parse_from(["test", "backup", "--notify-when", "never"]).is_err());
     }
 
-    fn validate_response_files(out_dir: &PathBuf, hostname: &str) {
-        let cfg_path = out_dir.join(format!("{hostname}_cfg.json"));
-        let presets_path = out_dir.join(format!("{hostname}_presets.json"));
+    #[test]
+    fn test_args_daemon() {
+        let args = Args::parse_from(["test", "daemon", "--interval", "6h", "--pretty"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Daemon(DaemonArgs {
+                interval: Some(chrono::Duration::hours(6)),
+                cron: None,
+                backup: BackupArgs {
+                    pretty: true,
+                    ..Default::default()
+                },
+            }))
+        );
+
+        let args = Args::parse_from(["test", "daemon", "--cron", "0 3 * * *"]);
+        let Some(Command::Daemon(daemon_args)) = args.command else {
+            panic!("expected daemon");
+        };
+        assert_eq!(daemon_args.cron, Some("0 3 * * *".parse().unwrap()));
 
-        validate_response_file(cfg_path, &cfg_body(hostname));
-        validate_response_file(presets_path, "presets data");
+        assert!(Args::try_parse_from(["test", "daemon"]).is_err());
+        assert!(Args::try_parse_from(["test", "daemon", "--cron", "0 3 * *"]).is_err());
+        let both = ["test", "daemon", "--interval", "1d", "--cron", "0 3 * * *"];
+        assert!(Args::try_parse_from(both).is_err());
     }
 
     #[test]
-    fn test_get_hostname_from_cfg_success() {
-        let cfg = json!({
-            "id": {
-                "name": "test_device"
-            }
-        });
+    fn test_args_install_systemd() {
+        let args = Args::parse_from(["test", "install-systemd", "--pretty"]);
+        assert_eq!(
+            args.command,
+            Some(Command::InstallSystemd {
+                interval: chrono::Duration::days(1),
+                install: false,
+                backup: BackupArgs {
+                    pretty: true,
+                    ..Default::default()
+                },
+            })
+        );
 
-        let result = get_hostname_from_cfg(&cfg);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), "test_device");
+        let args = Args::parse_from(["test", "install-systemd", "--interval", "6h", "--install"]);
+        let Some(Command::InstallSystemd {
+            interval, install, ..
+        }) = args.command
+        else {
+            panic!("expected install-systemd");
+        };
+        assert_eq!(interval, chrono::Duration::hours(6));
+        assert!(install);
     }
 
     #[test]
-    fn test_get_hostname_from_cfg_missing_id() {
-        let cfg = json!({
-            "other": "value"
-        });
+    fn test_args_lock_wait() {
+        assert_eq!(BackupArgs::default().lock_wait_secs, 0);
+        let args = Args::parse_from(["test", "backup", "--lock-wait-secs", "600"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                lock_wait_secs: 600,
+                ..Default::default()
+            }))
+        );
+    }
 
-        let result = get_hostname_from_cfg(&cfg);
-        assert!(result.is_err());
+    #[test]
+    fn test_args_on_collision() {
+        let args = Args::parse_from(["test", "backup", "--on-collision", "suffix-ip"]);
         assert_eq!(
-            result.unwrap_err().to_string(),
-            "Missing 'id' field in cfg.json"
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                on_collision: Collision::SuffixIp,
+                ..Default::default()
+            }))
         );
+        assert_eq!(BackupArgs::default().on_collision, Collision::SuffixMac);
     }
 
     #[test]
-    fn test_get_hostname_from_cfg_missing_name() {
-        let cfg = json!({
-            "id": {
-                "other": "value"
-            }
-        });
+    fn test_args_secrets() {
+        let args = Args::parse_from(["test", "backup", "--separate-secrets"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                separate_secrets: true,
+                ..Default::default()
+            }))
+        );
+        assert!(
+            Args::try_parse_from(["test", "backup", "--separate-secrets", "--redact-secrets"])
+                .is_err()
+        );
+    }
 
-        let result = get_hostname_from_cfg(&cfg);
-        assert!(result.is_err());
+    #[test]
+    fn test_args_compress() {
+        let args = Args::parse_from(["test", "backup", "--compress", "zstd"]);
         assert_eq!(
-            result.unwrap_err().to_string(),
-            "Missing 'name' field in cfg.json"
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                compress: Some(Compression::Zstd),
+                ..Default::default()
+            }))
         );
+        assert!(Args::try_parse_from(["test", "backup", "--compress", "xz"]).is_err());
     }
 
     #[test]
-    fn test_get_hostname_from_cfg_name_not_string() {
-        let cfg = json!({
-            "id": {
-                "name": 123
-            }
-        });
+    fn test_args_encrypt() {
+        let key = "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p";
+        let args = Args::parse_from(["test", "backup", "--encrypt-recipient", key]);
+        let Some(Command::Backup(backup_args)) = args.command else {
+            panic!("Expected backup");
+        };
+        assert_eq!(backup_args.encrypt_recipient, vec![key.parse().unwrap()]);
+        assert!(make_encryption(&backup_args).unwrap().is_some());
+        assert!(make_encryption(&BackupArgs::default()).unwrap().is_none());
 
-        let result = get_hostname_from_cfg(&cfg);
-        assert!(result.is_err());
+        assert!(Args::try_parse_from(["test", "backup", "--encrypt-recipient", "x"]).is_err());
+        let args = Args::parse_from(["test", "diff", "a", "b", "--identity", "key.txt"]);
+        assert_eq!(args.identity, vec![PathBuf::from("key.txt")]);
+    }
+
+    #[test]
+    fn test_args_archive() {
+        let args = Args::parse_from(["test", "backup", "--archive", "tar.gz"]);
         assert_eq!(
-            result.unwrap_err().to_string(),
-            "Expected 'name' to be a string in cfg.json"
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                archive: Some(ArchiveFormat::TarGz),
+                ..Default::default()
+            }))
         );
+
+        let args = Args::parse_from([
+            "test",
+            "backup",
+            "--archive",
+            "zip",
+            "--archive-scope",
+            "run",
+        ]);
+        let Some(Command::Backup(backup_args)) = args.command else {
+            panic!("Expected backup");
+        };
+        assert_eq!(backup_args.archive, Some(ArchiveFormat::Zip));
+        assert_eq!(backup_args.archive_scope, ArchiveScope::Run);
+
+        assert!(Args::try_parse_from(["test", "backup", "--archive", "rar"]).is_err());
+        assert!(Args::try_parse_from(["test", "backup", "--archive-scope", "run"]).is_err());
     }
 
     #[test]
-    fn test_get_hostname_from_cfg_empty_hostname() {
-        let cfg = json!({
-            "id": {
-                "name": ""
-            }
-        });
+    fn test_args_expect() {
+        assert_eq!(BackupArgs::default().expect, None);
+        let args = Args::parse_from(["test", "backup", "--expect", "5"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Backup(BackupArgs {
+                expect: Some(5),
+                ..Default::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn test_args_fleet() {
+        let args = Args::parse_from(["test", "backup", "--fleet", "fleet.toml"]);
+        let Some(Command::Backup(backup_args)) = args.command else {
+            panic!("Expected backup");
+        };
+        assert_eq!(backup_args.fleet, Some(PathBuf::from("fleet.toml")));
+    }
+
+    #[test]
+    fn test_args_filter() {
+        let args = Args::parse_from([
+            "test",
+            "backup",
+            "--include",
+            "garden-*",
+            "--include",
+            "porch",
+            "--exclude",
+            "*-bench",
+        ]);
+        let Some(Command::Backup(backup_args)) = args.command else {
+            panic!("Expected backup");
+        };
+        assert_eq!(backup_args.filter.include, vec!["garden-*", "porch"]);
+        assert_eq!(backup_args.filter.exclude, vec!["*-bench"]);
 
-        let result = get_hostname_from_cfg(&cfg);
-        assert!(result.is_err());
+        let args = Args::parse_from([
+            "test",
+            "backup",
+            "--include-net",
+            "192.168.10.0/24",
+            "--exclude-net",
+            "10.0.0.0/8",
+        ]);
+        let Some(Command::Backup(backup_args)) = args.command else {
+            panic!("Expected backup");
+        };
         assert_eq!(
-            result.unwrap_err().to_string(),
-            "Hostname is empty or contains only whitespace"
+            backup_args.filter.include_net[0].to_string(),
+            "192.168.10.0/24"
         );
+        assert_eq!(backup_args.filter.exclude_net[0].prefix, 8);
+        assert!(Args::try_parse_from(["test", "backup", "--include-net", "garden"]).is_err());
     }
 
     #[test]
-    fn test_get_hostname_from_cfg_whitespace_only_hostname() {
-        let cfg = json!({
-            "id": {
-                "name": "   \t\n  "
+    fn test_args_retention() {
+        let args = Args::parse_from([
+            "test",
+            "backup",
+            "-t",
+            "--keep-last",
+            "7",
+            "--keep-days",
+            "30",
+        ]);
+        let Some(Command::Backup(backup_args)) = args.command else {
+            panic!("Expected backup");
+        };
+        assert_eq!(
+            backup_args.retention,
+            RetentionPolicy {
+                keep_last: Some(7),
+                keep_days: Some(30),
             }
-        });
+        );
 
-        let result = get_hostname_from_cfg(&cfg);
-        assert!(result.is_err());
+        let args = Args::parse_from(["test", "prune", "--keep-last", "3"]);
         assert_eq!(
-            result.unwrap_err().to_string(),
-            "Hostname is empty or contains only whitespace"
+            args.command,
+            Some(Command::Prune(RetentionPolicy {
+                keep_last: Some(3),
+                keep_days: None,
+            }))
         );
     }
 
     #[test]
-    fn test_get_hostname_from_cfg_hostname_with_whitespace() {
-        let cfg = json!({
-            "id": {
-                "name": "  test_device  "
-            }
-        });
+    fn test_args_devices() {
+        let args = Args::parse_from([
+            "test",
+            "--device",
+            "192.168.5.20",
+            "-d",
+            "wled-porch.lan:8080",
+            "--discover",
+        ]);
+        assert_eq!(
+            args.devices,
+            vec![
+                "192.168.5.20".parse::<DeviceSpec>().unwrap(),
+                "wled-porch.lan:8080".parse::<DeviceSpec>().unwrap(),
+            ]
+        );
+        assert!(args.discover);
+    }
+
+    #[test]
+    fn test_args_cache_devices() {
+        assert!(!Args::parse_from(["test"]).cache_devices);
+        let args = Args::parse_from(["test", "backup", "--cache-devices"]);
+        assert!(args.cache_devices);
+    }
 
-        let result = get_hostname_from_cfg(&cfg);
-        assert!(result.is_ok());
-        assert_eq!(result.unwrap(), "  test_device  ");
+    #[test]
+    fn test_args_invalid_device() {
+        assert!(Args::try_parse_from(["test", "--device", "wled:port"]).is_err());
     }
 
     #[test]
-    fn test_backup_wled_creates_file() {
-        // Start server in a background thread
-        let servers = vec![mock_wled_server(
-            "127.0.0.1:88",
-            &cfg_body("testwled"),
-            Some("presets data"),
-        )];
+    fn test_args_restore() {
+        let args = Args::parse_from(["test", "restore", "porch", "--ip", "192.168.1.20"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Restore {
+                name: Some("porch".to_string()),
+                all: false,
+                batch: 1,
+                pause_secs: 10,
+                keep_going: false,
+                from: vec![],
+                ip: Some("192.168.1.20".parse().unwrap()),
+                port: 80,
+                secrets: None,
+                dry_run: false,
+                reboot_timeout_secs: 120,
+                no_wait: false,
+                force: false,
+                no_snapshot: false,
+                rollback: false,
+            })
+        );
+
+        let args = Args::parse_from(["test", "restore", "porch", "--ip", "10.0.0.5", "--dry-run"]);
+        let Some(Command::Restore { dry_run, .. }) = args.command else {
+            panic!("Expected restore");
+        };
+        assert!(dry_run);
+    }
 
-        // Use a temp directory
-        let dir = tempdir().unwrap();
-        let out_dir = dir.path().to_path_buf();
+    #[test]
+    fn test_args_restore_from() {
+        let args = Args::parse_from([
+            "test",
+            "restore",
+            "--from",
+            "wled_cfg.json",
+            "--from",
+            "wled_presets.json",
+            "--ip",
+            "192.168.1.20",
+        ]);
+        let Some(Command::Restore { name, from, .. }) = args.command else {
+            panic!("Expected restore");
+        };
+        assert_eq!(name, None);
+        assert_eq!(from.len(), 2);
 
-        // Perform the backup.
-        let backup_wled = backup_wled(
-            &IpAddr::V4("127.0.0.1".parse::<Ipv4Addr>().unwrap()),
-            88,
-            &out_dir,
+        assert!(Args::try_parse_from(["test", "restore", "--ip", "192.168.1.20"]).is_err());
+        assert!(
+            Args::try_parse_from([
+                "test",
+                "restore",
+                "porch",
+                "--from",
+                "x.json",
+                "--ip",
+                "192.168.1.20"
+            ])
+            .is_err()
         );
+    }
+
+    #[test]
+    fn test_args_restore_all() {
+        let args = Args::parse_from([
+            "test",
+            "restore",
+            "--all",
+            "--from",
+            "/backups/20261015T030000Z",
+            "--batch",
+            "3",
+            "--pause-secs",
+            "30",
+            "--continue",
+        ]);
+        let Some(Command::Restore {
+            all,
+            from,
+            batch,
+            pause_secs,
+            keep_going,
+            ..
+        }) = args.command
+        else {
+            panic!("Expected restore");
+        };
+        assert!(all && keep_going);
+        assert_eq!(from, [PathBuf::from("/backups/20261015T030000Z")]);
+        assert_eq!((batch, pause_secs), (3, 30));
 
-        assert!(backup_wled.is_ok(), "Backup failed");
+        assert!(Args::try_parse_from(["test", "restore", "--all", "--ip", "10.0.0.5"]).is_err());
+        assert!(Args::try_parse_from(["test", "restore", "--all", "--batch", "0"]).is_err());
+    }
 
-        // Check that the file exists
-        validate_response_files(&out_dir, "testwled");
+    #[test]
+    fn test_saved_address() {
+        let dir = tempfile::tempdir().unwrap();
+        let out_dir = dir.path().to_str().unwrap();
+        let args = Args::parse_from(["test", "--out-dir", out_dir]);
+        let layout = make_layout(&args);
+        let meta = DeviceMeta {
+            hostname: "porch".to_string(),
+            address: "192.168.5.20".to_string(),
+            port: 8080,
+            ..Default::default()
+        };
+        let path = layout.file_path("porch", META_FILE);
+        std::fs::write(path, serde_json::to_vec(&meta).unwrap()).unwrap();
 
-        // Shutdown the server
-        for handle in servers {
-            handle.join().unwrap();
-        }
+        assert_eq!(
+            saved_address(&args, &layout, "porch").unwrap(),
+            "192.168.5.20:8080".parse().unwrap()
+        );
+        assert!(saved_address(&args, &layout, "garden").is_err());
     }
 
     #[test]
-    fn test_backup_wleds_creates_files() {
-        // TODO: Add IP V6 test case.
+    fn test_args_preset() {
+        let args = Args::parse_from([
+            "test",
+            "preset",
+            "export",
+            "--device",
+            "porch",
+            "--id",
+            "5",
+            "--out",
+            "ocean.json",
+        ]);
+        assert_eq!(
+            args.command,
+            Some(Command::Preset {
+                preset: PresetCommand::Export {
+                    id: 5,
+                    out: Some(PathBuf::from("ocean.json")),
+                }
+            })
+        );
 
-        // Start server in a background thread
-        let servers = vec![
-            mock_wled_server("127.0.0.1:80", &cfg_body("testwled"), Some("presets data")),
-            mock_wled_server(
-                "127.0.0.1:8080",
-                &cfg_body("testwled_port"),
-                Some("presets data"),
-            ),
-        ];
+        let args = Args::parse_from(["test", "preset", "import", "--id", "7", "--from", "o.json"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Preset {
+                preset: PresetCommand::Import {
+                    id: 7,
+                    from: PathBuf::from("o.json"),
+                    force: false,
+                }
+            })
+        );
 
-        // Prepare mock WLED device
-        let wleds = vec![
-            mock_service_info("mdns_name", "127.0.0.1", 80),
-            mock_service_info("mdns_name_port", "127.0.0.1", 8080),
-        ];
+        let args = Args::parse_from([
+            "test",
+            "preset",
+            "sync",
+            "--from",
+            "porch",
+            "--to",
+            "porch-2",
+            "porch-3:8080",
+            "--ids",
+            "1-10,12",
+            "--map",
+            "3=13",
+        ]);
+        let Some(Command::Preset {
+            preset: PresetCommand::Sync { to, ids, map, .. },
+        }) = args.command
+        else {
+            panic!("Expected preset sync");
+        };
+        assert_eq!(to.len(), 2);
+        assert_eq!(ids, ["1-10".parse().unwrap(), "12".parse().unwrap()]);
+        assert_eq!(map, [SlotMap { from: 3, to: 13 }]);
+
+        assert!(Args::try_parse_from(["test", "preset", "export", "--id", "0"]).is_err());
+        assert!(Args::try_parse_from(["test", "preset", "export", "--id", "251"]).is_err());
+    }
 
-        // Use a temp directory
-        let dir = tempdir().unwrap();
-        let out_dir = dir.path().to_path_buf();
+    #[test]
+    fn test_args_library() {
+        let args = Args::parse_from(["test", "library", "add", "ocean-wave", "--from", "o.json"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Library {
+                library_dir: None,
+                library: LibraryCommand::Add {
+                    name: "ocean-wave".to_string(),
+                    from: Some(PathBuf::from("o.json")),
+                    id: None,
+                },
+            })
+        );
 
-        // Perform the backup.
-        let backup_wleds = backup_wleds(wleds, &out_dir);
+        let args = Args::parse_from([
+            "test",
+            "library",
+            "apply",
+            "ocean-wave=5",
+            "fire=6",
+            "--library-dir",
+            "lib",
+        ]);
+        assert_eq!(
+            args.command,
+            Some(Command::Library {
+                library_dir: Some(PathBuf::from("lib")),
+                library: LibraryCommand::Apply {
+                    presets: vec!["ocean-wave=5".parse().unwrap(), "fire=6".parse().unwrap(),],
+                },
+            })
+        );
 
-        assert!(backup_wleds.is_ok(), "Backup failed");
+        assert!(Args::try_parse_from(["test", "library", "add", "ocean-wave"]).is_err());
+        assert!(Args::try_parse_from(["test", "library", "apply"]).is_err());
+        assert!(Args::try_parse_from(["test", "library", "apply", "ocean-wave"]).is_err());
+    }
 
-        // Check that the file exists
-        validate_response_files(&out_dir, "testwled");
-        validate_response_files(&out_dir, "testwled_port");
+    #[test]
+    fn test_args_apply_template() {
+        let args = Args::parse_from([
+            "test",
+            "apply-template",
+            "--template",
+            "ntp.json",
+            "--all",
+            "--dry-run",
+        ]);
+        assert_eq!(
+            args.command,
+            Some(Command::ApplyTemplate {
+                template: PathBuf::from("ntp.json"),
+                all: true,
+                dry_run: true,
+            })
+        );
+        assert!(Args::try_parse_from(["test", "apply-template"]).is_err());
+    }
 
-        // Shutdown the server
-        for handle in servers {
-            handle.join().unwrap();
-        }
+    #[test]
+    fn test_args_cmd() {
+        let args = Args::parse_from([
+            "test",
+            "cmd",
+            "--json",
+            r#"{"on":false}"#,
+            "--include",
+            "garden-*",
+        ]);
+        let Some(Command::Cmd {
+            json,
+            filter,
+            dry_run,
+        }) = args.command
+        else {
+            panic!("expected cmd, got {:?}", args.command);
+        };
+        assert_eq!(json, serde_json::json!({"on": false}));
+        assert_eq!(filter.include, ["garden-*"]);
+        assert!(!dry_run);
+        assert!(Args::try_parse_from(["test", "cmd", "--json", "off"]).is_err());
+        assert!(Args::try_parse_from(["test", "cmd"]).is_err());
     }
 
     #[test]
-    fn test_backup_wled_invalid_cfg_json_no_files_written() {
-        let servers = vec![mock_wled_server(
-            "127.0.0.1:89",
-            "invalid json content",
-            None,
-        )];
+    fn test_args_reboot() {
+        let args = Args::parse_from(["test", "reboot", "--exclude", "*-bench", "-y"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Reboot {
+                filter: DeviceFilter {
+                    exclude: vec!["*-bench".to_string()],
+                    ..Default::default()
+                },
+                yes: true,
+            })
+        );
+    }
 
-        let dir = tempdir().unwrap();
-        let out_dir = dir.path().to_path_buf();
+    #[test]
+    fn test_args_state() {
+        let args = Args::parse_from(["test", "state", "snapshot", "--preset", "250"]);
+        assert_eq!(
+            args.command,
+            Some(Command::State {
+                state_dir: None,
+                state: StateCommand::Snapshot {
+                    filter: DeviceFilter::default(),
+                    preset: Some(250),
+                },
+            })
+        );
+        assert!(Args::try_parse_from(["test", "state", "snapshot", "--preset", "0"]).is_err());
 
-        let backup_result = backup_wled(
-            &IpAddr::V4("127.0.0.1".parse::<Ipv4Addr>().unwrap()),
-            89,
-            &out_dir,
+        let args = Args::parse_from(["test", "state", "--state-dir", "saved", "restore"]);
+        assert_eq!(
+            args.command,
+            Some(Command::State {
+                state_dir: Some(PathBuf::from("saved")),
+                state: StateCommand::Restore {
+                    filter: DeviceFilter::default(),
+                    dry_run: false,
+                },
+            })
         );
+    }
 
+    #[test]
+    fn test_args_ota() {
+        let args = Args::parse_from([
+            "test",
+            "ota",
+            "--firmware",
+            "WLED_0.15.0_ESP32.bin",
+            "--include",
+            "lab-*",
+            "--rolling",
+            "2",
+        ]);
+        let Some(Command::Ota {
+            firmware,
+            filter,
+            rolling,
+            reboot_timeout_secs,
+            no_backup,
+            ..
+        }) = args.command
+        else {
+            panic!("expected ota, got {:?}", args.command);
+        };
+        assert_eq!(firmware, Some(PathBuf::from("WLED_0.15.0_ESP32.bin")));
+        assert_eq!(filter.include, ["lab-*"]);
+        assert_eq!((rolling, reboot_timeout_secs, no_backup), (2, 180, false));
+        assert!(Args::try_parse_from(["test", "ota"]).is_err());
+        assert!(Args::try_parse_from(["test", "ota", "--release", "latest"]).is_ok());
+        let both = ["test", "ota", "--release", "latest", "--firmware", "x.bin"];
+        assert!(Args::try_parse_from(both).is_err());
         assert!(
-            backup_result.is_err(),
-            "Backup should fail with invalid JSON"
+            Args::try_parse_from(["test", "ota", "--firmware", "x.bin", "--rolling", "0"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_args_restore_rollback() {
+        let args = Args::parse_from(["test", "restore", "--rollback"]);
+        let Some(Command::Restore { rollback, ip, .. }) = args.command else {
+            panic!("Expected restore");
+        };
+        assert!(rollback);
+        assert_eq!(ip, None);
+
+        let args = Args::parse_from([
+            "test",
+            "restore",
+            "porch",
+            "--ip",
+            "10.0.0.5",
+            "--no-snapshot",
+        ]);
+        let Some(Command::Restore { no_snapshot, .. }) = args.command else {
+            panic!("Expected restore");
+        };
+        assert!(no_snapshot);
+
+        assert!(Args::try_parse_from(["test", "restore", "porch"]).is_err());
+        assert!(Args::try_parse_from(["test", "restore", "porch", "--rollback"]).is_err());
+    }
+
+    #[test]
+    fn test_args_prefer_ip_version() {
+        let args = Args::parse_from(["test", "--prefer-ipv4"]);
+        assert!(args.prefer_ipv4 && !args.prefer_ipv6);
+        let args = Args::parse_from(["test", "list", "--prefer-ipv6"]);
+        assert!(args.prefer_ipv6);
+        assert!(Args::try_parse_from(["test", "--prefer-ipv4", "--prefer-ipv6"]).is_err());
+    }
+
+    #[test]
+    fn test_args_scan() {
+        let args = Args::parse_from(["test", "--scan", "192.168.1.0/24", "--scan", "10.0.0.0/28"]);
+        assert_eq!(args.scan.len(), 2);
+        assert_eq!(args.scan[1].to_string(), "10.0.0.0/28");
+        assert_eq!(args.scan_timeout_ms, 500);
+        assert!(Args::try_parse_from(["test", "--scan", "192.168.1.0"]).is_err());
+    }
+
+    #[test]
+    fn test_args_nodes() {
+        let args = Args::parse_from(["test", "--nodes", "porch.local", "list"]);
+        assert_eq!(
+            args.nodes,
+            vec!["porch.local".parse::<DeviceSpec>().unwrap()]
         );
+        assert_eq!(args.command, Some(Command::List));
+    }
+
+    #[test]
+    fn test_discovery_sources() {
+        let describe = |args: &[&str], search: bool| {
+            let args = Args::parse_from(std::iter::once("test").chain(args.iter().copied()));
+            discovery_sources(&args, search)
+                .map(|sources| sources.iter().map(|s| s.describe()).collect::<Vec<_>>())
+                .map(|descriptions| descriptions.into_iter().flatten().collect::<Vec<_>>())
+        };
 
-        let entries: Vec<_> = fs::read_dir(&out_dir).unwrap().collect();
         assert_eq!(
-            entries.len(),
-            0,
-            "No files should be written when cfg.json parsing fails"
+            describe(&[], true).unwrap(),
+            vec!["Searching for 4 seconds"]
+        );
+        assert_eq!(describe(&[], false).unwrap(), Vec::<String>::new());
+        assert_eq!(
+            describe(&["--discovery", "udp", "--search-secs", "60"], true).unwrap(),
+            vec!["Listening for UDP announcements for 60 seconds"]
+        );
+        assert_eq!(
+            describe(&["--discovery", "scan", "--scan", "10.0.0.0/24"], true).unwrap(),
+            vec!["Scanning 1 networks"]
         );
+        assert_eq!(
+            describe(&["--nodes", "porch", "--discover"], true).unwrap(),
+            vec!["Asking porch:80 for its nodes", "Searching for 4 seconds"]
+        );
+        assert_eq!(
+            describe(&["--discovery", "mdns,udp", "--device", "porch"], true).unwrap(),
+            vec![
+                "Searching for 4 seconds",
+                "Listening for UDP announcements for 4 seconds"
+            ]
+        );
+        assert!(describe(&["--discovery", "scan"], true).is_err());
+        assert!(describe(&["--discovery", "nodes"], true).is_err());
+    }
 
-        for handle in servers {
-            handle.join().unwrap();
-        }
+    #[test]
+    fn test_args_interfaces() {
+        let args = Args::parse_from([
+            "test",
+            "--interface",
+            "eth0",
+            "--interface",
+            "wlan0",
+            "--bind-ip",
+            "192.168.1.5",
+        ]);
+        assert_eq!(args.interface, vec!["eth0", "wlan0"]);
+        assert_eq!(args.bind_ip, vec!["192.168.1.5".parse::<IpAddr>().unwrap()]);
+        assert!(Args::try_parse_from(["test", "--bind-ip", "eth0"]).is_err());
+    }
+
+    #[test]
+    fn test_args_output() {
+        assert_eq!(Args::parse_from(["test"]).output, OutputFormat::Text);
+        let args = Args::parse_from(["test", "list", "--output", "json"]);
+        assert_eq!(args.output, OutputFormat::Json);
+        assert!(Args::try_parse_from(["test", "--output", "xml"]).is_err());
     }
 
     #[test]
-    fn test_backup_wleds_returns_error() {
-        // Start server in a background thread. Use different ports to avoid conflicts.
-        let servers = vec![mock_wled_server(
-            "127.0.0.1:81",
-            &cfg_body("testwled"),
-            Some("presets data"),
-        )];
+    fn test_format_table() {
+        let rows = vec![
+            vec!["NAME".to_string(), "PORT".to_string()],
+            vec!["porch".to_string(), "80".to_string()],
+            vec!["wled-garden".to_string(), "8080".to_string()],
+        ];
+        assert_eq!(
+            format_table(&rows),
+            "NAME         PORT\nporch        80\nwled-garden  8080\n"
+        );
+    }
 
-        // Prepare mock WLED device
-        let wleds = vec![
-            mock_service_info("mdns_name_port", "127.0.0.1", 8081), // Not served, so will fail.
-            mock_service_info("mdns_name", "127.0.0.1", 81),
+    #[test]
+    fn test_format_csv() {
+        let rows = vec![
+            vec!["name".to_string(), "address".to_string()],
+            vec!["porch".to_string(), "10.0.0.5".to_string()],
+            vec!["say \"hi\", garden".to_string(), String::new()],
         ];
+        assert_eq!(
+            format_csv(&rows),
+            "name,address\r\nporch,10.0.0.5\r\n\"say \"\"hi\"\", garden\",\r\n"
+        );
 
-        // Use a temp directory
-        let dir = tempdir().unwrap();
-        let out_dir = dir.path().to_path_buf();
+        let args = Args::parse_from(["test", "inventory", "--format", "json"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Inventory {
+                format: Some(ExportFormat::Json)
+            })
+        );
+    }
 
-        // Perform the backup.
-        let backup_wleds = backup_wleds(wleds, &out_dir);
+    #[test]
+    fn test_args_audit_versions() {
+        let args = Args::parse_from(["test", "audit", "versions", "--min-version", "0.14.0"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Audit {
+                audit: AuditCommand::Versions {
+                    min_version: Some("0.14.0".parse().unwrap()),
+                    latest: false,
+                }
+            })
+        );
 
-        assert!(backup_wleds.is_err(), "Backup failed, as it should have.");
+        let args = Args::parse_from(["test", "audit", "versions", "--latest"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Audit {
+                audit: AuditCommand::Versions {
+                    min_version: None,
+                    latest: true,
+                }
+            })
+        );
 
-        // Check that the file exists for teh value correctly served.
-        validate_response_files(&out_dir, "testwled");
+        assert!(
+            Args::try_parse_from(["test", "audit", "versions", "--min-version", "new"]).is_err()
+        );
 
-        // Shutdown the server
-        for handle in servers {
-            handle.join().unwrap();
-        }
+        let args = Args::parse_from(["test", "audit", "security"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Audit {
+                audit: AuditCommand::Security
+            })
+        );
     }
 
     #[test]
-    fn test_args_defaults() {
-        let args = Args::parse_from(&["test"]);
-        assert_eq!(args.out_dir, PathBuf::from("."));
-        assert_eq!(args.search_secs, 4);
+    fn test_args_diff() {
+        let args = Args::parse_from(["test", "diff", "old", "new"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Diff {
+                old: Some(PathBuf::from("old")),
+                new: Some(PathBuf::from("new")),
+                live: false,
+            })
+        );
+
+        let args = Args::parse_from(["test", "diff", "--live"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Diff {
+                old: None,
+                new: None,
+                live: true,
+            })
+        );
+        assert!(Args::try_parse_from(["test", "diff", "old"]).is_err());
+        assert!(Args::try_parse_from(["test", "diff", "--live", "old", "new"]).is_err());
     }
 
     #[test]
-    fn test_args_custom() {
-        let args = Args::parse_from(&["test", "--out-dir", "mydir", "--search-secs", "10"]);
-        assert_eq!(args.out_dir, PathBuf::from("mydir"));
-        assert_eq!(args.search_secs, 10);
+    fn test_args_verify() {
+        let args = Args::parse_from(["test", "verify"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Verify {
+                manifest: None,
+                dir: None,
+                max_age: None,
+            })
+        );
+
+        let args = Args::parse_from([
+            "test",
+            "verify",
+            "backups/MANIFEST.json",
+            "--dir",
+            "backups",
+            "--max-age",
+            "36h",
+        ]);
+        assert_eq!(
+            args.command,
+            Some(Command::Verify {
+                manifest: Some(PathBuf::from("backups/MANIFEST.json")),
+                dir: Some(PathBuf::from("backups")),
+                max_age: Some(chrono::Duration::hours(36)),
+            })
+        );
+        assert!(Args::try_parse_from(["test", "verify", "--max-age", "36"]).is_err());
     }
 }
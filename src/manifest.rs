@@ -0,0 +1,236 @@
+use crate::layout::{Layout, write_atomic};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the manifest written for each backup run.
+pub const RUN_MANIFEST: &str = "MANIFEST.json";
+
+/// Where a run's manifest goes: out_dir/MANIFEST-<timestamp>.json if runs
+/// are timestamped, else out_dir/MANIFEST.json. Paths in it are relative to
+/// out_dir.
+pub fn manifest_path(layout: &Layout) -> PathBuf {
+    match &layout.timestamp {
+        Some(timestamp) => layout.out_dir.join(format!("MANIFEST-{timestamp}.json")),
+        None => layout.out_dir.join(RUN_MANIFEST),
+    }
+}
+
+/// The newest manifest in out_dir, to verify by default.
+pub fn latest_manifest(out_dir: &Path) -> PathBuf {
+    let timestamped = fs::read_dir(out_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("MANIFEST-") && name.ends_with(".json"))
+        .max();
+    match timestamped {
+        Some(name) => out_dir.join(name),
+        None => out_dir.join(RUN_MANIFEST),
+    }
+}
+
+pub fn sha256_hex(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Where a saved file came from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileSource {
+    pub device: String,
+    pub address: String,
+    pub mac: Option<String>,
+    /// Firmware version.
+    pub version: Option<String>,
+    /// When the file was downloaded, in RFC 3339 form.
+    pub fetched: String,
+}
+
+/// One file in a manifest.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// Relative to the manifest's directory, with '/' separators.
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+
+    /// None for files of the whole run, such as a run archive.
+    #[serde(flatten, default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<FileSource>,
+}
+
+/// Every file saved in a backup run, with its hash, so the backup can be
+/// checked later for tampering or bit-rot.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunManifest {
+    pub created: String,
+    pub files: Vec<ManifestFile>,
+}
+
+impl RunManifest {
+    /// Hash a saved file and add it. `dir` is the manifest's directory.
+    pub fn add(
+        &mut self,
+        dir: &Path,
+        path: &Path,
+        source: Option<FileSource>,
+    ) -> std::io::Result<()> {
+        let contents = fs::read(path)?;
+        let relative = path.strip_prefix(dir).unwrap_or(path);
+        let relative: Vec<String> = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect();
+        self.files.push(ManifestFile {
+            path: relative.join("/"),
+            size: contents.len() as u64,
+            sha256: sha256_hex(&contents),
+            source,
+        });
+        Ok(())
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut contents = serde_json::to_vec_pretty(self)?;
+        contents.push(b'\n');
+        write_atomic(path, &contents)
+    }
+
+    pub fn load(path: &Path) -> Result<RunManifest, Box<dyn std::error::Error + Send + Sync>> {
+        let contents =
+            fs::read(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        serde_json::from_slice(&contents)
+            .map_err(|e| format!("Invalid {}: {e}", path.display()).into())
+    }
+}
+
+/// What checking a file against the manifest found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    Ok,
+    Missing,
+    /// The contents no longer match the hash.
+    Changed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileCheck {
+    pub path: String,
+    pub status: FileStatus,
+}
+
+/// Re-hash every file listed in the manifest at `manifest_path`.
+pub fn verify(
+    manifest_path: &Path,
+) -> Result<Vec<FileCheck>, Box<dyn std::error::Error + Send + Sync>> {
+    let manifest = RunManifest::load(manifest_path)?;
+    let dir = manifest_path.parent().unwrap_or(Path::new("."));
+
+    Ok(manifest
+        .files
+        .iter()
+        .map(|file| {
+            let status = match fs::read(dir.join(&file.path)) {
+                Err(_) => FileStatus::Missing,
+                Ok(contents)
+                    if contents.len() as u64 != file.size
+                        || sha256_hex(&contents) != file.sha256 =>
+                {
+                    FileStatus::Changed
+                }
+                Ok(_) => FileStatus::Ok,
+            };
+            FileCheck {
+                path: file.path.clone(),
+                status,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::LayoutKind;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_manifest_path() {
+        let dir = tempdir().unwrap();
+        let mut layout = Layout::new(LayoutKind::PerDevice, dir.path());
+        assert_eq!(manifest_path(&layout), dir.path().join("MANIFEST.json"));
+        assert_eq!(
+            latest_manifest(dir.path()),
+            dir.path().join("MANIFEST.json")
+        );
+
+        for timestamp in ["20261015T030405Z", "20261016T030405Z"] {
+            layout.timestamp = Some(timestamp.to_string());
+            fs::write(manifest_path(&layout), "{}").unwrap();
+        }
+        assert_eq!(
+            latest_manifest(dir.path()),
+            dir.path().join("MANIFEST-20261016T030405Z.json")
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("porch")).unwrap();
+        for name in ["porch/cfg.json", "porch/presets.json", "porch/ledmap.json"] {
+            fs::write(dir.path().join(name), name).unwrap();
+        }
+
+        let mut manifest = RunManifest {
+            created: "2026-10-15T03:04:05+00:00".to_string(),
+            files: vec![],
+        };
+        let source = FileSource {
+            device: "porch".to_string(),
+            address: "192.168.1.20".to_string(),
+            mac: Some("aabbccddeeff".to_string()),
+            version: Some("0.15.0".to_string()),
+            fetched: "2026-10-15T03:04:05+00:00".to_string(),
+        };
+        for name in ["porch/cfg.json", "porch/presets.json", "porch/ledmap.json"] {
+            let path = dir.path().join(name);
+            manifest
+                .add(dir.path(), &path, Some(source.clone()))
+                .unwrap();
+        }
+        let manifest_path = dir.path().join(RUN_MANIFEST);
+        manifest.write(&manifest_path).unwrap();
+        assert_eq!(RunManifest::load(&manifest_path).unwrap(), manifest);
+        assert_eq!(manifest.files[0].path, "porch/cfg.json");
+        assert_eq!(manifest.files[0].size, 14);
+
+        fs::write(dir.path().join("porch/presets.json"), "bit rot").unwrap();
+        fs::remove_file(dir.path().join("porch/ledmap.json")).unwrap();
+        let statuses: Vec<FileStatus> = verify(&manifest_path)
+            .unwrap()
+            .iter()
+            .map(|check| check.status)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![FileStatus::Ok, FileStatus::Changed, FileStatus::Missing]
+        );
+
+        assert!(verify(&dir.path().join("missing.json")).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use crate::crypt::{Decryption, read_file};
+use crate::device::{Device, normalize_mac};
+use crate::error::BoxError;
+use crate::model::WledInfo;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Saved beside each device's backup, as <hostname>_meta.json.
+pub const META_FILE: &str = "meta.json";
+
+/// What was known about a device when it was backed up, so a backup says
+/// where it came from and restore can check it's going to the same device.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceMeta {
+    /// Host name from cfg.json.
+    pub hostname: String,
+    pub address: String,
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    /// Full mDNS service name, if the device was found by mDNS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mdns_name: Option<String>,
+    /// Firmware version, such as "0.15.0".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub build: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub led_count: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uptime_secs: Option<u64>,
+    /// When the backup was taken, in RFC 3339 form.
+    pub backed_up: String,
+}
+
+impl DeviceMeta {
+    /// The metadata for `device`, backed up from `ip`. `info` is its
+    /// /json/info, if it answered.
+    pub fn new(
+        device: &Device,
+        ip: &IpAddr,
+        hostname: &str,
+        info: Option<&WledInfo>,
+        backed_up: DateTime<Utc>,
+    ) -> Self {
+        let mac = info
+            .and_then(|info| info.mac.as_deref())
+            .or(device.mac.as_deref())
+            .map(normalize_mac);
+        DeviceMeta {
+            hostname: hostname.to_string(),
+            address: ip.to_string(),
+            port: device.port,
+            mac,
+            mdns_name: device.mdns_name.clone(),
+            version: info.and_then(|info| info.ver.clone()),
+            build: info.and_then(WledInfo::build),
+            led_count: info.and_then(WledInfo::led_count),
+            uptime_secs: info.and_then(WledInfo::uptime_secs),
+            backed_up: backed_up.to_rfc3339(),
+        }
+    }
+
+    /// Read a saved meta.json, decrypting and decompressing it as needed.
+    pub fn load(path: &Path, decryption: Option<&Decryption>) -> Result<DeviceMeta, BoxError> {
+        let contents = read_file(path, decryption)?;
+        serde_json::from_slice(&contents)
+            .map_err(|e| format!("Invalid {}: {e}", path.display()).into())
+    }
+
+    /// Why the device described by `info` might not be the one this backup
+    /// came from, if there's reason to think so.
+    pub fn mismatches(&self, info: &WledInfo) -> Vec<Mismatch> {
+        let mut mismatches = vec![];
+        let mac = info.mac.as_deref().map(normalize_mac);
+        if let (Some(saved), Some(found)) = (&self.mac, mac)
+            && *saved != found
+        {
+            let saved = saved.clone();
+            mismatches.push(Mismatch::Mac { saved, found });
+        }
+        if let Some(found) = &info.name
+            && !self.hostname.is_empty()
+            && self.hostname != *found
+        {
+            let (saved, found) = (self.hostname.clone(), found.clone());
+            mismatches.push(Mismatch::Name { saved, found });
+        }
+        if let (Some(saved), Some(found)) = (&self.version, &info.ver)
+            && saved != found
+        {
+            let (saved, found) = (saved.clone(), found.clone());
+            mismatches.push(Mismatch::Version { saved, found });
+        }
+        mismatches
+    }
+}
+
+/// A way a device differs from the one a backup came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Mac {
+        saved: String,
+        found: String,
+    },
+    Name {
+        saved: String,
+        found: String,
+    },
+    /// Expected after a firmware update, so not a reason to think it's
+    /// another device.
+    Version {
+        saved: String,
+        found: String,
+    },
+}
+
+impl Mismatch {
+    /// Does this mean the device is probably not the one backed up?
+    pub fn other_device(&self) -> bool {
+        !matches!(self, Mismatch::Version { .. })
+    }
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Mismatch::Mac { saved, found } => {
+                write!(f, "its MAC is {found}, but the backup is of {saved}")
+            }
+            Mismatch::Name { saved, found } => {
+                write!(f, "it's named {found}, but the backup is of {saved}")
+            }
+            Mismatch::Version { saved, found } => {
+                write!(f, "it runs WLED {found}, but the backup is from {saved}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn info(contents: serde_json::Value) -> WledInfo {
+        serde_json::from_value(contents).unwrap()
+    }
+
+    #[test]
+    fn test_device_meta() {
+        let mut device = Device::new("wled-porch", vec![], 80);
+        device.mdns_name = Some("wled-porch._wled._tcp.local.".to_string());
+        device.mac = Some("AA:BB:CC:DD:EE:FF".to_string());
+        let info = info(json!({
+            "ver": "0.15.0",
+            "vid": 2412100,
+            "leds": {"count": 120},
+            "uptime": 3600,
+        }));
+        let backed_up = "2026-10-15T03:00:00Z".parse().unwrap();
+        let ip = "192.168.5.20".parse().unwrap();
+        let meta = DeviceMeta::new(&device, &ip, "porch", Some(&info), backed_up);
+
+        assert_eq!(
+            serde_json::to_value(&meta).unwrap(),
+            json!({
+                "hostname": "porch",
+                "address": "192.168.5.20",
+                "port": 80,
+                "mac": "aabbccddeeff",
+                "mdns_name": "wled-porch._wled._tcp.local.",
+                "version": "0.15.0",
+                "build": 2412100,
+                "led_count": 120,
+                "uptime_secs": 3600,
+                "backed_up": "2026-10-15T03:00:00+00:00",
+            })
+        );
+
+        let meta = DeviceMeta::new(
+            &Device::new("porch", vec![], 80),
+            &ip,
+            "porch",
+            None,
+            backed_up,
+        );
+        assert_eq!(meta.mac, None);
+        assert_eq!(meta.version, None);
+    }
+
+    #[test]
+    fn test_mismatches() {
+        let meta = DeviceMeta {
+            hostname: "porch".to_string(),
+            mac: Some("aabbccddeeff".to_string()),
+            version: Some("0.15.0".to_string()),
+            ..Default::default()
+        };
+        let same = json!({"mac": "aabbccddeeff", "name": "porch", "ver": "0.15.0"});
+        assert!(meta.mismatches(&info(same)).is_empty());
+        assert!(meta.mismatches(&info(json!({}))).is_empty());
+
+        let other = json!({"mac": "112233445566", "name": "garden", "ver": "0.14.4"});
+        let mismatches = meta.mismatches(&info(other));
+        let messages: Vec<String> = mismatches.iter().map(Mismatch::to_string).collect();
+        assert_eq!(
+            messages,
+            [
+                "its MAC is 112233445566, but the backup is of aabbccddeeff",
+                "it's named garden, but the backup is of porch",
+                "it runs WLED 0.14.4, but the backup is from 0.15.0",
+            ]
+        );
+        let other_device: Vec<bool> = mismatches.iter().map(Mismatch::other_device).collect();
+        assert_eq!(other_device, [true, true, false]);
+    }
+}
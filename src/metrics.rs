@@ -0,0 +1,293 @@
+use crate::error::BoxError;
+use crate::layout::write_atomic;
+use crate::report::{DeviceReport, Summary};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::Duration;
+
+/// The Pushgateway job, and the prefix of every metric name.
+const JOB: &str = "wled_backup";
+
+const LAST_SUCCESS: &str = "wled_backup_last_success_timestamp_seconds";
+
+/// What a backup run did, as Prometheus metrics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunMetrics {
+    pub devices_discovered: usize,
+    pub summary: Summary,
+    pub bytes_written: u64,
+    pub duration: Duration,
+    pub finished: DateTime<Utc>,
+    /// When each device was last backed up, as a Unix time, for the devices
+    /// backed up (or unchanged) in this run.
+    pub last_success: BTreeMap<String, i64>,
+}
+
+impl RunMetrics {
+    pub fn new(
+        devices_discovered: usize,
+        backups: &[DeviceReport],
+        summary: Summary,
+        duration: Duration,
+        finished: DateTime<Utc>,
+    ) -> Self {
+        let last_success = backups
+            .iter()
+            .filter(|backup| backup.success)
+            .map(|backup| (backup.name.clone(), finished.timestamp()))
+            .collect();
+        RunMetrics {
+            devices_discovered,
+            summary,
+            bytes_written: backups.iter().map(|backup| backup.bytes).sum(),
+            duration,
+            finished,
+            last_success,
+        }
+    }
+
+    /// The metrics about the run as a whole, in the text exposition format.
+    fn run_text(&self) -> String {
+        let metrics = [
+            (
+                "devices_discovered",
+                "WLEDs found to back up.",
+                self.devices_discovered.to_string(),
+            ),
+            (
+                "backups_succeeded",
+                "Devices backed up with changes.",
+                self.summary.succeeded.to_string(),
+            ),
+            (
+                "backups_skipped",
+                "Devices unchanged since the previous backup.",
+                self.summary.skipped.to_string(),
+            ),
+            (
+                "backups_failed",
+                "Devices that couldn't be backed up.",
+                self.summary.failed.to_string(),
+            ),
+            (
+                "bytes_written",
+                "Bytes saved by the run.",
+                self.bytes_written.to_string(),
+            ),
+            (
+                "run_duration_seconds",
+                "How long the run took.",
+                format!("{:.3}", self.duration.as_secs_f64()),
+            ),
+            (
+                "last_run_timestamp_seconds",
+                "When the run finished.",
+                self.finished.timestamp().to_string(),
+            ),
+        ];
+        let mut text = String::new();
+        for (name, help, value) in metrics {
+            let _ = writeln!(text, "# HELP {JOB}_{name} {help}");
+            let _ = writeln!(text, "# TYPE {JOB}_{name} gauge");
+            let _ = writeln!(text, "{JOB}_{name} {value}");
+        }
+        text
+    }
+
+    /// Every metric, with the last success of each device in `last_success`
+    /// (previous runs' times, updated by this run's).
+    fn text(&self, last_success: &BTreeMap<String, i64>) -> String {
+        let mut text = self.run_text();
+        let _ = writeln!(
+            text,
+            "# HELP {LAST_SUCCESS} When the device was last backed up."
+        );
+        let _ = writeln!(text, "# TYPE {LAST_SUCCESS} gauge");
+        for (device, time) in last_success {
+            let _ = writeln!(
+                text,
+                "{LAST_SUCCESS}{{device=\"{}\"}} {time}",
+                escape_label(device)
+            );
+        }
+        text
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn unescape_label(value: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+/// The devices' last success times in a textfile written before, so devices
+/// that failed this time keep theirs.
+fn previous_successes(contents: &str) -> BTreeMap<String, i64> {
+    let prefix = format!("{LAST_SUCCESS}{{device=\"");
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (device, time) = line.strip_prefix(&prefix)?.rsplit_once("\"} ")?;
+            Some((unescape_label(device), time.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Write the metrics for node_exporter's textfile collector, keeping the
+/// last success times of devices not backed up this run.
+pub fn write_textfile(path: &Path, metrics: &RunMetrics) -> std::io::Result<()> {
+    let mut last_success = std::fs::read_to_string(path)
+        .map(|contents| previous_successes(&contents))
+        .unwrap_or_default();
+    last_success.extend(metrics.last_success.clone());
+    write_atomic(path, metrics.text(&last_success).as_bytes())
+}
+
+/// The URL of a Pushgateway group: the job, and the device if given.
+fn group_url(base: &str, device: Option<&str>) -> Result<reqwest::Url, BoxError> {
+    let mut url = reqwest::Url::parse(base)?;
+    {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| format!("{base} can't be a Pushgateway URL"))?;
+        segments.pop_if_empty().extend(["metrics", "job", JOB]);
+        if let Some(device) = device {
+            segments.extend(["device", device]);
+        }
+    }
+    Ok(url)
+}
+
+/// Push the metrics to a Pushgateway. Each device's last success time is in
+/// its own group, pushed only when it succeeded, so the Pushgateway keeps
+/// the previous time of a device that failed.
+pub async fn push(
+    client: &reqwest::Client,
+    url: &str,
+    metrics: &RunMetrics,
+) -> Result<(), BoxError> {
+    let mut groups = vec![(group_url(url, None)?, metrics.run_text())];
+    for (device, time) in metrics.last_success.iter() {
+        let text = format!("# TYPE {LAST_SUCCESS} gauge\n{LAST_SUCCESS} {time}\n");
+        groups.push((group_url(url, Some(device))?, text));
+    }
+    for (url, text) in groups {
+        client
+            .put(url)
+            .body(text)
+            .send()
+            .await?
+            .error_for_status()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock_recording_server;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn metrics() -> RunMetrics {
+        let backups = vec![
+            DeviceReport {
+                name: "porch".to_string(),
+                success: true,
+                bytes: 1200,
+                ..Default::default()
+            },
+            DeviceReport {
+                name: "attic".to_string(),
+                error: Some("timed out".to_string()),
+                ..Default::default()
+            },
+        ];
+        let summary = Summary::new(&backups, 0);
+        let finished = "2026-10-15T03:00:00Z".parse().unwrap();
+        RunMetrics::new(2, &backups, summary, Duration::from_millis(2500), finished)
+    }
+
+    #[test]
+    fn test_write_textfile() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("wled_backup.prom");
+        fs::write(
+            &path,
+            format!("{LAST_SUCCESS}{{device=\"attic\"}} 1760400000\n{LAST_SUCCESS}{{device=\"porch\"}} 1\n"),
+        )
+        .unwrap();
+        write_textfile(&path, &metrics()).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(
+            text.contains("\nwled_backup_devices_discovered 2\n"),
+            "{text}"
+        );
+        assert!(text.contains("\nwled_backup_backups_succeeded 1\n"));
+        assert!(text.contains("\nwled_backup_backups_failed 1\n"));
+        assert!(text.contains("\nwled_backup_bytes_written 1200\n"));
+        assert!(text.contains("\nwled_backup_run_duration_seconds 2.500\n"));
+        // attic failed, so keeps its previous time.
+        assert!(text.contains(&format!(
+            "\n{LAST_SUCCESS}{{device=\"attic\"}} 1760400000\n"
+        )));
+        assert!(text.contains(&format!(
+            "\n{LAST_SUCCESS}{{device=\"porch\"}} 1792033200\n"
+        )));
+    }
+
+    #[test]
+    fn test_label_escaping() {
+        let device = "say \"hi\"\\\n";
+        let line = format!("{LAST_SUCCESS}{{device=\"{}\"}} 5", escape_label(device));
+        assert_eq!(
+            previous_successes(&line),
+            BTreeMap::from([(device.to_string(), 5)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push() {
+        let server = mock_recording_server("127.0.0.1:145");
+        let client = reqwest::Client::new();
+        push(&client, "http://127.0.0.1:145/", &metrics())
+            .await
+            .unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        let (method, url, body) = &requests[0];
+        assert_eq!(
+            (method.as_str(), url.as_str()),
+            ("PUT", "/metrics/job/wled_backup")
+        );
+        assert!(body.contains("\nwled_backup_backups_failed 1\n"));
+        assert_eq!(requests[1].1, "/metrics/job/wled_backup/device/porch");
+        assert!(
+            requests[1]
+                .2
+                .ends_with(&format!("\n{LAST_SUCCESS} 1792033200\n"))
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+// Typed views of the JSON a WLED serves. Only the fields this tool uses are
+// named. The rest are kept in `other`, so nothing is lost when a file is read
+// and written back, whatever the firmware version.
+
+/// cfg.json.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WledCfg {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<WledId>,
+
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// The "id" section of cfg.json.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WledId {
+    /// Name shown in the UI, which backups are saved under.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// mDNS host name, without ".local".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mdns: Option<String>,
+
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl WledCfg {
+    pub fn parse(contents: &[u8]) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        serde_json::from_slice(contents).map_err(|e| format!("Invalid cfg.json: {e}").into())
+    }
+
+    /// The value at `path`, such as ["ota", "lock"].
+    fn field(&self, path: &[&str]) -> Option<&Value> {
+        let (first, rest) = path.split_first()?;
+        rest.iter()
+            .try_fold(self.other.get(*first)?, |value, key| value.get(key))
+    }
+
+    fn flag(&self, path: &[&str]) -> Option<bool> {
+        let value = self.field(path)?;
+        value.as_bool().or_else(|| Some(value.as_u64()? != 0))
+    }
+
+    /// Whether OTA updates and settings changes need the OTA password.
+    pub fn ota_locked(&self) -> Option<bool> {
+        self.flag(&["ota", "lock"])
+    }
+
+    /// The OTA password, if cfg.json was saved with its secrets.
+    pub fn ota_password(&self) -> Option<&str> {
+        self.field(&["ota", "pwd"])?.as_str()
+    }
+
+    pub fn arduino_ota(&self) -> Option<bool> {
+        self.flag(&["ota", "aota"])
+    }
+
+    /// When the access point opens: 0 at boot without Wi-Fi, 1 whenever
+    /// disconnected, 2 always, 3 never.
+    pub fn ap_behavior(&self) -> Option<u64> {
+        self.field(&["ap", "behav"])?.as_u64()
+    }
+
+    pub fn ap_password(&self) -> Option<&str> {
+        self.field(&["ap", "psk"])?.as_str()
+    }
+
+    /// The access point password's length, which is kept even when the
+    /// password itself isn't served.
+    pub fn ap_password_len(&self) -> Option<u64> {
+        self.field(&["ap", "pskl"])?.as_u64()
+    }
+
+    pub fn mqtt_enabled(&self) -> Option<bool> {
+        self.flag(&["if", "mqtt", "en"])
+    }
+
+    pub fn mqtt_user(&self) -> Option<&str> {
+        self.field(&["if", "mqtt", "user"])?.as_str()
+    }
+
+    pub fn mqtt_password_len(&self) -> Option<u64> {
+        self.field(&["if", "mqtt", "pskl"])?.as_u64()
+    }
+}
+
+/// presets.json: presets by number. Preset "0" is an empty placeholder.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Presets(pub BTreeMap<String, Preset>);
+
+/// One preset, or a playlist of presets.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Preset {
+    #[serde(rename = "n", default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// Quick load label, shown on the preset buttons.
+    #[serde(rename = "ql", default, skip_serializing_if = "Option::is_none")]
+    pub quick_label: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlist: Option<Playlist>,
+
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// The presets a playlist plays, and how.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Playlist {
+    /// Preset numbers, in order.
+    #[serde(rename = "ps", default)]
+    pub presets: Vec<u16>,
+
+    /// Preset to switch to when the playlist ends, or 0 to stay on the last.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<u16>,
+
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl Preset {
+    /// The presets a playlist plays, and the one it ends on.
+    pub fn references(&self) -> Vec<u16> {
+        let Some(playlist) = &self.playlist else {
+            return vec![];
+        };
+        let mut ids = playlist.presets.clone();
+        ids.extend(playlist.end.filter(|end| *end != 0));
+        ids
+    }
+}
+
+impl Presets {
+    pub fn parse(contents: &[u8]) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        serde_json::from_slice(contents).map_err(|e| format!("Invalid presets.json: {e}").into())
+    }
+}
+
+/// /json/info.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WledInfo {
+    /// Firmware version, such as "0.15.0".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ver: Option<String>,
+
+    /// MAC address, as 12 hex digits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+
+    /// "WLED", for WLED firmware.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brand: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl WledInfo {
+    /// The firmware build, such as 2405180.
+    pub fn build(&self) -> Option<u64> {
+        self.other.get("vid")?.as_u64()
+    }
+
+    pub fn led_count(&self) -> Option<u64> {
+        self.other.get("leds")?.get("count")?.as_u64()
+    }
+
+    pub fn uptime_secs(&self) -> Option<u64> {
+        self.other.get("uptime")?.as_u64()
+    }
+
+    /// The chip, such as "esp32".
+    pub fn arch(&self) -> Option<String> {
+        Some(self.other.get("arch")?.as_str()?.to_string())
+    }
+
+    /// The build the firmware came from, such as "ESP32" or "ESP32_Ethernet",
+    /// which names its official release image.
+    pub fn release_name(&self) -> Option<String> {
+        Some(self.other.get("release")?.as_str()?.to_string())
+    }
+
+    /// Wi-Fi signal quality, in percent.
+    pub fn signal(&self) -> Option<u64> {
+        self.other.get("wifi")?.get("signal")?.as_u64()
+    }
+
+    /// Wi-Fi signal strength, in dBm.
+    pub fn rssi(&self) -> Option<i64> {
+        self.other.get("wifi")?.get("rssi")?.as_i64()
+    }
+}
+
+/// /json/nodes: the other WLEDs a device has heard from.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WledNodes {
+    pub nodes: Vec<WledNode>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WledNode {
+    #[serde(default)]
+    pub ip: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// One entry of a `/edit?list=/` filesystem listing.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FsEntry {
+    /// "file" or "dir".
+    #[serde(rename = "type", default)]
+    pub kind: String,
+
+    /// Path from the filesystem root, such as "/cfg.json".
+    pub name: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_cfg_round_trip() {
+        let contents = json!({
+            "rev": [1, 0],
+            "id": {"mdns": "wled-porch", "name": "Porch", "inv": "Light"},
+            "nw": {"ins": [{"ssid": "home"}]},
+        });
+        let cfg: WledCfg = serde_json::from_value(contents.clone()).unwrap();
+
+        let id = cfg.id.as_ref().unwrap();
+        assert_eq!(id.name.as_deref(), Some("Porch"));
+        assert_eq!(id.mdns.as_deref(), Some("wled-porch"));
+        assert_eq!(id.other["inv"], "Light");
+        assert_eq!(serde_json::to_value(&cfg).unwrap(), contents);
+
+        let error = WledCfg::parse(br#"{"id":{"name":123}}"#).unwrap_err();
+        assert!(error.to_string().starts_with("Invalid cfg.json"), "{error}");
+    }
+
+    #[test]
+    fn test_presets() {
+        let presets = Presets::parse(
+            br#"{"0":{},"1":{"n":"Warm","ql":"W","on":true,"bri":128},
+                 "2":{"n":"Cycle","playlist":{"ps":[1,3],"dur":[100,100],"end":1}}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(presets.0["0"], Preset::default());
+        assert_eq!(presets.0["1"].name.as_deref(), Some("Warm"));
+        assert_eq!(presets.0["1"].quick_label.as_deref(), Some("W"));
+        assert_eq!(presets.0["1"].other["bri"], 128);
+
+        let playlist = presets.0["2"].playlist.as_ref().unwrap();
+        assert_eq!(playlist.presets, vec![1, 3]);
+        assert_eq!(playlist.end, Some(1));
+        assert_eq!(playlist.other["dur"], json!([100, 100]));
+        assert_eq!(presets.0["2"].references(), [1, 3, 1]);
+        assert!(presets.0["1"].references().is_empty());
+
+        assert!(Presets::parse(b"[]").is_err());
+        assert!(Presets::parse(b"<html>").is_err());
+    }
+}
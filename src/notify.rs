@@ -0,0 +1,390 @@
+use crate::error::BoxError;
+use crate::report::{DeviceReport, Failure, Summary};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::time::Duration;
+
+/// How a backup run went, as sent to a webhook.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RunNotice {
+    pub summary: Summary,
+    pub exit_code: i32,
+    pub duration_ms: u64,
+    pub errors: Vec<Failure>,
+}
+
+impl RunNotice {
+    pub fn new(
+        backups: &[DeviceReport],
+        summary: Summary,
+        exit_code: i32,
+        duration: Duration,
+    ) -> Self {
+        let errors = backups
+            .iter()
+            .filter_map(|backup| {
+                Some(Failure {
+                    device: backup.name.clone(),
+                    error: backup.error.clone()?,
+                    error_kind: backup.error_kind.clone(),
+                })
+            })
+            .collect();
+        RunNotice {
+            summary,
+            exit_code,
+            duration_ms: duration.as_millis() as u64,
+            errors,
+        }
+    }
+}
+
+/// POST `notice` to `url` as JSON, failing if the server answers with an
+/// error.
+pub async fn send_webhook(
+    client: &reqwest::Client,
+    url: &str,
+    notice: &RunNotice,
+) -> Result<(), BoxError> {
+    client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(notice)?)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// A healthchecks.io ping: a run starting, or how it ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ping {
+    Start,
+    Success,
+    Fail,
+}
+
+/// The URL to ping for `ping`, given a check's ping URL, as healthchecks.io
+/// expects: `/start` or `/fail` added, or the URL itself for success.
+pub fn ping_url(url: &str, ping: Ping) -> String {
+    let url = url.trim_end_matches('/');
+    match ping {
+        Ping::Start => format!("{url}/start"),
+        Ping::Success => url.to_string(),
+        Ping::Fail => format!("{url}/fail"),
+    }
+}
+
+/// Ping a healthchecks.io check, with `body` shown in its log.
+pub async fn send_ping(
+    client: &reqwest::Client,
+    url: &str,
+    ping: Ping,
+    body: &str,
+) -> Result<(), BoxError> {
+    client
+        .post(ping_url(url, ping))
+        .body(body.to_string())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// When to send a notification.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotifyWhen {
+    /// Only when a device failed
+    #[default]
+    Failure,
+    /// After every run
+    Always,
+}
+
+/// Where to send notifications, from the `[notify]` section of the
+/// inventory file or the command line.
+///
+/// ```toml
+/// [notify]
+/// ntfy = "https://ntfy.sh/my-wled-backups"
+/// slack = "https://hooks.slack.com/services/..."
+/// discord = "https://discord.com/api/webhooks/..."
+/// when = "always"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    /// An ntfy topic URL.
+    pub ntfy: Option<String>,
+    /// A Slack incoming webhook URL.
+    pub slack: Option<String>,
+    /// A Discord webhook URL.
+    pub discord: Option<String>,
+    pub when: Option<NotifyWhen>,
+}
+
+impl NotifyConfig {
+    /// This config, with anything set in `overrides` replacing it.
+    pub fn merge(self, overrides: NotifyConfig) -> NotifyConfig {
+        NotifyConfig {
+            ntfy: overrides.ntfy.or(self.ntfy),
+            slack: overrides.slack.or(self.slack),
+            discord: overrides.discord.or(self.discord),
+            when: overrides.when.or(self.when),
+        }
+    }
+
+    pub fn backends(&self) -> Vec<Backend> {
+        let mut backends = vec![];
+        backends.extend(self.ntfy.clone().map(Backend::Ntfy));
+        backends.extend(self.slack.clone().map(Backend::Slack));
+        backends.extend(self.discord.clone().map(Backend::Discord));
+        backends
+    }
+
+    /// Should a run that ended with `exit_code` be notified?
+    pub fn should_notify(&self, exit_code: i32) -> bool {
+        match self.when.unwrap_or_default() {
+            NotifyWhen::Failure => exit_code != 0,
+            NotifyWhen::Always => true,
+        }
+    }
+}
+
+/// A service that takes human-readable notifications, with its URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backend {
+    Ntfy(String),
+    Slack(String),
+    Discord(String),
+}
+
+/// Discord rejects longer messages.
+const DISCORD_LIMIT: usize = 2000;
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Ntfy(_) => "ntfy",
+            Backend::Slack(_) => "Slack",
+            Backend::Discord(_) => "Discord",
+        }
+    }
+
+    fn request(
+        &self,
+        client: &reqwest::Client,
+        title: &str,
+        message: &str,
+        failed: bool,
+    ) -> reqwest::RequestBuilder {
+        let post_json = |url: &str, body: serde_json::Value| {
+            client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string())
+        };
+        match self {
+            Backend::Ntfy(url) => client
+                .post(url)
+                .header("Title", title)
+                .header("Priority", if failed { "high" } else { "default" })
+                .header("Tags", if failed { "warning" } else { "floppy_disk" })
+                .body(message.to_string()),
+            Backend::Slack(url) => post_json(url, json!({"text": format!("*{title}*\n{message}")})),
+            Backend::Discord(url) => {
+                let content: String = format!("**{title}**\n{message}")
+                    .chars()
+                    .take(DISCORD_LIMIT)
+                    .collect();
+                post_json(url, json!({"content": content}))
+            }
+        }
+    }
+
+    /// Send `message`, headed by `title`. Failures are marked as urgent
+    /// where the service allows.
+    pub async fn send(
+        &self,
+        client: &reqwest::Client,
+        title: &str,
+        message: &str,
+        failed: bool,
+    ) -> Result<(), BoxError> {
+        self.request(client, title, message, failed)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock_recording_server;
+
+    fn notice() -> RunNotice {
+        let backups = vec![
+            DeviceReport {
+                name: "porch".to_string(),
+                success: true,
+                ..Default::default()
+            },
+            DeviceReport {
+                name: "attic".to_string(),
+                error: Some("http://10.0.0.9/cfg.json: timed out".to_string()),
+                error_kind: Some("http".to_string()),
+                ..Default::default()
+            },
+        ];
+        let summary = Summary::new(&backups, 0);
+        RunNotice::new(
+            &backups,
+            summary,
+            summary.exit_code(),
+            Duration::from_millis(1500),
+        )
+    }
+
+    #[test]
+    fn test_run_notice_json() {
+        assert_eq!(
+            serde_json::to_value(notice()).unwrap(),
+            json!({
+                "summary": {"succeeded": 1, "failed": 1, "skipped": 0, "unresolved": 0},
+                "exit_code": 1,
+                "duration_ms": 1500,
+                "errors": [{
+                    "device": "attic",
+                    "error": "http://10.0.0.9/cfg.json: timed out",
+                    "error_kind": "http",
+                }],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_webhook() {
+        let server = mock_recording_server("127.0.0.1:139");
+        let client = reqwest::Client::new();
+        send_webhook(&client, "http://127.0.0.1:139/hook", &notice())
+            .await
+            .unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 1);
+        let (method, url, body) = &requests[0];
+        assert_eq!((method.as_str(), url.as_str()), ("POST", "/hook"));
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body, serde_json::to_value(notice()).unwrap());
+
+        // Nothing listening.
+        assert!(
+            send_webhook(&client, "http://127.0.0.1:140/hook", &notice())
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_ping_url() {
+        let url = "https://hc-ping.com/0a1b2c";
+        assert_eq!(
+            ping_url(url, Ping::Start),
+            "https://hc-ping.com/0a1b2c/start"
+        );
+        assert_eq!(ping_url(url, Ping::Success), url);
+        assert_eq!(
+            ping_url("https://hc-ping.com/0a1b2c/", Ping::Fail),
+            "https://hc-ping.com/0a1b2c/fail"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_ping() {
+        let server = mock_recording_server("127.0.0.1:141");
+        let client = reqwest::Client::new();
+        let url = "http://127.0.0.1:141/check";
+        send_ping(&client, url, Ping::Start, "").await.unwrap();
+        send_ping(
+            &client,
+            url,
+            Ping::Fail,
+            "Backup: 0 succeeded, 1 failed, 0 skipped",
+        )
+        .await
+        .unwrap();
+
+        let requests = server.join().unwrap();
+        let requests: Vec<(&str, &str)> = requests
+            .iter()
+            .map(|(_, url, body)| (url.as_str(), body.as_str()))
+            .collect();
+        assert_eq!(
+            requests,
+            vec![
+                ("/check/start", ""),
+                ("/check/fail", "Backup: 0 succeeded, 1 failed, 0 skipped")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_notify_config() {
+        let config: NotifyConfig =
+            toml::from_str("ntfy = \"https://ntfy.sh/wled\"\nwhen = \"always\"").unwrap();
+        assert_eq!(config.when, Some(NotifyWhen::Always));
+        assert!(config.should_notify(0));
+        assert!(toml::from_str::<NotifyConfig>("email = \"me@example.com\"").is_err());
+
+        let flags = NotifyConfig {
+            discord: Some("https://discord.com/api/webhooks/1".to_string()),
+            when: Some(NotifyWhen::Failure),
+            ..Default::default()
+        };
+        let config = config.merge(flags);
+        assert_eq!(
+            config.backends(),
+            vec![
+                Backend::Ntfy("https://ntfy.sh/wled".to_string()),
+                Backend::Discord("https://discord.com/api/webhooks/1".to_string()),
+            ]
+        );
+        assert!(!config.should_notify(0));
+        assert!(config.should_notify(2));
+        assert!(!NotifyConfig::default().should_notify(0));
+    }
+
+    #[tokio::test]
+    async fn test_backend_send() {
+        let server = mock_recording_server("127.0.0.1:142");
+        let client = reqwest::Client::new();
+        let url = |path: &str| format!("http://127.0.0.1:142/{path}");
+        let backends = [
+            Backend::Ntfy(url("wled")),
+            Backend::Slack(url("slack")),
+            Backend::Discord(url("discord")),
+        ];
+        for backend in backends.iter() {
+            backend
+                .send(&client, "WLED backup failed", "attic: FAILED", true)
+                .await
+                .unwrap();
+        }
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].2, "attic: FAILED");
+        let slack: serde_json::Value = serde_json::from_str(&requests[1].2).unwrap();
+        assert_eq!(
+            slack,
+            json!({"text": "*WLED backup failed*\nattic: FAILED"})
+        );
+        let discord: serde_json::Value = serde_json::from_str(&requests[2].2).unwrap();
+        assert_eq!(
+            discord,
+            json!({"content": "**WLED backup failed**\nattic: FAILED"})
+        );
+    }
+}
@@ -0,0 +1,360 @@
+use crate::device::DeviceOptions;
+use crate::error::BoxError;
+use crate::http::{Fetcher, unlock};
+use crate::layout::{Layout, LayoutKind, write_atomic};
+use crate::model::WledInfo;
+use crate::restore::{Expected, get_info, wait_for_reboot};
+use reqwest::multipart::{Form, Part};
+use serde::Deserialize;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Under out_dir, where ota backs up each WLED before updating it.
+pub const PRE_UPDATE_DIR: &str = "pre-update";
+
+/// Under out_dir, where firmware downloaded from WLED's releases is kept.
+pub const FIRMWARE_DIR: &str = "firmware";
+
+/// WLED's releases on GitHub.
+pub const RELEASES_URL: &str = "https://api.github.com/repos/wled/WLED/releases";
+
+/// Where the backups taken before updates are saved: per device and
+/// timestamped, under out_dir/pre-update/.
+pub fn backup_layout(out_dir: &Path, timestamp: &str) -> Layout {
+    let mut layout = Layout::new(LayoutKind::PerDevice, &out_dir.join(PRE_UPDATE_DIR));
+    layout.timestamp = Some(timestamp.to_string());
+    layout
+}
+
+/// The part of an official release's file name that names the chip it's
+/// for, and the chip as /json/info names it. More specific names come first.
+const CHIPS: [(&str, &str); 7] = [
+    ("ESP32-C3", "esp32-c3"),
+    ("ESP32-S2", "esp32-s2"),
+    ("ESP32-S3", "esp32-s3"),
+    ("ESP32", "esp32"),
+    ("ESP8266", "esp8266"),
+    ("ESP01", "esp8266"),
+    ("ESP02", "esp8266"),
+];
+
+/// A firmware image to install.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Firmware {
+    pub path: PathBuf,
+    pub contents: Vec<u8>,
+    /// The WLED version it installs, if known.
+    pub version: Option<String>,
+    /// The chip it's for, as /json/info names it, if known.
+    pub chip: Option<&'static str>,
+}
+
+impl Firmware {
+    /// Read a firmware image. Its version and chip come from `version`, or
+    /// else from its file name, if it's named as official releases are, such
+    /// as WLED_0.15.0_ESP32.bin.
+    pub fn load(path: &Path, version: Option<String>) -> Result<Firmware, BoxError> {
+        let contents =
+            std::fs::read(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        if contents.is_empty() {
+            return Err(format!("{} is empty", path.display()).into());
+        }
+        let name = path
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_uppercase())
+            .unwrap_or_default();
+        let parts: Vec<&str> = name.split('_').collect();
+        let version = version.or_else(|| {
+            parts
+                .iter()
+                .find(|part| part.starts_with(|c: char| c.is_ascii_digit()) && part.contains('.'))
+                .map(|part| part.to_lowercase())
+        });
+        let chip = CHIPS
+            .iter()
+            .find(|(marker, _)| parts.contains(marker))
+            .map(|(_, chip)| *chip);
+        Ok(Firmware {
+            path: path.to_path_buf(),
+            contents,
+            version,
+            chip,
+        })
+    }
+
+    /// Why the firmware looks wrong for the WLED with `info`, if it does.
+    pub fn mismatch(&self, info: &WledInfo) -> Option<String> {
+        match (self.chip, info.arch()) {
+            (Some(chip), Some(arch)) if !chip.eq_ignore_ascii_case(&arch) => Some(format!(
+                "{} is for {chip}, but the WLED is an {arch}",
+                self.path.display()
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// An official WLED release.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+    #[serde(default)]
+    pub assets: Vec<Asset>,
+}
+
+/// A file in a release, such as WLED_0.15.0_ESP32.bin.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Asset {
+    pub name: String,
+    pub browser_download_url: String,
+}
+
+impl Release {
+    /// The WLED version it installs, such as 0.15.0.
+    pub fn version(&self) -> &str {
+        self.tag_name.trim_start_matches(['v', 'V'])
+    }
+
+    /// The release's firmware image for the WLED with `info`: the same
+    /// build it runs now, as /json/info names it, or else the plain build
+    /// for its chip.
+    pub fn asset_for(&self, info: &WledInfo) -> Result<&Asset, String> {
+        let chip_build = || {
+            let arch = info.arch()?;
+            let (marker, _) = CHIPS
+                .iter()
+                .find(|(_, chip)| arch.eq_ignore_ascii_case(chip))?;
+            Some(marker.to_string())
+        };
+        let Some(build) = info.release_name().or_else(chip_build) else {
+            return Err("it doesn't say which chip it has; give --firmware".to_string());
+        };
+        let wanted = format!("WLED_{}_{build}.bin", self.version());
+        self.assets
+            .iter()
+            .find(|asset| asset.name.eq_ignore_ascii_case(&wanted))
+            .ok_or_else(|| format!("WLED {} has no {wanted}; give --firmware", self.version()))
+    }
+}
+
+/// The release of `version`, such as 0.15.0, or the newest if it's
+/// "latest", from `releases_url`, such as [`RELEASES_URL`].
+pub async fn fetch_release(
+    fetcher: &Fetcher,
+    releases_url: &str,
+    version: &str,
+) -> Result<Release, BoxError> {
+    let url = match version {
+        "latest" => format!("{releases_url}/latest"),
+        version => format!(
+            "{releases_url}/tags/v{}",
+            version.trim_start_matches(['v', 'V'])
+        ),
+    };
+    let response = fetcher.client.get(&url).send().await?;
+    let body = response.error_for_status()?.bytes().await?;
+    serde_json::from_slice(&body).map_err(|e| format!("Invalid release from {url}: {e}").into())
+}
+
+/// The firmware image `asset` of `release`, downloaded into `cache_dir`
+/// unless it's there already.
+pub async fn cached_firmware(
+    fetcher: &Fetcher,
+    release: &Release,
+    asset: &Asset,
+    cache_dir: &Path,
+) -> Result<Firmware, BoxError> {
+    if asset.name.contains(['/', '\\']) || asset.name.starts_with('.') {
+        return Err(format!("Invalid release file name '{}'", asset.name).into());
+    }
+    let path = cache_dir.join(&asset.name);
+    if !path.exists() {
+        info!("downloading: {}", asset.browser_download_url);
+        let response = fetcher
+            .client
+            .get(&asset.browser_download_url)
+            .send()
+            .await?;
+        let contents = response.error_for_status()?.bytes().await?;
+        fs::create_dir_all(cache_dir)?;
+        write_atomic(&path, &contents)?;
+    }
+    Firmware::load(&path, Some(release.version().to_string()))
+}
+
+/// Upload a firmware image to a WLED's /update, as its update page does.
+/// WLED reboots into it once it's written.
+async fn upload_firmware(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    firmware: &Firmware,
+) -> Result<(), BoxError> {
+    let base_url = options.base_url(ip, port);
+    unlock(client, &base_url, options).await?;
+    let part = Part::bytes(firmware.contents.clone()).file_name("firmware.bin");
+    let form = Form::new().part("update", part);
+    options
+        .apply(client.post(format!("{base_url}/update")))
+        .multipart(form)
+        .send()
+        .await?
+        .error_for_status()?;
+    info!("uploaded: {}", firmware.path.display());
+    Ok(())
+}
+
+/// Install `firmware` on a WLED, and wait up to `wait` for it to come back
+/// running the firmware's version, if that's known. Refuses firmware for
+/// another chip unless `force`. Returns /json/info from before and after.
+pub async fn update_wled(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    firmware: &Firmware,
+    force: bool,
+    wait: Duration,
+) -> Result<(WledInfo, WledInfo), BoxError> {
+    let before = get_info(client, ip, port, options).await?;
+    if let Some(problem) = firmware.mismatch(&before)
+        && !force
+    {
+        return Err(format!("{problem} (--force updates anyway)").into());
+    }
+
+    upload_firmware(client, ip, port, options, firmware).await?;
+    let rebooted = Instant::now();
+    let expected = Expected {
+        name: before.name.clone(),
+        version: firmware.version.clone(),
+    };
+    let after = wait_for_reboot(client, ip, port, options, &expected, rebooted, wait).await?;
+    info!("back up after updating");
+    Ok((before, after))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mock_routes_server, mock_sequence_server};
+    use std::net::Ipv4Addr;
+    use tempfile::tempdir;
+
+    fn firmware(name: &str) -> Firmware {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(name);
+        std::fs::write(&path, b"\xe9firmware").unwrap();
+        Firmware::load(&path, None).unwrap()
+    }
+
+    #[test]
+    fn test_firmware_load() {
+        let esp32 = firmware("WLED_0.15.0_ESP32.bin");
+        assert_eq!(esp32.version.as_deref(), Some("0.15.0"));
+        assert_eq!(esp32.chip, Some("esp32"));
+        let c3 = firmware("WLED_0.15.0-b3_ESP32-C3.bin");
+        assert_eq!(c3.version.as_deref(), Some("0.15.0-b3"));
+        assert_eq!(c3.chip, Some("esp32-c3"));
+        let custom = firmware("my-build.bin");
+        assert_eq!((custom.version, custom.chip), (None, None));
+
+        let info = |contents| serde_json::from_str::<WledInfo>(contents).unwrap();
+        assert_eq!(esp32.mismatch(&info(r#"{"arch":"esp32"}"#)), None);
+        assert!(esp32.mismatch(&info(r#"{"arch":"esp8266"}"#)).is_some());
+        assert_eq!(esp32.mismatch(&info("{}")), None);
+    }
+
+    fn release() -> Release {
+        let asset = |name: &str| Asset {
+            name: name.to_string(),
+            browser_download_url: format!("http://127.0.0.1:165/download/{name}"),
+        };
+        Release {
+            tag_name: "v0.15.0".to_string(),
+            assets: vec![
+                asset("WLED_0.15.0_ESP32.bin"),
+                asset("WLED_0.15.0_ESP32_Ethernet.bin"),
+                asset("WLED_0.15.0_ESP8266.bin"),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_asset_for() {
+        let release = release();
+        let info = |contents| serde_json::from_str::<WledInfo>(contents).unwrap();
+        let asset = |contents| release.asset_for(&info(contents)).map(|a| a.name.as_str());
+        assert_eq!(
+            asset(r#"{"arch":"esp32","release":"ESP32_Ethernet"}"#),
+            Ok("WLED_0.15.0_ESP32_Ethernet.bin")
+        );
+        assert_eq!(
+            asset(r#"{"arch":"esp8266"}"#),
+            Ok("WLED_0.15.0_ESP8266.bin")
+        );
+        assert!(asset(r#"{"arch":"esp32-s3"}"#).is_err());
+        assert!(asset("{}").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cached_firmware() {
+        let tag = r#"{"tag_name":"v0.15.0","assets":[]}"#;
+        let routes = [
+            ("/releases/tags/v0.15.0", tag),
+            ("/download/WLED_0.15.0_ESP32.bin", "firmware"),
+        ];
+        let server = mock_routes_server("127.0.0.1:165", &routes);
+        let fetcher = Fetcher::default();
+        let found = fetch_release(&fetcher, "http://127.0.0.1:165/releases", "0.15.0")
+            .await
+            .unwrap();
+        assert_eq!(found.version(), "0.15.0");
+
+        let dir = tempdir().unwrap();
+        let release = release();
+        let asset = &release.assets[0];
+        let firmware = cached_firmware(&fetcher, &release, asset, dir.path())
+            .await
+            .unwrap();
+        assert_eq!(firmware.contents, b"firmware");
+        assert_eq!(firmware.version.as_deref(), Some("0.15.0"));
+        server.join().unwrap();
+
+        // With the server gone, it comes from the cache.
+        let cached = cached_firmware(&fetcher, &release, asset, dir.path())
+            .await
+            .unwrap();
+        assert_eq!(cached, firmware);
+    }
+
+    #[tokio::test]
+    async fn test_update_wled() {
+        let before = r#"{"ver":"0.14.4","name":"lab-1","arch":"esp32","uptime":5000}"#;
+        let after = r#"{"ver":"0.15.0","name":"lab-1","arch":"esp32","uptime":0}"#;
+        // The first answer is for the refused update.
+        let info: &[&str] = &[before, before, after];
+        let server =
+            mock_sequence_server("127.0.0.1:164", &[("/json/info", info), ("/update", &[""])]);
+        let client = reqwest::Client::new();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let options = DeviceOptions::default();
+        let wait = Duration::from_secs(5);
+
+        let esp8266 = firmware("WLED_0.15.0_ESP8266.bin");
+        let result = update_wled(&client, &ip, 164, &options, &esp8266, false, wait).await;
+        assert!(result.unwrap_err().to_string().contains("--force"));
+
+        let esp32 = firmware("WLED_0.15.0_ESP32.bin");
+        let (before, after) = update_wled(&client, &ip, 164, &options, &esp32, false, wait)
+            .await
+            .unwrap();
+        assert_eq!(before.ver.as_deref(), Some("0.14.4"));
+        assert_eq!(after.ver.as_deref(), Some("0.15.0"));
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,555 @@
+use crate::device::DeviceOptions;
+use crate::error::BoxError;
+use crate::http::{Fetcher, unlock};
+use crate::model::{Preset, Presets};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+/// The highest preset number WLED has room for. Preset 0 isn't a real
+/// preset.
+pub const MAX_PRESET: u16 = 250;
+
+/// Download a WLED's presets.json.
+pub async fn fetch_presets(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+) -> Result<Presets, BoxError> {
+    let base_url = options.base_url(ip, port);
+    unlock(&fetcher.client, &base_url, options).await?;
+    let url = format!("{base_url}/presets.json");
+    let response = fetcher.get(&url, options).await?.error_for_status()?;
+    Presets::parse(&response.bytes().await?)
+}
+
+/// What preset export saves: one preset, or a playlist along with the
+/// presets it plays, by their numbers on the WLED it came from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum PresetFile {
+    Playlist { playlist: u16, presets: Presets },
+    Preset(Preset),
+}
+
+impl PresetFile {
+    /// The preset, or the playlist itself.
+    pub fn preset(&self) -> Result<&Preset, BoxError> {
+        match self {
+            PresetFile::Preset(preset) => Ok(preset),
+            PresetFile::Playlist { playlist, presets } => presets
+                .0
+                .get(&playlist.to_string())
+                .ok_or_else(|| format!("The file doesn't have playlist {playlist}").into()),
+        }
+    }
+}
+
+/// The presets that preset `id` plays, if it's a playlist, including those
+/// played by playlists it plays. Returns those `presets` has, and those it
+/// doesn't.
+pub fn playlist_presets(presets: &Presets, id: u16) -> (BTreeSet<u16>, BTreeSet<u16>) {
+    let (mut found, mut missing) = (BTreeSet::new(), BTreeSet::new());
+    let mut queue = vec![id];
+    while let Some(next) = queue.pop() {
+        let Some(preset) = presets.0.get(&next.to_string()) else {
+            continue;
+        };
+        for reference in preset.references() {
+            if reference == id
+                || !(1..=MAX_PRESET).contains(&reference)
+                || found.contains(&reference)
+                || missing.contains(&reference)
+            {
+                continue;
+            }
+            match presets.0.contains_key(&reference.to_string()) {
+                true => {
+                    found.insert(reference);
+                    queue.push(reference);
+                }
+                false => {
+                    missing.insert(reference);
+                }
+            }
+        }
+    }
+    (found, missing)
+}
+
+/// Preset `id` from `presets`, with the presets it plays if it's a playlist.
+pub fn bundle(presets: &Presets, id: u16) -> Option<PresetFile> {
+    let preset = presets.0.get(&id.to_string())?;
+    if preset.playlist.is_none() {
+        return Some(PresetFile::Preset(preset.clone()));
+    }
+    let (found, missing) = playlist_presets(presets, id);
+    for missing in missing {
+        warn!("Playlist {id} plays preset {missing}, which doesn't exist");
+    }
+    let played = found.into_iter().chain([id]).map(|id| {
+        let key = id.to_string();
+        (key.clone(), presets.0[&key].clone())
+    });
+    Some(PresetFile::Playlist {
+        playlist: id,
+        presets: Presets(played.collect()),
+    })
+}
+
+/// Preset `id` from a WLED, with the presets it plays if it's a playlist.
+pub async fn export_preset(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    id: u16,
+) -> Result<PresetFile, BoxError> {
+    let presets = fetch_presets(fetcher, ip, port, options).await?;
+    bundle(&presets, id).ok_or_else(|| format!("{ip}:{port} has no preset {id}").into())
+}
+
+/// What to send to /json/state to save `preset` as preset `id`. As in the
+/// WLED web UI, "psave" says where, and "o" to save the JSON as given rather
+/// than the WLED's current state.
+pub fn save_request(id: u16, preset: &Preset) -> Result<Value, BoxError> {
+    if !(1..=MAX_PRESET).contains(&id) {
+        return Err(format!("There's no preset {id}: presets are 1 to {MAX_PRESET}").into());
+    }
+    let mut request = serde_json::to_value(preset)?;
+    let Some(fields) = request.as_object_mut() else {
+        return Err("A preset must be a JSON object".into());
+    };
+    fields.insert("psave".to_string(), id.into());
+    fields.insert("o".to_string(), true.into());
+    Ok(request)
+}
+
+/// Save `preset` as preset `id` on a WLED, replacing whatever was there but
+/// leaving its other presets alone.
+pub async fn import_preset(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    id: u16,
+    preset: &Preset,
+) -> Result<(), BoxError> {
+    let body = save_request(id, preset)?.to_string();
+    let base_url = options.base_url(ip, port);
+    unlock(&fetcher.client, &base_url, options).await?;
+    let request = fetcher
+        .client
+        .post(format!("{base_url}/json/state"))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body);
+    options.apply(request).send().await?.error_for_status()?;
+    info!("saved preset {id} on {ip}:{port}");
+    Ok(())
+}
+
+/// The changes that save `file` on a WLED whose presets are `target`, as
+/// preset `id`. A playlist's presets are saved under their own numbers,
+/// unless the WLED already has them, and the WLED's other presets there are
+/// only replaced with `force`.
+pub fn plan_import(
+    target: &Presets,
+    id: u16,
+    file: &PresetFile,
+    force: bool,
+) -> Result<Vec<PresetChange>, BoxError> {
+    let preset = file.preset()?;
+    let mut changes = vec![];
+    if let PresetFile::Playlist { playlist, presets } = file {
+        for (number, played) in presets.0.iter() {
+            let number: u16 = number
+                .parse()
+                .map_err(|_| format!("Invalid preset number '{number}'"))?;
+            if number == *playlist {
+                continue;
+            }
+            if number == id {
+                return Err(format!(
+                    "The playlist plays preset {id}, so it can't be saved as preset {id}"
+                )
+                .into());
+            }
+            match target.0.get(&number.to_string()) {
+                Some(current) if current == played => {}
+                Some(current) if !force => {
+                    let name = current.name.as_deref().unwrap_or("unnamed");
+                    return Err(format!(
+                        "The playlist plays preset {number}, but the WLED's preset {number} is \
+                         {name} (--force replaces it)"
+                    )
+                    .into());
+                }
+                _ => changes.push(PresetChange {
+                    id: number,
+                    preset: Some(played.clone()),
+                }),
+            }
+        }
+        for played in presets.0.values() {
+            for reference in played.references() {
+                let key = reference.to_string();
+                if !presets.0.contains_key(&key) && !target.0.contains_key(&key) {
+                    warn!("The playlist plays preset {reference}, which the WLED doesn't have");
+                }
+            }
+        }
+    }
+    changes.push(PresetChange {
+        id,
+        preset: Some(preset.clone()),
+    });
+    Ok(changes)
+}
+
+/// Save `file` as preset `id` on a WLED, along with the presets it plays if
+/// it's a playlist. See plan_import.
+pub async fn import_file(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    id: u16,
+    file: &PresetFile,
+    force: bool,
+) -> Result<(), BoxError> {
+    match file {
+        PresetFile::Preset(preset) => import_preset(fetcher, ip, port, options, id, preset).await,
+        PresetFile::Playlist { .. } => {
+            let target = fetch_presets(fetcher, ip, port, options).await?;
+            let changes = plan_import(&target, id, file, force)?;
+            apply_sync(fetcher, ip, port, options, &changes).await
+        }
+    }
+}
+
+/// Delete preset `id` from a WLED.
+pub async fn delete_preset(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    id: u16,
+) -> Result<(), BoxError> {
+    let base_url = options.base_url(ip, port);
+    unlock(&fetcher.client, &base_url, options).await?;
+    let request = fetcher
+        .client
+        .post(format!("{base_url}/json/state"))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::json!({ "pdel": id }).to_string());
+    options.apply(request).send().await?.error_for_status()?;
+    info!("deleted preset {id} on {ip}:{port}");
+    Ok(())
+}
+
+/// Preset numbers, given as "5" or "1-10".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PresetRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PresetRange {
+    pub fn contains(&self, id: u16) -> bool {
+        (self.first..=self.last).contains(&id)
+    }
+}
+
+impl FromStr for PresetRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let id = |id: &str| match id.trim().parse::<u16>() {
+            Ok(id) if (1..=MAX_PRESET).contains(&id) => Ok(id),
+            _ => Err(format!(
+                "Invalid preset number '{id}': presets are 1 to {MAX_PRESET}"
+            )),
+        };
+        let (first, last) = match s.split_once('-') {
+            Some((first, last)) => (id(first)?, id(last)?),
+            None => (id(s)?, id(s)?),
+        };
+        if first > last {
+            return Err(format!("Invalid preset range '{s}'"));
+        }
+        Ok(PresetRange { first, last })
+    }
+}
+
+/// A preset to sync into another slot, given as "3=13".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotMap {
+    pub from: u16,
+    pub to: u16,
+}
+
+impl FromStr for SlotMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, to) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid slot mapping '{s}': expected FROM=TO"))?;
+        let from = from.parse::<PresetRange>()?;
+        let to = to.parse::<PresetRange>()?;
+        if from.first != from.last || to.first != to.last {
+            return Err(format!("Invalid slot mapping '{s}': expected FROM=TO"));
+        }
+        Ok(SlotMap {
+            from: from.first,
+            to: to.first,
+        })
+    }
+}
+
+/// A change to one of a WLED's presets: save it, or delete it if None.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PresetChange {
+    pub id: u16,
+    pub preset: Option<Preset>,
+}
+
+/// `preset`, playing the presets it plays under their new numbers.
+fn renumber(preset: &Preset, moved: impl Fn(u16) -> u16) -> Preset {
+    let mut preset = preset.clone();
+    if let Some(playlist) = preset.playlist.as_mut() {
+        playlist.presets = playlist.presets.iter().map(|id| moved(*id)).collect();
+        playlist.end = playlist
+            .end
+            .map(|end| if end == 0 { 0 } else { moved(end) });
+    }
+    preset
+}
+
+/// The changes that make a WLED's presets, `target`, match the presets
+/// numbered `ids` in `source`, each moved to another number if `map` says.
+/// The presets that synced playlists play are synced too, and playlists are
+/// changed to play them under their new numbers. Presets the source doesn't
+/// have are deleted, and ones that already match are left alone.
+pub fn plan_sync(
+    source: &Presets,
+    target: &Presets,
+    ids: &[PresetRange],
+    map: &[SlotMap],
+) -> Vec<PresetChange> {
+    let moved = |id: u16| map.iter().find(|m| m.from == id).map_or(id, |m| m.to);
+    let mut synced: BTreeSet<u16> = (1..=MAX_PRESET)
+        .filter(|id| ids.iter().any(|range| range.contains(*id)))
+        .collect();
+    for id in synced.clone() {
+        let (found, missing) = playlist_presets(source, id);
+        for missing in missing {
+            warn!("Playlist {id} plays preset {missing}, which isn't there to copy");
+        }
+        synced.extend(found);
+    }
+
+    let mut changes = vec![];
+    for from in synced {
+        let to = moved(from);
+        let wanted = source.0.get(&from.to_string()).map(|p| renumber(p, moved));
+        let current = target.0.get(&to.to_string());
+        if wanted.as_ref() != current {
+            changes.push(PresetChange {
+                id: to,
+                preset: wanted,
+            });
+        }
+    }
+    changes.sort_by_key(|change| change.id);
+    changes
+}
+
+/// Make the changes from plan_sync on a WLED, one preset at a time.
+pub async fn apply_sync(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    changes: &[PresetChange],
+) -> Result<(), BoxError> {
+    for change in changes.iter() {
+        match &change.preset {
+            Some(preset) => import_preset(fetcher, ip, port, options, change.id, preset).await?,
+            None => delete_preset(fetcher, ip, port, options, change.id).await?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{mock_recording_server, mock_routes_server};
+    use serde_json::json;
+    use std::net::Ipv4Addr;
+
+    fn preset(contents: Value) -> Preset {
+        serde_json::from_value(contents).unwrap()
+    }
+
+    #[test]
+    fn test_save_request() {
+        let ocean = preset(json!({"n": "Ocean", "on": true, "seg": [{"fx": 101}]}));
+        assert_eq!(
+            save_request(7, &ocean).unwrap(),
+            json!({"n": "Ocean", "on": true, "seg": [{"fx": 101}], "psave": 7, "o": true})
+        );
+        assert!(save_request(0, &ocean).is_err());
+        assert!(save_request(251, &ocean).is_err());
+    }
+
+    #[test]
+    fn test_plan_sync() {
+        let presets = |contents: Value| serde_json::from_value::<Presets>(contents).unwrap();
+        let source = presets(json!({
+            "0": {},
+            "1": {"n": "Ocean"},
+            "2": {"n": "Fire"},
+            "3": {"n": "Party"},
+            "12": {"n": "Not synced"},
+        }));
+        let target = presets(json!({
+            "1": {"n": "Ocean"},
+            "2": {"n": "Old fire"},
+            "4": {"n": "Gone"},
+            "20": {"n": "Kept"},
+        }));
+        let ids = ["1-4".parse().unwrap()];
+        let map = ["3=13".parse().unwrap()];
+
+        let changes = plan_sync(&source, &target, &ids, &map);
+        let summary: Vec<(u16, Option<String>)> = changes
+            .into_iter()
+            .map(|change| (change.id, change.preset.and_then(|p| p.name)))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (2, Some("Fire".to_string())),
+                (4, None),
+                (13, Some("Party".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_sync_playlist() {
+        let presets = |contents: Value| serde_json::from_value::<Presets>(contents).unwrap();
+        let source = presets(json!({
+            "3": {"n": "Party"},
+            "5": {"n": "Cycle", "playlist": {"ps": [3, 6, 8], "end": 3}},
+            "6": {"n": "Nested", "playlist": {"ps": [7]}},
+            "7": {"n": "Fire"},
+        }));
+        let ids = ["5".parse().unwrap()];
+        let map = ["3=13".parse().unwrap()];
+
+        let changes = plan_sync(&source, &Presets::default(), &ids, &map);
+        let ids: Vec<u16> = changes.iter().map(|change| change.id).collect();
+        assert_eq!(ids, [5, 6, 7, 13]);
+        let cycle = changes[0]
+            .preset
+            .as_ref()
+            .unwrap()
+            .playlist
+            .as_ref()
+            .unwrap();
+        assert_eq!(cycle.presets, [13, 6, 8]);
+        assert_eq!(cycle.end, Some(13));
+    }
+
+    #[test]
+    fn test_plan_import() {
+        let presets = |contents: Value| serde_json::from_value::<Presets>(contents).unwrap();
+        let source = presets(json!({
+            "1": {"n": "Ocean"},
+            "2": {"n": "Fire"},
+            "5": {"n": "Cycle", "playlist": {"ps": [1, 2, 9]}},
+        }));
+        let file = bundle(&source, 5).unwrap();
+        assert!(matches!(file, PresetFile::Playlist { playlist: 5, .. }));
+        let ocean = bundle(&source, 1).unwrap();
+        assert_eq!(ocean, PresetFile::Preset(preset(json!({"n": "Ocean"}))));
+
+        let target = presets(json!({"1": {"n": "Ocean"}, "2": {"n": "Other"}}));
+        assert!(plan_import(&target, 7, &file, false).is_err());
+        assert!(plan_import(&Presets::default(), 2, &file, false).is_err());
+
+        let changes = plan_import(&target, 7, &file, true).unwrap();
+        let ids: Vec<u16> = changes.iter().map(|change| change.id).collect();
+        assert_eq!(ids, [2, 7]);
+        assert_eq!(changes[1].preset.as_ref(), file.preset().ok());
+
+        let contents = serde_json::to_string(&file).unwrap();
+        assert_eq!(serde_json::from_str::<PresetFile>(&contents).unwrap(), file);
+    }
+
+    #[test]
+    fn test_preset_range() {
+        assert_eq!(
+            "1-10".parse::<PresetRange>(),
+            Ok(PresetRange { first: 1, last: 10 })
+        );
+        assert_eq!(
+            "5".parse::<PresetRange>(),
+            Ok(PresetRange { first: 5, last: 5 })
+        );
+        assert!("0-3".parse::<PresetRange>().is_err());
+        assert!("10-1".parse::<PresetRange>().is_err());
+        assert_eq!("3=13".parse::<SlotMap>(), Ok(SlotMap { from: 3, to: 13 }));
+        assert!("3-4=13".parse::<SlotMap>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_export_preset() {
+        let presets = r#"{"0":{},"5":{"n":"Ocean","bri":128}}"#;
+        let server = mock_routes_server("127.0.0.1:160", &[("/presets.json", presets)]);
+        let (fetcher, ip) = (Fetcher::default(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let options = DeviceOptions::default();
+
+        let ocean = export_preset(&fetcher, &ip, 160, &options, 5)
+            .await
+            .unwrap();
+        assert_eq!(
+            ocean,
+            PresetFile::Preset(preset(json!({"n": "Ocean", "bri": 128})))
+        );
+        let missing = export_preset(&fetcher, &ip, 160, &options, 6).await;
+        assert_eq!(
+            missing.unwrap_err().to_string(),
+            "127.0.0.1:160 has no preset 6"
+        );
+
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_import_preset() {
+        let server = mock_recording_server("127.0.0.1:161");
+        let (fetcher, ip) = (Fetcher::default(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let ocean = preset(json!({"n": "Ocean", "bri": 128}));
+        import_preset(&fetcher, &ip, 161, &DeviceOptions::default(), 7, &ocean)
+            .await
+            .unwrap();
+
+        let seen = server.join().unwrap();
+        assert_eq!(seen.len(), 1);
+        let (method, url, body) = &seen[0];
+        assert_eq!((method.as_str(), url.as_str()), ("POST", "/json/state"));
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            body,
+            json!({"n": "Ocean", "bri": 128, "psave": 7, "o": true})
+        );
+    }
+}
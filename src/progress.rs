@@ -0,0 +1,97 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::Write;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Downloads at least this big get a progress bar of their own.
+pub const LARGE_DOWNLOAD: u64 = 64 * 1024;
+
+static BARS: OnceLock<MultiProgress> = OnceLock::new();
+
+/// Show progress bars on stderr from now on. Until this is called, every bar
+/// is hidden.
+pub fn enable() {
+    BARS.get_or_init(|| MultiProgress::with_draw_target(ProgressDrawTarget::stderr()));
+}
+
+fn add(bar: ProgressBar, style: &str) -> ProgressBar {
+    let Some(bars) = BARS.get() else {
+        bar.set_draw_target(ProgressDrawTarget::hidden());
+        return bar;
+    };
+    if let Ok(style) = ProgressStyle::with_template(style) {
+        bar.set_style(style);
+    }
+    bars.add(bar)
+}
+
+/// A bar counting down the time left in a search.
+pub fn countdown(message: &str, duration: Duration) -> ProgressBar {
+    let bar = add(
+        ProgressBar::new(duration.as_millis() as u64),
+        "{msg} [{bar:30}] {prefix}",
+    );
+    bar.set_message(message.to_string());
+    bar
+}
+
+/// Move a countdown on to `elapsed` of its time.
+pub fn set_elapsed(bar: &ProgressBar, elapsed: Duration) {
+    let total = Duration::from_millis(bar.length().unwrap_or_default());
+    bar.set_position(elapsed.as_millis() as u64);
+    bar.set_prefix(format!("{}s left", total.saturating_sub(elapsed).as_secs()));
+}
+
+/// A spinner showing that work on `name` is under way.
+pub fn spinner(name: &str) -> ProgressBar {
+    let bar = add(ProgressBar::new_spinner(), "{spinner} {msg} {elapsed}");
+    bar.set_message(name.to_string());
+    bar.enable_steady_tick(Duration::from_millis(100));
+    bar
+}
+
+/// A bar for downloading `len` bytes of `name`.
+pub fn download(name: &str, len: u64) -> ProgressBar {
+    let bar = add(
+        ProgressBar::new(len),
+        "{msg} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec}",
+    );
+    bar.set_message(name.to_string());
+    bar
+}
+
+/// Writes log messages to stderr, clearing the progress bars while it does
+/// so the two don't garble each other.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match BARS.get() {
+            Some(bars) => bars.suspend(|| std::io::stderr().write(buf)),
+            None => std::io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+/// For the log's `with_writer`.
+pub fn log_writer() -> LogWriter {
+    LogWriter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_countdown() {
+        // Not enabled in tests, so nothing is drawn, but the bar still counts.
+        let bar = countdown("Searching", Duration::from_secs(4));
+        assert!(bar.is_hidden());
+        set_elapsed(&bar, Duration::from_millis(1500));
+        assert_eq!(bar.position(), 1500);
+    }
+}
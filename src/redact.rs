@@ -0,0 +1,220 @@
+use serde_json::{Map, Value};
+
+/// Fields of cfg.json holding passwords and keys, as paths from the top. "*"
+/// matches every element of an array. Older firmware also keeps them in
+/// wsec.json, with the same layout.
+pub const SECRET_FIELDS: &[&[&str]] = &[
+    // WiFi network passwords.
+    &["nw", "ins", "*", "psk"],
+    // Access point password.
+    &["ap", "psk"],
+    // MQTT broker password.
+    &["if", "mqtt", "psk"],
+    // Philips Hue bridge API key.
+    &["if", "hue", "key"],
+    // OTA update password.
+    &["ota", "pwd"],
+];
+
+/// The file secrets are saved to with --separate-secrets, such as
+/// porch_secrets.json.
+pub const SECRETS_FILE: &str = "secrets.json";
+
+/// What to do with the secrets in cfg.json.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SecretHandling {
+    /// Save them in cfg.json, as the device has them.
+    #[default]
+    Keep,
+    /// Leave them out.
+    Redact,
+    /// Move them to their own file, to be merged back on restore.
+    Separate,
+}
+
+/// Files that hold secrets.
+pub fn has_secrets(file_name: &str) -> bool {
+    file_name == "cfg.json" || file_name == "wsec.json"
+}
+
+/// Is the field at `path`, such as ["nw", "ins", "0", "psk"], a secret?
+pub fn is_secret_path(path: &[String]) -> bool {
+    SECRET_FIELDS.iter().any(|fields| {
+        fields.len() == path.len()
+            && fields.iter().zip(path).all(|(field, key)| {
+                *field == key || (*field == "*" && key.parse::<usize>().is_ok())
+            })
+    })
+}
+
+/// Remove the field at `path`. Returns it nested as it was, such as
+/// `{"ap":{"psk":"..."}}`, so it can be merged back. Array elements without
+/// the field are left as `{}`, to keep the others' positions.
+fn take_path(value: &mut Value, path: &[&str]) -> Option<Value> {
+    match path {
+        [] => None,
+        ["*", rest @ ..] => {
+            let taken: Vec<Option<Value>> = value
+                .as_array_mut()?
+                .iter_mut()
+                .map(|item| take_path(item, rest))
+                .collect();
+            taken.iter().any(Option::is_some).then(|| {
+                let items = taken
+                    .into_iter()
+                    .map(|item| item.unwrap_or_else(|| Value::Object(Map::new())));
+                Value::Array(items.collect())
+            })
+        }
+        [field] => {
+            let taken = value.as_object_mut()?.remove(*field)?;
+            Some(Value::Object(Map::from_iter([(field.to_string(), taken)])))
+        }
+        [field, rest @ ..] => {
+            let taken = take_path(value.get_mut(*field)?, rest)?;
+            Some(Value::Object(Map::from_iter([(field.to_string(), taken)])))
+        }
+    }
+}
+
+/// Merge `from` into `into`: objects key by key, arrays element by element,
+/// and anything else replaced.
+pub fn merge(into: &mut Value, from: Value) {
+    match (into, from) {
+        (Value::Object(into), Value::Object(from)) => {
+            for (key, value) in from {
+                match into.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        into.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Array(into), Value::Array(from)) => {
+            for (i, value) in from.into_iter().enumerate() {
+                match into.get_mut(i) {
+                    Some(existing) => merge(existing, value),
+                    None => into.push(value),
+                }
+            }
+        }
+        (into, from) => *into = from,
+    }
+}
+
+/// Remove every secret field, returning them in the same layout, or an
+/// empty object if there were none. Removing them, rather than blanking
+/// them, means restoring the backup leaves the device's own passwords alone.
+pub fn take_secrets(cfg: &mut Value) -> Value {
+    let mut secrets = Value::Object(Map::new());
+    for path in SECRET_FIELDS.iter() {
+        if let Some(taken) = take_path(cfg, path) {
+            merge(&mut secrets, taken);
+        }
+    }
+    secrets
+}
+
+/// Remove the secrets from a cfg.json or wsec.json download. Returns the
+/// file without them, and the secrets.
+pub fn split_file(contents: &[u8]) -> Result<(Vec<u8>, Value), serde_json::Error> {
+    let mut cfg: Value = serde_json::from_slice(contents)?;
+    let secrets = take_secrets(&mut cfg);
+    Ok((serde_json::to_vec(&cfg)?, secrets))
+}
+
+/// Put secrets saved by [`split_file`] back into cfg.json.
+pub fn inject_secrets(cfg: &[u8], secrets: &[u8]) -> Result<Vec<u8>, serde_json::Error> {
+    let mut cfg: Value = serde_json::from_slice(cfg)?;
+    merge(&mut cfg, serde_json::from_slice(secrets)?);
+    serde_json::to_vec(&cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_redact_secrets() {
+        let mut cfg = json!({
+            "id": {"name": "porch"},
+            "nw": {"ins": [
+                {"ssid": "home", "psk": "hunter2"},
+                {"ssid": "guest", "psk": "guest123"},
+            ]},
+            "ap": {"ssid": "WLED-AP", "psk": "wled1234"},
+            "if": {
+                "mqtt": {"en": true, "user": "wled", "psk": "mqttpass"},
+                "hue": {"en": false, "key": "abcdef"},
+            },
+            "ota": {"lock": false, "pwd": "wledota"},
+        });
+        let original = cfg.clone();
+
+        let secrets = take_secrets(&mut cfg);
+        assert_eq!(
+            secrets,
+            json!({
+                "nw": {"ins": [{"psk": "hunter2"}, {"psk": "guest123"}]},
+                "ap": {"psk": "wled1234"},
+                "if": {"mqtt": {"psk": "mqttpass"}, "hue": {"key": "abcdef"}},
+                "ota": {"pwd": "wledota"},
+            })
+        );
+        assert_eq!(
+            cfg,
+            json!({
+                "id": {"name": "porch"},
+                "nw": {"ins": [{"ssid": "home"}, {"ssid": "guest"}]},
+                "ap": {"ssid": "WLED-AP"},
+                "if": {
+                    "mqtt": {"en": true, "user": "wled"},
+                    "hue": {"en": false},
+                },
+                "ota": {"lock": false},
+            })
+        );
+
+        // Nothing to remove the second time, or from an unexpected layout.
+        assert_eq!(take_secrets(&mut cfg.clone()), json!({}));
+        assert_eq!(
+            take_secrets(&mut json!({"nw": {"ins": "x"}, "ap": 1})),
+            json!({})
+        );
+
+        // Merging them back gives the original.
+        merge(&mut cfg, secrets);
+        assert_eq!(cfg, original);
+    }
+
+    #[test]
+    fn test_split_and_inject() {
+        let (redacted, secrets) =
+            split_file(br#"{"ap":{"psk":"wled1234","ssid":"WLED-AP"}}"#).unwrap();
+        assert_eq!(redacted, br#"{"ap":{"ssid":"WLED-AP"}}"#);
+        assert_eq!(secrets, json!({"ap": {"psk": "wled1234"}}));
+
+        // Only the second network has a password.
+        let (redacted, secrets) =
+            split_file(br#"{"nw":{"ins":[{"ssid":"open"},{"ssid":"home","psk":"x"}]}}"#).unwrap();
+        assert_eq!(secrets, json!({"nw": {"ins": [{}, {"psk": "x"}]}}));
+        let restored = inject_secrets(&redacted, &serde_json::to_vec(&secrets).unwrap()).unwrap();
+        assert_eq!(
+            restored,
+            br#"{"nw":{"ins":[{"ssid":"open"},{"psk":"x","ssid":"home"}]}}"#
+        );
+
+        assert!(split_file(b"<html>").is_err());
+        assert!(inject_secrets(b"{}", b"<html>").is_err());
+        assert!(has_secrets("cfg.json"));
+        let path = |p: &[&str]| p.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(is_secret_path(&path(&["nw", "ins", "1", "psk"])));
+        assert!(is_secret_path(&path(&["ota", "pwd"])));
+        assert!(!is_secret_path(&path(&["nw", "ins", "1", "ssid"])));
+        assert!(!is_secret_path(&path(&["nw", "ins", "x", "psk"])));
+        assert!(!is_secret_path(&path(&["ap"])));
+        assert!(!has_secrets("presets.json"));
+    }
+}
@@ -0,0 +1,527 @@
+use crate::device::{Device, normalize_mac};
+use crate::error::BackupError;
+use crate::fleet::FleetDrift;
+use crate::model::WledInfo;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How results are printed on stdout.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Lines of text, for people
+    #[default]
+    Text,
+    /// A single JSON document at the end, for scripts
+    Json,
+}
+
+/// How the inventory of devices is printed.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Comma separated values, with a header row, for spreadsheets
+    #[default]
+    Csv,
+    /// A JSON array of devices
+    Json,
+}
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_output_format(format: OutputFormat) {
+    JSON_OUTPUT.store(format == OutputFormat::Json, Ordering::Relaxed);
+}
+
+/// Is stdout reserved for the JSON report?
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Print a result, such as a line of a diff: to stdout normally, or to
+/// stderr when stdout holds the JSON report. Progress is logged instead.
+#[macro_export]
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::report::json_output() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// A device as found by discovery, the command line, or the inventory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DeviceSummary {
+    pub name: String,
+    pub addresses: Vec<String>,
+    pub port: u16,
+    pub mac: Option<String>,
+    /// Firmware version, when asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+impl From<&Device> for DeviceSummary {
+    fn from(device: &Device) -> Self {
+        DeviceSummary {
+            name: device.name.clone(),
+            addresses: device.addresses.iter().map(|ip| ip.to_string()).collect(),
+            port: device.port,
+            mac: device.mac.clone(),
+            version: None,
+        }
+    }
+}
+
+/// A row of the inventory: what a device says about itself in /json/info.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceDetails {
+    pub name: String,
+    /// The address that answered, or the first known.
+    pub address: String,
+    pub mac: Option<String>,
+    pub version: Option<String>,
+    /// The chip, such as "esp32".
+    pub chip: Option<String>,
+    pub led_count: Option<u64>,
+    /// Wi-Fi signal quality, in percent.
+    pub signal: Option<u64>,
+    /// Wi-Fi signal strength, in dBm.
+    pub rssi: Option<i64>,
+}
+
+impl DeviceDetails {
+    pub const HEADER: [&str; 8] = [
+        "name",
+        "address",
+        "mac",
+        "version",
+        "chip",
+        "led_count",
+        "signal",
+        "rssi",
+    ];
+
+    /// The details of `device`, from its /json/info at `address` if it
+    /// answered.
+    pub fn new(device: &Device, address: Option<&IpAddr>, info: Option<&WledInfo>) -> Self {
+        let address = address.or(device.addresses.first());
+        DeviceDetails {
+            name: device.name.clone(),
+            address: address.map(|ip| ip.to_string()).unwrap_or_default(),
+            mac: info
+                .and_then(|info| info.mac.as_deref())
+                .or(device.mac.as_deref())
+                .map(normalize_mac),
+            version: info.and_then(|info| info.ver.clone()),
+            chip: info.and_then(WledInfo::arch),
+            led_count: info.and_then(WledInfo::led_count),
+            signal: info.and_then(WledInfo::signal),
+            rssi: info.and_then(WledInfo::rssi),
+        }
+    }
+
+    /// The details as cells, in the order of [`Self::HEADER`], with unknown
+    /// ones left empty.
+    pub fn cells(&self) -> Vec<String> {
+        let cell = |value: Option<String>| value.unwrap_or_default();
+        vec![
+            self.name.clone(),
+            self.address.clone(),
+            cell(self.mac.clone()),
+            cell(self.version.clone()),
+            cell(self.chip.clone()),
+            cell(self.led_count.map(|count| count.to_string())),
+            cell(self.signal.map(|signal| signal.to_string())),
+            cell(self.rssi.map(|rssi| rssi.to_string())),
+        ]
+    }
+}
+
+/// How the backup of one device went.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceReport {
+    pub name: String,
+    pub address: String,
+    pub port: u16,
+    pub success: bool,
+    /// Every file matched the previous backup.
+    pub unchanged: bool,
+    /// Host name from cfg.json, once it was downloaded.
+    pub hostname: Option<String>,
+    /// Files written, relative to out_dir.
+    pub files: Vec<String>,
+    pub bytes: u64,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+    /// What kind of error, such as "http" if the device couldn't be reached
+    /// or "io" if saving failed. See [`crate::error::BackupError::kind`].
+    pub error_kind: Option<String>,
+}
+
+/// How backing up a device went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceStatus {
+    Ok,
+    Failed,
+    /// Every file matched the previous backup.
+    Unchanged,
+    /// The device had no address to try.
+    NoAddress,
+}
+
+impl std::fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            DeviceStatus::Ok => "OK",
+            DeviceStatus::Failed => "FAILED",
+            DeviceStatus::Unchanged => "SKIPPED (unchanged)",
+            DeviceStatus::NoAddress => "NO ADDRESS",
+        })
+    }
+}
+
+impl DeviceReport {
+    pub fn status(&self) -> DeviceStatus {
+        match (self.success, self.unchanged) {
+            (false, _) if self.error_kind.as_deref() == Some("no_addresses") => {
+                DeviceStatus::NoAddress
+            }
+            (false, _) => DeviceStatus::Failed,
+            (true, true) => DeviceStatus::Unchanged,
+            (true, false) => DeviceStatus::Ok,
+        }
+    }
+
+    /// The status, with why a failed device failed, such as "FAILED:
+    /// http://10.0.0.5/cfg.json: timed out".
+    pub fn status_line(&self) -> String {
+        match (self.status(), &self.error) {
+            (DeviceStatus::Failed, Some(error)) => format!("FAILED: {error}"),
+            (status, _) => status.to_string(),
+        }
+    }
+}
+
+/// A device's report, with its status, for the JSON report.
+#[derive(Serialize)]
+struct WithStatus<'a> {
+    status: DeviceStatus,
+    #[serde(flatten)]
+    report: &'a DeviceReport,
+}
+
+fn serialize_backups<S: serde::Serializer>(
+    backups: &[DeviceReport],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(backups.iter().map(|report| WithStatus {
+        status: report.status(),
+        report,
+    }))
+}
+
+/// Counts of how each device went, which decide the exit code.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    pub succeeded: usize,
+    /// Including devices that couldn't be resolved or found.
+    pub failed: usize,
+    /// Unchanged since the previous backup.
+    pub skipped: usize,
+    /// Of those failed, the devices that couldn't be resolved or found, or
+    /// had no address.
+    pub unresolved: usize,
+}
+
+impl Summary {
+    pub fn new(backups: &[DeviceReport], unresolved: usize) -> Self {
+        let count = |status| backups.iter().filter(|b| b.status() == status).count();
+        let unresolved = count(DeviceStatus::NoAddress) + unresolved;
+        Summary {
+            succeeded: count(DeviceStatus::Ok),
+            failed: count(DeviceStatus::Failed) + unresolved,
+            skipped: count(DeviceStatus::Unchanged),
+            unresolved,
+        }
+    }
+
+    /// 0 if every device was backed up, 1 if some failed, 2 if all failed,
+    /// and 3 if there were no devices at all.
+    pub fn exit_code(&self) -> i32 {
+        if self.succeeded + self.failed + self.skipped == 0 {
+            3
+        } else if self.failed == 0 {
+            0
+        } else if self.succeeded + self.skipped == 0 {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.exit_code() == 3 {
+            return write!(f, "No WLEDs found");
+        }
+        write!(f, "{} succeeded, {} failed", self.succeeded, self.failed)?;
+        if self.unresolved > 0 {
+            write!(f, " ({} unresolved)", self.unresolved)?;
+        }
+        write!(f, ", {} skipped", self.skipped)
+    }
+}
+
+/// Everything a backup run found and did.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RunReport {
+    pub devices: Vec<DeviceSummary>,
+    #[serde(serialize_with = "serialize_backups")]
+    pub backups: Vec<DeviceReport>,
+    pub summary: Summary,
+    /// Differences from the fleet, when one is given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fleet: Option<FleetDrift>,
+    /// How copying the run to each --dest went.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub uploads: Vec<UploadReport>,
+    /// Everything that failed, including files saved for the whole run.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub failures: Vec<Failure>,
+}
+
+/// Something that failed in a backup run: a device, or a file saved for the
+/// whole run, such as the run's archive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Failure {
+    pub device: String,
+    pub error: String,
+    pub error_kind: Option<String>,
+}
+
+impl Failure {
+    pub fn new(device: &str, error: &BackupError) -> Self {
+        Failure {
+            device: device.to_string(),
+            error: error.to_string(),
+            error_kind: Some(error.kind().to_string()),
+        }
+    }
+}
+
+/// How copying a run to one destination went.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UploadReport {
+    pub destination: String,
+    pub success: bool,
+    pub files: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// How restoring one device went, for restore --all.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RestoreReport {
+    pub name: String,
+    /// Where it was restored to, once that was known.
+    pub address: Option<String>,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// How sending a command to one device went.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ActionReport {
+    pub name: String,
+    /// The address that answered, or was tried last.
+    pub address: Option<String>,
+    pub success: bool,
+    /// The firmware version it ran before an update.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_version: Option<String>,
+    /// The firmware version it runs after an update.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Print a report as JSON on stdout.
+pub fn print_json<T: Serialize>(report: &T) {
+    println!("{}", serde_json::to_string_pretty(report).unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock_device;
+    use serde_json::json;
+
+    #[test]
+    fn test_run_report_json() {
+        let report = RunReport {
+            devices: vec![DeviceSummary::from(&mock_device("porch", "127.0.0.1", 80))],
+            backups: vec![DeviceReport {
+                name: "porch".to_string(),
+                address: "127.0.0.1".to_string(),
+                port: 80,
+                success: false,
+                error: Some("HTTP 500".to_string()),
+                error_kind: Some("http".to_string()),
+                ..Default::default()
+            }],
+            summary: Summary {
+                succeeded: 0,
+                failed: 1,
+                skipped: 0,
+                unresolved: 0,
+            },
+            fleet: None,
+            uploads: vec![UploadReport {
+                destination: "s3://backups/wled".to_string(),
+                success: false,
+                files: 0,
+                error: Some("403 Forbidden".to_string()),
+            }],
+            failures: vec![Failure::new(
+                "porch",
+                &BackupError::Invalid("HTTP 500".to_string()),
+            )],
+        };
+
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            json!({
+                "devices": [{
+                    "name": "porch",
+                    "addresses": ["127.0.0.1"],
+                    "port": 80,
+                    "mac": null,
+                }],
+                "backups": [{
+                    "status": "failed",
+                    "name": "porch",
+                    "address": "127.0.0.1",
+                    "port": 80,
+                    "success": false,
+                    "unchanged": false,
+                    "hostname": null,
+                    "files": [],
+                    "bytes": 0,
+                    "duration_ms": 0,
+                    "error": "HTTP 500",
+                    "error_kind": "http",
+                }],
+                "summary": {"succeeded": 0, "failed": 1, "skipped": 0, "unresolved": 0},
+                "uploads": [{
+                    "destination": "s3://backups/wled",
+                    "success": false,
+                    "files": 0,
+                    "error": "403 Forbidden",
+                }],
+                "failures": [{"device": "porch", "error": "HTTP 500", "error_kind": "invalid"}],
+            })
+        );
+    }
+
+    #[test]
+    fn test_device_status() {
+        let mut report = DeviceReport {
+            success: true,
+            unchanged: true,
+            ..Default::default()
+        };
+        assert_eq!(report.status_line(), "SKIPPED (unchanged)");
+        report.unchanged = false;
+        assert_eq!(report.status_line(), "OK");
+
+        report.success = false;
+        report.error = Some("HTTP 500".to_string());
+        report.error_kind = Some("http".to_string());
+        assert_eq!(report.status(), DeviceStatus::Failed);
+        assert_eq!(report.status_line(), "FAILED: HTTP 500");
+        report.error_kind = Some("no_addresses".to_string());
+        assert_eq!(report.status_line(), "NO ADDRESS");
+
+        let summary = Summary::new(&[report], 1);
+        assert_eq!(summary.failed, 2);
+    }
+
+    #[test]
+    fn test_device_details() {
+        let device = mock_device("porch", "192.168.5.20", 80);
+        let info: WledInfo = serde_json::from_value(json!({
+            "ver": "0.15.0",
+            "mac": "aabbccddeeff",
+            "arch": "esp32",
+            "leds": {"count": 120},
+            "wifi": {"signal": 76, "rssi": -62},
+        }))
+        .unwrap();
+        let details = DeviceDetails::new(&device, None, Some(&info));
+        assert_eq!(
+            details.cells(),
+            [
+                "porch",
+                "192.168.5.20",
+                "aabbccddeeff",
+                "0.15.0",
+                "esp32",
+                "120",
+                "76",
+                "-62"
+            ]
+        );
+
+        // A device that didn't answer is still listed.
+        let details = DeviceDetails::new(&device, None, None);
+        assert_eq!(
+            details.cells(),
+            ["porch", "192.168.5.20", "", "", "", "", "", ""]
+        );
+    }
+
+    fn report(success: bool, unchanged: bool) -> DeviceReport {
+        DeviceReport {
+            success,
+            unchanged,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_summary_exit_code() {
+        let ok = report(true, false);
+        let skipped = report(true, true);
+        let failed = report(false, false);
+
+        let summary = Summary::new(&[ok.clone(), skipped.clone(), failed.clone()], 0);
+        assert_eq!(
+            summary,
+            Summary {
+                succeeded: 1,
+                failed: 1,
+                skipped: 1,
+                unresolved: 0,
+            }
+        );
+        assert_eq!(summary.to_string(), "1 succeeded, 1 failed, 1 skipped");
+        assert_eq!(summary.exit_code(), 1);
+
+        assert_eq!(Summary::new(&[ok.clone(), skipped], 0).exit_code(), 0);
+        assert_eq!(Summary::new(&[ok], 1).exit_code(), 1);
+        assert_eq!(Summary::new(&[failed], 0).exit_code(), 2);
+        assert_eq!(Summary::new(&[], 2).exit_code(), 2);
+        assert_eq!(
+            Summary::new(&[], 2).to_string(),
+            "0 succeeded, 2 failed (2 unresolved), 0 skipped"
+        );
+        assert_eq!(Summary::new(&[], 0).exit_code(), 3);
+        assert_eq!(Summary::new(&[], 0).to_string(), "No WLEDs found");
+    }
+}
@@ -0,0 +1,1057 @@
+use crate::backup::get_hostname_from_cfg;
+use crate::crypt::{Decryption, read_file};
+use crate::device::DeviceOptions;
+use crate::error::BoxError;
+use crate::http::unlock;
+use crate::layout::{Layout, LayoutKind, write_atomic};
+use crate::meta::{DeviceMeta, Mismatch};
+use crate::model::{Presets, WledCfg, WledInfo};
+use crate::redact::{inject_secrets, take_secrets};
+use reqwest::multipart::{Form, Part};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+/// Upload a single file to the WLED filesystem, as the WLED web UI does.
+async fn upload_file(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    contents: Vec<u8>,
+    remote_name: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // WLED stores the upload under the file name of the "data" part.
+    let part = Part::bytes(contents).file_name(format!("/{remote_name}"));
+    let form = Form::new().part("data", part);
+
+    let url = format!("{}/upload", options.base_url(ip, port));
+    options
+        .apply(client.post(url))
+        .multipart(form)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    info!("uploaded: {remote_name}");
+    Ok(())
+}
+
+/// Upload files, given as (name on the device, contents), in order, then
+/// reboot the WLED so they take effect. With `wait`, wait that long for it to
+/// come back, then check it kept the files.
+async fn upload_and_reboot(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    files: Vec<(String, Vec<u8>)>,
+    wait: Option<Duration>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let base_url = options.base_url(ip, port);
+    unlock(client, &base_url, options).await?;
+    // The firmware doesn't change, so it should come back running the same.
+    let version = match wait {
+        Some(_) => get_info(client, ip, port, options)
+            .await
+            .ok()
+            .and_then(|info| info.ver),
+        None => None,
+    };
+    for (remote_name, contents) in files.iter() {
+        upload_file(client, ip, port, options, contents.clone(), remote_name).await?;
+    }
+
+    options
+        .apply(client.post(format!("{base_url}/json/state")))
+        .body(r#"{"rb":true}"#)
+        .send()
+        .await?
+        .error_for_status()?;
+    info!("rebooting");
+    let rebooted = Instant::now();
+
+    let Some(wait) = wait else {
+        return Ok(());
+    };
+    let name = files
+        .iter()
+        .find(|(remote_name, _)| remote_name == "cfg.json")
+        .and_then(|(_, contents)| WledCfg::parse(contents).ok())
+        .and_then(|cfg| get_hostname_from_cfg(&cfg).ok().map(str::to_string));
+    let expected = Expected { name, version };
+    wait_for_reboot(client, ip, port, options, &expected, rebooted, wait).await?;
+    info!("back up after rebooting");
+    // The reboot ended the PIN session, and cfg.json needs it to read back.
+    unlock(client, &base_url, options).await?;
+    verify_uploaded(client, ip, port, options, &files).await?;
+    info!("verified: {} files", files.len());
+
+    Ok(())
+}
+
+/// How often to ask a rebooting WLED if it's back.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a WLED should say in /json/info once it's back from a reboot.
+pub(crate) struct Expected {
+    pub(crate) name: Option<String>,
+    pub(crate) version: Option<String>,
+}
+
+pub(crate) async fn get_info(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+) -> Result<WledInfo, BoxError> {
+    let url = format!("{}/json/info", options.base_url(ip, port));
+    let contents = options
+        .apply(client.get(url))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(serde_json::from_slice(&contents.bytes().await?)?)
+}
+
+/// Poll /json/info until the WLED answers having booted since `rebooted`,
+/// with the expected name and version, or `wait` has passed.
+pub(crate) async fn wait_for_reboot(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    expected: &Expected,
+    rebooted: Instant,
+    wait: Duration,
+) -> Result<WledInfo, BoxError> {
+    let deadline = rebooted + wait;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let info = tokio::time::timeout(remaining, get_info(client, ip, port, options)).await;
+        let problem = match info {
+            Ok(Ok(info)) => match reboot_problem(&info, expected, rebooted.elapsed()) {
+                None => return Ok(info),
+                Some(problem) => problem,
+            },
+            Ok(Err(result)) => result.to_string(),
+            Err(_) => "it didn't answer".to_string(),
+        };
+        if Instant::now() + POLL_INTERVAL > deadline {
+            let secs = wait.as_secs();
+            return Err(format!("{ip}:{port} didn't come back within {secs}s: {problem}").into());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Why `info` isn't yet the WLED back from its reboot `elapsed` ago, if it
+/// isn't.
+fn reboot_problem(info: &WledInfo, expected: &Expected, elapsed: Duration) -> Option<String> {
+    if let Some(uptime) = info.uptime_secs()
+        && Duration::from_secs(uptime) > elapsed
+    {
+        return Some("it hasn't rebooted".to_string());
+    }
+    if let (Some(expected), Some(name)) = (&expected.name, &info.name)
+        && expected != name
+    {
+        return Some(format!("it's named {name}, not {expected}"));
+    }
+    if let (Some(expected), Some(version)) = (&expected.version, &info.ver)
+        && expected != version
+    {
+        return Some(format!("it runs WLED {version}, not {expected}"));
+    }
+    None
+}
+
+/// Download each uploaded file again, and fail unless the WLED kept
+/// everything in it. WLED may add settings to cfg.json when it loads it,
+/// and keeps passwords elsewhere, so those aren't counted as differences.
+async fn verify_uploaded(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    files: &[(String, Vec<u8>)],
+) -> Result<(), BoxError> {
+    let base_url = options.base_url(ip, port);
+    for (remote_name, uploaded) in files.iter() {
+        let url = match remote_name.as_str() {
+            "cfg.json" | "presets.json" => format!("{base_url}/{remote_name}"),
+            _ => format!("{base_url}/edit?download=/{remote_name}"),
+        };
+        let response = options.apply(client.get(url)).send().await?;
+        let found = response.error_for_status()?.bytes().await?;
+        if !kept(uploaded, &found) {
+            return Err(format!("{remote_name} on {ip}:{port} isn't what was restored").into());
+        }
+    }
+    Ok(())
+}
+
+/// Does `found`, downloaded from a WLED, hold everything `uploaded` did?
+fn kept(uploaded: &[u8], found: &[u8]) -> bool {
+    let parse = serde_json::from_slice::<Value>;
+    let (Ok(mut uploaded), Ok(mut found)) = (parse(uploaded), parse(found)) else {
+        return uploaded == found;
+    };
+    take_secrets(&mut uploaded);
+    take_secrets(&mut found);
+    contains(&found, &uploaded)
+}
+
+/// Is every value in `part` also in `whole`, at the same place?
+fn contains(whole: &Value, part: &Value) -> bool {
+    match (whole, part) {
+        (Value::Object(whole), Value::Object(part)) => part
+            .iter()
+            .all(|(key, value)| whole.get(key).is_some_and(|found| contains(found, value))),
+        _ => whole == part,
+    }
+}
+
+/// The files of a saved backup to restore. Any ending in .age are
+/// decrypted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SavedFiles {
+    pub cfg: PathBuf,
+    pub presets: PathBuf,
+
+    /// Secrets saved separately by --separate-secrets, to merge back into
+    /// cfg.json.
+    pub secrets: Option<PathBuf>,
+
+    /// Files such as LED maps, as (local path, name on the device).
+    pub extra: Vec<(PathBuf, String)>,
+}
+
+/// How to restore a saved backup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreSettings {
+    /// Secrets to merge into cfg.json, instead of any saved with the backup.
+    pub secrets: Option<PathBuf>,
+
+    /// Restore even if the WLED's MAC or name isn't the backed up device's.
+    pub force: bool,
+
+    /// How long to wait for the WLED to come back after rebooting, before
+    /// checking it kept the restored files. Doesn't wait if None.
+    pub reboot_timeout: Option<Duration>,
+}
+
+/// What a restore would do, found without uploading anything.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RestorePlan {
+    /// The host name the WLED has now.
+    pub device: String,
+    pub address: String,
+    /// The files that would be uploaded, in order, before rebooting.
+    pub files: Vec<String>,
+}
+
+/// Plan uploading `files`, given as (name on the device, contents): check
+/// the WLED answers, and ask its name.
+async fn plan_upload(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    files: &[(String, Vec<u8>)],
+) -> Result<RestorePlan, Box<dyn std::error::Error + Send + Sync>> {
+    let base_url = options.base_url(ip, port);
+    unlock(client, &base_url, options).await?;
+    let url = format!("{base_url}/cfg.json");
+    let contents = options
+        .apply(client.get(url))
+        .send()
+        .await?
+        .error_for_status()?;
+    let cfg = WledCfg::parse(&contents.bytes().await?)?;
+    Ok(RestorePlan {
+        device: get_hostname_from_cfg(&cfg)?.to_string(),
+        address: format!("{ip}:{port}"),
+        files: files.iter().map(|(name, _)| name.clone()).collect(),
+    })
+}
+
+/// Why the WLED at `ip` might not be the device `meta` describes, going by
+/// its /json/info.
+pub async fn target_mismatches(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    meta: &DeviceMeta,
+) -> Result<Vec<Mismatch>, BoxError> {
+    let info = get_info(client, ip, port, options).await?;
+    Ok(meta.mismatches(&info))
+}
+
+/// The saved files to upload, as (name on the device, contents), in order.
+/// Everything is read first, so a missing file doesn't leave a half restore.
+fn read_saved(
+    saved: &SavedFiles,
+    decryption: Option<&Decryption>,
+) -> Result<Vec<(String, Vec<u8>)>, BoxError> {
+    let mut files = vec![];
+    for (local_path, remote_name) in saved.extra.iter() {
+        files.push((remote_name.clone(), read_file(local_path, decryption)?));
+    }
+    files.push((
+        "presets.json".to_string(),
+        read_file(&saved.presets, decryption)?,
+    ));
+    let mut cfg = read_file(&saved.cfg, decryption)?;
+    if let Some(secrets_path) = &saved.secrets {
+        cfg = inject_secrets(&cfg, &read_file(secrets_path, decryption)?)
+            .map_err(|e| format!("Failed to add {}: {e}", secrets_path.display()))?;
+    }
+    files.push(("cfg.json".to_string(), cfg));
+    Ok(files)
+}
+
+/// Push a saved cfg.json and presets.json back to a WLED, then reboot it so
+/// the restored configuration takes effect. With `wait`, wait up to that long
+/// for it to come back, and check it kept the files.
+pub async fn restore_wled(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    saved: &SavedFiles,
+    decryption: Option<&Decryption>,
+    wait: Option<Duration>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let files = read_saved(saved, decryption)?;
+    upload_and_reboot(client, ip, port, options, files, wait).await
+}
+
+/// What restore_wled would upload, after checking the saved files can be
+/// read, and which WLED it would go to.
+pub async fn plan_restore(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    saved: &SavedFiles,
+    decryption: Option<&Decryption>,
+) -> Result<RestorePlan, Box<dyn std::error::Error + Send + Sync>> {
+    let files = read_saved(saved, decryption)?;
+    plan_upload(client, ip, port, options, &files).await
+}
+
+/// The files from a WLED web UI "Backup & Restore" export.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Export {
+    pub cfg: Option<Vec<u8>>,
+    pub presets: Option<Vec<u8>>,
+}
+
+/// Which file an export is, going by its contents rather than its name, since
+/// browsers and users rename downloads. cfg.json has an "id" section, and
+/// presets.json is keyed by preset number.
+fn export_kind(contents: &[u8]) -> Option<&'static str> {
+    if WledCfg::parse(contents).is_ok_and(|cfg| cfg.id.is_some()) {
+        return Some("cfg.json");
+    }
+
+    let presets = Presets::parse(contents).ok()?;
+    let numbered = presets.0.keys().all(|k| k.parse::<u32>().is_ok());
+    (!presets.0.is_empty() && numbered).then_some("presets.json")
+}
+
+impl Export {
+    fn add(
+        &mut self,
+        source: &str,
+        contents: Vec<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let kind = export_kind(&contents)
+            .ok_or_else(|| format!("{source} is not a WLED cfg or presets backup"))?;
+        let slot = match kind {
+            "cfg.json" => &mut self.cfg,
+            _ => &mut self.presets,
+        };
+        if slot.is_some() {
+            return Err(format!("{source} is a second {kind} backup").into());
+        }
+        *slot = Some(contents);
+        Ok(())
+    }
+
+    /// The files to upload, as (name on the device, contents), in order.
+    fn files(self) -> Vec<(String, Vec<u8>)> {
+        [("presets.json", self.presets), ("cfg.json", self.cfg)]
+            .into_iter()
+            .filter_map(|(name, contents)| Some((name.to_string(), contents?)))
+            .collect()
+    }
+
+    /// Load an export from its downloaded .json files, or a zip of them.
+    pub fn load(paths: &[PathBuf]) -> Result<Export, Box<dyn std::error::Error + Send + Sync>> {
+        let mut export = Export::default();
+
+        for path in paths.iter() {
+            let source = path.display().to_string();
+            if path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("zip"))
+            {
+                let mut zip = zip::ZipArchive::new(fs::File::open(path)?)?;
+                for i in 0..zip.len() {
+                    let mut entry = zip.by_index(i)?;
+                    let name = entry.name()?.to_string();
+                    if !entry.is_file() || !name.ends_with(".json") {
+                        continue;
+                    }
+                    let entry_source = format!("{source}:{name}");
+                    let mut contents = vec![];
+                    entry.read_to_end(&mut contents)?;
+                    // Zips may hold other JSON, such as our manifest.
+                    if export_kind(&contents).is_some() {
+                        export.add(&entry_source, contents)?;
+                    }
+                }
+            } else {
+                export.add(&source, fs::read(path)?)?;
+            }
+        }
+
+        if export.cfg.is_none() && export.presets.is_none() {
+            return Err("No WLED cfg or presets backup found".into());
+        }
+        Ok(export)
+    }
+}
+
+/// Push a WLED web UI export back to a WLED, then reboot it, waiting for it
+/// as restore_wled does.
+pub async fn restore_export(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    export: Export,
+    wait: Option<Duration>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    upload_and_reboot(client, ip, port, options, export.files(), wait).await
+}
+
+/// What restore_export would upload, and which WLED it would go to.
+pub async fn plan_export(
+    client: &reqwest::Client,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    export: Export,
+) -> Result<RestorePlan, Box<dyn std::error::Error + Send + Sync>> {
+    plan_upload(client, ip, port, options, &export.files()).await
+}
+
+/// Under out_dir, where restore backs up a WLED before uploading to it.
+pub const PRE_RESTORE_DIR: &str = "pre-restore";
+
+/// In PRE_RESTORE_DIR, the snapshots `restore --rollback` goes back to.
+const LAST_RESTORE_FILE: &str = "last-restore.json";
+
+/// A backup of a WLED taken just before restoring to it, so the restore can
+/// be undone.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Host name the WLED had, which the snapshot is saved under.
+    pub hostname: String,
+    pub address: SocketAddr,
+    /// The snapshot's backup set.
+    pub timestamp: String,
+}
+
+/// What LAST_RESTORE_FILE holds: one snapshot per WLED of the last restore,
+/// or just one, as older versions saved.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LastRestore {
+    Each(Vec<Snapshot>),
+    One(Snapshot),
+}
+
+impl Snapshot {
+    /// Where snapshots are saved: per device and timestamped, under
+    /// out_dir/pre-restore/.
+    pub fn layout(out_dir: &Path, timestamp: &str) -> Layout {
+        let mut layout = Layout::new(LayoutKind::PerDevice, &out_dir.join(PRE_RESTORE_DIR));
+        layout.timestamp = Some(timestamp.to_string());
+        layout
+    }
+
+    fn path(out_dir: &Path) -> PathBuf {
+        out_dir.join(PRE_RESTORE_DIR).join(LAST_RESTORE_FILE)
+    }
+
+    /// The snapshots taken before the last restore, one for each WLED
+    /// restore --all restored to, less any rolled back since.
+    pub fn load_last(out_dir: &Path) -> Result<Vec<Snapshot>, BoxError> {
+        let path = Snapshot::path(out_dir);
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        let last = serde_json::from_slice(&fs::read(&path)?)
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        Ok(match last {
+            LastRestore::Each(snapshots) => snapshots,
+            LastRestore::One(snapshot) => vec![snapshot],
+        })
+    }
+
+    /// Record `snapshots` as the ones to roll back to, in place of those of
+    /// the restore before. None leaves nothing to roll back.
+    pub fn save_last(snapshots: &[Snapshot], out_dir: &Path) -> std::io::Result<()> {
+        if snapshots.is_empty() {
+            return match fs::remove_file(Snapshot::path(out_dir)) {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                removed => removed,
+            };
+        }
+        let contents = serde_json::to_vec_pretty(snapshots)?;
+        fs::create_dir_all(out_dir.join(PRE_RESTORE_DIR))?;
+        write_atomic(&Snapshot::path(out_dir), &contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{cfg_body, mock_routes_server};
+    use std::net::Ipv4Addr;
+    use std::thread;
+    use tempfile::tempdir;
+    use tiny_http::{Response, Server};
+
+    // Records the (url, body) of every request it receives.
+    fn recording_server(addr: &str, requests: usize) -> thread::JoinHandle<Vec<(String, String)>> {
+        let server = Server::http(addr).unwrap();
+        thread::spawn(move || {
+            let mut seen = vec![];
+            for _ in 0..requests {
+                if let Ok(mut request) = server.recv() {
+                    let mut body = String::new();
+                    request.as_reader().read_to_string(&mut body).unwrap();
+                    seen.push((request.url().to_string(), body));
+                    let _ = request.respond(Response::from_string("ok"));
+                }
+            }
+            seen
+        })
+    }
+
+    #[tokio::test]
+    async fn test_restore_wled_uploads_and_reboots() {
+        let server = recording_server("127.0.0.1:90", 3);
+
+        let dir = tempdir().unwrap();
+        let cfg_path = dir.path().join("testwled_cfg.json");
+        let presets_path = dir.path().join("testwled_presets.json");
+        fs::write(&cfg_path, "cfg data").unwrap();
+        fs::write(&presets_path, "presets data").unwrap();
+
+        let saved = SavedFiles {
+            cfg: cfg_path,
+            presets: presets_path,
+            ..Default::default()
+        };
+        let result = restore_wled(
+            &reqwest::Client::new(),
+            &IpAddr::V4(Ipv4Addr::LOCALHOST),
+            90,
+            &DeviceOptions::default(),
+            &saved,
+            None,
+            None,
+        )
+        .await;
+        assert!(result.is_ok(), "Restore failed");
+
+        let seen = server.join().unwrap();
+        assert_eq!(seen.len(), 3);
+
+        assert_eq!(seen[0].0, "/upload");
+        assert!(seen[0].1.contains(r#"filename="/presets.json""#));
+        assert!(seen[0].1.contains("presets data"));
+
+        assert_eq!(seen[1].0, "/upload");
+        assert!(seen[1].1.contains(r#"filename="/cfg.json""#));
+        assert!(seen[1].1.contains("cfg data"));
+
+        assert_eq!(
+            seen[2],
+            ("/json/state".to_string(), r#"{"rb":true}"#.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_wled_sends_pin() {
+        let server = recording_server("127.0.0.1:153", 4);
+
+        let dir = tempdir().unwrap();
+        let saved = SavedFiles {
+            cfg: dir.path().join("testwled_cfg.json"),
+            presets: dir.path().join("testwled_presets.json"),
+            ..Default::default()
+        };
+        fs::write(&saved.cfg, "cfg data").unwrap();
+        fs::write(&saved.presets, "presets data").unwrap();
+
+        let options = DeviceOptions {
+            pin: Some("1234".to_string()),
+            ..Default::default()
+        };
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        restore_wled(
+            &reqwest::Client::new(),
+            &ip,
+            153,
+            &options,
+            &saved,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+        // The PIN goes first, so the uploads are accepted.
+        let seen = server.join().unwrap();
+        assert_eq!(
+            seen[0],
+            ("/json/state".to_string(), r#"{"pin":"1234"}"#.to_string())
+        );
+        assert_eq!(seen[1].0, "/upload");
+    }
+
+    #[tokio::test]
+    async fn test_restore_wled_missing_file() {
+        let dir = tempdir().unwrap();
+
+        let saved = SavedFiles {
+            cfg: dir.path().join("missing_cfg.json"),
+            presets: dir.path().join("missing_presets.json"),
+            ..Default::default()
+        };
+        let result = restore_wled(
+            &reqwest::Client::new(),
+            &IpAddr::V4(Ipv4Addr::LOCALHOST),
+            91,
+            &DeviceOptions::default(),
+            &saved,
+            None,
+            None,
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_plan_restore() {
+        let server = mock_routes_server("127.0.0.1:148", &[("/cfg.json", &cfg_body("porch"))]);
+
+        let dir = tempdir().unwrap();
+        let saved = SavedFiles {
+            cfg: dir.path().join("testwled_cfg.json"),
+            presets: dir.path().join("testwled_presets.json"),
+            extra: vec![(
+                dir.path().join("testwled_ledmap.json"),
+                "ledmap.json".to_string(),
+            )],
+            ..Default::default()
+        };
+        fs::write(&saved.cfg, "cfg data").unwrap();
+        fs::write(&saved.presets, "presets data").unwrap();
+        fs::write(&saved.extra[0].0, "ledmap data").unwrap();
+
+        let plan = plan_restore(
+            &reqwest::Client::new(),
+            &IpAddr::V4(Ipv4Addr::LOCALHOST),
+            148,
+            &DeviceOptions::default(),
+            &saved,
+            None,
+        )
+        .await
+        .unwrap();
+        server.join().unwrap();
+        assert_eq!(
+            plan,
+            RestorePlan {
+                device: "porch".to_string(),
+                address: "127.0.0.1:148".to_string(),
+                files: vec![
+                    "ledmap.json".to_string(),
+                    "presets.json".to_string(),
+                    "cfg.json".to_string()
+                ],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_target_mismatches() {
+        let info = r#"{"ver":"0.15.0","mac":"112233445566"}"#;
+        let server = mock_routes_server("127.0.0.1:150", &[("/json/info", info)]);
+        let meta = DeviceMeta {
+            mac: Some("aabbccddeeff".to_string()),
+            version: Some("0.15.0".to_string()),
+            ..Default::default()
+        };
+        let mismatches = target_mismatches(
+            &reqwest::Client::new(),
+            &IpAddr::V4(Ipv4Addr::LOCALHOST),
+            150,
+            &DeviceOptions::default(),
+            &meta,
+        )
+        .await
+        .unwrap();
+        server.join().unwrap();
+        assert_eq!(
+            mismatches,
+            [Mismatch::Mac {
+                saved: "aabbccddeeff".to_string(),
+                found: "112233445566".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restore_wled_waits_and_verifies() {
+        let cfg = cfg_body("porch");
+        let info = r#"{"ver":"0.15.0","name":"porch","uptime":0}"#;
+        let routes = |presets| {
+            [
+                ("/json/info", info),
+                ("/upload", ""),
+                ("/json/state", ""),
+                ("/cfg.json", cfg.as_str()),
+                ("/presets.json", presets),
+            ]
+        };
+
+        let dir = tempdir().unwrap();
+        let saved = SavedFiles {
+            cfg: dir.path().join("porch_cfg.json"),
+            presets: dir.path().join("porch_presets.json"),
+            ..Default::default()
+        };
+        fs::write(&saved.cfg, &cfg).unwrap();
+        fs::write(&saved.presets, r#"{"0":{},"1":{"n":"Ocean"}}"#).unwrap();
+        let client = reqwest::Client::new();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let options = DeviceOptions::default();
+        let wait = Some(Duration::from_secs(5));
+
+        // The device kept the presets, and added one of its own.
+        let server = mock_routes_server(
+            "127.0.0.1:158",
+            &routes(r#"{"0":{},"1":{"n":"Ocean"},"2":{}}"#),
+        );
+        restore_wled(&client, &ip, 158, &options, &saved, None, wait)
+            .await
+            .unwrap();
+        server.join().unwrap();
+
+        // It lost them.
+        let server = mock_routes_server("127.0.0.1:159", &routes(r#"{"0":{}}"#));
+        let result = restore_wled(&client, &ip, 159, &options, &saved, None, wait)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            result.to_string(),
+            "presets.json on 127.0.0.1:159 isn't what was restored"
+        );
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_restore_wled_unlocks_after_reboot() {
+        let cfg = cfg_body("porch");
+        let presets = r#"{"0":{},"1":{"n":"Ocean"}}"#;
+        let info = r#"{"ver":"0.15.0","name":"porch","uptime":0}"#;
+
+        // Like WLED, cfg.json needs the PIN, and a reboot forgets it was sent.
+        let server = Server::http("127.0.0.1:171").unwrap();
+        let device = {
+            let cfg = cfg.clone();
+            thread::spawn(move || {
+                let mut unlocked = false;
+                let mut next = server.recv().ok();
+                while let Some(mut request) = next {
+                    let mut body = String::new();
+                    request.as_reader().read_to_string(&mut body).unwrap();
+                    let response = match request.url() {
+                        "/json/state" if body.contains("pin") => {
+                            unlocked = true;
+                            Response::from_string("")
+                        }
+                        "/json/state" => {
+                            unlocked = false;
+                            Response::from_string("")
+                        }
+                        "/cfg.json" if unlocked => Response::from_string(cfg.clone()),
+                        "/cfg.json" => Response::from_string("").with_status_code(401),
+                        "/presets.json" => Response::from_string(presets),
+                        "/json/info" => Response::from_string(info),
+                        _ => Response::from_string(""),
+                    };
+                    let _ = request.respond(response);
+                    next = server.recv_timeout(Duration::from_secs(1)).ok().flatten();
+                }
+            })
+        };
+
+        let dir = tempdir().unwrap();
+        let saved = SavedFiles {
+            cfg: dir.path().join("porch_cfg.json"),
+            presets: dir.path().join("porch_presets.json"),
+            ..Default::default()
+        };
+        fs::write(&saved.cfg, &cfg).unwrap();
+        fs::write(&saved.presets, presets).unwrap();
+        let options = DeviceOptions {
+            pin: Some("1234".to_string()),
+            ..Default::default()
+        };
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let wait = Some(Duration::from_secs(5));
+        restore_wled(
+            &reqwest::Client::new(),
+            &ip,
+            171,
+            &options,
+            &saved,
+            None,
+            wait,
+        )
+        .await
+        .unwrap();
+        device.join().unwrap();
+    }
+
+    #[test]
+    fn test_last_restore() {
+        let dir = tempdir().unwrap();
+        let snapshot = |hostname: &str, address: &str| Snapshot {
+            hostname: hostname.to_string(),
+            address: address.parse().unwrap(),
+            timestamp: "20240501T120000Z".to_string(),
+        };
+
+        // restore --all records every WLED it restored to.
+        let taken = vec![
+            snapshot("porch", "10.0.0.2:80"),
+            snapshot("garden", "10.0.0.3:80"),
+        ];
+        Snapshot::save_last(&taken, dir.path()).unwrap();
+        assert_eq!(Snapshot::load_last(dir.path()).unwrap(), taken);
+
+        // Older versions recorded only one.
+        let path = dir.path().join(PRE_RESTORE_DIR).join(LAST_RESTORE_FILE);
+        fs::write(&path, serde_json::to_vec(&taken[0]).unwrap()).unwrap();
+        assert_eq!(Snapshot::load_last(dir.path()).unwrap(), taken[..1]);
+
+        Snapshot::save_last(&[], dir.path()).unwrap();
+        assert!(!path.exists());
+        assert_eq!(Snapshot::load_last(dir.path()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_reboot_problem() {
+        let expected = Expected {
+            name: Some("porch".to_string()),
+            version: Some("0.15.0".to_string()),
+        };
+        let info = |contents| serde_json::from_str::<WledInfo>(contents).unwrap();
+        let elapsed = Duration::from_secs(10);
+
+        let back = info(r#"{"name":"porch","ver":"0.15.0","uptime":3}"#);
+        assert_eq!(reboot_problem(&back, &expected, elapsed), None);
+        let not_yet = info(r#"{"name":"porch","ver":"0.15.0","uptime":3600}"#);
+        assert_eq!(
+            reboot_problem(&not_yet, &expected, elapsed).unwrap(),
+            "it hasn't rebooted"
+        );
+        let renamed = info(r#"{"name":"garden","ver":"0.15.0","uptime":3}"#);
+        assert_eq!(
+            reboot_problem(&renamed, &expected, elapsed).unwrap(),
+            "it's named garden, not porch"
+        );
+    }
+
+    #[test]
+    fn test_kept() {
+        // WLED moves passwords out of cfg.json, and adds its own defaults.
+        let uploaded = br#"{"id":{"name":"porch"},"nw":{"ins":[{"psk":"secret"}]}}"#;
+        let found = br#"{"id":{"name":"porch"},"nw":{"ins":[{}]},"new":1}"#;
+        assert!(kept(uploaded, found));
+        assert!(!kept(uploaded, br#"{"id":{"name":"garden"}}"#));
+        assert!(kept(b"not json", b"not json"));
+        assert!(!kept(b"not json", b"other"));
+    }
+
+    #[tokio::test]
+    async fn test_restore_wled_extra_files() {
+        let server = recording_server("127.0.0.1:107", 4);
+
+        let dir = tempdir().unwrap();
+        let cfg_path = dir.path().join("testwled_cfg.json");
+        let presets_path = dir.path().join("testwled_presets.json");
+        let ledmap_path = dir.path().join("testwled_ledmap.json");
+        fs::write(&cfg_path, "cfg data").unwrap();
+        fs::write(&presets_path, "presets data").unwrap();
+        fs::write(&ledmap_path, "ledmap data").unwrap();
+
+        let saved = SavedFiles {
+            cfg: cfg_path,
+            presets: presets_path,
+            secrets: None,
+            extra: vec![(ledmap_path, "ledmap.json".to_string())],
+        };
+        let result = restore_wled(
+            &reqwest::Client::new(),
+            &IpAddr::V4(Ipv4Addr::LOCALHOST),
+            107,
+            &DeviceOptions::default(),
+            &saved,
+            None,
+            None,
+        )
+        .await;
+        assert!(result.is_ok(), "Restore failed");
+
+        let seen = server.join().unwrap();
+        assert!(seen[0].1.contains(r#"filename="/ledmap.json""#));
+        assert!(seen[0].1.contains("ledmap data"));
+        assert!(seen[1].1.contains(r#"filename="/presets.json""#));
+    }
+
+    #[tokio::test]
+    async fn test_restore_wled_injects_secrets() {
+        let server = recording_server("127.0.0.1:133", 3);
+
+        let dir = tempdir().unwrap();
+        let cfg_path = dir.path().join("testwled_cfg.json");
+        let presets_path = dir.path().join("testwled_presets.json");
+        let secrets_path = dir.path().join("testwled_secrets.json");
+        fs::write(&cfg_path, r#"{"ap":{"ssid":"WLED-AP"}}"#).unwrap();
+        fs::write(&presets_path, "presets data").unwrap();
+        fs::write(&secrets_path, r#"{"ap":{"psk":"wled1234"}}"#).unwrap();
+
+        let saved = SavedFiles {
+            cfg: cfg_path,
+            presets: presets_path,
+            secrets: Some(secrets_path),
+            extra: vec![],
+        };
+        let result = restore_wled(
+            &reqwest::Client::new(),
+            &IpAddr::V4(Ipv4Addr::LOCALHOST),
+            133,
+            &DeviceOptions::default(),
+            &saved,
+            None,
+            None,
+        )
+        .await;
+        assert!(result.is_ok(), "Restore failed");
+
+        let seen = server.join().unwrap();
+        assert!(
+            seen[1]
+                .1
+                .contains(r#"{"ap":{"psk":"wled1234","ssid":"WLED-AP"}}"#)
+        );
+    }
+
+    const CFG: &str = r#"{"rev":[1,0],"id":{"name":"porch"}}"#;
+    const PRESETS: &str = r#"{"0":{},"1":{"n":"Warm"}}"#;
+
+    #[test]
+    fn test_export_kind() {
+        assert_eq!(export_kind(CFG.as_bytes()), Some("cfg.json"));
+        assert_eq!(export_kind(PRESETS.as_bytes()), Some("presets.json"));
+        assert_eq!(export_kind(br#"{"files":[]}"#), None);
+        assert_eq!(export_kind(b"not json"), None);
+    }
+
+    #[test]
+    fn test_export_load_files() {
+        let dir = tempdir().unwrap();
+        // Named as the WLED UI names them, but told apart by contents.
+        let cfg_path = dir.path().join("wled_cfg_porch.json");
+        let presets_path = dir.path().join("wled_presets_porch.json");
+        fs::write(&cfg_path, CFG).unwrap();
+        fs::write(&presets_path, PRESETS).unwrap();
+
+        let export = Export::load(&[presets_path, cfg_path.clone()]).unwrap();
+        assert_eq!(export.cfg, Some(CFG.as_bytes().to_vec()));
+        assert_eq!(export.presets, Some(PRESETS.as_bytes().to_vec()));
+
+        assert!(Export::load(&[cfg_path.clone(), cfg_path]).is_err());
+    }
+
+    #[test]
+    fn test_export_load_zip() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("export.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        for (name, contents) in [
+            ("cfg.json", CFG),
+            ("presets.json", PRESETS),
+            ("manifest.json", "{}"),
+        ] {
+            zip.start_file(name, options).unwrap();
+            std::io::Write::write_all(&mut zip, contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+
+        let export = Export::load(&[path]).unwrap();
+        assert_eq!(export.cfg, Some(CFG.as_bytes().to_vec()));
+        assert_eq!(export.presets, Some(PRESETS.as_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_export_load_not_a_backup() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("notes.json");
+        fs::write(&path, r#"{"todo":1}"#).unwrap();
+        assert!(Export::load(&[path]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_restore_export_cfg_only() {
+        let server = recording_server("127.0.0.1:112", 2);
+
+        let export = Export {
+            cfg: Some(CFG.as_bytes().to_vec()),
+            presets: None,
+        };
+        let result = restore_export(
+            &reqwest::Client::new(),
+            &IpAddr::V4(Ipv4Addr::LOCALHOST),
+            112,
+            &DeviceOptions::default(),
+            export,
+            None,
+        )
+        .await;
+        assert!(result.is_ok(), "Restore failed");
+
+        let seen = server.join().unwrap();
+        assert!(seen[0].1.contains(r#"filename="/cfg.json""#));
+        assert_eq!(seen[1].0, "/json/state");
+    }
+}
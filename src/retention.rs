@@ -0,0 +1,223 @@
+use crate::layout::{LATEST, Layout, LayoutKind, parse_timestamp_name};
+use chrono::{DateTime, Duration, Utc};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Which timestamped backup sets to keep. A set is kept if any rule keeps it.
+#[derive(clap::Args, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Keep the newest N backup sets (per device with --layout per-device)
+    #[arg(long, value_name = "N")]
+    pub keep_last: Option<usize>,
+
+    /// Keep backup sets newer than D days
+    #[arg(long, value_name = "D")]
+    pub keep_days: Option<u64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_set(&self) -> bool {
+        self.keep_last.is_some() || self.keep_days.is_some()
+    }
+
+    /// Pick the backup sets to delete from the given (name, time) pairs.
+    fn expired<'a>(&self, sets: &'a [(String, DateTime<Utc>)], now: DateTime<Utc>) -> Vec<&'a str> {
+        if !self.is_set() {
+            return vec![];
+        }
+
+        let mut newest_first: Vec<&(String, DateTime<Utc>)> = sets.iter().collect();
+        newest_first.sort_by_key(|set| std::cmp::Reverse(set.1));
+
+        newest_first
+            .iter()
+            .enumerate()
+            .filter(|(index, (_, time))| {
+                let keep_last = self.keep_last.is_some_and(|n| *index < n);
+                let keep_days = self
+                    .keep_days
+                    .is_some_and(|d| now - *time < Duration::days(d as i64));
+                !keep_last && !keep_days
+            })
+            .map(|(_, (name, _))| name.as_str())
+            .collect()
+    }
+}
+
+/// Delete expired backup sets in one directory, never touching the set the
+/// latest link points at. Returns the deleted directories.
+fn prune_dir(
+    dir: &Path,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+) -> std::io::Result<Vec<PathBuf>> {
+    let latest = fs::read_link(dir.join(LATEST)).ok();
+
+    let mut sets = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        if let Some(time) = parse_timestamp_name(&name) {
+            sets.push((name, time));
+        }
+    }
+
+    let mut removed = vec![];
+    for name in policy.expired(&sets, now) {
+        if latest.as_deref() == Some(Path::new(name)) {
+            continue;
+        }
+        let path = dir.join(name);
+        fs::remove_dir_all(&path)?;
+        removed.push(path);
+    }
+
+    Ok(removed)
+}
+
+/// Delete expired backup sets under out_dir, for every device if the layout
+/// is per-device. Returns the deleted directories.
+pub fn prune(
+    layout: &Layout,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+) -> std::io::Result<Vec<PathBuf>> {
+    match layout.kind {
+        LayoutKind::Flat => prune_dir(&layout.out_dir, policy, now),
+        LayoutKind::PerDevice => {
+            let mut removed = vec![];
+            for entry in fs::read_dir(&layout.out_dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    removed.extend(prune_dir(&entry.path(), policy, now)?);
+                }
+            }
+            Ok(removed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::timestamp_name;
+    use chrono::TimeZone;
+    use tempfile::tempdir;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 10, 15, 12, 0, 0).unwrap()
+    }
+
+    fn days_ago(days: i64) -> String {
+        timestamp_name(now() - Duration::days(days))
+    }
+
+    // Create backup sets 0..count days old in dir.
+    fn make_sets(dir: &Path, count: i64) {
+        for days in 0..count {
+            fs::create_dir_all(dir.join(days_ago(days))).unwrap();
+        }
+    }
+
+    fn remaining(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn policy(keep_last: Option<usize>, keep_days: Option<u64>) -> RetentionPolicy {
+        RetentionPolicy {
+            keep_last,
+            keep_days,
+        }
+    }
+
+    #[test]
+    fn test_prune_keep_last() {
+        let dir = tempdir().unwrap();
+        make_sets(dir.path(), 5);
+        fs::write(dir.path().join("porch_cfg.json"), "not a set").unwrap();
+
+        let layout = Layout::new(LayoutKind::Flat, dir.path());
+        let removed = prune(&layout, &policy(Some(2), None), now()).unwrap();
+
+        assert_eq!(removed.len(), 3);
+        assert_eq!(
+            remaining(dir.path()),
+            vec![days_ago(1), days_ago(0), "porch_cfg.json".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_prune_keep_days() {
+        let dir = tempdir().unwrap();
+        make_sets(dir.path(), 5);
+
+        let layout = Layout::new(LayoutKind::Flat, dir.path());
+        prune(&layout, &policy(None, Some(3)), now()).unwrap();
+
+        assert_eq!(
+            remaining(dir.path()),
+            vec![days_ago(2), days_ago(1), days_ago(0)]
+        );
+    }
+
+    #[test]
+    fn test_prune_either_rule_keeps() {
+        let dir = tempdir().unwrap();
+        make_sets(dir.path(), 5);
+
+        let layout = Layout::new(LayoutKind::Flat, dir.path());
+        prune(&layout, &policy(Some(1), Some(2)), now()).unwrap();
+
+        assert_eq!(remaining(dir.path()), vec![days_ago(1), days_ago(0)]);
+    }
+
+    #[test]
+    fn test_prune_no_policy_keeps_everything() {
+        let dir = tempdir().unwrap();
+        make_sets(dir.path(), 3);
+
+        let layout = Layout::new(LayoutKind::Flat, dir.path());
+        let removed = prune(&layout, &RetentionPolicy::default(), now()).unwrap();
+
+        assert!(removed.is_empty());
+        assert_eq!(remaining(dir.path()).len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_prune_keeps_latest() {
+        let dir = tempdir().unwrap();
+        make_sets(dir.path(), 3);
+        std::os::unix::fs::symlink(days_ago(2), dir.path().join(LATEST)).unwrap();
+
+        let layout = Layout::new(LayoutKind::Flat, dir.path());
+        prune(&layout, &policy(Some(1), None), now()).unwrap();
+
+        assert_eq!(
+            remaining(dir.path()),
+            vec![days_ago(2), days_ago(0), LATEST.to_string()]
+        );
+    }
+
+    #[test]
+    fn test_prune_per_device() {
+        let dir = tempdir().unwrap();
+        make_sets(&dir.path().join("porch"), 3);
+        make_sets(&dir.path().join("garden"), 2);
+
+        let layout = Layout::new(LayoutKind::PerDevice, dir.path());
+        let removed = prune(&layout, &policy(Some(1), None), now()).unwrap();
+
+        assert_eq!(removed.len(), 3);
+        assert_eq!(remaining(&dir.path().join("porch")), vec![days_ago(0)]);
+        assert_eq!(remaining(&dir.path().join("garden")), vec![days_ago(0)]);
+    }
+}
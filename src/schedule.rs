@@ -0,0 +1,258 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, TimeZone, Timelike};
+use std::str::FromStr;
+
+/// Parse a duration such as "36h" or "7d". Minutes, hours and days are
+/// allowed.
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || format!("{duration:?} isn't a number followed by m, h or d");
+    let (count, unit) = duration.split_at(duration.len().saturating_sub(1));
+    let count: i64 = count.parse().map_err(|_| invalid())?;
+    match unit {
+        "m" => Ok(Duration::minutes(count)),
+        "h" => Ok(Duration::hours(count)),
+        "d" => Ok(Duration::days(count)),
+        _ => Err(invalid()),
+    }
+}
+
+/// One field of a cron expression: the values it matches, and whether it
+/// started with "*".
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CronField {
+    values: Vec<u32>,
+    any: bool,
+}
+
+impl CronField {
+    /// Parse a field such as "*", "5", "1-5", "*/15" or "0,30", with values
+    /// from `min` to `max`.
+    fn parse(field: &str, min: u32, max: u32) -> Result<CronField, String> {
+        let mut values = vec![];
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => {
+                    let step: u32 = step.parse().map_err(|_| format!("bad step in {part:?}"))?;
+                    (range, step.max(1))
+                }
+                None => (part, 1),
+            };
+            let (first, last) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((first, last)) => (
+                        first.parse().map_err(|_| format!("bad range {range:?}"))?,
+                        last.parse().map_err(|_| format!("bad range {range:?}"))?,
+                    ),
+                    None => {
+                        let value = range.parse().map_err(|_| format!("bad value {range:?}"))?;
+                        // "5/15" means from 5 to the end, every 15.
+                        (value, if part.contains('/') { max } else { value })
+                    }
+                },
+            };
+            if first < min || last > max || first > last {
+                return Err(format!("{part:?} is outside {min}-{max}"));
+            }
+            values.extend((first..=last).step_by(step as usize));
+        }
+        Ok(CronField {
+            values,
+            any: field.starts_with('*'),
+        })
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.values.contains(&value)
+    }
+}
+
+/// A five-field cron expression, such as "0 3 * * *" for 3am every day, in
+/// local time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minute: CronField,
+    hour: CronField,
+    day: CronField,
+    month: CronField,
+    weekday: CronField,
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(cron: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = cron.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "{cron:?} isn't five fields: minute hour day month weekday"
+            ));
+        };
+        let mut weekday = CronField::parse(weekday, 0, 7)?;
+        // Both 0 and 7 are Sunday.
+        if weekday.matches(7) {
+            weekday.values.push(0);
+        }
+        Ok(Cron {
+            minute: CronField::parse(minute, 0, 59)?,
+            hour: CronField::parse(hour, 0, 23)?,
+            day: CronField::parse(day, 1, 31)?,
+            month: CronField::parse(month, 1, 12)?,
+            weekday,
+        })
+    }
+}
+
+impl Cron {
+    fn matches_day(&self, time: &NaiveDateTime) -> bool {
+        if !self.month.matches(time.month()) {
+            return false;
+        }
+        let day = self.day.matches(time.day());
+        let weekday = self.weekday.matches(time.weekday().num_days_from_sunday());
+        // As in cron, if both are given, either will do.
+        match (self.day.any, self.weekday.any) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first time after `after` that the expression matches, or None if
+    /// it never does, as with "0 0 31 2 *".
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut time =
+            after.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        // Every schedule that matches at all does within a leap year cycle.
+        let end = time + Duration::days(4 * 366);
+        while time < end {
+            if !self.matches_day(&time) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !self.hour.matches(time.hour()) {
+                time = time.with_minute(0)? + Duration::hours(1);
+            } else if !self.minute.matches(time.minute()) {
+                time += Duration::minutes(1);
+            } else if let Some(local) = Local.from_local_datetime(&time).earliest() {
+                return Some(local);
+            } else {
+                // Skipped by a daylight saving change.
+                time += Duration::minutes(1);
+            }
+        }
+        None
+    }
+}
+
+/// When the daemon runs backups.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Straight away, and then this long after each run started.
+    Every(Duration),
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// When to run next, given when the previous run started, if there was
+    /// one.
+    pub fn next_run(
+        &self,
+        previous: Option<DateTime<Local>>,
+        now: DateTime<Local>,
+    ) -> Option<DateTime<Local>> {
+        match (self, previous) {
+            (Schedule::Every(_), None) => Some(now),
+            (Schedule::Every(interval), Some(previous)) => Some((previous + *interval).max(now)),
+            (Schedule::Cron(cron), _) => cron.next_after(now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(time: &str) -> DateTime<Local> {
+        let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap();
+        Local.from_local_datetime(&time).earliest().unwrap()
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("36h"), Ok(Duration::hours(36)));
+        assert_eq!(parse_duration("7d"), Ok(Duration::days(7)));
+        assert_eq!(parse_duration("90m"), Ok(Duration::minutes(90)));
+        assert!(parse_duration("7").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("7w").is_err());
+    }
+
+    #[test]
+    fn test_cron_parse() {
+        assert!("0 3 * * *".parse::<Cron>().is_ok());
+        assert!("*/15 9-17 * * 1-5".parse::<Cron>().is_ok());
+        assert!("0 3 * *".parse::<Cron>().is_err());
+        assert!("60 3 * * *".parse::<Cron>().is_err());
+        assert!("0 3 0 * *".parse::<Cron>().is_err());
+        assert!("0 5-3 * * *".parse::<Cron>().is_err());
+        assert!("x 3 * * *".parse::<Cron>().is_err());
+
+        let field = CronField::parse("5/20,1", 0, 59).unwrap();
+        assert_eq!(field.values, vec![5, 25, 45, 1]);
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        let next = |cron: &str, after: &str| {
+            cron.parse::<Cron>()
+                .unwrap()
+                .next_after(local(after))
+                .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+        };
+
+        assert_eq!(
+            next("0 3 * * *", "2026-10-15 02:59").as_deref(),
+            Some("2026-10-15 03:00")
+        );
+        assert_eq!(
+            next("0 3 * * *", "2026-10-15 03:00").as_deref(),
+            Some("2026-10-16 03:00")
+        );
+        assert_eq!(
+            next("*/15 * * * *", "2026-10-15 10:07").as_deref(),
+            Some("2026-10-15 10:15")
+        );
+        // 2026-10-15 is a Thursday.
+        assert_eq!(
+            next("30 6 * * 0", "2026-10-15 12:00").as_deref(),
+            Some("2026-10-18 06:30")
+        );
+        // The 1st of the month, or any Monday.
+        assert_eq!(
+            next("0 0 1 * 1", "2026-10-15 12:00").as_deref(),
+            Some("2026-10-19 00:00")
+        );
+        assert_eq!(
+            next("0 0 29 2 *", "2026-10-15 12:00").as_deref(),
+            Some("2028-02-29 00:00")
+        );
+        assert_eq!(next("0 0 31 2 *", "2026-10-15 12:00"), None);
+    }
+
+    #[test]
+    fn test_schedule_next_run() {
+        let now = local("2026-10-15 12:00");
+        let every = Schedule::Every(Duration::hours(6));
+        assert_eq!(every.next_run(None, now), Some(now));
+        assert_eq!(
+            every.next_run(Some(local("2026-10-15 11:00")), now),
+            Some(local("2026-10-15 17:00"))
+        );
+        // A run that took longer than the interval is followed straight away.
+        assert_eq!(
+            every.next_run(Some(local("2026-10-15 05:00")), now),
+            Some(now)
+        );
+
+        let cron = Schedule::Cron("0 3 * * *".parse().unwrap());
+        assert_eq!(cron.next_run(None, now), Some(local("2026-10-16 03:00")));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::error::BoxError;
+use clap::{ArgAction, Command};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// Every option can be set with an environment variable named after it, such
+/// as WLED_BACKUP_OUT_DIR for --out-dir.
+pub const ENV_PREFIX: &str = "WLED_BACKUP_";
+
+/// Names the settings file to use instead of the default one.
+pub const SETTINGS_FILE_ENV: &str = "WLED_BACKUP_CONFIG_FILE";
+
+/// Defaults for command line options, from a TOML file such as:
+///
+/// ```toml
+/// out_dir = "/backup/wled"
+/// search_secs = 10
+/// retries = 3
+/// timestamped = true
+/// webhook_url = "https://monitoring.example.com/hooks/wled"
+/// ```
+///
+/// Keys are the long option names, with `_` for `-`. Options given on the
+/// command line or in the environment take precedence.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    values: BTreeMap<String, Vec<String>>,
+}
+
+/// ~/.config/wled_backup/config.toml, or under $XDG_CONFIG_HOME.
+pub fn default_path() -> Option<PathBuf> {
+    let config = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("wled_backup").join("config.toml"))
+}
+
+/// A setting's value as command line arguments.
+fn arg_values(value: &toml::Value) -> Option<Vec<String>> {
+    match value {
+        toml::Value::String(value) => Some(vec![value.clone()]),
+        toml::Value::Integer(value) => Some(vec![value.to_string()]),
+        toml::Value::Float(value) => Some(vec![value.to_string()]),
+        toml::Value::Boolean(value) => Some(vec![value.to_string()]),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|value| arg_values(value)?.into_iter().next())
+            .collect(),
+        _ => None,
+    }
+}
+
+impl Settings {
+    pub fn parse(contents: &str) -> Result<Settings, BoxError> {
+        let table: toml::Table = toml::from_str(contents)?;
+        let mut values = BTreeMap::new();
+        for (key, value) in table {
+            let value = arg_values(&value).ok_or_else(|| format!("{key} isn't a plain value"))?;
+            values.insert(key, value);
+        }
+        Ok(Settings { values })
+    }
+
+    pub fn load(path: &Path) -> Result<Settings, BoxError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+        Self::parse(&contents)
+            .map_err(|e| format!("Failed to parse {}: {e}", path.display()).into())
+    }
+
+    /// The settings file named by $WLED_BACKUP_CONFIG_FILE, which must exist,
+    /// or the default one, if there is one.
+    pub fn load_default() -> Result<Settings, BoxError> {
+        if let Some(path) = std::env::var_os(SETTINGS_FILE_ENV) {
+            return Self::load(Path::new(&path));
+        }
+        match default_path() {
+            Some(path) if path.exists() => Self::load(&path),
+            _ => Ok(Settings::default()),
+        }
+    }
+
+    /// `command`, and all of its subcommands, with an environment variable
+    /// for each option and these settings as defaults. Fails on a setting
+    /// that isn't an option.
+    pub fn apply(&self, command: Command) -> Result<Command, String> {
+        let mut known = BTreeSet::new();
+        let command = self.apply_to(command, &mut known);
+        match self.values.keys().find(|key| !known.contains(*key)) {
+            Some(key) => Err(format!("Unknown setting {key}")),
+            None => Ok(command),
+        }
+    }
+
+    fn apply_to(&self, command: Command, known: &mut BTreeSet<String>) -> Command {
+        let mut command = command.mut_args(|arg| {
+            let Some(long) = arg.get_long() else {
+                return arg;
+            };
+            // -v and -q are counted, so they can't be given as a value.
+            if matches!(
+                arg.get_action(),
+                ArgAction::Count | ArgAction::Help | ArgAction::Version
+            ) {
+                return arg;
+            }
+            let key = long.replace('-', "_");
+            let env = format!("{ENV_PREFIX}{}", key.to_uppercase());
+            let arg = match self.values.get(&key) {
+                Some(values) => arg.default_values(values),
+                None => arg,
+            };
+            known.insert(key);
+            // Options naming their own variable, such as --pin, keep it.
+            if arg.get_env().is_some() {
+                return arg;
+            }
+            arg.env(env)
+        });
+        let names: Vec<String> = command
+            .get_subcommands()
+            .map(|subcommand| subcommand.get_name().to_string())
+            .collect();
+        for name in names {
+            command = command.mut_subcommand(name, |subcommand| self.apply_to(subcommand, known));
+        }
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+
+    fn command() -> Command {
+        Command::new("test")
+            .arg(
+                Arg::new("retries")
+                    .long("retries")
+                    .default_value("2")
+                    .global(true),
+            )
+            .arg(
+                Arg::new("verbose")
+                    .short('v')
+                    .long("verbose")
+                    .action(ArgAction::Count),
+            )
+            .subcommand(
+                Command::new("backup")
+                    .arg(
+                        Arg::new("timestamped")
+                            .long("timestamped")
+                            .action(ArgAction::SetTrue),
+                    )
+                    .arg(
+                        Arg::new("endpoints")
+                            .long("endpoints")
+                            .action(ArgAction::Append),
+                    ),
+            )
+    }
+
+    #[test]
+    fn test_settings_apply() {
+        let settings =
+            Settings::parse("retries = 5\ntimestamped = true\nendpoints = [\"info\", \"state\"]")
+                .unwrap();
+        let command = settings.apply(command()).unwrap();
+
+        let matches = command.clone().get_matches_from(["test", "backup"]);
+        assert_eq!(matches.get_one::<String>("retries").unwrap(), "5");
+        let (_, backup) = matches.subcommand().unwrap();
+        assert!(backup.get_flag("timestamped"));
+        let endpoints: Vec<&String> = backup.get_many("endpoints").unwrap().collect();
+        assert_eq!(endpoints, ["info", "state"]);
+
+        // The command line wins.
+        let matches = command.clone().get_matches_from(["test", "--retries", "1"]);
+        assert_eq!(matches.get_one::<String>("retries").unwrap(), "1");
+
+        let retries = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == "retries")
+            .unwrap();
+        assert_eq!(
+            retries.get_env(),
+            Some(std::ffi::OsStr::new("WLED_BACKUP_RETRIES"))
+        );
+    }
+
+    #[test]
+    fn test_settings_unknown() {
+        let settings = Settings::parse("verbose = 2").unwrap();
+        assert_eq!(
+            settings.apply(command()).unwrap_err(),
+            "Unknown setting verbose"
+        );
+        assert!(Settings::parse("devices = { porch = 1 }").is_err());
+        assert!(Settings::parse("out_dir = ").is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use chrono::Duration;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The unit files are named after this.
+pub const UNIT_NAME: &str = "wled-backup";
+
+/// Beside the units, where the secrets kept out of them go.
+pub const ENVIRONMENT_FILE: &str = "wled-backup.env";
+
+/// Quote an argument for a unit file's ExecStart line. '%' and '$' would
+/// otherwise be expanded by systemd.
+pub fn quote_arg(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    let plain = !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c == '\\' || c == ';');
+    if plain {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A time span as systemd writes them, such as "1d", "6h" or "90min".
+pub fn format_interval(interval: Duration) -> String {
+    let minutes = interval.num_minutes().max(1);
+    if minutes % (24 * 60) == 0 {
+        format!("{}d", minutes / (24 * 60))
+    } else if minutes % 60 == 0 {
+        format!("{}h", minutes / 60)
+    } else {
+        format!("{minutes}min")
+    }
+}
+
+/// The backup command to run, from the command line that ran `subcommand`:
+/// the same options, with `backup` in place of the subcommand and its own
+/// options, given in `own_options` with whether each takes a value, left
+/// out.
+pub fn backup_command(
+    args: &[String],
+    subcommand: &str,
+    own_options: &[(&str, bool)],
+) -> Vec<String> {
+    let mut command = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == subcommand {
+            command.push("backup".to_string());
+            continue;
+        }
+        let option = own_options.iter().find(|(name, _)| {
+            arg == name
+                || arg
+                    .strip_prefix(name)
+                    .is_some_and(|rest| rest.starts_with('='))
+        });
+        match option {
+            Some((name, true)) if arg == name => {
+                args.next();
+            }
+            Some(_) => {}
+            None => command.push(arg.clone()),
+        }
+    }
+    command
+}
+
+/// Options that hold secrets, which are kept out of the unit file, and the
+/// environment variables that give them instead.
+pub const SECRET_OPTIONS: [(&str, &str); 4] = [
+    ("--pin", "WLED_PIN"),
+    ("--http-password", "WLED_HTTP_PASSWORD"),
+    ("--s3-secret-access-key", "AWS_SECRET_ACCESS_KEY"),
+    ("--webdav-password", "WLED_WEBDAV_PASSWORD"),
+];
+
+/// Take the options holding secrets out of `command`, returning the command
+/// left and the environment variables to give them in instead.
+pub fn split_secrets(command: &[String]) -> (Vec<String>, Vec<(String, String)>) {
+    let mut rest = vec![];
+    let mut secrets = vec![];
+    let mut args = command.iter();
+    while let Some(arg) = args.next() {
+        let secret = SECRET_OPTIONS.iter().find_map(|(name, var)| {
+            if arg == name {
+                return Some((var, args.next().cloned().unwrap_or_default()));
+            }
+            let value = arg.strip_prefix(name)?.strip_prefix('=')?;
+            Some((var, value.to_string()))
+        });
+        match secret {
+            Some((var, value)) => secrets.push((var.to_string(), value)),
+            None => rest.push(arg.clone()),
+        }
+    }
+    (rest, secrets)
+}
+
+/// An EnvironmentFile= setting each variable.
+pub fn environment_file(vars: &[(String, String)]) -> String {
+    vars.iter()
+        .map(|(name, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("{name}=\"{value}\"\n")
+        })
+        .collect()
+}
+
+/// Write a file only its owner can read, as secrets need.
+pub fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // The mode only applies to a new file.
+        if path.exists() {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+/// A service that runs `command` once, from `working_dir`, with the
+/// variables in `env_file`, if any.
+pub fn service_unit(command: &[String], working_dir: &Path, env_file: Option<&Path>) -> String {
+    let exec: Vec<String> = command.iter().map(|arg| quote_arg(arg)).collect();
+    let environment = env_file
+        .map(|path| format!("EnvironmentFile={}\n", quote_arg(&path.to_string_lossy())))
+        .unwrap_or_default();
+    format!(
+        "[Unit]\n\
+         Description=Back up WLED devices\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         WorkingDirectory={}\n\
+         {environment}\
+         ExecStart={}\n",
+        quote_arg(&working_dir.to_string_lossy()),
+        exec.join(" ")
+    )
+}
+
+/// A timer that starts the service soon after boot, and then every
+/// `interval`.
+pub fn timer_unit(interval: Duration) -> String {
+    format!(
+        "[Unit]\n\
+         Description=Back up WLED devices every {interval}\n\
+         \n\
+         [Timer]\n\
+         OnBootSec=5min\n\
+         OnUnitActiveSec={interval}\n\
+         \n\
+         [Install]\n\
+         WantedBy=timers.target\n",
+        interval = format_interval(interval)
+    )
+}
+
+/// Where user units go: ~/.config/systemd/user, or under $XDG_CONFIG_HOME.
+pub fn user_unit_dir() -> Option<PathBuf> {
+    let config = match std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("systemd").join("user"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_quote_arg() {
+        assert_eq!(quote_arg("--pretty"), "--pretty");
+        assert_eq!(quote_arg("/backup/WLED lights"), "\"/backup/WLED lights\"");
+        assert_eq!(quote_arg("50%"), "50%%");
+        assert_eq!(quote_arg("$HOME"), "$$HOME");
+        assert_eq!(quote_arg("say \"hi\""), "\"say \\\"hi\\\"\"");
+        assert_eq!(quote_arg(""), "\"\"");
+    }
+
+    #[test]
+    fn test_format_interval() {
+        assert_eq!(format_interval(Duration::days(1)), "1d");
+        assert_eq!(format_interval(Duration::hours(6)), "6h");
+        assert_eq!(format_interval(Duration::hours(36)), "36h");
+        assert_eq!(format_interval(Duration::minutes(90)), "90min");
+    }
+
+    #[test]
+    fn test_backup_command() {
+        let own = [("--interval", true), ("--install", false)];
+        let args = strings(&[
+            "/usr/bin/wled-backup",
+            "-o",
+            "/backup",
+            "install-systemd",
+            "--interval",
+            "1d",
+            "--pretty",
+            "--install",
+            "--interval=6h",
+        ]);
+        assert_eq!(
+            backup_command(&args, "install-systemd", &own),
+            strings(&[
+                "/usr/bin/wled-backup",
+                "-o",
+                "/backup",
+                "backup",
+                "--pretty"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_split_secrets() {
+        let command = strings(&[
+            "/usr/bin/wled-backup",
+            "--pin",
+            "1234",
+            "backup",
+            "--webdav-password=a \"b\"",
+            "--pretty",
+        ]);
+        let (command, secrets) = split_secrets(&command);
+        assert_eq!(
+            command,
+            strings(&["/usr/bin/wled-backup", "backup", "--pretty"])
+        );
+        assert_eq!(
+            environment_file(&secrets),
+            "WLED_PIN=\"1234\"\nWLED_WEBDAV_PASSWORD=\"a \\\"b\\\"\"\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(ENVIRONMENT_FILE);
+        std::fs::write(&path, "old").unwrap();
+        write_private(&path, "WLED_PIN=\"1234\"\n").unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "WLED_PIN=\"1234\"\n"
+        );
+    }
+
+    #[test]
+    fn test_units() {
+        let command = strings(&["/usr/bin/wled-backup", "-o", "/backup", "backup"]);
+        let service = service_unit(&command, Path::new("/home/me"), None);
+        assert!(!service.contains("EnvironmentFile"), "{service}");
+        let env = Path::new("/home/me/.config/systemd/user/wled-backup.env");
+        let private = service_unit(&command, Path::new("/home/me"), Some(env));
+        assert!(private.contains(
+            "\nEnvironmentFile=/home/me/.config/systemd/user/wled-backup.env\nExecStart="
+        ));
+        assert!(service.contains("\nType=oneshot\n"), "{service}");
+        assert!(
+            service.contains("\nWorkingDirectory=/home/me\n"),
+            "{service}"
+        );
+        assert!(service.contains("\nExecStart=/usr/bin/wled-backup -o /backup backup\n"));
+
+        let timer = timer_unit(Duration::days(1));
+        assert!(timer.contains("\nOnUnitActiveSec=1d\n"), "{timer}");
+        assert!(timer.contains("\nWantedBy=timers.target\n"), "{timer}");
+    }
+}
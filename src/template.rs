@@ -0,0 +1,121 @@
+use crate::backup::DeviceHttp;
+use crate::device::DeviceOptions;
+use crate::diff::{FieldChange, diff_json};
+use crate::error::{BackupError, BoxError};
+use crate::http::Fetcher;
+use crate::redact::merge;
+use serde_json::Value;
+use std::net::IpAddr;
+use std::path::Path;
+use tracing::info;
+
+/// Read a template: a piece of cfg.json holding just the settings to change,
+/// such as {"if": {"ntp": {"host": "pool.ntp.org"}}}.
+pub fn load_template(path: &Path) -> Result<Value, BoxError> {
+    let contents =
+        std::fs::read(path).map_err(|e| format!("Failed to read {}: {e}", path.display()))?;
+    let template: Value = serde_json::from_slice(&contents)
+        .map_err(|e| format!("Invalid {}: {e}", path.display()))?;
+    if !template.is_object() {
+        return Err(format!(
+            "Invalid {}: a template must be a JSON object",
+            path.display()
+        )
+        .into());
+    }
+    Ok(template)
+}
+
+/// How merging `template` into `cfg` changes it.
+pub fn template_changes(cfg: &Value, template: &Value) -> Vec<FieldChange> {
+    let mut merged = cfg.clone();
+    merge(&mut merged, template.clone());
+    diff_json(cfg, &merged)
+}
+
+/// Merge `template` into a WLED's settings through /json/cfg, unless
+/// `dry_run`. Returns what changed, or would have. A WLED the template
+/// wouldn't change is left alone.
+pub async fn apply_template(
+    fetcher: &Fetcher,
+    ip: &IpAddr,
+    port: u16,
+    options: &DeviceOptions,
+    template: &Value,
+    dry_run: bool,
+) -> Result<Vec<FieldChange>, BackupError> {
+    let http = DeviceHttp::new(fetcher, ip, port, options);
+    http.unlock().await?;
+    let cfg: Value = serde_json::from_slice(&http.get("/cfg.json").await?)
+        .map_err(|e| BackupError::Invalid(format!("Invalid cfg.json: {e}")))?;
+    let changes = template_changes(&cfg, template);
+    if changes.is_empty() || dry_run {
+        return Ok(changes);
+    }
+
+    // WLED merges what it's sent into its own settings, so only the template
+    // is sent, and passwords it doesn't serve are left alone.
+    http.post_json("/json/cfg", template.to_string()).await?;
+    info!("changed {} settings on {ip}:{port}", changes.len());
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::mock_routes_server;
+    use serde_json::json;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_template_changes() {
+        let cfg = json!({
+            "id": {"name": "porch"},
+            "if": {"ntp": {"en": false, "host": "0.wled.pool.ntp.org"}},
+        });
+        let template = json!({"if": {"ntp": {"en": true, "host": "0.wled.pool.ntp.org"}}});
+        assert_eq!(
+            template_changes(&cfg, &template),
+            [FieldChange::Changed {
+                path: "if.ntp.en".to_string(),
+                old: json!(false),
+                new: json!(true),
+            }]
+        );
+
+        let template = json!({"if": {"mqtt": {"broker": "mqtt.local"}}});
+        assert_eq!(
+            template_changes(&cfg, &template),
+            [FieldChange::Added {
+                path: "if.mqtt".to_string(),
+                new: json!({"broker": "mqtt.local"}),
+            }]
+        );
+        assert_eq!(
+            template_changes(&cfg, &json!({"id": {"name": "porch"}})),
+            []
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_template() {
+        let cfg = r#"{"id":{"name":"porch"},"if":{"ntp":{"en":false}}}"#;
+        let routes = [("/cfg.json", cfg), ("/json/cfg", r#"{"success":true}"#)];
+        let server = mock_routes_server("127.0.0.1:162", &routes);
+        let (fetcher, ip) = (Fetcher::default(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let options = DeviceOptions::default();
+
+        let template = json!({"if": {"ntp": {"en": true}}});
+        let changes = apply_template(&fetcher, &ip, 162, &options, &template, false)
+            .await
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        let template = json!({"if": {"ntp": {"en": false}}});
+        let changes = apply_template(&fetcher, &ip, 162, &options, &template, true)
+            .await
+            .unwrap();
+        assert_eq!(changes, []);
+
+        server.join().unwrap();
+    }
+}
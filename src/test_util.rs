@@ -0,0 +1,200 @@
+use crate::device::Device;
+use mdns_sd::ServiceInfo;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Response, Server};
+
+// Mock ServiceInfo for testing
+pub fn mock_service_info(name: &str, ip: &str, port: u16) -> ServiceInfo {
+    ServiceInfo::new("_wled._tcp.local.", name, name, ip, port, None).unwrap()
+}
+
+pub fn mock_device(name: &str, ip: &str, port: u16) -> Device {
+    Device::from(&mock_service_info(name, ip, port))
+}
+
+/// A device with no presets saved yet.
+pub const PRESETS_BODY: &str = r#"{"0":{}}"#;
+
+pub fn cfg_body(hostname: &str) -> String {
+    format!(r#"{{"id":{{"name":"{}"}}}}"#, hostname)
+}
+
+/// How long a mock server waits for another request before shutting down.
+const IDLE: Duration = Duration::from_millis(250);
+
+pub fn mock_wled_server(
+    addr: &str,
+    cfg_body: &str,
+    presets_body: Option<&str>,
+) -> thread::JoinHandle<()> {
+    // Start server in a background thread
+
+    let cfg_body = cfg_body.to_string();
+    let presets_body = presets_body.map(|s| s.to_string());
+
+    let server = Server::http(addr).unwrap();
+    thread::spawn(move || {
+        // Wait as long as it takes for the first request, then serve until
+        // the client goes quiet.
+        let mut next = server.recv().ok();
+        while let Some(request) = next {
+            let url = request.url();
+            let response = if url.ends_with("/cfg.json") {
+                Response::from_string(cfg_body.clone())
+                // .with_header("Content-Type: application/json".parse().unwrap())
+            } else if url.ends_with("/presets.json") {
+                if let Some(ref presets) = presets_body {
+                    Response::from_string(presets.clone())
+                    // .with_header("Content-Type: application/json".parse().unwrap())
+                } else {
+                    Response::from_string("not found").with_status_code(404)
+                }
+            } else {
+                Response::from_string("not found").with_status_code(404)
+            };
+            let _ = request.respond(response);
+            next = server.recv_timeout(IDLE).ok().flatten();
+        }
+    })
+}
+
+/// Serve each body at exactly its URL, and 404 for anything else.
+pub fn mock_routes_server(addr: &str, routes: &[(&str, &str)]) -> thread::JoinHandle<()> {
+    let routes: Vec<(String, String)> = routes
+        .iter()
+        .map(|(url, body)| (url.to_string(), body.to_string()))
+        .collect();
+
+    let server = Server::http(addr).unwrap();
+    thread::spawn(move || {
+        let mut next = server.recv().ok();
+        while let Some(request) = next {
+            let response = match routes.iter().find(|(url, _)| url == request.url()) {
+                Some((_, body)) => Response::from_string(body.clone()),
+                None => Response::from_string("not found").with_status_code(404),
+            };
+            let _ = request.respond(response);
+            next = server.recv_timeout(IDLE).ok().flatten();
+        }
+    })
+}
+
+/// Serve each URL's bodies in turn, repeating the last, and 404 for anything
+/// else. For a device whose files change between requests.
+pub fn mock_sequence_server(addr: &str, routes: &[(&str, &[&str])]) -> thread::JoinHandle<()> {
+    let mut routes: Vec<(String, Vec<String>)> = routes
+        .iter()
+        .map(|(url, bodies)| {
+            (
+                url.to_string(),
+                bodies.iter().map(|b| b.to_string()).collect(),
+            )
+        })
+        .collect();
+
+    let server = Server::http(addr).unwrap();
+    thread::spawn(move || {
+        let mut next = server.recv().ok();
+        while let Some(request) = next {
+            let response = match routes.iter_mut().find(|(url, _)| url == request.url()) {
+                Some((_, bodies)) if bodies.len() > 1 => Response::from_string(bodies.remove(0)),
+                Some((_, bodies)) => Response::from_string(bodies[0].clone()),
+                None => Response::from_string("not found").with_status_code(404),
+            };
+            let _ = request.respond(response);
+            next = server.recv_timeout(IDLE).ok().flatten();
+        }
+    })
+}
+
+pub fn validate_response_file(expected_file: PathBuf, expected_content: &str) {
+    assert!(expected_file.exists());
+    let contents = fs::read_to_string(expected_file).unwrap();
+    assert_eq!(contents, expected_content);
+}
+
+pub fn validate_response_files(out_dir: &Path, hostname: &str) {
+    let cfg_path = out_dir.join(format!("{hostname}_cfg.json"));
+    let presets_path = out_dir.join(format!("{hostname}_presets.json"));
+
+    validate_response_file(cfg_path, &cfg_body(hostname));
+    validate_response_file(presets_path, PRESETS_BODY);
+}
+
+/// A server that answers every request with 200 OK, and returns each
+/// request's method, URL and body once the client goes quiet.
+pub fn mock_recording_server(addr: &str) -> thread::JoinHandle<Vec<(String, String, String)>> {
+    let server = Server::http(addr).unwrap();
+    thread::spawn(move || {
+        let mut requests = vec![];
+        let mut next = server.recv().ok();
+        while let Some(mut request) = next {
+            let mut body = String::new();
+            let _ = request.as_reader().read_to_string(&mut body);
+            requests.push((
+                request.method().to_string(),
+                request.url().to_string(),
+                body,
+            ));
+            let _ = request.respond(Response::from_string("OK"));
+            next = server.recv_timeout(IDLE).ok().flatten();
+        }
+        requests
+    })
+}
+
+/// A one-connection MQTT broker, just enough for publishing: it accepts the
+/// connection, acknowledges each publish, and returns the topics and payloads
+/// published once the client disconnects.
+pub fn mock_mqtt_broker(addr: &str) -> thread::JoinHandle<Vec<(String, String)>> {
+    use std::io::{Read, Write};
+
+    // A packet's type and flags, and the rest of it.
+    fn read_packet(stream: &mut std::net::TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut header = [0u8];
+        stream.read_exact(&mut header).ok()?;
+        let (mut length, mut shift) = (0usize, 0);
+        loop {
+            let mut byte = [0u8];
+            stream.read_exact(&mut byte).ok()?;
+            length |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).ok()?;
+        Some((header[0], body))
+    }
+
+    let listener = std::net::TcpListener::bind(addr).unwrap();
+    thread::spawn(move || {
+        let mut published = vec![];
+        let (mut stream, _) = listener.accept().unwrap();
+        while let Some((header, body)) = read_packet(&mut stream) {
+            match header >> 4 {
+                // CONNECT
+                1 => stream.write_all(&[0x20, 2, 0, 0]).unwrap(),
+                // PUBLISH
+                3 => {
+                    let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                    let topic = String::from_utf8_lossy(&body[2..2 + topic_len]).into_owned();
+                    let mut rest = &body[2 + topic_len..];
+                    if header & 0x06 != 0 {
+                        stream.write_all(&[0x40, 2, rest[0], rest[1]]).unwrap();
+                        rest = &rest[2..];
+                    }
+                    published.push((topic, String::from_utf8_lossy(rest).into_owned()));
+                }
+                // DISCONNECT
+                14 => break,
+                _ => {}
+            }
+        }
+        published
+    })
+}